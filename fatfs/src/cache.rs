@@ -0,0 +1,520 @@
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+use crate::fs::{ReadSeek, ReadWriteSeek};
+use crate::io::{IoBase, Read, ReadOnly, Seek, SeekFrom, Write};
+
+/// Statistics of a `BlockCache`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    writes: u64,
+    bytes_unchanged: u64,
+}
+
+impl CacheStats {
+    /// Number of block accesses served from the cache.
+    #[must_use]
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of block accesses that needed a block not present in the cache.
+    #[must_use]
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Number of bytes read from the underlying storage.
+    #[must_use]
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Number of bytes written to the underlying storage.
+    #[must_use]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Number of write requests issued to the underlying storage.
+    #[must_use]
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Number of bytes not written because they did not change, only counted in write tracking mode.
+    #[must_use]
+    pub fn bytes_unchanged(&self) -> u64 {
+        self.bytes_unchanged
+    }
+}
+
+struct Block {
+    // shorter than the block size if the storage ends inside the block
+    data: Vec<u8>,
+    // content of the storage, only kept in write tracking mode
+    original: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Copy, Clone)]
+struct WriteTracking {
+    sector_size: u64,
+    erase_block_size: u64,
+}
+
+/// A write-back LRU cache of fixed size blocks.
+///
+/// The cache is a storage adapter, it is supposed to be placed between the storage and the `FileSystem`. Blocks are
+/// read from the underlying storage on first access unless they are overwritten completely. Modified blocks are
+/// written back when they are evicted and on `flush`, contiguous modified blocks are written in one go.
+/// `FileSystem::unmount` flushes the storage so no data is lost when the filesystem is unmounted properly.
+///
+/// Flash memory is erased in big erase blocks, in write tracking mode (see `track_writes`) the cache tries to write
+/// as little as possible at the cost of reading every modified block first.
+pub struct BlockCache<IO> {
+    inner: IO,
+    block_size: u64,
+    capacity: usize,
+    tracking: Option<WriteTracking>,
+    // blocks by their offset on the storage
+    blocks: BTreeMap<u64, Block>,
+    // offsets of blocks by the time of the last access, the first one is the least recently used
+    lru: BTreeMap<u64, u64>,
+    time: u64,
+    pos: u64,
+    stats: CacheStats,
+}
+
+impl<IO: ReadWriteSeek> BlockCache<IO> {
+    /// Creates a cache holding up to `capacity` blocks of `block_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` or `capacity` is zero.
+    pub fn new(inner: IO, block_size: u32, capacity: usize) -> Self {
+        assert!(block_size > 0, "block size must not be zero");
+        assert!(capacity > 0, "capacity must not be zero");
+        Self {
+            inner,
+            block_size: u64::from(block_size),
+            capacity,
+            tracking: None,
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            time: 0,
+            pos: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Enables write tracking mode.
+    ///
+    /// Modified blocks are compared with the content of the storage and only the sectors that changed are written.
+    /// When a modified block is written back all modified blocks in the same erase block are written with it so
+    /// the storage does not have to rewrite the same erase block many times.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block size is not a multiple of `sector_size` or `erase_block_size` is not a multiple of the
+    /// block size.
+    #[must_use]
+    pub fn track_writes(mut self, sector_size: u32, erase_block_size: u32) -> Self {
+        let sector_size = u64::from(sector_size);
+        let erase_block_size = u64::from(erase_block_size);
+        assert!(
+            sector_size > 0 && self.block_size % sector_size == 0,
+            "block size must be a multiple of the sector size"
+        );
+        assert!(
+            erase_block_size % self.block_size == 0,
+            "erase block size must be a multiple of the block size"
+        );
+        self.tracking = Some(WriteTracking {
+            sector_size,
+            erase_block_size,
+        });
+        self
+    }
+
+    /// Returns statistics of the cache since it was created.
+    #[must_use]
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Writes back all modified blocks and returns the underlying storage.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the underlying storage if writing fails.
+    pub fn into_inner(mut self) -> Result<IO, IO::Error> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+
+    fn touch(&mut self, offset: u64) {
+        self.time += 1;
+        // unwrap is safe because only cached blocks are touched
+        let block = self.blocks.get_mut(&offset).unwrap();
+        self.lru.remove(&block.last_used);
+        block.last_used = self.time;
+        self.lru.insert(self.time, offset);
+    }
+
+    /// Makes sure the block at `offset` is cached, it is not read from the storage unless `load` is set
+    fn cache_block(&mut self, offset: u64, load: bool) -> Result<&mut Block, IO::Error> {
+        if self.blocks.contains_key(&offset) {
+            self.stats.hits += 1;
+            self.touch(offset);
+        } else {
+            self.stats.misses += 1;
+            if self.blocks.len() >= self.capacity {
+                self.evict()?;
+            }
+            let data = if load { self.read_block(offset)? } else { Vec::new() };
+            let original = if self.tracking.is_some() {
+                data.clone()
+            } else {
+                Vec::new()
+            };
+            self.time += 1;
+            let block = Block {
+                data,
+                original,
+                dirty: false,
+                last_used: self.time,
+            };
+            self.blocks.insert(offset, block);
+            self.lru.insert(self.time, offset);
+        }
+        // unwrap is safe because the block is cached now
+        Ok(self.blocks.get_mut(&offset).unwrap())
+    }
+
+    fn read_block(&mut self, offset: u64) -> Result<Vec<u8>, IO::Error> {
+        let mut data = vec![0; self.block_size as usize];
+        self.inner.seek(SeekFrom::Start(offset))?;
+        let mut len = 0;
+        while len < data.len() {
+            let n = self.inner.read(&mut data[len..])?;
+            if n == 0 {
+                // end of the storage
+                break;
+            }
+            len += n;
+        }
+        data.truncate(len);
+        self.stats.bytes_read += len as u64;
+        Ok(data)
+    }
+
+    fn evict(&mut self) -> Result<(), IO::Error> {
+        // unwrap is safe because the cache is full
+        let (&time, &offset) = self.lru.iter().next().unwrap();
+        if self.blocks[&offset].dirty {
+            self.write_back(offset)?;
+        }
+        self.lru.remove(&time);
+        self.blocks.remove(&offset);
+        Ok(())
+    }
+
+    /// Writes the modified block at `offset` together with the modified blocks following it, or in write tracking
+    /// mode with the modified blocks in the same erase block, returns the offset after the written region
+    fn write_back(&mut self, offset: u64) -> Result<u64, IO::Error> {
+        // offsets and data of contiguous writes
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        let mut written: Vec<u64> = Vec::new();
+        let end = if let Some(tracking) = self.tracking {
+            let start = offset - offset % tracking.erase_block_size;
+            let end = start + tracking.erase_block_size;
+            let sector_size = tracking.sector_size as usize;
+            for (&block_offset, block) in self.blocks.range(start..end) {
+                if !block.dirty {
+                    continue;
+                }
+                for (i, sector) in block.data.chunks(sector_size).enumerate() {
+                    let sector_start = i * sector_size;
+                    if block.original.get(sector_start..sector_start + sector.len()) == Some(sector) {
+                        self.stats.bytes_unchanged += sector.len() as u64;
+                    } else {
+                        push_run(&mut runs, block_offset + sector_start as u64, sector);
+                    }
+                }
+                written.push(block_offset);
+            }
+            end
+        } else {
+            let mut end = offset;
+            for (&block_offset, block) in self.blocks.range(offset..) {
+                if !block.dirty || block_offset != end {
+                    break;
+                }
+                push_run(&mut runs, block_offset, &block.data);
+                written.push(block_offset);
+                end += block.data.len() as u64;
+                if block.data.len() as u64 != self.block_size {
+                    // the next block cannot be contiguous
+                    break;
+                }
+            }
+            end
+        };
+        for (run_offset, data) in runs {
+            self.inner.seek(SeekFrom::Start(run_offset))?;
+            self.inner.write_all(&data)?;
+            self.stats.bytes_written += data.len() as u64;
+            self.stats.writes += 1;
+        }
+        // blocks stay dirty until they are written so a failed write is retried on the next flush
+        for block_offset in written {
+            // unwrap is safe because nothing was evicted in between
+            let block = self.blocks.get_mut(&block_offset).unwrap();
+            if self.tracking.is_some() {
+                block.original.clone_from(&block.data);
+            }
+            block.dirty = false;
+        }
+        Ok(end)
+    }
+}
+
+/// Appends data to the last run if it is contiguous or starts a new one
+fn push_run(runs: &mut Vec<(u64, Vec<u8>)>, offset: u64, data: &[u8]) {
+    match runs.last_mut() {
+        Some((run_offset, run)) if *run_offset + run.len() as u64 == offset => run.extend_from_slice(data),
+        _ => runs.push((offset, data.to_vec())),
+    }
+}
+
+impl<IO: ReadSeek> BlockCache<ReadOnly<IO>> {
+    /// Creates a cache for storage that can only be read, for use with `FileSystem::new_read_only`.
+    ///
+    /// Nothing is ever written back so the storage does not have to implement `Write`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` or `capacity` is zero.
+    pub fn read_only(inner: IO, block_size: u32, capacity: usize) -> Self {
+        Self::new(ReadOnly::new(inner), block_size, capacity)
+    }
+}
+
+impl<IO: IoBase> IoBase for BlockCache<IO> {
+    type Error = IO::Error;
+}
+
+impl<IO: ReadWriteSeek> Read for BlockCache<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let offset = self.pos - self.pos % self.block_size;
+        let start = (self.pos - offset) as usize;
+        let block = self.cache_block(offset, true)?;
+        if start >= block.data.len() {
+            // end of the storage
+            return Ok(0);
+        }
+        let n = buf.len().min(block.data.len() - start);
+        buf[..n].copy_from_slice(&block.data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<IO: ReadWriteSeek> Write for BlockCache<IO> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let offset = self.pos - self.pos % self.block_size;
+        let start = (self.pos - offset) as usize;
+        let n = buf.len().min(self.block_size as usize - start);
+        // a block that is overwritten completely does not have to be read unless it is compared later
+        let load = self.tracking.is_some() || n as u64 != self.block_size;
+        let block = self.cache_block(offset, load)?;
+        if block.data.len() < start + n {
+            block.data.resize(start + n, 0);
+        }
+        block.data[start..start + n].copy_from_slice(&buf[..n]);
+        block.dirty = true;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        let mut next = 0;
+        loop {
+            let dirty_offset = self
+                .blocks
+                .range(next..)
+                .find(|(_, block)| block.dirty)
+                .map(|(&offset, _)| offset);
+            match dirty_offset {
+                Some(offset) => next = self.write_back(offset)?,
+                None => break,
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+impl<IO: ReadWriteSeek> Seek for BlockCache<IO> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.pos = match pos {
+            SeekFrom::Start(x) => x,
+            // let the storage validate the position
+            SeekFrom::Current(_) => {
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+                self.inner.seek(pos)?
+            }
+            SeekFrom::End(_) => self.inner.seek(pos)?,
+        };
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::StdIoWrapper;
+    use std::cell::Cell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    fn cache(capacity: usize) -> BlockCache<StdIoWrapper<Cursor<Vec<u8>>>> {
+        let data = (0..64).collect::<Vec<u8>>();
+        BlockCache::new(StdIoWrapper::new(Cursor::new(data)), 16, capacity)
+    }
+
+    #[test]
+    fn read_hits_cached_blocks() {
+        let mut cache = cache(4);
+        let mut buf = [0; 4];
+        cache.seek(SeekFrom::Start(18)).unwrap();
+        cache.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [18, 19, 20, 21]);
+        cache.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [22, 23, 24, 25]);
+        assert_eq!(cache.stats().misses(), 1);
+        assert_eq!(cache.stats().hits(), 1);
+        assert_eq!(cache.stats().bytes_read(), 16);
+        // end of the storage
+        cache.seek(SeekFrom::Start(64)).unwrap();
+        assert_eq!(cache.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_only_storage() {
+        let data = (0..64).collect::<Vec<u8>>();
+        let mut cache = BlockCache::read_only(StdIoWrapper::new(Cursor::new(&data[..])), 16, 2);
+        let mut buf = [0; 4];
+        cache.seek(SeekFrom::Start(30)).unwrap();
+        cache.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [30, 31, 32, 33]);
+        assert_eq!(cache.stats().misses(), 2);
+        // the write stays in the cache until it is evicted or flushed
+        cache.write_all(&[0xFF]).unwrap();
+        assert!(cache.flush().is_err());
+    }
+
+    #[test]
+    fn writes_are_written_back() {
+        let mut cache = cache(2);
+        cache.seek(SeekFrom::Start(14)).unwrap();
+        cache.write_all(&[0xFF; 4]).unwrap();
+        assert_eq!(cache.stats().bytes_written(), 0);
+        // evicts the first block, the second one is written with it because it is contiguous
+        cache.seek(SeekFrom::Start(40)).unwrap();
+        cache.write_all(&[0xEE; 2]).unwrap();
+        assert_eq!(cache.stats().bytes_written(), 32);
+        // a whole block does not have to be read
+        cache.seek(SeekFrom::Start(48)).unwrap();
+        cache.write_all(&[0xDD; 16]).unwrap();
+        assert_eq!(cache.stats().bytes_read(), 48);
+        let data = cache.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(data[13..19], [13, 0xFF, 0xFF, 0xFF, 0xFF, 18]);
+        assert_eq!(data[39..43], [39, 0xEE, 0xEE, 42]);
+        assert_eq!(data[48..], [0xDD; 16]);
+    }
+
+    /// Storage that fails every write while `fail` is set
+    struct FailingWrites {
+        inner: Cursor<Vec<u8>>,
+        fail: Rc<Cell<bool>>,
+    }
+
+    impl std::io::Read for FailingWrites {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl std::io::Write for FailingWrites {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.fail.get() {
+                return Err(std::io::Error::other("write failed"));
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for FailingWrites {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn failed_writes_are_retried() {
+        let fail = Rc::new(Cell::new(true));
+        let storage = FailingWrites {
+            inner: Cursor::new(vec![0; 64]),
+            fail: Rc::clone(&fail),
+        };
+        let mut cache = BlockCache::new(StdIoWrapper::new(storage), 16, 1);
+        cache.write_all(&[0xFF; 4]).unwrap();
+        assert!(cache.flush().is_err());
+        // evicting the block fails too and keeps it cached
+        cache.seek(SeekFrom::Start(32)).unwrap();
+        assert!(cache.write_all(&[0xEE]).is_err());
+        fail.set(false);
+        cache.flush().unwrap();
+        let data = cache.into_inner().unwrap().into_inner().inner.into_inner();
+        assert_eq!(data[0..5], [0xFF, 0xFF, 0xFF, 0xFF, 0]);
+    }
+
+    #[test]
+    fn tracking_skips_unchanged_sectors() {
+        // sectors of 4 bytes, erase blocks of 2 cache blocks
+        let mut cache = cache(8).track_writes(4, 32);
+        cache.seek(SeekFrom::Start(0)).unwrap();
+        cache.write_all(&[0, 1, 2, 3, 4, 5, 0xFF]).unwrap();
+        // a whole block is read anyway so it can be compared
+        cache.seek(SeekFrom::Start(48)).unwrap();
+        cache.write_all(&(48..64).collect::<Vec<u8>>()).unwrap();
+        cache.seek(SeekFrom::Start(20)).unwrap();
+        cache.write_all(&[0xEE]).unwrap();
+        cache.flush().unwrap();
+        let stats = cache.stats();
+        // changed sectors in the same erase block are written together
+        assert_eq!(stats.bytes_written(), 4 + 4);
+        assert_eq!(stats.writes(), 2);
+        assert_eq!(stats.bytes_unchanged(), 32 + 16 - 8);
+        // writing the same data again does not write anything
+        cache.seek(SeekFrom::Start(20)).unwrap();
+        cache.write_all(&[0xEE]).unwrap();
+        cache.flush().unwrap();
+        assert_eq!(cache.stats().bytes_written(), 8);
+        let data = cache.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(data[4..8], [4, 5, 0xFF, 7]);
+        assert_eq!(data[20], 0xEE);
+    }
+}
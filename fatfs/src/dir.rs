@@ -1,10 +1,12 @@
-#[cfg(all(not(feature = "std"), feature = "alloc", feature = "lfn"))]
-use alloc::vec::Vec;
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::{string::String, vec, vec::Vec};
 use core::num;
 use core::str;
 #[cfg(feature = "lfn")]
 use core::{iter, slice};
 
+#[cfg(feature = "alloc")]
+use crate::dir_entry::index_key;
 use crate::dir_entry::{
     DirEntry, DirEntryData, DirFileEntryData, DirLfnEntryData, FileAttributes, ShortName, DIR_ENTRY_SIZE,
 };
@@ -13,7 +15,7 @@ use crate::dir_entry::{LFN_ENTRY_LAST_FLAG, LFN_PART_LEN};
 use crate::dir_entry::{SFN_PADDING, SFN_SIZE};
 use crate::error::{Error, IoError};
 use crate::file::File;
-use crate::fs::{DiskSlice, FileSystem, FsIoAdapter, OemCpConverter, ReadWriteSeek};
+use crate::fs::{write_zeros, DiskSlice, FileSystem, FsIoAdapter, OemCpConverter, ReadWriteSeek};
 use crate::io::{self, IoBase, Read, Seek, SeekFrom, Write};
 use crate::time::TimeProvider;
 
@@ -94,13 +96,58 @@ impl<IO: ReadWriteSeek, TP, OCC> Seek for DirRawStream<'_, IO, TP, OCC> {
     }
 }
 
-fn split_path(path: &str) -> (&str, Option<&str>) {
+pub(crate) fn split_path(path: &str) -> (&str, Option<&str>) {
     let trimmed_path = path.trim_matches('/');
     trimmed_path.find('/').map_or((trimmed_path, None), |n| {
         (&trimmed_path[..n], Some(&trimmed_path[n + 1..]))
     })
 }
 
+#[cfg(feature = "std")]
+pub(crate) type NameMap<K, V> = std::collections::HashMap<K, V>;
+#[cfg(feature = "std")]
+type NameSet<T> = std::collections::HashSet<T>;
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+pub(crate) type NameMap<K, V> = alloc::collections::BTreeMap<K, V>;
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+type NameSet<T> = alloc::collections::BTreeSet<T>;
+
+/// Directory indexes keyed by the first cluster of the directory, `None` is the FAT12/16 root directory.
+#[cfg(feature = "alloc")]
+pub(crate) type DirIndexes = NameMap<Option<u32>, DirIndex>;
+
+/// In-memory index of directory entries used when `FsOptions::dir_index` is enabled.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+pub(crate) struct DirIndex {
+    // long and short names converted by `index_key` mapped to the offset of the first entry (LFN or SFN)
+    names: NameMap<String, u64>,
+    // raw short names used for short name generation
+    short_names: NameSet<[u8; SFN_SIZE]>,
+}
+
+#[cfg(feature = "alloc")]
+impl DirIndex {
+    fn insert<IO: ReadWriteSeek, TP, OCC: OemCpConverter>(&mut self, e: &DirEntry<IO, TP, OCC>) {
+        let (lfn_key, sfn_key) = e.index_keys();
+        for key in lfn_key.into_iter().chain(core::iter::once(sfn_key)) {
+            // the first entry wins like when scanning the directory
+            self.names.entry(key).or_insert(e.offset_range.0);
+        }
+        self.short_names.insert(*e.raw_short_name());
+    }
+
+    fn remove<IO: ReadWriteSeek, TP, OCC: OemCpConverter>(&mut self, e: &DirEntry<IO, TP, OCC>) {
+        let (lfn_key, sfn_key) = e.index_keys();
+        for key in lfn_key.into_iter().chain(core::iter::once(sfn_key)) {
+            if self.names.get(&key) == Some(&e.offset_range.0) {
+                self.names.remove(&key);
+            }
+        }
+        self.short_names.remove(e.raw_short_name());
+    }
+}
+
 enum DirEntryOrShortName<'a, IO: ReadWriteSeek, TP, OCC> {
     DirEntry(DirEntry<'a, IO, TP, OCC>),
     ShortName([u8; SFN_SIZE]),
@@ -130,6 +177,19 @@ impl<'a, IO: ReadWriteSeek, TP, OCC> Dir<'a, IO, TP, OCC> {
 
 impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, TP, OCC> {
     fn find_entry(
+        &self,
+        name: &str,
+        is_dir: Option<bool>,
+        short_name_gen: Option<&mut ShortNameGenerator>,
+    ) -> Result<DirEntry<'a, IO, TP, OCC>, Error<IO::Error>> {
+        #[cfg(feature = "alloc")]
+        if self.fs.options.dir_index {
+            return self.find_indexed_entry(name, is_dir, short_name_gen);
+        }
+        self.scan_for_entry(name, is_dir, short_name_gen)
+    }
+
+    fn scan_for_entry(
         &self,
         name: &str,
         is_dir: Option<bool>,
@@ -139,16 +199,7 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
             let e = r?;
             // compare name ignoring case
             if e.eq_name(name) {
-                // check if file or directory is expected
-                if is_dir.is_some() && Some(e.is_dir()) != is_dir {
-                    if e.is_dir() {
-                        error!("Is a directory");
-                    } else {
-                        error!("Not a directory");
-                    }
-                    return Err(Error::InvalidInput);
-                }
-                return Ok(e);
+                return Self::check_entry_type(e, is_dir);
             }
             // update short name generator state
             if let Some(ref mut gen) = short_name_gen {
@@ -158,6 +209,81 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
         Err(Error::NotFound) //("No such file or directory"))
     }
 
+    #[cfg(feature = "alloc")]
+    fn find_indexed_entry(
+        &self,
+        name: &str,
+        is_dir: Option<bool>,
+        short_name_gen: Option<&mut ShortNameGenerator>,
+    ) -> Result<DirEntry<'a, IO, TP, OCC>, Error<IO::Error>> {
+        let key = index_key(name);
+        let mut short_name_gen = short_name_gen;
+        let offset_opt = self.with_index(|index| {
+            let offset_opt = index.names.get(&key).copied();
+            if let (None, Some(gen)) = (offset_opt, short_name_gen.as_mut()) {
+                // update short name generator state
+                gen.add_existing_candidates(|short_name| index.short_names.contains(short_name));
+            }
+            offset_opt
+        })?;
+        let Some(offset) = offset_opt else {
+            return Err(Error::NotFound);
+        };
+        // read the entry at the indexed position
+        let mut stream = self.stream.clone();
+        stream.seek(SeekFrom::Start(offset))?;
+        match DirIter::new(stream, self.fs, true).next() {
+            Some(Ok(e)) if e.offset_range.0 == offset && e.eq_name(name) => Self::check_entry_type(e, is_dir),
+            Some(Err(err)) => Err(err),
+            _ => {
+                // the directory was modified bypassing the index
+                warn!("Directory index is out of date, rebuilding it");
+                self.fs.dir_indexes.borrow_mut().remove(&self.stream.first_cluster());
+                self.scan_for_entry(name, is_dir, short_name_gen)
+            }
+        }
+    }
+
+    /// Runs `f` with the index of this directory, the index is built if it does not exist yet
+    #[cfg(feature = "alloc")]
+    fn with_index<R, F: FnOnce(&mut DirIndex) -> R>(&self, f: F) -> Result<R, Error<IO::Error>> {
+        let id = self.stream.first_cluster();
+        if !self.fs.dir_indexes.borrow().contains_key(&id) {
+            let mut index = DirIndex::default();
+            for r in self.iter() {
+                index.insert(&r?);
+            }
+            self.fs.dir_indexes.borrow_mut().insert(id, index);
+        }
+        let mut indexes = self.fs.dir_indexes.borrow_mut();
+        // unwrap is safe because the index was inserted above
+        Ok(f(indexes.get_mut(&id).unwrap()))
+    }
+
+    /// Runs `f` with the index of this directory if it was built
+    #[cfg(feature = "alloc")]
+    fn update_index<F: FnOnce(&mut DirIndex)>(&self, f: F) {
+        if let Some(index) = self.fs.dir_indexes.borrow_mut().get_mut(&self.stream.first_cluster()) {
+            f(index);
+        }
+    }
+
+    fn check_entry_type(
+        e: DirEntry<'a, IO, TP, OCC>,
+        is_dir: Option<bool>,
+    ) -> Result<DirEntry<'a, IO, TP, OCC>, Error<IO::Error>> {
+        // check if file or directory is expected
+        if is_dir.is_some() && Some(e.is_dir()) != is_dir {
+            if e.is_dir() {
+                error!("Is a directory");
+            } else {
+                error!("Not a directory");
+            }
+            return Err(Error::InvalidInput);
+        }
+        Ok(e)
+    }
+
     #[allow(clippy::type_complexity)]
     pub(crate) fn find_volume_entry(&self) -> Result<Option<DirEntry<'a, IO, TP, OCC>>, Error<IO::Error>> {
         for r in DirIter::new(self.stream.clone(), self.fs, false) {
@@ -169,6 +295,20 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
         Ok(None)
     }
 
+    /// Renames the volume entry or creates a new one if missing, only valid for the root directory
+    pub(crate) fn set_volume_entry(&self, volume_label: [u8; SFN_SIZE]) -> Result<(), Error<IO::Error>> {
+        if let Some(e) = self.find_volume_entry()? {
+            let mut disk = self.fs.disk.borrow_mut();
+            disk.seek(io::SeekFrom::Start(e.entry_pos))?;
+            e.data.renamed(volume_label).serialize(&mut *disk)?;
+            return Ok(());
+        }
+        let volume_entry = self.create_sfn_entry(volume_label, FileAttributes::VOLUME_ID, None);
+        let (mut stream, _) = self.alloc_sfn_entry()?;
+        volume_entry.serialize(&mut stream)?;
+        Ok(())
+    }
+
     fn check_for_existence(
         &self,
         name: &str,
@@ -429,6 +569,14 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
             stream.seek(SeekFrom::Current(-i64::from(DIR_ENTRY_SIZE)))?;
             data.serialize(&mut stream)?;
         }
+        #[cfg(feature = "alloc")]
+        {
+            self.update_index(|index| index.remove(&e));
+            // the clusters can be reused by a new directory
+            if e.is_dir() {
+                self.fs.dir_indexes.borrow_mut().remove(&e.first_cluster());
+            }
+        }
         Ok(())
     }
 
@@ -538,24 +686,168 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
             stream.seek(SeekFrom::Current(-i64::from(DIR_ENTRY_SIZE)))?;
             data.serialize(&mut stream)?;
         }
+        #[cfg(feature = "alloc")]
+        self.update_index(|index| index.remove(&e));
         // save new directory entry
         let sfn_entry = e.data.renamed(short_name);
         dst_dir.write_entry(dst_name, sfn_entry)?;
         Ok(())
     }
 
+    /// Rewrites the directory without the holes left by removed entries, the order of entries is kept.
+    ///
+    /// New entries reuse the holes so they end up in unpredictable positions, which matters for devices that
+    /// process the entries in their raw order. The directory is not shrunk.
+    /// Make sure there is no `File` or `Dir` instance for any entry in this directory or filesystem corruption
+    /// can happen.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    #[cfg(feature = "alloc")]
+    pub fn compact(&self) -> Result<(), Error<IO::Error>> {
+        self.rewrite_entries(|_| {})
+    }
+
+    /// Rewrites the directory sorted by a key without the holes left by removed entries.
+    ///
+    /// The sort is stable. `.`, `..` and the volume label entries are kept at the start.
+    /// Make sure there is no `File` or `Dir` instance for any entry in this directory or filesystem corruption
+    /// can happen.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    #[cfg(feature = "alloc")]
+    pub fn sort_by_key<K, F>(&self, f: F) -> Result<(), Error<IO::Error>>
+    where
+        K: Ord,
+        F: FnMut(&DirEntry<'a, IO, TP, OCC>) -> K,
+    {
+        self.rewrite_entries(|entries| entries.sort_by_key(f))
+    }
+
+    #[cfg(feature = "alloc")]
+    fn rewrite_entries<F>(&self, order: F) -> Result<(), Error<IO::Error>>
+    where
+        F: FnOnce(&mut [DirEntry<'a, IO, TP, OCC>]),
+    {
+        // split entries that must stay at the start from the rest
+        let mut pinned = Vec::new();
+        let mut entries = Vec::new();
+        for r in DirIter::new(self.stream.clone(), self.fs, false) {
+            let e = r?;
+            if e.data.is_volume() || e.data.name()[0] == b'.' {
+                pinned.push(e);
+            } else {
+                entries.push(e);
+            }
+        }
+        order(&mut entries);
+        let old_len = self.used_len()?;
+        let mut stream = self.stream.clone();
+        // copy raw entries including LFN entries, they stay valid because the checksum does not depend on position
+        let mut data = Vec::new();
+        for e in pinned.iter().chain(entries.iter()) {
+            let (begin, end) = e.offset_range;
+            let start = data.len();
+            data.resize(start + (end - begin) as usize, 0);
+            stream.seek(SeekFrom::Start(begin))?;
+            stream.read_exact(&mut data[start..])?;
+        }
+        // write entries back and clear the rest, a zeroed entry marks the end of the directory
+        stream.seek(SeekFrom::Start(0))?;
+        stream.write_all(&data)?;
+        write_zeros(&mut stream, old_len - data.len() as u64)?;
+        stream.flush()?;
+        // entries moved so the index is built again when needed
+        self.fs.dir_indexes.borrow_mut().remove(&self.stream.first_cluster());
+        Ok(())
+    }
+
+    /// Returns the raw directory entries up to the end of the directory, including deleted and LFN entries.
+    ///
+    /// Together with `set_raw_entries` it can be used to restore the directory if `compact` or `sort_by_key` was
+    /// interrupted.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    #[cfg(feature = "alloc")]
+    pub fn raw_entries(&self) -> Result<Vec<u8>, Error<IO::Error>> {
+        let mut data = vec![0; self.used_len()? as usize];
+        let mut stream = self.stream.clone();
+        stream.seek(SeekFrom::Start(0))?;
+        stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Replaces the raw directory entries with ones returned by `raw_entries`, entries after them are cleared.
+    ///
+    /// Make sure there is no `File` or `Dir` instance for any entry in this directory or filesystem corruption
+    /// can happen.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if the length of `data` is not a multiple of the entry size.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    #[cfg(feature = "alloc")]
+    pub fn set_raw_entries(&self, data: &[u8]) -> Result<(), Error<IO::Error>> {
+        if data.len() % DIR_ENTRY_SIZE as usize != 0 {
+            return Err(Error::InvalidInput);
+        }
+        let old_len = self.used_len()?;
+        let mut stream = self.stream.clone();
+        stream.seek(SeekFrom::Start(0))?;
+        stream.write_all(data)?;
+        write_zeros(&mut stream, old_len.saturating_sub(data.len() as u64))?;
+        stream.flush()?;
+        self.fs.dir_indexes.borrow_mut().remove(&self.stream.first_cluster());
+        Ok(())
+    }
+
+    /// Length of the used space including deleted entries
+    #[cfg(feature = "alloc")]
+    fn used_len(&self) -> Result<u64, Error<IO::Error>> {
+        let mut stream = self.stream.clone();
+        stream.seek(SeekFrom::Start(0))?;
+        let mut len: u64 = 0;
+        loop {
+            match DirEntryData::deserialize(&mut stream) {
+                Ok(raw_entry) if raw_entry.is_end() => break,
+                Ok(_) => len += u64::from(DIR_ENTRY_SIZE),
+                Err(Error::UnexpectedEof) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(len)
+    }
+
     fn find_free_entries(&self, num_entries: u32) -> Result<DirRawStream<'a, IO, TP, OCC>, Error<IO::Error>> {
         let mut stream = self.stream.clone();
+        // root directory of FAT12/FAT16 has a fixed size and cannot grow
+        let max_entries = match self.stream {
+            DirRawStream::Root(_) => self.fs.max_root_dir_entries().map(u32::from),
+            DirRawStream::File(_) => None,
+        };
         let mut first_free: u32 = 0;
         let mut num_free: u32 = 0;
         let mut i: u32 = 0;
         loop {
+            if matches!(max_entries, Some(max) if i >= max) {
+                return Err(Error::NotEnoughSpace);
+            }
             let raw_entry = DirEntryData::deserialize(&mut stream)?;
             if raw_entry.is_end() {
                 // first unused entry - all remaining space can be used
                 if num_free == 0 {
                     first_free = i;
                 }
+                if matches!(max_entries, Some(max) if first_free + num_entries > max) {
+                    return Err(Error::NotEnoughSpace);
+                }
                 let pos = u64::from(first_free * DIR_ENTRY_SIZE);
                 stream.seek(io::SeekFrom::Start(pos))?;
                 return Ok(stream);
@@ -661,7 +953,7 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
         let start_abs_pos = end_abs_pos - u64::from(DIR_ENTRY_SIZE);
         // return new logical entry descriptor
         let short_name = ShortName::new(raw_entry.name());
-        Ok(DirEntry {
+        let e = DirEntry {
             data: raw_entry,
             short_name,
             #[cfg(feature = "lfn")]
@@ -669,7 +961,10 @@ impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Dir<'a, IO, T
             fs: self.fs,
             entry_pos: start_abs_pos,
             offset_range: (start_pos, end_pos),
-        })
+        };
+        #[cfg(feature = "alloc")]
+        self.update_index(|index| index.insert(&e));
+        Ok(e)
     }
 }
 
@@ -1233,6 +1528,24 @@ impl ShortNameGenerator {
         self.check_for_short_prefix_collision(short_name);
     }
 
+    /// Updates the state like `add_existing` called for every short name in the directory but only checks the
+    /// names this generator can produce
+    #[cfg(feature = "alloc")]
+    fn add_existing_candidates<F: Fn(&[u8; SFN_SIZE]) -> bool>(&mut self, exists: F) {
+        let short_name = self.short_name;
+        if exists(&short_name) {
+            self.add_existing(&short_name);
+        }
+        for num in 0..10 {
+            for with_chksum in [false, true] {
+                let candidate = self.build_prefixed_name(num, with_chksum);
+                if exists(&candidate) {
+                    self.add_existing(&candidate);
+                }
+            }
+        }
+    }
+
     fn check_for_long_prefix_collision(&mut self, short_name: &[u8; SFN_SIZE]) {
         // check for long prefix form collision (TEXTFI~1.TXT)
         let long_prefix_len = 6.min(self.basename_len);
@@ -530,7 +530,6 @@ impl DirEntryEditor {
 /// A FAT directory entry.
 ///
 /// `DirEntry` is returned by `DirIter` when reading a directory.
-#[derive(Clone)]
 pub struct DirEntry<'a, IO: ReadWriteSeek, TP, OCC> {
     pub(crate) data: DirFileEntryData,
     pub(crate) short_name: ShortName,
@@ -541,6 +540,21 @@ pub struct DirEntry<'a, IO: ReadWriteSeek, TP, OCC> {
     pub(crate) fs: &'a FileSystem<IO, TP, OCC>,
 }
 
+// Note: derive cannot be used because of invalid bounds. See: https://github.com/rust-lang/rust/issues/26925
+impl<IO: ReadWriteSeek, TP, OCC> Clone for DirEntry<'_, IO, TP, OCC> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            short_name: self.short_name.clone(),
+            #[cfg(feature = "lfn")]
+            lfn_utf16: self.lfn_utf16.clone(),
+            entry_pos: self.entry_pos,
+            offset_range: self.offset_range,
+            fs: self.fs,
+        }
+    }
+}
+
 #[allow(clippy::len_without_is_empty)]
 impl<'a, IO: ReadWriteSeek, TP, OCC: OemCpConverter> DirEntry<'a, IO, TP, OCC> {
     /// Returns short file name.
@@ -606,10 +620,29 @@ impl<'a, IO: ReadWriteSeek, TP, OCC: OemCpConverter> DirEntry<'a, IO, TP, OCC> {
         self.data.is_file()
     }
 
-    pub(crate) fn first_cluster(&self) -> Option<u32> {
+    /// Returns the first cluster of the data, entries sharing the data (hardlinks) have the same first cluster.
+    ///
+    /// `None` is returned for empty files.
+    #[must_use]
+    pub fn first_cluster(&self) -> Option<u32> {
         self.data.first_cluster(self.fs.fat_type())
     }
 
+    /// Counts clusters allocated for the data.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::CorruptedFileSystem` will be returned if the cluster chain is broken or contains a loop.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn cluster_count(&self) -> Result<u32, Error<IO::Error>> {
+        match self.first_cluster() {
+            Some(n) => self.fs.count_chain_clusters(n),
+            None => Ok(0),
+        }
+    }
+
     fn editor(&self) -> DirEntryEditor {
         DirEntryEditor::new(self.data.clone(), self.entry_pos)
     }
@@ -714,6 +747,36 @@ impl<'a, IO: ReadWriteSeek, TP, OCC: OemCpConverter> DirEntry<'a, IO, TP, OCC> {
 
         self.short_name.eq_ignore_case(name, &self.fs.options.oem_cp_converter)
     }
+
+    /// Names matched by `eq_name` converted to the form used by `index_key`
+    #[cfg(feature = "alloc")]
+    pub(crate) fn index_keys(&self) -> (Option<String>, String) {
+        #[cfg(feature = "lfn")]
+        let lfn_key = self.long_file_name_as_ucs2_units().and_then(|lfn| {
+            char::decode_utf16(lfn.iter().copied())
+                .collect::<Result<String, _>>()
+                .ok()
+                .map(|name| index_key(&name))
+        });
+        #[cfg(not(feature = "lfn"))]
+        let lfn_key = None;
+
+        let oem_cp_converter = &self.fs.options.oem_cp_converter;
+        let sfn_key = self
+            .short_name
+            .as_bytes()
+            .iter()
+            .map(|c| oem_cp_converter.decode(*c))
+            .flat_map(char_to_uppercase)
+            .collect();
+        (lfn_key, sfn_key)
+    }
+}
+
+/// Converts a name to the key of the directory index, names that only differ in case have the same key
+#[cfg(feature = "alloc")]
+pub(crate) fn index_key(name: &str) -> String {
+    name.chars().flat_map(char_to_uppercase).collect()
 }
 
 impl<IO: ReadWriteSeek, TP, OCC> fmt::Debug for DirEntry<'_, IO, TP, OCC> {
@@ -88,6 +88,15 @@ pub trait IoError: core::fmt::Debug {
     fn is_interrupted(&self) -> bool;
     fn new_unexpected_eof_error() -> Self;
     fn new_write_zero_error() -> Self;
+
+    /// Returns the error for a write to storage mounted read-only, by default the same as `new_write_zero_error`.
+    #[must_use]
+    fn new_read_only_error() -> Self
+    where
+        Self: Sized,
+    {
+        Self::new_write_zero_error()
+    }
 }
 
 impl<T: core::fmt::Debug + IoError> IoError for Error<T> {
@@ -134,4 +143,8 @@ impl IoError for std::io::Error {
     fn new_write_zero_error() -> Self {
         Self::new(std::io::ErrorKind::WriteZero, "failed to write whole buffer")
     }
+
+    fn new_read_only_error() -> Self {
+        Self::new(std::io::ErrorKind::PermissionDenied, "storage is mounted read-only")
+    }
 }
@@ -0,0 +1,247 @@
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::{vec, vec::Vec};
+
+use crate::error::{Error, IoError};
+use crate::fs::FsStatusFlags;
+
+/// Sectors of the main boot region: boot sector, 8 extended boot sectors, OEM parameters, a reserved sector and
+/// the checksum sector. The backup boot region follows it.
+pub(crate) const BOOT_REGION_SECTORS: u32 = 12;
+
+/// Sectors covered by the boot region checksum.
+pub(crate) const CHECKSUMMED_SECTORS: u32 = 11;
+
+const FS_NAME: &[u8; 8] = b"EXFAT   ";
+const JUMP_BOOT: [u8; 3] = [0xEB, 0x76, 0x90];
+const BOOT_SIGNATURE: u16 = 0xAA55;
+const FS_REVISION: u16 = 0x0100;
+
+/// Offset of `VolumeSerialNumber` in the boot sector, covered by the checksum.
+pub(crate) const VOLUME_SERIAL_NUMBER_OFFSET: u64 = 100;
+/// Offset of `VolumeFlags` in the boot sector, the field (like `PercentInUse`) is not covered by the checksum so it
+/// can be updated in place.
+pub(crate) const VOLUME_FLAGS_OFFSET: u64 = 106;
+const VOLUME_FLAGS_DIRTY: u16 = 1 << 1;
+const VOLUME_FLAGS_MEDIA_FAILURE: u16 = 1 << 2;
+const PERCENT_IN_USE_OFFSET: usize = 112;
+
+/// Values of the exFAT boot sector.
+///
+/// Offsets and lengths are in sectors unless stated otherwise.
+#[derive(Debug, Clone)]
+pub(crate) struct BootSector {
+    pub(crate) volume_length: u64,
+    pub(crate) fat_offset: u32,
+    pub(crate) fat_length: u32,
+    pub(crate) cluster_heap_offset: u32,
+    pub(crate) cluster_count: u32,
+    pub(crate) root_dir_first_cluster: u32,
+    pub(crate) volume_serial_number: u32,
+    pub(crate) volume_flags: u16,
+    pub(crate) bytes_per_sector_shift: u8,
+    pub(crate) sectors_per_cluster_shift: u8,
+    pub(crate) number_of_fats: u8,
+}
+
+impl BootSector {
+    pub(crate) fn deserialize<E: IoError>(buf: &[u8; 512]) -> Result<Self, Error<E>> {
+        if &buf[3..11] != FS_NAME {
+            error!("Not an exFAT volume");
+            return Err(Error::CorruptedFileSystem);
+        }
+        if u16::from_le_bytes([buf[510], buf[511]]) != BOOT_SIGNATURE {
+            error!("Invalid boot sector signature");
+            return Err(Error::CorruptedFileSystem);
+        }
+        // replaces the BPB of FAT so FAT implementations do not mount the volume
+        if buf[11..64].iter().any(|b| *b != 0) {
+            error!("Invalid boot sector, BPB area is not zeroed");
+            return Err(Error::CorruptedFileSystem);
+        }
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]);
+        let mut volume_length = [0_u8; 8];
+        volume_length.copy_from_slice(&buf[72..80]);
+        let boot = Self {
+            volume_length: u64::from_le_bytes(volume_length),
+            fat_offset: u32_at(80),
+            fat_length: u32_at(84),
+            cluster_heap_offset: u32_at(88),
+            cluster_count: u32_at(92),
+            root_dir_first_cluster: u32_at(96),
+            volume_serial_number: u32_at(100),
+            volume_flags: u16::from_le_bytes([buf[106], buf[107]]),
+            bytes_per_sector_shift: buf[108],
+            sectors_per_cluster_shift: buf[109],
+            number_of_fats: buf[110],
+        };
+        boot.validate()?;
+        Ok(boot)
+    }
+
+    pub(crate) fn serialize(&self) -> [u8; 512] {
+        let mut buf = [0_u8; 512];
+        buf[0..3].copy_from_slice(&JUMP_BOOT);
+        buf[3..11].copy_from_slice(FS_NAME);
+        // partition offset is left zero which means it should be ignored
+        buf[72..80].copy_from_slice(&self.volume_length.to_le_bytes());
+        buf[80..84].copy_from_slice(&self.fat_offset.to_le_bytes());
+        buf[84..88].copy_from_slice(&self.fat_length.to_le_bytes());
+        buf[88..92].copy_from_slice(&self.cluster_heap_offset.to_le_bytes());
+        buf[92..96].copy_from_slice(&self.cluster_count.to_le_bytes());
+        buf[96..100].copy_from_slice(&self.root_dir_first_cluster.to_le_bytes());
+        buf[100..104].copy_from_slice(&self.volume_serial_number.to_le_bytes());
+        buf[104..106].copy_from_slice(&FS_REVISION.to_le_bytes());
+        buf[106..108].copy_from_slice(&self.volume_flags.to_le_bytes());
+        buf[108] = self.bytes_per_sector_shift;
+        buf[109] = self.sectors_per_cluster_shift;
+        buf[110] = self.number_of_fats;
+        // drive select
+        buf[111] = 0x80;
+        // usage is not tracked
+        buf[PERCENT_IN_USE_OFFSET] = 0xFF;
+        buf[510..512].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+        buf
+    }
+
+    fn validate<E: IoError>(&self) -> Result<(), Error<E>> {
+        if !(9..=12).contains(&self.bytes_per_sector_shift) {
+            error!("Invalid bytes per sector shift {}", self.bytes_per_sector_shift);
+            return Err(Error::CorruptedFileSystem);
+        }
+        // clusters can be at most 32 MiB
+        if u32::from(self.bytes_per_sector_shift) + u32::from(self.sectors_per_cluster_shift) > 25 {
+            error!("Invalid sectors per cluster shift {}", self.sectors_per_cluster_shift);
+            return Err(Error::CorruptedFileSystem);
+        }
+        if !(1..=2).contains(&self.number_of_fats) {
+            error!("Invalid number of FATs {}", self.number_of_fats);
+            return Err(Error::CorruptedFileSystem);
+        }
+        if self.fat_offset < BOOT_REGION_SECTORS * 2
+            || self.bytes_from_sectors(u64::from(self.fat_length)) < (u64::from(self.cluster_count) + 2) * 4
+            || u64::from(self.cluster_heap_offset)
+                < u64::from(self.fat_offset) + u64::from(self.fat_length) * u64::from(self.number_of_fats)
+            || self.cluster_count > 0xFFFF_FFF5
+            || u64::from(self.cluster_heap_offset) + (u64::from(self.cluster_count) << self.sectors_per_cluster_shift)
+                > self.volume_length
+        {
+            error!("Invalid volume layout {:?}", self);
+            return Err(Error::CorruptedFileSystem);
+        }
+        if self.root_dir_first_cluster < 2 || self.root_dir_first_cluster > self.cluster_count + 1 {
+            error!("Invalid root directory cluster {}", self.root_dir_first_cluster);
+            return Err(Error::CorruptedFileSystem);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bytes_per_sector(&self) -> u32 {
+        1 << self.bytes_per_sector_shift
+    }
+
+    pub(crate) fn cluster_size(&self) -> u32 {
+        1 << (self.bytes_per_sector_shift + self.sectors_per_cluster_shift)
+    }
+
+    pub(crate) fn bytes_from_sectors(&self, sectors: u64) -> u64 {
+        sectors << self.bytes_per_sector_shift
+    }
+
+    /// Byte offset of the FAT in use, the second FAT is only used by `TexFAT`
+    pub(crate) fn fat_pos(&self) -> u64 {
+        let active_fat = u64::from(self.volume_flags & 1);
+        self.bytes_from_sectors(u64::from(self.fat_offset) + active_fat * u64::from(self.fat_length))
+    }
+
+    pub(crate) fn status_flags(&self) -> FsStatusFlags {
+        FsStatusFlags {
+            dirty: self.volume_flags & VOLUME_FLAGS_DIRTY != 0,
+            io_error: self.volume_flags & VOLUME_FLAGS_MEDIA_FAILURE != 0,
+        }
+    }
+
+    pub(crate) fn clear_status_flags(&mut self) {
+        self.volume_flags &= !(VOLUME_FLAGS_DIRTY | VOLUME_FLAGS_MEDIA_FAILURE);
+    }
+
+    pub(crate) fn volume_flags_with_dirty(&self, dirty: bool) -> u16 {
+        if dirty {
+            self.volume_flags | VOLUME_FLAGS_DIRTY
+        } else {
+            self.volume_flags & !VOLUME_FLAGS_DIRTY
+        }
+    }
+}
+
+/// Checksum of the first 11 sectors of a boot region, stored repeatedly in the 12th sector.
+pub(crate) fn boot_checksum(region: &[u8]) -> u32 {
+    region.iter().enumerate().fold(0_u32, |sum, (i, byte)| {
+        // volume flags and percent in use change without updating the checksum
+        if i == 106 || i == 107 || i == PERCENT_IN_USE_OFFSET {
+            sum
+        } else {
+            sum.rotate_right(1).wrapping_add(u32::from(*byte))
+        }
+    })
+}
+
+/// Builds a whole boot region, `sector_size` bytes for each of its sectors.
+pub(crate) fn boot_region(boot: &BootSector) -> Vec<u8> {
+    let sector_size = boot.bytes_per_sector() as usize;
+    let mut region = vec![0_u8; sector_size * BOOT_REGION_SECTORS as usize];
+    region[..512].copy_from_slice(&boot.serialize());
+    // extended boot sectors only have a signature at the end
+    for sector in 1..=8 {
+        let end = (sector + 1) * sector_size;
+        region[end - 4..end].copy_from_slice(&[0x00, 0x00, 0x55, 0xAA]);
+    }
+    let checksummed = sector_size * CHECKSUMMED_SECTORS as usize;
+    let checksum = boot_checksum(&region[..checksummed]).to_le_bytes();
+    for chunk in region[checksummed..].chunks_exact_mut(4) {
+        chunk.copy_from_slice(&checksum);
+    }
+    region
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot_sector() -> BootSector {
+        BootSector {
+            volume_length: 0x10_0000,
+            fat_offset: 128,
+            fat_length: 256,
+            cluster_heap_offset: 512,
+            cluster_count: 0x7FF0,
+            root_dir_first_cluster: 5,
+            volume_serial_number: 0x1234_5678,
+            volume_flags: 0,
+            bytes_per_sector_shift: 9,
+            sectors_per_cluster_shift: 5,
+            number_of_fats: 1,
+        }
+    }
+
+    #[test]
+    fn boot_sector_roundtrip() {
+        let boot = boot_sector();
+        let buf = boot.serialize();
+        let read = BootSector::deserialize::<()>(&buf).unwrap();
+        assert_eq!(read.cluster_count, boot.cluster_count);
+        assert_eq!(read.cluster_size(), 16 * 1024);
+        assert_eq!(read.fat_pos(), 128 * 512);
+    }
+
+    #[test]
+    fn checksum_ignores_volume_flags() {
+        let mut boot = boot_sector();
+        let region = boot_region(&boot);
+        boot.volume_flags = boot.volume_flags_with_dirty(true);
+        let dirty_region = boot_region(&boot);
+        assert_ne!(region[106], dirty_region[106]);
+        assert_eq!(region[512 * 11..], dirty_region[512 * 11..]);
+    }
+}
@@ -0,0 +1,1107 @@
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::{string::String, vec, vec::Vec};
+use core::cell::RefCell;
+use core::fmt;
+
+use crate::dir::{split_path, NameMap};
+use crate::dir_entry::FileAttributes;
+use crate::error::{Error, IoError};
+use crate::fs::ReadWriteSeek;
+use crate::io::{Seek, SeekFrom};
+use crate::time::{DateTime, TimeProvider};
+
+use super::file::File;
+use super::FileSystem;
+
+pub(crate) const ENTRY_SIZE: usize = 32;
+
+/// A raw directory entry with its position on the storage.
+pub(crate) type RawEntry = (u64, [u8; ENTRY_SIZE]);
+
+/// Bit of the entry type set for entries in use, cleared when an entry set is deleted.
+pub(crate) const IN_USE: u8 = 0x80;
+
+const TYPE_END: u8 = 0x00;
+pub(crate) const TYPE_BITMAP: u8 = 0x81;
+pub(crate) const TYPE_UPCASE: u8 = 0x82;
+pub(crate) const TYPE_LABEL: u8 = 0x83;
+const TYPE_FILE: u8 = 0x85;
+const TYPE_STREAM: u8 = 0xC0;
+const TYPE_NAME: u8 = 0xC1;
+
+/// Bit of the entry type set for secondary entries.
+const SECONDARY: u8 = 0x40;
+
+const FLAG_ALLOCATION_POSSIBLE: u8 = 1 << 0;
+const FLAG_NO_FAT_CHAIN: u8 = 1 << 1;
+
+const NAME_ENTRY_CHARS: usize = 15;
+const MAX_NAME_LEN: usize = 255;
+
+/// In-memory index of a directory used when `FsOptions::dir_index` is enabled.
+#[derive(Default)]
+pub(crate) struct DirIndex {
+    // up-cased names mapped to the offset of the file entry in the directory
+    names: NameMap<Vec<u16>, u64>,
+    // all entries before this offset are in use so free entries are only searched after it
+    free_from: u64,
+}
+
+/// Entries of a file or directory: a file entry, a stream extension entry and file name entries.
+///
+/// The entries are kept as read from the volume so entries this library does not know (like vendor extensions) are
+/// written back unchanged.
+#[derive(Clone)]
+pub(crate) struct EntrySet {
+    entries: Vec<[u8; ENTRY_SIZE]>,
+    // position of each entry on the storage, empty if the set has not been written yet
+    positions: Vec<u64>,
+}
+
+impl EntrySet {
+    fn new(name: &[u16], name_hash: u16, attributes: FileAttributes, now: DateTime) -> Self {
+        let name_entries = (name.len() + NAME_ENTRY_CHARS - 1) / NAME_ENTRY_CHARS;
+        let mut file = [0_u8; ENTRY_SIZE];
+        file[0] = TYPE_FILE;
+        let mut stream = [0_u8; ENTRY_SIZE];
+        stream[0] = TYPE_STREAM;
+        stream[1] = FLAG_ALLOCATION_POSSIBLE;
+
+        let mut set = Self {
+            entries: Vec::with_capacity(name_entries + 2),
+            positions: Vec::new(),
+        };
+        set.entries.push(file);
+        set.entries.push(stream);
+        set.set_name(name, name_hash);
+        set.set_attributes(attributes);
+        set.set_created(now);
+        set.set_modified(now);
+        set.set_accessed(now);
+        set
+    }
+
+    /// Copies the set with a different name, used for renaming and hardlinks.
+    fn with_name(&self, name: &[u16], name_hash: u16) -> Self {
+        let mut set = Self {
+            entries: self.entries[..2].to_vec(),
+            positions: Vec::new(),
+        };
+        set.set_name(name, name_hash);
+        set
+    }
+
+    fn set_name(&mut self, name: &[u16], name_hash: u16) {
+        self.entries.truncate(2);
+        for chunk in name.chunks(NAME_ENTRY_CHARS) {
+            let mut entry = [0_u8; ENTRY_SIZE];
+            entry[0] = TYPE_NAME;
+            for (i, unit) in chunk.iter().enumerate() {
+                entry[2 + i * 2..4 + i * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.entries.push(entry);
+        }
+        // safe cast: names have at most 255 characters so there are at most 18 name entries
+        self.entries[0][1] = (self.entries.len() - 1) as u8;
+        // safe cast: names have at most 255 characters
+        self.entries[1][3] = name.len() as u8;
+        self.entries[1][4..6].copy_from_slice(&name_hash.to_le_bytes());
+    }
+
+    /// Checks an entry set read from the volume.
+    fn from_entries<E: IoError>(entries: Vec<[u8; ENTRY_SIZE]>, positions: Vec<u64>) -> Result<Self, Error<E>> {
+        let set = Self { entries, positions };
+        let name_len = usize::from(set.entries[1][3]);
+        let name_entries = set.entries[2..]
+            .iter()
+            .take_while(|entry| entry[0] == TYPE_NAME)
+            .count();
+        if set.entries[1][0] != TYPE_STREAM || name_len == 0 || name_entries * NAME_ENTRY_CHARS < name_len {
+            error!("Invalid entry set");
+            return Err(Error::CorruptedFileSystem);
+        }
+        if set.checksum() != u16::from_le_bytes([set.entries[0][2], set.entries[0][3]]) {
+            error!("Invalid entry set checksum");
+            return Err(Error::CorruptedFileSystem);
+        }
+        Ok(set)
+    }
+
+    fn checksum(&self) -> u16 {
+        let mut checksum = 0_u16;
+        for (i, entry) in self.entries.iter().enumerate() {
+            for (j, byte) in entry.iter().enumerate() {
+                // the checksum field itself
+                if i == 0 && (j == 2 || j == 3) {
+                    continue;
+                }
+                checksum = checksum.rotate_right(1).wrapping_add(u16::from(*byte));
+            }
+        }
+        checksum
+    }
+
+    fn name(&self) -> Vec<u16> {
+        let len = usize::from(self.entries[1][3]);
+        self.entries[2..]
+            .iter()
+            .flat_map(|entry| entry[2..].chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])))
+            .take(len)
+            .collect()
+    }
+
+    fn name_hash(&self) -> u16 {
+        u16::from_le_bytes([self.entries[1][4], self.entries[1][5]])
+    }
+
+    fn attributes(&self) -> FileAttributes {
+        FileAttributes::from_bits_truncate(self.entries[0][4])
+    }
+
+    fn set_attributes(&mut self, attributes: FileAttributes) {
+        self.entries[0][4..6].copy_from_slice(&u16::from(attributes.bits()).to_le_bytes());
+    }
+
+    pub(crate) fn is_dir(&self) -> bool {
+        self.attributes().contains(FileAttributes::DIRECTORY)
+    }
+
+    fn stream_u64(&self, offset: usize) -> u64 {
+        let mut bytes = [0_u8; 8];
+        bytes.copy_from_slice(&self.entries[1][offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    pub(crate) fn first_cluster(&self) -> Option<u32> {
+        let stream = &self.entries[1];
+        Some(u32::from_le_bytes([stream[20], stream[21], stream[22], stream[23]])).filter(|cluster| *cluster != 0)
+    }
+
+    pub(crate) fn no_fat_chain(&self) -> bool {
+        self.entries[1][1] & FLAG_NO_FAT_CHAIN != 0
+    }
+
+    pub(crate) fn valid_data_length(&self) -> u64 {
+        self.stream_u64(8)
+    }
+
+    pub(crate) fn data_length(&self) -> u64 {
+        self.stream_u64(24)
+    }
+
+    pub(crate) fn set_stream(&mut self, first_cluster: Option<u32>, no_fat_chain: bool, valid: u64, len: u64) {
+        let stream = &mut self.entries[1];
+        stream[1] = FLAG_ALLOCATION_POSSIBLE | if no_fat_chain { FLAG_NO_FAT_CHAIN } else { 0 };
+        stream[8..16].copy_from_slice(&valid.to_le_bytes());
+        stream[20..24].copy_from_slice(&first_cluster.unwrap_or(0).to_le_bytes());
+        stream[24..32].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// Decodes a timestamp at `offset` of the file entry with 10 ms increments at `increment_offset`.
+    fn timestamp(&self, offset: usize, increment_offset: Option<usize>) -> DateTime {
+        let file = &self.entries[0];
+        let time = u16::from_le_bytes([file[offset], file[offset + 1]]);
+        let date = u16::from_le_bytes([file[offset + 2], file[offset + 3]]);
+        DateTime::decode(date, time, increment_offset.map_or(0, |i| file[i]))
+    }
+
+    fn set_timestamp(&mut self, offset: usize, increment_offset: Option<usize>, date_time: DateTime) {
+        let (time, increment) = date_time.time.encode();
+        let file = &mut self.entries[0];
+        file[offset..offset + 2].copy_from_slice(&time.to_le_bytes());
+        file[offset + 2..offset + 4].copy_from_slice(&date_time.date.encode().to_le_bytes());
+        if let Some(i) = increment_offset {
+            file[i] = increment;
+        }
+    }
+
+    fn created(&self) -> DateTime {
+        self.timestamp(8, Some(20))
+    }
+
+    pub(crate) fn set_created(&mut self, date_time: DateTime) {
+        self.set_timestamp(8, Some(20), date_time);
+    }
+
+    fn modified(&self) -> DateTime {
+        self.timestamp(12, Some(21))
+    }
+
+    pub(crate) fn set_modified(&mut self, date_time: DateTime) {
+        self.set_timestamp(12, Some(21), date_time);
+    }
+
+    fn accessed(&self) -> DateTime {
+        self.timestamp(16, None)
+    }
+
+    pub(crate) fn set_accessed(&mut self, date_time: DateTime) {
+        self.set_timestamp(16, None, date_time);
+    }
+
+    /// Updates the checksum and writes the entries to their positions.
+    pub(crate) fn write<IO: ReadWriteSeek, TP>(&mut self, fs: &FileSystem<IO, TP>) -> Result<(), Error<IO::Error>> {
+        let checksum = self.checksum();
+        self.entries[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        // the file entry is written last so the set is not in use until it is complete
+        for (entry, pos) in self.entries.iter().zip(&self.positions).rev() {
+            fs.write_at(*pos, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the entries as not in use.
+    fn delete<IO: ReadWriteSeek, TP>(&self, fs: &FileSystem<IO, TP>) -> Result<(), Error<IO::Error>> {
+        for (entry, pos) in self.entries.iter().zip(&self.positions) {
+            fs.write_at(*pos, &[entry[0] & !IN_USE])?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for EntrySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntrySet")
+            .field("name", &String::from_utf16_lossy(&self.name()))
+            .field("attributes", &self.attributes())
+            .field("first_cluster", &self.first_cluster())
+            .field("data_length", &self.data_length())
+            .field("positions", &self.positions)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Checks a file name and converts it to UTF-16.
+fn name_units<E: IoError>(name: &str) -> Result<Vec<u16>, Error<E>> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    if units.is_empty() || units.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidFileNameLength);
+    }
+    if name == "." || name == ".." {
+        return Err(Error::InvalidInput);
+    }
+    if units
+        .iter()
+        .any(|unit| *unit < 0x20 || "\"*/:<>?\\|".encode_utf16().any(|c| c == *unit))
+    {
+        return Err(Error::UnsupportedFileNameCharacter);
+    }
+    Ok(units)
+}
+
+/// An exFAT directory entry.
+///
+/// `DirEntry` is returned by `DirIter` when reading a directory.
+pub struct DirEntry<'a, IO: ReadWriteSeek, TP> {
+    set: EntrySet,
+    // offset of the file entry in the directory
+    offset: u64,
+    fs: &'a FileSystem<IO, TP>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, IO: ReadWriteSeek, TP> DirEntry<'a, IO, TP> {
+    /// Returns the file name of this entry.
+    #[must_use]
+    pub fn file_name(&self) -> String {
+        String::from_utf16_lossy(&self.set.name())
+    }
+
+    /// Returns file attributes.
+    #[must_use]
+    pub fn attributes(&self) -> FileAttributes {
+        self.set.attributes()
+    }
+
+    /// Checks if entry belongs to directory.
+    #[must_use]
+    pub fn is_dir(&self) -> bool {
+        self.set.is_dir()
+    }
+
+    /// Checks if entry belongs to regular file.
+    #[must_use]
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    /// Returns the first cluster of the file or `None` if it is empty.
+    ///
+    /// Hardlinks share the first cluster with the file they point to.
+    #[must_use]
+    pub fn first_cluster(&self) -> Option<u32> {
+        self.set.first_cluster()
+    }
+
+    /// Counts clusters allocated for the data.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::CorruptedFileSystem` will be returned if the cluster chain is broken or contains a loop.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn cluster_count(&self) -> Result<u32, Error<IO::Error>> {
+        match self.first_cluster() {
+            Some(first_cluster) => {
+                self.fs
+                    .count_clusters(first_cluster, self.set.no_fat_chain(), self.set.data_length())
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Returns file size or 0 for a directory.
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        if self.is_dir() {
+            0
+        } else {
+            self.set.data_length()
+        }
+    }
+
+    /// Returns file creation date and time.
+    #[must_use]
+    pub fn created(&self) -> DateTime {
+        self.set.created()
+    }
+
+    /// Returns file last access date and time.
+    #[must_use]
+    pub fn accessed(&self) -> DateTime {
+        self.set.accessed()
+    }
+
+    /// Returns file last modification date and time.
+    #[must_use]
+    pub fn modified(&self) -> DateTime {
+        self.set.modified()
+    }
+
+    /// Returns `File` struct for this entry.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this is not a file.
+    #[must_use]
+    pub fn to_file(&self) -> File<'a, IO, TP> {
+        assert!(!self.is_dir(), "Not a file entry");
+        File::from_entry(self.set.clone(), self.fs)
+    }
+
+    /// Returns `Dir` struct for this entry.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if this is not a directory.
+    #[must_use]
+    pub fn to_dir(&self) -> Dir<'a, IO, TP> {
+        assert!(self.is_dir(), "Not a directory entry");
+        Dir::new(File::from_entry(self.set.clone(), self.fs))
+    }
+
+    fn eq_name(&self, name: &[u16], name_hash: u16) -> bool {
+        self.set.name_hash() == name_hash && self.fs.upcase().eq_ignore_case(&self.set.name(), name)
+    }
+}
+
+// Note: derive cannot be used because of invalid bounds. See: https://github.com/rust-lang/rust/issues/26925
+impl<IO: ReadWriteSeek, TP> Clone for DirEntry<'_, IO, TP> {
+    fn clone(&self) -> Self {
+        Self {
+            set: self.set.clone(),
+            offset: self.offset,
+            fs: self.fs,
+        }
+    }
+}
+
+impl<IO: ReadWriteSeek, TP> fmt::Debug for DirEntry<'_, IO, TP> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.set.fmt(f)
+    }
+}
+
+/// An exFAT directory.
+///
+/// This struct is created by the `open_dir` or `create_dir` methods on `Dir`.
+/// The root directory is returned by the `root_dir` method on `FileSystem`.
+pub struct Dir<'a, IO: ReadWriteSeek, TP> {
+    // changed when the directory grows
+    stream: RefCell<File<'a, IO, TP>>,
+}
+
+impl<'a, IO: ReadWriteSeek, TP> Dir<'a, IO, TP> {
+    pub(crate) fn new(stream: File<'a, IO, TP>) -> Self {
+        Self {
+            stream: RefCell::new(stream),
+        }
+    }
+
+    fn fs(&self) -> &'a FileSystem<IO, TP> {
+        self.stream.borrow().fs()
+    }
+
+    fn index_key(&self, name: &[u16]) -> Vec<u16> {
+        let upcase = self.fs().upcase();
+        name.iter().map(|unit| upcase.upcase(*unit)).collect()
+    }
+
+    /// Runs `f` with the index of this directory, the index is built if it does not exist yet. Returns `None` if
+    /// indexing is disabled.
+    fn with_index<R, F: FnOnce(&mut DirIndex) -> R>(&self, f: F) -> Result<Option<R>, Error<IO::Error>> {
+        let fs = self.fs();
+        let Some(id) = self.stream.borrow().first_cluster().filter(|_| fs.dir_index) else {
+            return Ok(None);
+        };
+        if !fs.dir_indexes.borrow().contains_key(&id) {
+            let mut index = DirIndex::default();
+            for r in self.iter() {
+                let e = r?;
+                index.names.entry(self.index_key(&e.set.name())).or_insert(e.offset);
+            }
+            fs.dir_indexes.borrow_mut().insert(id, index);
+        }
+        let mut indexes = fs.dir_indexes.borrow_mut();
+        // unwrap is safe because the index was inserted above
+        Ok(Some(f(indexes.get_mut(&id).unwrap())))
+    }
+
+    /// Runs `f` with the index of this directory if it was built.
+    fn update_index<F: FnOnce(&mut DirIndex)>(&self, f: F) {
+        if let Some(id) = self.stream.borrow().first_cluster() {
+            if let Some(index) = self.fs().dir_indexes.borrow_mut().get_mut(&id) {
+                f(index);
+            }
+        }
+    }
+
+    /// Drops the index of this directory so it is built again when needed.
+    fn drop_index(&self) {
+        if let Some(id) = self.stream.borrow().first_cluster() {
+            self.fs().dir_indexes.borrow_mut().remove(&id);
+        }
+    }
+
+    /// Creates directory entries iterator.
+    #[must_use]
+    #[allow(clippy::iter_without_into_iter)]
+    pub fn iter(&self) -> DirIter<'a, IO, TP> {
+        let mut stream = self.stream.borrow().clone();
+        let _ = stream.seek(SeekFrom::Start(0));
+        DirIter { stream, err: false }
+    }
+
+    /// Returns the first entry for which `f` returns true with its position on the storage.
+    pub(crate) fn find_raw_entry<F: Fn(&[u8; ENTRY_SIZE]) -> bool>(
+        &self,
+        f: F,
+    ) -> Result<Option<RawEntry>, Error<IO::Error>> {
+        let mut stream = self.stream.borrow().clone();
+        stream.seek(SeekFrom::Start(0))?;
+        while let Some((pos, entry)) = stream.read_entry()? {
+            if entry[0] == TYPE_END {
+                break;
+            }
+            if f(&entry) {
+                return Ok(Some((pos, entry)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds `count` consecutive free entries, the directory grows if there are not enough of them.
+    ///
+    /// Returns the offset of the first entry in the directory and the positions of the entries on the storage.
+    pub(crate) fn alloc_entries(&self, count: usize) -> Result<(u64, Vec<u64>), Error<IO::Error>> {
+        let start = self.with_index(|index| index.free_from)?.unwrap_or(0);
+        let mut stream = self.stream.borrow().clone();
+        stream.seek(SeekFrom::Start(start))?;
+        let mut free = Vec::with_capacity(count);
+        let mut run_offset = start;
+        let mut first_free = None;
+        loop {
+            let offset = stream.offset();
+            let (pos, entry) = self.read_or_extend(&mut stream)?;
+            if entry[0] & IN_USE != 0 {
+                free.clear();
+                continue;
+            }
+            if free.is_empty() {
+                run_offset = offset;
+            }
+            let first_free = *first_free.get_or_insert(offset);
+            free.push(pos);
+            if free.len() == count {
+                // free entries skipped because the run was too short are still free
+                let end = offset + ENTRY_SIZE as u64;
+                self.update_index(|index| {
+                    index.free_from = if first_free == run_offset { end } else { first_free };
+                });
+                return Ok((run_offset, free));
+            }
+        }
+    }
+
+    /// Reads the entry at the offset of `stream`, the directory grows if the stream is at its end.
+    fn read_or_extend(&self, stream: &mut File<'a, IO, TP>) -> Result<RawEntry, Error<IO::Error>> {
+        if let Some(entry) = stream.read_entry()? {
+            return Ok(entry);
+        }
+        // continue in the new cluster
+        let offset = stream.offset();
+        self.stream.borrow_mut().extend_dir()?;
+        *stream = self.stream.borrow().clone();
+        stream.seek(SeekFrom::Start(offset))?;
+        stream.read_entry()?.ok_or(Error::CorruptedFileSystem)
+    }
+
+    fn find_entry(&self, name: &str, is_dir: Option<bool>) -> Result<DirEntry<'a, IO, TP>, Error<IO::Error>> {
+        let units = name_units(name)?;
+        self.find_entry_by_units(&units, is_dir)?.ok_or(Error::NotFound)
+    }
+
+    fn find_entry_by_units(
+        &self,
+        name: &[u16],
+        is_dir: Option<bool>,
+    ) -> Result<Option<DirEntry<'a, IO, TP>>, Error<IO::Error>> {
+        let name_hash = self.fs().upcase().name_hash(name);
+        let key = self.index_key(name);
+        let entry = match self.with_index(|index| index.names.get(&key).copied())? {
+            Some(offset) => self.find_indexed_entry(offset, name, name_hash)?,
+            None => self.scan_for_entry(name, name_hash)?,
+        };
+        match entry {
+            Some(e) if is_dir.is_some() && Some(e.is_dir()) != is_dir => {
+                if e.is_dir() {
+                    error!("Is a directory");
+                } else {
+                    error!("Not a directory");
+                }
+                Err(Error::InvalidInput)
+            }
+            entry => Ok(entry),
+        }
+    }
+
+    fn scan_for_entry(&self, name: &[u16], name_hash: u16) -> Result<Option<DirEntry<'a, IO, TP>>, Error<IO::Error>> {
+        for r in self.iter() {
+            let e = r?;
+            if e.eq_name(name, name_hash) {
+                return Ok(Some(e));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the entry set at the offset found in the index, `None` if the name is not in the index.
+    fn find_indexed_entry(
+        &self,
+        offset: Option<u64>,
+        name: &[u16],
+        name_hash: u16,
+    ) -> Result<Option<DirEntry<'a, IO, TP>>, Error<IO::Error>> {
+        let Some(offset) = offset else {
+            return Ok(None);
+        };
+        let mut stream = self.stream.borrow().clone();
+        stream.seek(SeekFrom::Start(offset))?;
+        match (DirIter { stream, err: false }).next() {
+            Some(Ok(e)) if e.offset == offset && e.eq_name(name, name_hash) => Ok(Some(e)),
+            Some(Err(err)) => Err(err),
+            _ => {
+                // the directory was modified bypassing the index
+                warn!("Directory index is out of date, rebuilding it");
+                self.drop_index();
+                self.scan_for_entry(name, name_hash)
+            }
+        }
+    }
+
+    /// Opens existing subdirectory.
+    ///
+    /// `path` is a '/' separated directory path relative to self directory.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::NotFound` will be returned if `path` does not point to any existing directory entry.
+    /// * `Error::InvalidInput` will be returned if `path` points to a file that is not a directory.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn open_dir(&self, path: &str) -> Result<Self, Error<IO::Error>> {
+        trace!("exfat::Dir::open_dir {}", path);
+        let (name, rest_opt) = split_path(path);
+        let e = self.find_entry(name, Some(true))?;
+        match rest_opt {
+            Some(rest) => e.to_dir().open_dir(rest),
+            None => Ok(e.to_dir()),
+        }
+    }
+
+    /// Opens existing file.
+    ///
+    /// `path` is a '/' separated file path relative to self directory.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::NotFound` will be returned if `path` points to a non-existing directory entry.
+    /// * `Error::InvalidInput` will be returned if `path` points to a file that is a directory.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn open_file(&self, path: &str) -> Result<File<'a, IO, TP>, Error<IO::Error>> {
+        trace!("exfat::Dir::open_file {}", path);
+        let (name, rest_opt) = split_path(path);
+        if let Some(rest) = rest_opt {
+            return self.find_entry(name, Some(true))?.to_dir().open_file(rest);
+        }
+        Ok(self.find_entry(name, Some(false))?.to_file())
+    }
+
+    /// Checks if the directory contains no entries.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn is_empty(&self) -> Result<bool, Error<IO::Error>> {
+        match self.iter().next() {
+            Some(r) => r.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Removes existing file or directory.
+    ///
+    /// `path` is a '/' separated file path relative to self directory. Clusters of the file are freed, so all other
+    /// hardlinks of a file have to be removed with `remove_entry` before.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::NotFound` will be returned if `path` points to a non-existing directory entry.
+    /// * `Error::DirectoryIsNotEmpty` will be returned if the specified directory is not empty.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn remove(&self, path: &str) -> Result<(), Error<IO::Error>> {
+        self.remove_internal(path, true)
+    }
+
+    /// Removes the entry set of a file or directory without freeing its clusters.
+    ///
+    /// This is used to remove hardlinks, the clusters stay in use by the other entry sets pointing to them.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::NotFound` will be returned if `path` points to a non-existing directory entry.
+    /// * `Error::DirectoryIsNotEmpty` will be returned if the specified directory is not empty.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn remove_entry(&self, path: &str) -> Result<(), Error<IO::Error>> {
+        self.remove_internal(path, false)
+    }
+
+    fn remove_internal(&self, path: &str, free_clusters: bool) -> Result<(), Error<IO::Error>> {
+        trace!("exfat::Dir::remove {}", path);
+        let (name, rest_opt) = split_path(path);
+        if let Some(rest) = rest_opt {
+            return self
+                .find_entry(name, Some(true))?
+                .to_dir()
+                .remove_internal(rest, free_clusters);
+        }
+        let e = self.find_entry(name, None)?;
+        if e.is_dir() && !e.to_dir().is_empty()? {
+            return Err(Error::DirectoryIsNotEmpty);
+        }
+        // the entry set is deleted first so a failure cannot leave it pointing to free clusters
+        self.delete_entry_set(&e)?;
+        if let (true, Some(first_cluster)) = (free_clusters, e.first_cluster()) {
+            let fs = self.fs();
+            // a new directory can reuse the clusters
+            fs.dir_indexes.borrow_mut().remove(&first_cluster);
+            fs.free_clusters(
+                first_cluster,
+                fs.clusters_from_bytes(e.set.data_length()),
+                e.set.no_fat_chain(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes a new entry set to free entries of the directory.
+    fn write_entry_set(&self, set: &mut EntrySet) -> Result<(), Error<IO::Error>> {
+        let (offset, positions) = self.alloc_entries(set.entries.len())?;
+        set.positions = positions;
+        set.write(self.fs())?;
+        let key = self.index_key(&set.name());
+        self.update_index(|index| {
+            index.names.insert(key, offset);
+        });
+        Ok(())
+    }
+
+    /// Deletes the entry set of an entry of this directory.
+    fn delete_entry_set(&self, e: &DirEntry<'a, IO, TP>) -> Result<(), Error<IO::Error>> {
+        e.set.delete(self.fs())?;
+        let key = self.index_key(&e.set.name());
+        self.update_index(|index| {
+            // a renamed entry may already be indexed at its new position
+            if index.names.get(&key) == Some(&e.offset) {
+                index.names.remove(&key);
+            }
+            index.free_from = index.free_from.min(e.offset);
+        });
+        Ok(())
+    }
+
+    /// Rewrites the directory without the holes left by removed entries, the order of entries is kept.
+    ///
+    /// New entries reuse the holes so they end up in unpredictable positions, which matters for devices that
+    /// process the entries in their raw order. The directory is not shrunk.
+    /// Make sure there is no `File` or `Dir` instance for any entry in this directory or filesystem corruption
+    /// can happen.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn compact(&self) -> Result<(), Error<IO::Error>> {
+        self.rewrite_entries(|_| {})
+    }
+
+    /// Rewrites the directory sorted by a key without the holes left by removed entries.
+    ///
+    /// The sort is stable. Entries that do not belong to files, like the allocation bitmap and the volume label in
+    /// the root directory, are kept at the start.
+    /// Make sure there is no `File` or `Dir` instance for any entry in this directory or filesystem corruption
+    /// can happen.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn sort_by_key<K, F>(&self, f: F) -> Result<(), Error<IO::Error>>
+    where
+        K: Ord,
+        F: FnMut(&DirEntry<'a, IO, TP>) -> K,
+    {
+        self.rewrite_entries(|entries| entries.sort_by_key(f))
+    }
+
+    fn rewrite_entries<F>(&self, order: F) -> Result<(), Error<IO::Error>>
+    where
+        F: FnOnce(&mut [DirEntry<'a, IO, TP>]),
+    {
+        let raw = self.raw_entries()?;
+        let mut entries = self.iter().collect::<Result<Vec<_>, _>>()?;
+        // other entries in use stay at the start
+        let mut in_sets = vec![false; raw.len() / ENTRY_SIZE];
+        for e in &entries {
+            // safe cast: the offset is within the raw entries which are in memory
+            let first = (e.offset / ENTRY_SIZE as u64) as usize;
+            in_sets[first..first + e.set.entries.len()].fill(true);
+        }
+        let mut data = Vec::with_capacity(raw.len());
+        for (entry, in_set) in raw.chunks_exact(ENTRY_SIZE).zip(in_sets) {
+            if entry[0] & IN_USE != 0 && !in_set {
+                data.extend_from_slice(entry);
+            }
+        }
+        order(&mut entries);
+        // entry sets stay valid because the checksum does not depend on position
+        for e in &entries {
+            for entry in &e.set.entries {
+                data.extend_from_slice(entry);
+            }
+        }
+        drop(entries);
+        self.set_raw_entries(&data)
+    }
+
+    /// Returns the raw directory entries up to the end of the directory, including deleted entries.
+    ///
+    /// Together with `set_raw_entries` it can be used to restore the directory if `compact` or `sort_by_key` was
+    /// interrupted.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn raw_entries(&self) -> Result<Vec<u8>, Error<IO::Error>> {
+        let mut stream = self.stream.borrow().clone();
+        stream.seek(SeekFrom::Start(0))?;
+        let mut data = Vec::new();
+        while let Some((_, entry)) = stream.read_entry()? {
+            if entry[0] == TYPE_END {
+                break;
+            }
+            data.extend_from_slice(&entry);
+        }
+        Ok(data)
+    }
+
+    /// Replaces the raw directory entries with ones returned by `raw_entries`, entries after them are cleared.
+    ///
+    /// Make sure there is no `File` or `Dir` instance for any entry in this directory or filesystem corruption
+    /// can happen.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if the length of `data` is not a multiple of the entry size.
+    /// * `Error::NotEnoughSpace` will be returned if the directory cannot grow to hold the entries.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn set_raw_entries(&self, data: &[u8]) -> Result<(), Error<IO::Error>> {
+        if data.len() % ENTRY_SIZE != 0 {
+            return Err(Error::InvalidInput);
+        }
+        let old_len = self.raw_entries()?.len();
+        let zeros = [0_u8; ENTRY_SIZE];
+        let mut entries = data.chunks_exact(ENTRY_SIZE);
+        let mut stream = self.stream.borrow().clone();
+        stream.seek(SeekFrom::Start(0))?;
+        // a zeroed entry marks the end of the directory
+        for _ in 0..data.len().max(old_len) / ENTRY_SIZE {
+            let (pos, _) = self.read_or_extend(&mut stream)?;
+            self.fs().write_at(pos, entries.next().unwrap_or(&zeros))?;
+        }
+        self.drop_index();
+        Ok(())
+    }
+}
+
+impl<'a, IO: ReadWriteSeek, TP: TimeProvider> Dir<'a, IO, TP> {
+    fn now(&self) -> DateTime {
+        self.fs().time_provider().get_current_date_time()
+    }
+
+    /// Creates new or opens existing file.
+    ///
+    /// `path` is a '/' separated file path relative to self directory.
+    /// File is never truncated when opening. It can be achieved by calling `File::truncate` method after opening.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if `path` points to an existing file that is a directory.
+    /// * `Error::InvalidFileNameLength` will be returned if the file name is empty or if it is too long.
+    /// * `Error::UnsupportedFileNameCharacter` will be returned if the file name contains an invalid character.
+    /// * `Error::NotEnoughSpace` will be returned if there is not enough free space to create a new file.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn create_file(&self, path: &str) -> Result<File<'a, IO, TP>, Error<IO::Error>> {
+        trace!("exfat::Dir::create_file {}", path);
+        let (name, rest_opt) = split_path(path);
+        if let Some(rest) = rest_opt {
+            return self.find_entry(name, Some(true))?.to_dir().create_file(rest);
+        }
+        let units = name_units(name)?;
+        if let Some(e) = self.find_entry_by_units(&units, Some(false))? {
+            return Ok(e.to_file());
+        }
+        let name_hash = self.fs().upcase().name_hash(&units);
+        let mut set = EntrySet::new(&units, name_hash, FileAttributes::ARCHIVE, self.now());
+        self.write_entry_set(&mut set)?;
+        Ok(File::from_entry(set, self.fs()))
+    }
+
+    /// Creates new directory or opens existing.
+    ///
+    /// `path` is a '/' separated path relative to self directory.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if `path` points to an existing file that is not a directory.
+    /// * `Error::InvalidFileNameLength` will be returned if the file name is empty or if it is too long.
+    /// * `Error::UnsupportedFileNameCharacter` will be returned if the file name contains an invalid character.
+    /// * `Error::NotEnoughSpace` will be returned if there is not enough free space to create a new directory.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn create_dir(&self, path: &str) -> Result<Self, Error<IO::Error>> {
+        trace!("exfat::Dir::create_dir {}", path);
+        let (name, rest_opt) = split_path(path);
+        if let Some(rest) = rest_opt {
+            return self.find_entry(name, Some(true))?.to_dir().create_dir(rest);
+        }
+        let units = name_units(name)?;
+        if let Some(e) = self.find_entry_by_units(&units, Some(true))? {
+            return Ok(e.to_dir());
+        }
+
+        let fs = self.fs();
+        let cluster = fs.alloc_cluster(None, true)?;
+        let name_hash = fs.upcase().name_hash(&units);
+        let mut set = EntrySet::new(&units, name_hash, FileAttributes::DIRECTORY, self.now());
+        let cluster_size = u64::from(fs.cluster_size());
+        set.set_stream(Some(cluster), false, cluster_size, cluster_size);
+        if let Err(err) = self.write_entry_set(&mut set) {
+            fs.free_clusters(cluster, 1, false)?;
+            return Err(err);
+        }
+        Ok(Dir::new(File::from_entry(set, fs)))
+    }
+
+    /// Creates a hardlink, an entry set pointing to the clusters of an existing file.
+    ///
+    /// An existing file at `path` is replaced without freeing its clusters. The link is marked as read-only and
+    /// system file to make other implementations warn before changing it.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::NotFound` will be returned if `target` does not exist in `target_dir`.
+    /// * `Error::InvalidInput` will be returned if `target` or `path` is a directory.
+    /// * `Error::NotEnoughSpace` will be returned if the directory is full and cannot grow.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn create_hardlink(&self, path: &str, target_dir: &Self, target: &str) -> Result<(), Error<IO::Error>> {
+        let (name, rest_opt) = split_path(path);
+        if let Some(rest) = rest_opt {
+            return self
+                .find_entry(name, Some(true))?
+                .to_dir()
+                .create_hardlink(rest, target_dir, target);
+        }
+
+        let target_entry = target_dir.find_entry(target, None)?;
+        if target_entry.is_dir() {
+            error!("Cannot hardlink a directory");
+            return Err(Error::InvalidInput);
+        }
+
+        let units = name_units(name)?;
+        if let Some(e) = self.find_entry_by_units(&units, Some(false))? {
+            self.delete_entry_set(&e)?;
+        }
+        let name_hash = self.fs().upcase().name_hash(&units);
+        let mut set = target_entry.set.with_name(&units, name_hash);
+        let mut attributes = set.attributes();
+        attributes.set(FileAttributes::SYSTEM, true);
+        attributes.set(FileAttributes::READ_ONLY, true);
+        set.set_attributes(attributes);
+        self.write_entry_set(&mut set)
+    }
+
+    /// Renames or moves existing file or directory.
+    ///
+    /// `src_path` is a '/' separated source file path relative to self directory.
+    /// `dst_path` is a '/' separated destination file path relative to `dst_dir`.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::NotFound` will be returned if `src_path` points to a non-existing directory entry or if `dst_path`
+    ///   stripped from the last component does not point to an existing directory.
+    /// * `Error::AlreadyExists` will be returned if `dst_path` points to an existing directory entry.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn rename(&self, src_path: &str, dst_dir: &Self, dst_path: &str) -> Result<(), Error<IO::Error>> {
+        trace!("exfat::Dir::rename {} {}", src_path, dst_path);
+        let (src_name, src_rest_opt) = split_path(src_path);
+        if let Some(rest) = src_rest_opt {
+            return self
+                .find_entry(src_name, Some(true))?
+                .to_dir()
+                .rename(rest, dst_dir, dst_path);
+        }
+        let (dst_name, dst_rest_opt) = split_path(dst_path);
+        if let Some(rest) = dst_rest_opt {
+            let dir = dst_dir.find_entry(dst_name, Some(true))?.to_dir();
+            return self.rename(src_path, &dir, rest);
+        }
+
+        let e = self.find_entry(src_name, None)?;
+        let units = name_units(dst_name)?;
+        if let Some(existing) = dst_dir.find_entry_by_units(&units, None)? {
+            // changing the case of a name finds the entry itself
+            if existing.set.positions != e.set.positions {
+                return Err(Error::AlreadyExists);
+            }
+        }
+        let name_hash = self.fs().upcase().name_hash(&units);
+        let mut set = e.set.with_name(&units, name_hash);
+        // the new entries are written first so a full directory does not lose the file
+        dst_dir.write_entry_set(&mut set)?;
+        self.delete_entry_set(&e)
+    }
+}
+
+// Note: derive cannot be used because of invalid bounds. See: https://github.com/rust-lang/rust/issues/26925
+impl<IO: ReadWriteSeek, TP> Clone for Dir<'_, IO, TP> {
+    fn clone(&self) -> Self {
+        Self {
+            stream: RefCell::new(self.stream.borrow().clone()),
+        }
+    }
+}
+
+/// An iterator over the directory entries.
+///
+/// This struct is created by the `iter` method on `Dir`.
+pub struct DirIter<'a, IO: ReadWriteSeek, TP> {
+    stream: File<'a, IO, TP>,
+    err: bool,
+}
+
+impl<'a, IO: ReadWriteSeek, TP> DirIter<'a, IO, TP> {
+    fn read_entry_set(&mut self) -> Result<Option<DirEntry<'a, IO, TP>>, Error<IO::Error>> {
+        loop {
+            let offset = self.stream.offset();
+            let Some((pos, entry)) = self.stream.read_entry()? else {
+                return Ok(None);
+            };
+            match entry[0] {
+                TYPE_END => return Ok(None),
+                TYPE_FILE => {}
+                // unused entries, other primary entries and secondary entries that do not belong to a file
+                _ => continue,
+            }
+
+            let secondary_count = usize::from(entry[1]);
+            if !(2..=18).contains(&secondary_count) {
+                error!("Invalid secondary count {}", secondary_count);
+                return Err(Error::CorruptedFileSystem);
+            }
+            let mut entries = Vec::with_capacity(secondary_count + 1);
+            let mut positions = Vec::with_capacity(secondary_count + 1);
+            entries.push(entry);
+            positions.push(pos);
+            for _ in 0..secondary_count {
+                let Some((pos, entry)) = self.stream.read_entry()? else {
+                    error!("Entry set is cut off by the end of the directory");
+                    return Err(Error::CorruptedFileSystem);
+                };
+                if entry[0] & (IN_USE | SECONDARY) != IN_USE | SECONDARY {
+                    error!("Entry set is missing secondary entries");
+                    return Err(Error::CorruptedFileSystem);
+                }
+                entries.push(entry);
+                positions.push(pos);
+            }
+            let set = EntrySet::from_entries(entries, positions)?;
+            return Ok(Some(DirEntry {
+                set,
+                offset,
+                fs: self.stream.fs(),
+            }));
+        }
+    }
+}
+
+impl<'a, IO: ReadWriteSeek, TP> Iterator for DirIter<'a, IO, TP> {
+    type Item = Result<DirEntry<'a, IO, TP>, Error<IO::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.err {
+            return None;
+        }
+        let r = self.read_entry_set();
+        if r.is_err() {
+            self.err = true;
+        }
+        r.transpose()
+    }
+}
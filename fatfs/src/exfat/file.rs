@@ -0,0 +1,474 @@
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::vec;
+
+use crate::error::Error;
+use crate::fs::ReadWriteSeek;
+use crate::io::{IoBase, Read, Seek, SeekFrom, Write};
+use crate::time::{DateTime, TimeProvider};
+
+use super::dir::{EntrySet, RawEntry, ENTRY_SIZE};
+use super::FileSystem;
+
+/// Directories cannot be bigger than 256 MiB.
+const MAX_DIR_SIZE: u64 = 256 * 1024 * 1024;
+
+/// An exFAT file or directory stream.
+///
+/// Unlike FAT, exFAT files can be stored in contiguous clusters without a FAT chain. Such files get a FAT chain when
+/// they grow. Like on FAT dropping it updates the entry set but does not flush the storage, call `flush` to make sure
+/// the data reached the storage.
+pub struct File<'a, IO: ReadWriteSeek, TP> {
+    fs: &'a FileSystem<IO, TP>,
+    // entry set of the file, None for the root directory
+    entry: Option<EntrySet>,
+    first_cluster: Option<u32>,
+    no_fat_chain: bool,
+    // allocated size, None for the root directory which is only limited by its cluster chain
+    size: Option<u64>,
+    // bytes after this offset read as zeros
+    valid_size: u64,
+    offset: u64,
+    // index in the cluster chain and cluster of the last cluster looked up
+    cached_cluster: Option<(u64, u32)>,
+    entry_dirty: bool,
+}
+
+impl<'a, IO: ReadWriteSeek, TP> File<'a, IO, TP> {
+    pub(crate) fn root(first_cluster: u32, fs: &'a FileSystem<IO, TP>) -> Self {
+        Self {
+            fs,
+            entry: None,
+            first_cluster: Some(first_cluster),
+            no_fat_chain: false,
+            size: None,
+            valid_size: u64::MAX,
+            offset: 0,
+            cached_cluster: None,
+            entry_dirty: false,
+        }
+    }
+
+    pub(crate) fn from_entry(entry: EntrySet, fs: &'a FileSystem<IO, TP>) -> Self {
+        Self {
+            fs,
+            first_cluster: entry.first_cluster(),
+            no_fat_chain: entry.no_fat_chain(),
+            size: Some(entry.data_length()),
+            valid_size: entry.valid_data_length(),
+            offset: 0,
+            cached_cluster: None,
+            entry_dirty: false,
+            entry: Some(entry),
+        }
+    }
+
+    pub(crate) fn fs(&self) -> &'a FileSystem<IO, TP> {
+        self.fs
+    }
+
+    pub(crate) fn first_cluster(&self) -> Option<u32> {
+        self.first_cluster
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn cluster_size(&self) -> u64 {
+        u64::from(self.fs.cluster_size())
+    }
+
+    /// Returns the cluster at `index` in the chain or `None` if the file is not that big.
+    fn cluster_at(&mut self, index: u64) -> Result<Option<u32>, Error<IO::Error>> {
+        let Some(first_cluster) = self.first_cluster else {
+            return Ok(None);
+        };
+        if let Some(size) = self.size {
+            if index >= self.fs.clusters_from_bytes(size) {
+                return Ok(None);
+            }
+        }
+        if self.no_fat_chain {
+            // safe cast: the index is lower than the number of clusters of the file which is an u32
+            return Ok(Some(first_cluster + index as u32));
+        }
+
+        let (mut i, mut cluster) = match self.cached_cluster {
+            Some((i, cluster)) if i <= index => (i, cluster),
+            _ => (0, first_cluster),
+        };
+        while i < index {
+            match self.fs.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None if self.size.is_some() => {
+                    error!("Cluster chain is shorter than the file");
+                    return Err(Error::CorruptedFileSystem);
+                }
+                None => return Ok(None),
+            }
+            i += 1;
+        }
+        self.cached_cluster = Some((index, cluster));
+        Ok(Some(cluster))
+    }
+
+    /// Returns the size of the file, for the root directory the size of its cluster chain.
+    fn len(&mut self) -> Result<u64, Error<IO::Error>> {
+        if let Some(size) = self.size {
+            return Ok(size);
+        }
+        let mut clusters = 0;
+        while self.cluster_at(clusters)?.is_some() {
+            clusters += 1;
+        }
+        Ok(clusters * self.cluster_size())
+    }
+
+    /// Links the contiguous clusters of the file in the FAT so the file can get fragmented.
+    fn create_fat_chain(&mut self) -> Result<(), Error<IO::Error>> {
+        if let (Some(first_cluster), Some(size)) = (self.first_cluster, self.size) {
+            // safe cast: the number of clusters of the file is an u32
+            let clusters = self.fs.clusters_from_bytes(size) as u32;
+            for i in 0..clusters {
+                let next = Some(first_cluster + i + 1).filter(|_| i + 1 < clusters);
+                self.fs.set_next_cluster(first_cluster + i, next)?;
+            }
+        }
+        self.no_fat_chain = false;
+        self.entry_dirty = true;
+        Ok(())
+    }
+
+    /// Allocates the cluster at `index` which must follow the last cluster of the file.
+    fn alloc_cluster(&mut self, index: u64) -> Result<u32, Error<IO::Error>> {
+        let prev_cluster = match index {
+            0 => None,
+            _ => Some(self.cluster_at(index - 1)?.ok_or(Error::CorruptedFileSystem)?),
+        };
+        if self.no_fat_chain {
+            self.create_fat_chain()?;
+        }
+        let is_dir = self.is_dir();
+        let cluster = self.fs.alloc_cluster(prev_cluster, is_dir)?;
+        if self.first_cluster.is_none() {
+            self.first_cluster = Some(cluster);
+        }
+        self.cached_cluster = Some((index, cluster));
+        self.entry_dirty = true;
+        Ok(cluster)
+    }
+
+    /// Adds a zeroed cluster to the end of a directory.
+    pub(crate) fn extend_dir(&mut self) -> Result<(), Error<IO::Error>> {
+        let len = self.len()?;
+        if len + self.cluster_size() > MAX_DIR_SIZE {
+            return Err(Error::NotEnoughSpace);
+        }
+        self.alloc_cluster(len / self.cluster_size())?;
+        if let Some(size) = self.size.as_mut() {
+            *size += u64::from(self.fs.cluster_size());
+            self.valid_size = *size;
+        }
+        self.flush_entry()
+    }
+
+    /// Returns the position on the storage of the directory entry at the current offset and moves past it.
+    pub(crate) fn read_entry(&mut self) -> Result<Option<RawEntry>, Error<IO::Error>> {
+        if let Some(size) = self.size {
+            if self.offset + ENTRY_SIZE as u64 > size {
+                return Ok(None);
+            }
+        }
+        let Some(cluster) = self.cluster_at(self.offset / self.cluster_size())? else {
+            return Ok(None);
+        };
+        let pos = self.fs.offset_from_cluster(cluster)? + self.offset % self.cluster_size();
+        let mut entry = [0_u8; ENTRY_SIZE];
+        self.fs.read_at(pos, &mut entry)?;
+        self.offset += ENTRY_SIZE as u64;
+        Ok(Some((pos, entry)))
+    }
+
+    /// Writes zeros to the range between the valid data length and the current offset.
+    fn zero_invalid_data(&mut self) -> Result<(), Error<IO::Error>> {
+        let offset = self.offset;
+        let mut pos = self.valid_size;
+        while pos < offset {
+            let cluster = self
+                .cluster_at(pos / self.cluster_size())?
+                .ok_or(Error::CorruptedFileSystem)?;
+            let len = (self.cluster_size() - pos % self.cluster_size()).min(offset - pos);
+            // safe cast: len is not bigger than the cluster size
+            let zeros = vec![0_u8; len as usize];
+            self.fs.write_at(
+                self.fs.offset_from_cluster(cluster)? + pos % self.cluster_size(),
+                &zeros,
+            )?;
+            pos += len;
+        }
+        self.valid_size = offset;
+        self.entry_dirty = true;
+        Ok(())
+    }
+
+    /// Truncates the file to the current position.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn truncate(&mut self) -> Result<(), Error<IO::Error>> {
+        let Some(size) = self.size else {
+            return Err(Error::InvalidInput);
+        };
+        if self.offset >= size {
+            return Ok(());
+        }
+        let clusters = self.fs.clusters_from_bytes(size);
+        let keep = self.fs.clusters_from_bytes(self.offset);
+        if let Some(first_cluster) = self.first_cluster {
+            if keep == 0 {
+                self.fs.free_clusters(first_cluster, clusters, self.no_fat_chain)?;
+                self.first_cluster = None;
+                self.no_fat_chain = false;
+            } else if keep < clusters {
+                let last = self.cluster_at(keep - 1)?.ok_or(Error::CorruptedFileSystem)?;
+                if self.no_fat_chain {
+                    self.fs.free_clusters(last + 1, clusters - keep, true)?;
+                } else if let Some(next) = self.fs.next_cluster(last)? {
+                    self.fs.free_clusters(next, clusters - keep, false)?;
+                    self.fs.set_next_cluster(last, None)?;
+                }
+            }
+            self.cached_cluster = None;
+        }
+        self.size = Some(self.offset);
+        self.valid_size = self.valid_size.min(self.offset);
+        self.entry_dirty = true;
+        Ok(())
+    }
+
+    fn is_dir(&self) -> bool {
+        self.entry.as_ref().map_or(true, EntrySet::is_dir)
+    }
+
+    /// Writes the changed size and first cluster to the entry set.
+    fn flush_entry(&mut self) -> Result<(), Error<IO::Error>> {
+        if let (true, Some(entry)) = (self.entry_dirty, self.entry.as_mut()) {
+            entry.set_stream(
+                self.first_cluster,
+                self.no_fat_chain,
+                self.valid_size,
+                self.size.unwrap_or(0),
+            );
+            entry.write(self.fs)?;
+        }
+        self.entry_dirty = false;
+        Ok(())
+    }
+
+    /// Flushes the entry set and the storage.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn flush(&mut self) -> Result<(), Error<IO::Error>> {
+        self.flush_entry()?;
+        self.fs.disk.borrow_mut().flush()?;
+        Ok(())
+    }
+
+    /// Sets date and time of creation for this file.
+    pub fn set_created(&mut self, date_time: DateTime) {
+        if let Some(entry) = self.entry.as_mut() {
+            entry.set_created(date_time);
+            self.entry_dirty = true;
+        }
+    }
+
+    /// Sets date and time of last access for this file.
+    pub fn set_accessed(&mut self, date_time: DateTime) {
+        if let Some(entry) = self.entry.as_mut() {
+            entry.set_accessed(date_time);
+            self.entry_dirty = true;
+        }
+    }
+
+    /// Sets date and time of last modification for this file.
+    pub fn set_modified(&mut self, date_time: DateTime) {
+        if let Some(entry) = self.entry.as_mut() {
+            entry.set_modified(date_time);
+            self.entry_dirty = true;
+        }
+    }
+}
+
+impl<IO: ReadWriteSeek, TP: TimeProvider> File<'_, IO, TP> {
+    fn update_modified(&mut self) {
+        if !self.is_dir() {
+            let now = self.fs.time_provider().get_current_date_time();
+            self.set_modified(now);
+        }
+    }
+}
+
+// The storage is not flushed, that would flush it after every directory operation. It is flushed by `flush` and
+// when the filesystem is unmounted.
+impl<IO: ReadWriteSeek, TP> Drop for File<'_, IO, TP> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush_entry() {
+            error!("flush failed {:?}", err);
+        }
+    }
+}
+
+// Note: derive cannot be used because of invalid bounds. See: https://github.com/rust-lang/rust/issues/26925
+impl<IO: ReadWriteSeek, TP> Clone for File<'_, IO, TP> {
+    fn clone(&self) -> Self {
+        Self {
+            fs: self.fs,
+            entry: self.entry.clone(),
+            first_cluster: self.first_cluster,
+            no_fat_chain: self.no_fat_chain,
+            size: self.size,
+            valid_size: self.valid_size,
+            offset: self.offset,
+            cached_cluster: self.cached_cluster,
+            entry_dirty: self.entry_dirty,
+        }
+    }
+}
+
+impl<IO: ReadWriteSeek, TP> IoBase for File<'_, IO, TP> {
+    type Error = Error<IO::Error>;
+}
+
+impl<IO: ReadWriteSeek, TP> Read for File<'_, IO, TP> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let cluster_size = self.cluster_size();
+        let mut len = (buf.len() as u64).min(cluster_size - self.offset % cluster_size);
+        if let Some(size) = self.size {
+            len = len.min(size.saturating_sub(self.offset));
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let Some(cluster) = self.cluster_at(self.offset / cluster_size)? else {
+            return Ok(0);
+        };
+        if self.offset >= self.valid_size {
+            // safe cast: len is not bigger than buf.len()
+            buf[..len as usize].fill(0);
+        } else {
+            len = len.min(self.valid_size - self.offset);
+            let pos = self.fs.offset_from_cluster(cluster)? + self.offset % cluster_size;
+            self.fs.read_at(pos, &mut buf[..len as usize])?;
+        }
+        self.offset += len;
+        Ok(len as usize)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<IO: ReadWriteSeek, TP> std::io::Read for File<'_, IO, TP>
+where
+    std::io::Error: From<Error<IO::Error>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(Read::read(self, buf)?)
+    }
+}
+
+impl<IO: ReadWriteSeek, TP: TimeProvider> Write for File<'_, IO, TP> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.size.is_some() && self.offset > self.valid_size {
+            self.zero_invalid_data()?;
+        }
+        let cluster_size = self.cluster_size();
+        let index = self.offset / cluster_size;
+        let cluster = match self.cluster_at(index)? {
+            Some(cluster) => cluster,
+            None => self.alloc_cluster(index)?,
+        };
+        let offset_in_cluster = self.offset % cluster_size;
+        // safe cast: the result is not bigger than buf.len()
+        let len = (buf.len() as u64).min(cluster_size - offset_in_cluster) as usize;
+        self.fs
+            .write_at(self.fs.offset_from_cluster(cluster)? + offset_in_cluster, &buf[..len])?;
+        self.offset += len as u64;
+
+        if let Some(size) = self.size.as_mut() {
+            if self.offset > *size {
+                *size = self.offset;
+            }
+            if self.offset > self.valid_size {
+                self.valid_size = self.offset;
+            }
+            self.entry_dirty = true;
+        }
+        self.update_modified();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Self::flush(self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<IO: ReadWriteSeek, TP: TimeProvider> std::io::Write for File<'_, IO, TP>
+where
+    std::io::Error: From<Error<IO::Error>>,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(Write::write(self, buf)?)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        Ok(Write::write_all(self, buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(Write::flush(self)?)
+    }
+}
+
+impl<IO: ReadWriteSeek, TP> Seek for File<'_, IO, TP> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let len = self.len()?;
+        let new_offset = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => add_signed(self.offset, x),
+            SeekFrom::End(x) => add_signed(len, x),
+        };
+        let Some(mut new_offset) = new_offset else {
+            error!("Invalid seek offset");
+            return Err(Error::InvalidInput);
+        };
+        if new_offset > len {
+            warn!("Seek beyond the end of the file");
+            new_offset = len;
+        }
+        self.offset = new_offset;
+        Ok(new_offset)
+    }
+}
+
+fn add_signed(offset: u64, delta: i64) -> Option<u64> {
+    i64::try_from(offset)
+        .ok()
+        .and_then(|offset| offset.checked_add(delta))
+        .and_then(|offset| u64::try_from(offset).ok())
+}
+
+#[cfg(feature = "std")]
+impl<IO: ReadWriteSeek, TP> std::io::Seek for File<'_, IO, TP>
+where
+    std::io::Error: From<Error<IO::Error>>,
+{
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        Ok(Seek::seek(self, pos.into())?)
+    }
+}
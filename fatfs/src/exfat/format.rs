@@ -0,0 +1,320 @@
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::error::Error;
+use crate::fs::ReadWriteSeek;
+use crate::io::SeekFrom;
+
+use super::boot_sector::{boot_region, BootSector, BOOT_REGION_SECTORS};
+use super::dir::{ENTRY_SIZE, TYPE_BITMAP, TYPE_LABEL, TYPE_UPCASE};
+use super::upcase::{table_checksum, UpcaseTable};
+use super::{END_OF_CHAIN, FIRST_CLUSTER, MAX_LABEL_LEN};
+
+const MB: u64 = 1024 * 1024;
+const GB: u64 = 1024 * MB;
+
+/// An exFAT filesystem formatting options
+///
+/// This struct implements a builder pattern.
+/// Options are specified as an argument for `format_volume` function.
+#[derive(Debug, Clone)]
+pub struct FormatVolumeOptions {
+    bytes_per_sector: u16,
+    total_sectors: Option<u64>,
+    bytes_per_cluster: Option<u32>,
+    volume_id: u32,
+    volume_label: Option<String>,
+}
+
+impl Default for FormatVolumeOptions {
+    fn default() -> Self {
+        Self {
+            bytes_per_sector: 512,
+            total_sectors: None,
+            bytes_per_cluster: None,
+            volume_id: 0x1234_5678,
+            volume_label: None,
+        }
+    }
+}
+
+impl FormatVolumeOptions {
+    /// Create options struct for `format_volume` function
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set size of cluster in bytes (must be dividable by sector size)
+    ///
+    /// If option is not specified the cluster size is selected based on the volume size like Windows does: 4 KiB up
+    /// to 256 MiB, 32 KiB up to 32 GiB and 128 KiB for bigger volumes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_cluster` is not a power of two, is lower than `512` or higher than 32 MiB.
+    #[must_use]
+    pub fn bytes_per_cluster(mut self, bytes_per_cluster: u32) -> Self {
+        assert!(
+            bytes_per_cluster.is_power_of_two() && (512..=32 * 1024 * 1024).contains(&bytes_per_cluster),
+            "Invalid bytes_per_cluster"
+        );
+        self.bytes_per_cluster = Some(bytes_per_cluster);
+        self
+    }
+
+    /// Set sector size in bytes
+    ///
+    /// Sector size must be a power of two between 512 and 4096. Default is 512.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sector` is not a power of two or is not in the supported range.
+    #[must_use]
+    pub fn bytes_per_sector(mut self, bytes_per_sector: u16) -> Self {
+        assert!(
+            bytes_per_sector.is_power_of_two() && (512..=4096).contains(&bytes_per_sector),
+            "Invalid bytes_per_sector"
+        );
+        self.bytes_per_sector = bytes_per_sector;
+        self
+    }
+
+    /// Set total number of sectors
+    ///
+    /// If option is not specified total number of sectors is calculated as storage device size divided by sector size.
+    #[must_use]
+    pub fn total_sectors(mut self, total_sectors: u64) -> Self {
+        self.total_sectors = Some(total_sectors);
+        self
+    }
+
+    /// Set volume serial number
+    ///
+    /// Default is `0x12345678`.
+    #[must_use]
+    pub fn volume_id(mut self, volume_id: u32) -> Self {
+        self.volume_id = volume_id;
+        self
+    }
+
+    /// Set volume label
+    ///
+    /// The label can have at most 11 UTF-16 code units. Default is empty label.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the label is too long.
+    #[must_use]
+    pub fn volume_label(mut self, volume_label: &str) -> Self {
+        assert!(
+            volume_label.encode_utf16().count() <= MAX_LABEL_LEN,
+            "Invalid volume_label"
+        );
+        self.volume_label = Some(String::from(volume_label));
+        self
+    }
+}
+
+fn default_cluster_size(volume_size: u64) -> u32 {
+    if volume_size <= 256 * MB {
+        4 * 1024
+    } else if volume_size <= 32 * GB {
+        32 * 1024
+    } else {
+        128 * 1024
+    }
+}
+
+fn round_up(n: u64, multiple: u64) -> u64 {
+    (n + multiple - 1) / multiple * multiple
+}
+
+/// Computes the volume layout: the FAT is aligned to a cluster and the cluster heap follows it.
+fn layout(options: &FormatVolumeOptions, total_sectors: u64) -> Option<BootSector> {
+    let sector_size = u64::from(options.bytes_per_sector);
+    let cluster_size = options
+        .bytes_per_cluster
+        .unwrap_or_else(|| default_cluster_size(total_sectors * sector_size));
+    let sectors_per_cluster = u64::from(cluster_size) / sector_size;
+    if sectors_per_cluster == 0 {
+        return None;
+    }
+
+    let fat_offset = round_up(u64::from(BOOT_REGION_SECTORS) * 2, sectors_per_cluster);
+    let fat_length = |cluster_count: u64| round_up((cluster_count + 2) * 4, sector_size) / sector_size;
+    let heap_offset = |cluster_count: u64| round_up(fat_offset + fat_length(cluster_count), sectors_per_cluster);
+    let fits = |cluster_count: u64| {
+        total_sectors.saturating_sub(heap_offset(cluster_count)) / sectors_per_cluster >= cluster_count
+    };
+    // a bigger FAT leaves less space for clusters so search for the highest cluster count that still fits
+    let (mut low, mut high) = (0, total_sectors.checked_sub(fat_offset)? / sectors_per_cluster);
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    let cluster_count = low;
+    let (fat_length, cluster_heap_offset) = (fat_length(cluster_count), heap_offset(cluster_count));
+    if cluster_heap_offset >= total_sectors {
+        return None;
+    }
+    if cluster_count > 0xFFFF_FFF5 {
+        return None;
+    }
+
+    Some(BootSector {
+        volume_length: total_sectors,
+        fat_offset: u32::try_from(fat_offset).ok()?,
+        fat_length: u32::try_from(fat_length).ok()?,
+        cluster_heap_offset: u32::try_from(cluster_heap_offset).ok()?,
+        cluster_count: u32::try_from(cluster_count).ok()?,
+        // set below
+        root_dir_first_cluster: 0,
+        volume_serial_number: options.volume_id,
+        volume_flags: 0,
+        // safe cast: power of two with an exponent of at most 12
+        bytes_per_sector_shift: options.bytes_per_sector.trailing_zeros() as u8,
+        // safe cast: power of two with an exponent of at most 16
+        sectors_per_cluster_shift: sectors_per_cluster.trailing_zeros() as u8,
+        number_of_fats: 1,
+    })
+}
+
+/// Create an exFAT filesystem on a disk or partition.
+///
+/// The allocation bitmap, up-case table and root directory are placed at the beginning of the cluster heap.
+///
+/// # Errors
+///
+/// Errors that can be returned:
+///
+/// * `Error::InvalidInput` will be returned if `options` describes an invalid file system that cannot be created, for
+///   example if the storage is too small.
+/// * `Error::Io` will be returned if the provided storage object returned an I/O error.
+///
+/// # Panics
+///
+/// Panics in non-optimized build if `storage` position returned by `seek` is not zero.
+#[allow(clippy::needless_pass_by_value)]
+pub fn format_volume<S: ReadWriteSeek>(storage: &mut S, options: FormatVolumeOptions) -> Result<(), Error<S::Error>> {
+    trace!("exfat::format_volume");
+    debug_assert!(storage.seek(SeekFrom::Current(0))? == 0);
+
+    let total_sectors = match options.total_sectors {
+        Some(total_sectors) => total_sectors,
+        None => storage.seek(SeekFrom::End(0))? / u64::from(options.bytes_per_sector),
+    };
+    let Some(mut boot) = layout(&options, total_sectors) else {
+        error!("Cannot create exFAT volume with {} sectors", total_sectors);
+        return Err(Error::InvalidInput);
+    };
+    let cluster_size = u64::from(boot.cluster_size());
+    let clusters_from_bytes = |bytes: u64| (bytes + cluster_size - 1) / cluster_size;
+
+    // allocation bitmap, up-case table and root directory fill the first clusters
+    let upcase_table = UpcaseTable::generate().encode();
+    let bitmap_len = (u64::from(boot.cluster_count) + 7) / 8;
+    let bitmap_clusters = clusters_from_bytes(bitmap_len);
+    let upcase_clusters = clusters_from_bytes(upcase_table.len() as u64);
+    let used_clusters = bitmap_clusters + upcase_clusters + 1;
+    if used_clusters > u64::from(boot.cluster_count) {
+        error!("Volume is too small for exFAT metadata");
+        return Err(Error::InvalidInput);
+    }
+    // safe casts: checked above that the clusters fit on the volume
+    let upcase_first_cluster = FIRST_CLUSTER + bitmap_clusters as u32;
+    boot.root_dir_first_cluster = upcase_first_cluster + upcase_clusters as u32;
+
+    // boot regions and the FAT
+    let boot_region = boot_region(&boot);
+    storage.seek(SeekFrom::Start(0))?;
+    storage.write_all(&boot_region)?;
+    storage.write_all(&boot_region)?;
+    let sector_size = u64::from(boot.bytes_per_sector());
+    let fat_pos = boot.bytes_from_sectors(u64::from(boot.fat_offset));
+    storage.seek(SeekFrom::Start(sector_size * u64::from(BOOT_REGION_SECTORS) * 2))?;
+    crate::fs::write_zeros(storage, fat_pos - sector_size * u64::from(BOOT_REGION_SECTORS) * 2)?;
+
+    let mut fat = Vec::with_capacity((used_clusters as usize + 2) * 4);
+    fat.extend_from_slice(&0xFFFF_FFF8_u32.to_le_bytes());
+    fat.extend_from_slice(&END_OF_CHAIN.to_le_bytes());
+    for (first, count) in [
+        (FIRST_CLUSTER, bitmap_clusters),
+        (upcase_first_cluster, upcase_clusters),
+        (boot.root_dir_first_cluster, 1),
+    ] {
+        // safe cast: checked above that the clusters fit on the volume
+        let count = count as u32;
+        for i in 0..count {
+            let next = if i + 1 < count { first + i + 1 } else { END_OF_CHAIN };
+            fat.extend_from_slice(&next.to_le_bytes());
+        }
+    }
+    storage.write_all(&fat)?;
+    crate::fs::write_zeros(
+        storage,
+        boot.bytes_from_sectors(u64::from(boot.fat_length)) - fat.len() as u64,
+    )?;
+
+    // allocation bitmap with the metadata clusters in use
+    let mut bitmap = vec![0_u8; (bitmap_clusters * cluster_size) as usize];
+    for i in 0..used_clusters as usize {
+        bitmap[i / 8] |= 1 << (i % 8);
+    }
+    let heap_pos = boot.bytes_from_sectors(u64::from(boot.cluster_heap_offset));
+    storage.seek(SeekFrom::Start(heap_pos))?;
+    storage.write_all(&bitmap)?;
+    storage.write_all(&upcase_table)?;
+    crate::fs::write_zeros(storage, upcase_clusters * cluster_size - upcase_table.len() as u64)?;
+
+    // root directory
+    let mut root_dir = vec![0_u8; cluster_size as usize];
+    let mut entries = root_dir.chunks_exact_mut(ENTRY_SIZE);
+    if let Some(label) = options.volume_label.as_ref().filter(|label| !label.is_empty()) {
+        let entry = entries.next().unwrap();
+        entry[0] = TYPE_LABEL;
+        let units: Vec<u16> = label.encode_utf16().collect();
+        // safe cast: the length is checked when setting the option
+        entry[1] = units.len() as u8;
+        for (i, unit) in units.iter().enumerate() {
+            entry[2 + i * 2..4 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entry = entries.next().unwrap();
+    entry[0] = TYPE_BITMAP;
+    entry[20..24].copy_from_slice(&FIRST_CLUSTER.to_le_bytes());
+    entry[24..32].copy_from_slice(&bitmap_len.to_le_bytes());
+    let entry = entries.next().unwrap();
+    entry[0] = TYPE_UPCASE;
+    entry[4..8].copy_from_slice(&table_checksum(&upcase_table).to_le_bytes());
+    entry[20..24].copy_from_slice(&upcase_first_cluster.to_le_bytes());
+    entry[24..32].copy_from_slice(&(upcase_table.len() as u64).to_le_bytes());
+    storage.write_all(&root_dir)?;
+
+    storage.seek(SeekFrom::Start(0))?;
+    trace!("exfat::format_volume end");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_fits_the_volume() {
+        for (size, cluster_size) in [(8 * MB, 4096), (GB, 32 * 1024), (64 * GB, 128 * 1024)] {
+            let boot = layout(&FormatVolumeOptions::new(), size / 512).unwrap();
+            assert_eq!(boot.cluster_size(), cluster_size);
+            assert!(boot.bytes_from_sectors(u64::from(boot.fat_length)) >= (u64::from(boot.cluster_count) + 2) * 4);
+            let heap_end =
+                u64::from(boot.cluster_heap_offset) + (u64::from(boot.cluster_count) << boot.sectors_per_cluster_shift);
+            assert!(heap_end <= size / 512);
+            // not more than a cluster is wasted
+            assert!(size / 512 - heap_end < 1 << boot.sectors_per_cluster_shift);
+        }
+    }
+}
@@ -0,0 +1,699 @@
+//! exFAT filesystem support.
+//!
+//! exFAT is the filesystem of SDXC cards (bigger than 32 GB). Free clusters are tracked by an allocation bitmap,
+//! the FAT is only used for fragmented files, and files are described by entry sets made of a file entry, a stream
+//! extension entry and file name entries. File names are compared ignoring case using the up-case table stored on
+//! the volume.
+//!
+//! Like on FAT, several entry sets can point to the same clusters, see `Dir::create_hardlink`.
+//!
+//! Only the first FAT is used (`TexFAT` is not supported) and access control entries are ignored.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::io::prelude::*;
+//!
+//! fn main() -> Result<(), fatfs::Error<std::io::Error>> {
+//!     let img = std::io::Cursor::new(vec![0_u8; 8 * 1024 * 1024]);
+//!     let mut storage = fatfs::StdIoWrapper::from(img);
+//!     fatfs::exfat::format_volume(&mut storage, fatfs::exfat::FormatVolumeOptions::new())?;
+//!     let fs = fatfs::exfat::FileSystem::new(storage, fatfs::FsOptions::new())?;
+//!
+//!     let mut file = fs.root_dir().create_file("hello.txt")?;
+//!     file.write_all(b"Hello World!")?;
+//!     # Ok(())
+//! }
+//! ```
+
+mod boot_sector;
+mod dir;
+mod file;
+mod format;
+mod upcase;
+
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::cell::{Cell, Ref, RefCell};
+use core::ops::Range;
+
+use crate::dir::NameMap;
+use crate::error::Error;
+use crate::fs::{
+    DiscardHook, FileSystemStats, FsOptions, FsStatusFlags, IntoReadStorage, IntoStorage, ReadSeek, ReadWriteSeek,
+};
+use crate::io::{ReadOnly, SeekFrom};
+use crate::time::{DefaultTimeProvider, TimeProvider};
+
+use self::boot_sector::{
+    boot_checksum, BootSector, BOOT_REGION_SECTORS, CHECKSUMMED_SECTORS, VOLUME_FLAGS_OFFSET,
+    VOLUME_SERIAL_NUMBER_OFFSET,
+};
+use self::dir::{DirIndex, ENTRY_SIZE, TYPE_BITMAP, TYPE_LABEL, TYPE_UPCASE};
+use self::upcase::{table_checksum, UpcaseTable};
+
+pub use self::dir::{Dir, DirEntry, DirIter};
+pub use self::file::File;
+pub use self::format::{format_volume, FormatVolumeOptions};
+
+/// First cluster of the cluster heap.
+const FIRST_CLUSTER: u32 = 2;
+/// FAT entry ending a cluster chain.
+const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+/// FAT entry of a bad cluster.
+const BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+
+/// Maximal number of UTF-16 code units of the volume label.
+const MAX_LABEL_LEN: usize = 11;
+
+/// In memory copy of the allocation bitmap, changes are written through right away.
+struct Bitmap {
+    /// Clusters holding the bitmap on the volume
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+    free_clusters: u32,
+    next_free: u32,
+}
+
+impl Bitmap {
+    fn is_used(&self, cluster: u32) -> bool {
+        let index = (cluster - FIRST_CLUSTER) as usize;
+        self.data[index / 8] & (1 << (index % 8)) != 0
+    }
+}
+
+/// An exFAT filesystem object.
+///
+/// `FileSystem` struct is representing a state of a mounted exFAT volume.
+pub struct FileSystem<IO: ReadWriteSeek, TP = DefaultTimeProvider> {
+    disk: RefCell<IO>,
+    time_provider: TP,
+    boot: BootSector,
+    bitmap: RefCell<Bitmap>,
+    upcase: UpcaseTable,
+    volume_label: RefCell<String>,
+    dirty: Cell<bool>,
+    dir_index: bool,
+    // indexes of directories keyed by their first cluster
+    pub(crate) dir_indexes: RefCell<NameMap<u32, DirIndex>>,
+    discard_hook: RefCell<Option<DiscardHook>>,
+    // runs of clusters freed since the last discard, as the first cluster and the length
+    freed: RefCell<Vec<(u32, u32)>>,
+}
+
+impl<IO: ReadWriteSeek, TP> FileSystem<IO, TP> {
+    /// Creates a new filesystem object instance.
+    ///
+    /// Only the time provider and the `strict` and `dir_index` flags of `options` are used. With `strict` enabled the
+    /// boot region and up-case table checksums are verified.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::CorruptedFileSystem` will be returned if the storage does not contain a valid exFAT volume.
+    /// * `Error::Io` will be returned if the provided storage object returned an I/O error.
+    ///
+    /// # Panics
+    ///
+    /// Panics in non-optimized build if `storage` position returned by `seek` is not zero.
+    pub fn new<T: IntoStorage<IO>, OCC>(storage: T, options: FsOptions<TP, OCC>) -> Result<Self, Error<IO::Error>> {
+        trace!("exfat::FileSystem::new");
+        let mut disk = storage.into_storage();
+        debug_assert!(disk.seek(SeekFrom::Current(0))? == 0);
+
+        let mut buf = [0_u8; 512];
+        disk.read_exact(&mut buf)?;
+        let boot = BootSector::deserialize(&buf)?;
+        if options.strict {
+            let mut region = vec![0_u8; boot.bytes_per_sector() as usize * (CHECKSUMMED_SECTORS as usize + 1)];
+            disk.seek(SeekFrom::Start(0))?;
+            disk.read_exact(&mut region)?;
+            let (checksummed, checksums) =
+                region.split_at(boot.bytes_per_sector() as usize * CHECKSUMMED_SECTORS as usize);
+            let checksum = boot_checksum(checksummed);
+            if checksums
+                .chunks_exact(4)
+                .any(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) != checksum)
+            {
+                error!("Invalid boot region checksum");
+                return Err(Error::CorruptedFileSystem);
+            }
+        }
+
+        let mut fs = Self {
+            disk: RefCell::new(disk),
+            time_provider: options.time_provider,
+            boot,
+            bitmap: RefCell::new(Bitmap {
+                clusters: Vec::new(),
+                data: Vec::new(),
+                free_clusters: 0,
+                next_free: FIRST_CLUSTER,
+            }),
+            upcase: UpcaseTable::generate(),
+            volume_label: RefCell::new(String::new()),
+            dirty: Cell::new(false),
+            dir_index: options.dir_index,
+            dir_indexes: RefCell::new(NameMap::default()),
+            discard_hook: RefCell::new(None),
+            freed: RefCell::new(Vec::new()),
+        };
+        fs.load_root_dir_entries(options.strict)?;
+        trace!("exfat::FileSystem::new end");
+        Ok(fs)
+    }
+
+    /// Reads the allocation bitmap, the up-case table and the volume label described by root directory entries.
+    fn load_root_dir_entries(&mut self, strict: bool) -> Result<(), Error<IO::Error>> {
+        let root_dir = self.root_dir();
+        let Some((_, bitmap_entry)) = root_dir.find_raw_entry(|entry| entry[0] == TYPE_BITMAP)? else {
+            error!("Allocation bitmap not found");
+            return Err(Error::CorruptedFileSystem);
+        };
+        let Some((_, upcase_entry)) = root_dir.find_raw_entry(|entry| entry[0] == TYPE_UPCASE)? else {
+            error!("Up-case table not found");
+            return Err(Error::CorruptedFileSystem);
+        };
+        let label_entry = root_dir.find_raw_entry(|entry| entry[0] == TYPE_LABEL)?;
+        drop(root_dir);
+
+        let (clusters, mut data) = self.read_metadata_stream(&bitmap_entry)?;
+        let cluster_count = self.boot.cluster_count as usize;
+        if data.len() < (cluster_count + 7) / 8 {
+            error!("Allocation bitmap is too short");
+            return Err(Error::CorruptedFileSystem);
+        }
+        data.truncate((cluster_count + 7) / 8);
+        let used: usize = data.iter().map(|byte| byte.count_ones() as usize).sum();
+        // safe cast: there are not more used clusters than clusters which is an u32
+        let free_clusters = (cluster_count - used.min(cluster_count)) as u32;
+        *self.bitmap.get_mut() = Bitmap {
+            clusters,
+            data,
+            free_clusters,
+            next_free: FIRST_CLUSTER,
+        };
+
+        let (_, table) = self.read_metadata_stream(&upcase_entry)?;
+        let checksum = u32::from_le_bytes([upcase_entry[4], upcase_entry[5], upcase_entry[6], upcase_entry[7]]);
+        if strict && table_checksum(&table) != checksum {
+            error!("Invalid up-case table checksum");
+            return Err(Error::CorruptedFileSystem);
+        }
+        self.upcase = UpcaseTable::decode(&table);
+
+        if let Some((_, entry)) = label_entry {
+            let len = usize::from(entry[1]).min(MAX_LABEL_LEN);
+            let units: Vec<u16> = entry[2..2 + len * 2]
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            *self.volume_label.get_mut() = String::from_utf16_lossy(&units);
+        }
+        Ok(())
+    }
+
+    /// Reads the clusters and data of the allocation bitmap or up-case table.
+    #[allow(clippy::type_complexity)]
+    fn read_metadata_stream(&self, entry: &[u8; ENTRY_SIZE]) -> Result<(Vec<u32>, Vec<u8>), Error<IO::Error>> {
+        let first_cluster = u32::from_le_bytes([entry[20], entry[21], entry[22], entry[23]]);
+        let mut len = [0_u8; 8];
+        len.copy_from_slice(&entry[24..32]);
+        let len = u64::from_le_bytes(len);
+        let cluster_size = u64::from(self.cluster_size());
+        if len > u64::from(self.boot.cluster_count) * cluster_size {
+            error!("Invalid length of metadata stream {}", len);
+            return Err(Error::CorruptedFileSystem);
+        }
+
+        // safe cast: checked above that the stream fits on the volume which is smaller than the address space
+        let mut data = vec![0_u8; len as usize];
+        let mut clusters = Vec::new();
+        let mut cluster = Some(first_cluster);
+        for chunk in data.chunks_mut(cluster_size as usize) {
+            let Some(current) = cluster else {
+                error!("Metadata stream is longer than its cluster chain");
+                return Err(Error::CorruptedFileSystem);
+            };
+            self.read_at(self.offset_from_cluster(current)?, chunk)?;
+            clusters.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok((clusters, data))
+    }
+
+    /// Returns the volume serial number.
+    pub fn volume_id(&self) -> u32 {
+        self.boot.volume_serial_number
+    }
+
+    /// Returns the volume label stored in the root directory.
+    pub fn volume_label(&self) -> String {
+        self.volume_label.borrow().clone()
+    }
+
+    /// Returns cluster size in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.boot.cluster_size()
+    }
+
+    /// Returns status flags for this volume, the dirty flag set by writes of this mount is not included.
+    pub fn read_status_flags(&self) -> FsStatusFlags {
+        self.boot.status_flags()
+    }
+
+    /// Returns filesystem statistics like number of total and free clusters.
+    ///
+    /// Unlike FAT the number of free clusters is always exact as it is counted in the allocation bitmap on mount.
+    pub fn stats(&self) -> FileSystemStats {
+        FileSystemStats {
+            cluster_size: self.cluster_size(),
+            total_clusters: self.boot.cluster_count,
+            free_clusters: self.bitmap.borrow().free_clusters,
+        }
+    }
+
+    /// Clears the dirty and media failure flags in the boot sector.
+    ///
+    /// Like on FAT flags that were set on mount are kept even after a clean unmount, this should only be called once
+    /// the volume was checked. Writes mark the volume dirty again until it is unmounted.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn clear_status_flags(&mut self) -> Result<(), Error<IO::Error>> {
+        self.boot.clear_status_flags();
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(VOLUME_FLAGS_OFFSET))?;
+        disk.write_all(&self.boot.volume_flags_with_dirty(self.dirty.get()).to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Unmounts the filesystem.
+    ///
+    /// Restores the volume flags read on mount and flushes the storage.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn unmount(self) -> Result<(), Error<IO::Error>> {
+        self.flush()
+    }
+
+    /// Restores the volume flags read on mount and flushes the storage.
+    ///
+    /// The filesystem stays mounted and the dirty flag is set again by the next write.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn flush(&self) -> Result<(), Error<IO::Error>> {
+        let mut disk = self.disk.borrow_mut();
+        if self.dirty.get() {
+            // make sure everything else is written before the volume is marked as clean
+            disk.flush()?;
+            disk.seek(SeekFrom::Start(VOLUME_FLAGS_OFFSET))?;
+            disk.write_all(&self.boot.volume_flags.to_le_bytes())?;
+            self.dirty.set(false);
+        }
+        disk.flush()?;
+        Ok(())
+    }
+
+    /// Sets the volume dirty flag before the first write.
+    fn set_dirty_flag(&self) -> Result<(), Error<IO::Error>> {
+        if !self.dirty.get() {
+            let mut disk = self.disk.borrow_mut();
+            disk.seek(SeekFrom::Start(VOLUME_FLAGS_OFFSET))?;
+            disk.write_all(&self.boot.volume_flags_with_dirty(true).to_le_bytes())?;
+            // the flag must be set before anything else is written
+            disk.flush()?;
+            self.dirty.set(true);
+        }
+        Ok(())
+    }
+
+    /// Returns the root directory.
+    pub fn root_dir(&self) -> Dir<'_, IO, TP> {
+        Dir::new(File::root(self.boot.root_dir_first_cluster, self))
+    }
+
+    /// Returns the underlying storage, for example to read statistics of a `BlockCache`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the storage is being accessed by the filesystem at the same time, which cannot happen unless it is
+    /// called from a storage method.
+    pub fn storage(&self) -> Ref<'_, IO> {
+        self.disk.borrow()
+    }
+
+    /// Sets a hook called with the byte offset and length of every contiguous run of free clusters on the storage.
+    ///
+    /// Works like `FileSystem::set_discard_hook` of FAT, the hook is only called by `discard_freed_clusters` and
+    /// `discard_free_space`.
+    pub fn set_discard_hook<F: FnMut(u64, u64) + Send + 'static>(&mut self, hook: F) {
+        *self.discard_hook.get_mut() = Some(Box::new(hook));
+    }
+
+    /// Calls the discard hook for the clusters freed since the last discard that are still free.
+    ///
+    /// Call `flush` first so the allocation bitmap freeing the clusters is on the storage before their data is
+    /// discarded. Clusters allocated again in the meantime are skipped.
+    ///
+    /// Returns the number of bytes passed to the hook, which is zero if no hook is set.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn discard_freed_clusters(&self) -> Result<u64, Error<IO::Error>> {
+        let freed = core::mem::take(&mut *self.freed.borrow_mut());
+        let mut discarded = 0;
+        for (first, count) in freed {
+            discarded += self.discard_free_runs(first..first + count)?;
+        }
+        Ok(discarded)
+    }
+
+    /// Calls the discard hook for every run of free clusters on the volume.
+    ///
+    /// The clusters freed so far are included so they are not discarded again by `discard_freed_clusters`. Call
+    /// `flush` first for the same reason as with `discard_freed_clusters`.
+    ///
+    /// Returns the number of bytes passed to the hook, which is zero if no hook is set.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn discard_free_space(&self) -> Result<u64, Error<IO::Error>> {
+        self.freed.borrow_mut().clear();
+        self.discard_free_runs(FIRST_CLUSTER..FIRST_CLUSTER + self.boot.cluster_count)
+    }
+
+    /// Calls the discard hook for the runs of free clusters in `clusters`, returns the number of bytes discarded.
+    fn discard_free_runs(&self, clusters: Range<u32>) -> Result<u64, Error<IO::Error>> {
+        let mut hook = self.discard_hook.borrow_mut();
+        let Some(hook) = hook.as_mut() else {
+            return Ok(0);
+        };
+        let bitmap = self.bitmap.borrow();
+        let cluster_size = u64::from(self.cluster_size());
+        let mut discarded = 0;
+        let mut run_start = None;
+        for cluster in clusters.start..=clusters.end {
+            let free = cluster < clusters.end && !bitmap.is_used(cluster);
+            match (free, run_start) {
+                (true, None) => run_start = Some(cluster),
+                (false, Some(first)) => {
+                    let len = u64::from(cluster - first) * cluster_size;
+                    hook(self.offset_from_cluster(first)?, len);
+                    discarded += len;
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        Ok(discarded)
+    }
+
+    pub(crate) fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), Error<IO::Error>> {
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(pos))?;
+        disk.read_exact(buf)?;
+        Ok(())
+    }
+
+    pub(crate) fn write_at(&self, pos: u64, buf: &[u8]) -> Result<(), Error<IO::Error>> {
+        self.set_dirty_flag()?;
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(pos))?;
+        disk.write_all(buf)?;
+        Ok(())
+    }
+
+    pub(crate) fn upcase(&self) -> &UpcaseTable {
+        &self.upcase
+    }
+
+    pub(crate) fn offset_from_cluster(&self, cluster: u32) -> Result<u64, Error<IO::Error>> {
+        self.check_cluster(cluster)?;
+        let sector = u64::from(self.boot.cluster_heap_offset)
+            + (u64::from(cluster - FIRST_CLUSTER) << self.boot.sectors_per_cluster_shift);
+        Ok(self.boot.bytes_from_sectors(sector))
+    }
+
+    pub(crate) fn clusters_from_bytes(&self, bytes: u64) -> u64 {
+        let cluster_size = u64::from(self.cluster_size());
+        (bytes + cluster_size - 1) / cluster_size
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), Error<IO::Error>> {
+        if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.boot.cluster_count {
+            error!("Invalid cluster {}", cluster);
+            return Err(Error::CorruptedFileSystem);
+        }
+        Ok(())
+    }
+
+    fn fat_entry_pos(&self, cluster: u32) -> u64 {
+        self.boot.fat_pos() + u64::from(cluster) * 4
+    }
+
+    /// Counts the clusters of a file, the clusters are contiguous up to the data length with `no_fat_chain`, otherwise
+    /// the whole FAT chain is counted.
+    pub(crate) fn count_clusters(
+        &self,
+        first_cluster: u32,
+        no_fat_chain: bool,
+        len: u64,
+    ) -> Result<u32, Error<IO::Error>> {
+        if no_fat_chain {
+            let Ok(count) = u32::try_from(self.clusters_from_bytes(len)) else {
+                error!("Invalid file length {}", len);
+                return Err(Error::CorruptedFileSystem);
+            };
+            if count > 0 {
+                self.check_cluster(first_cluster.saturating_add(count - 1))?;
+            }
+            return Ok(count);
+        }
+        let mut count = 1;
+        let mut cluster = first_cluster;
+        while let Some(next) = self.next_cluster(cluster)? {
+            count += 1;
+            if count > self.boot.cluster_count {
+                error!("Cluster chain contains a loop");
+                return Err(Error::CorruptedFileSystem);
+            }
+            cluster = next;
+        }
+        Ok(count)
+    }
+
+    /// Returns the cluster following `cluster` in its FAT chain.
+    pub(crate) fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Error<IO::Error>> {
+        self.check_cluster(cluster)?;
+        let mut buf = [0_u8; 4];
+        self.read_at(self.fat_entry_pos(cluster), &mut buf)?;
+        match u32::from_le_bytes(buf) {
+            END_OF_CHAIN => Ok(None),
+            BAD_CLUSTER => {
+                error!("Bad cluster {} in a cluster chain", cluster);
+                Err(Error::CorruptedFileSystem)
+            }
+            next => {
+                self.check_cluster(next)?;
+                Ok(Some(next))
+            }
+        }
+    }
+
+    pub(crate) fn set_next_cluster(&self, cluster: u32, next: Option<u32>) -> Result<(), Error<IO::Error>> {
+        let value = next.unwrap_or(END_OF_CHAIN);
+        self.write_at(self.fat_entry_pos(cluster), &value.to_le_bytes())
+    }
+
+    /// Marks `cluster` as used or free in the allocation bitmap.
+    fn set_cluster_used(&self, cluster: u32, used: bool) -> Result<(), Error<IO::Error>> {
+        self.check_cluster(cluster)?;
+        let mut bitmap = self.bitmap.borrow_mut();
+        if bitmap.is_used(cluster) == used {
+            if !used {
+                warn!("Freeing a free cluster {}", cluster);
+            }
+            return Ok(());
+        }
+        let index = (cluster - FIRST_CLUSTER) as usize;
+        let byte_index = index / 8;
+        bitmap.data[byte_index] ^= 1 << (index % 8);
+        if used {
+            bitmap.free_clusters -= 1;
+        } else {
+            bitmap.free_clusters += 1;
+        }
+        let cluster_size = self.cluster_size() as usize;
+        let bitmap_cluster = bitmap.clusters[byte_index / cluster_size];
+        let pos = self.offset_from_cluster(bitmap_cluster)? + (byte_index % cluster_size) as u64;
+        let byte = bitmap.data[byte_index];
+        drop(bitmap);
+        self.write_at(pos, &[byte])
+    }
+
+    /// Allocates a cluster and links it after `prev_cluster` in the FAT.
+    pub(crate) fn alloc_cluster(&self, prev_cluster: Option<u32>, zero: bool) -> Result<u32, Error<IO::Error>> {
+        let cluster = {
+            let bitmap = self.bitmap.borrow();
+            if bitmap.free_clusters == 0 {
+                return Err(Error::NotEnoughSpace);
+            }
+            let end = FIRST_CLUSTER + self.boot.cluster_count;
+            let start = bitmap.next_free.clamp(FIRST_CLUSTER, end - 1);
+            let Some(cluster) = (start..end).chain(FIRST_CLUSTER..start).find(|c| !bitmap.is_used(*c)) else {
+                return Err(Error::NotEnoughSpace);
+            };
+            cluster
+        };
+        self.set_cluster_used(cluster, true)?;
+        self.bitmap.borrow_mut().next_free = cluster + 1;
+        self.set_next_cluster(cluster, None)?;
+        if let Some(prev_cluster) = prev_cluster {
+            self.set_next_cluster(prev_cluster, Some(cluster))?;
+        }
+        if zero {
+            let zeros = vec![0_u8; self.cluster_size() as usize];
+            self.write_at(self.offset_from_cluster(cluster)?, &zeros)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Frees `count` clusters from `first_cluster`, either following the FAT or contiguous clusters if `no_fat_chain`
+    /// is set.
+    pub(crate) fn free_clusters(
+        &self,
+        first_cluster: u32,
+        count: u64,
+        no_fat_chain: bool,
+    ) -> Result<(), Error<IO::Error>> {
+        // the clusters are contiguous unless the chain is fragmented
+        let mut run: Option<(u32, u32)> = None;
+        let mut cluster = Some(first_cluster);
+        for i in 0..count {
+            let Some(current) = cluster else {
+                break;
+            };
+            cluster = if no_fat_chain {
+                // safe cast: count is at most the number of clusters of the volume
+                Some(first_cluster + (i + 1) as u32).filter(|_| i + 1 < count)
+            } else {
+                self.next_cluster(current)?
+            };
+            self.set_cluster_used(current, false)?;
+            if self.discard_hook.borrow().is_some() {
+                match run {
+                    Some((first, len)) if current == first + len => run = Some((first, len + 1)),
+                    _ => {
+                        self.freed.borrow_mut().extend(run);
+                        run = Some((current, 1));
+                    }
+                }
+            }
+        }
+        self.freed.borrow_mut().extend(run);
+        Ok(())
+    }
+}
+
+impl<IO: ReadSeek, TP> FileSystem<ReadOnly<IO>, TP> {
+    /// Creates a new filesystem object instance that never writes to the storage.
+    ///
+    /// The storage only has to implement `Read` and `Seek`, the volume flags are never updated and operations that
+    /// modify the filesystem fail with the error returned by `IoError::new_read_only_error`.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::CorruptedFileSystem` will be returned if the storage does not contain a valid exFAT volume.
+    /// * `Error::Io` will be returned if the provided storage object returned an I/O error.
+    pub fn new_read_only<T: IntoReadStorage<IO>, OCC>(
+        storage: T,
+        options: FsOptions<TP, OCC>,
+    ) -> Result<Self, Error<IO::Error>> {
+        Self::new(ReadOnly::new(storage.into_read_storage()), options)
+    }
+}
+
+impl<IO: ReadWriteSeek, TP: TimeProvider> FileSystem<IO, TP> {
+    pub(crate) fn time_provider(&self) -> &TP {
+        &self.time_provider
+    }
+
+    /// Changes the volume label stored in the root directory.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if the label is longer than 11 UTF-16 code units.
+    /// * `Error::NotEnoughSpace` will be returned if the root directory is full and cannot grow.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn set_volume_label(&self, volume_label: &str) -> Result<(), Error<IO::Error>> {
+        let units: Vec<u16> = volume_label.encode_utf16().collect();
+        if units.len() > MAX_LABEL_LEN {
+            return Err(Error::InvalidInput);
+        }
+        let mut entry = [0_u8; ENTRY_SIZE];
+        // an empty label is stored as an unused label entry
+        entry[0] = if units.is_empty() {
+            TYPE_LABEL & !dir::IN_USE
+        } else {
+            TYPE_LABEL
+        };
+        // safe cast: checked above
+        entry[1] = units.len() as u8;
+        for (i, unit) in units.iter().enumerate() {
+            entry[2 + i * 2..4 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        let root_dir = self.root_dir();
+        let pos = match root_dir.find_raw_entry(|entry| entry[0] & !dir::IN_USE == TYPE_LABEL & !dir::IN_USE)? {
+            Some((pos, _)) => pos,
+            None => root_dir.alloc_entries(1)?.1[0],
+        };
+        self.write_at(pos, &entry)?;
+        *self.volume_label.borrow_mut() = String::from(volume_label);
+        Ok(())
+    }
+
+    /// Changes the volume serial number in both boot regions and updates their checksums.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn set_volume_id(&mut self, volume_id: u32) -> Result<(), Error<IO::Error>> {
+        let sector_size = u64::from(self.boot.bytes_per_sector());
+        // safe cast: sectors are at most 4 KiB
+        let mut region = vec![0_u8; (sector_size * u64::from(CHECKSUMMED_SECTORS)) as usize];
+        for region_pos in [0, sector_size * u64::from(BOOT_REGION_SECTORS)] {
+            self.read_at(region_pos, &mut region)?;
+            let serial_number_pos = VOLUME_SERIAL_NUMBER_OFFSET as usize;
+            region[serial_number_pos..serial_number_pos + 4].copy_from_slice(&volume_id.to_le_bytes());
+            // safe cast: sectors are at most 4 KiB
+            let checksums = boot_checksum(&region).to_le_bytes().repeat((sector_size / 4) as usize);
+            self.write_at(region_pos + VOLUME_SERIAL_NUMBER_OFFSET, &volume_id.to_le_bytes())?;
+            self.write_at(region_pos + sector_size * u64::from(CHECKSUMMED_SECTORS), &checksums)?;
+        }
+        self.boot.volume_serial_number = volume_id;
+        Ok(())
+    }
+}
+
+/// `Drop` implementation tries to unmount the filesystem when dropping.
+impl<IO: ReadWriteSeek, TP> Drop for FileSystem<IO, TP> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("unmount failed {:?}", err);
+        }
+    }
+}
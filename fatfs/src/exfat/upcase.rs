@@ -0,0 +1,146 @@
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::vec::Vec;
+
+/// Number of UTF-16 code units covered by the up-case table.
+const TABLE_LEN: usize = 0x1_0000;
+
+/// Marks a run of code units that map to themselves in a compressed up-case table, followed by the run length.
+const IDENTITY_RUN: u16 = 0xFFFF;
+
+/// Mapping of UTF-16 code units to upper case used for case-insensitive file name comparison.
+///
+/// exFAT stores the table on the volume so all implementations compare names the same way.
+pub(crate) struct UpcaseTable {
+    map: Vec<u16>,
+}
+
+impl UpcaseTable {
+    /// Creates a table from the case conversion of the standard library, or for ASCII letters only if the `unicode`
+    /// feature is disabled.
+    pub(crate) fn generate() -> Self {
+        let map = (0..=u16::MAX).map(upcase_char).collect();
+        Self { map }
+    }
+
+    /// Decodes a table as stored on the volume, either compressed or not.
+    pub(crate) fn decode(data: &[u8]) -> Self {
+        let mut map: Vec<u16> = (0..=u16::MAX).collect();
+        let mut units = data.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let mut pos = 0_usize;
+        while let Some(unit) = units.next() {
+            if pos >= TABLE_LEN {
+                break;
+            }
+            if unit == IDENTITY_RUN {
+                if let Some(run) = units.next() {
+                    pos += usize::from(run);
+                    continue;
+                }
+            }
+            map[pos] = unit;
+            pos += 1;
+        }
+        Self { map }
+    }
+
+    /// Encodes the table in the compressed form, code units mapping to themselves are stored as runs.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut units = Vec::new();
+        let mut pos = 0_usize;
+        while pos < TABLE_LEN {
+            let run = self.map[pos..]
+                .iter()
+                .enumerate()
+                .take_while(|(i, unit)| usize::from(**unit) == pos + i)
+                .count();
+            if run > 1 {
+                // longer runs are split, safe cast: the run is limited to u16::MAX
+                let run = run.min(usize::from(u16::MAX));
+                units.push(IDENTITY_RUN);
+                units.push(run as u16);
+                pos += run;
+            } else {
+                units.push(self.map[pos]);
+                pos += 1;
+            }
+        }
+        units.iter().flat_map(|unit| unit.to_le_bytes()).collect()
+    }
+
+    pub(crate) fn upcase(&self, unit: u16) -> u16 {
+        self.map[usize::from(unit)]
+    }
+
+    /// Compares two names ignoring case.
+    pub(crate) fn eq_ignore_case(&self, a: &[u16], b: &[u16]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| self.upcase(*x) == self.upcase(*y))
+    }
+
+    /// Hash of the up-cased name stored in the stream extension entry to speed up lookups.
+    pub(crate) fn name_hash(&self, name: &[u16]) -> u16 {
+        let mut hash = 0_u16;
+        for unit in name {
+            for byte in self.upcase(*unit).to_le_bytes() {
+                hash = hash.rotate_right(1).wrapping_add(u16::from(byte));
+            }
+        }
+        hash
+    }
+}
+
+#[cfg(feature = "unicode")]
+fn upcase_char(unit: u16) -> u16 {
+    let Some(c) = char::from_u32(u32::from(unit)) else {
+        // surrogates
+        return unit;
+    };
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        // characters becoming multiple characters (like 'ß') cannot be mapped
+        (Some(u), None) => u16::try_from(u32::from(u)).unwrap_or(unit),
+        _ => unit,
+    }
+}
+
+#[cfg(not(feature = "unicode"))]
+fn upcase_char(unit: u16) -> u16 {
+    if (u16::from(b'a')..=u16::from(b'z')).contains(&unit) {
+        unit - 0x20
+    } else {
+        unit
+    }
+}
+
+/// Checksum of the up-case table stored in its directory entry.
+pub(crate) fn table_checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0_u32, |sum, byte| sum.rotate_right(1).wrapping_add(u32::from(*byte)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_table_decodes_to_the_same_mapping() {
+        let table = UpcaseTable::generate();
+        let encoded = table.encode();
+        // identity runs make the table much smaller than 128 KiB
+        assert!(encoded.len() < 16 * 1024);
+        let decoded = UpcaseTable::decode(&encoded);
+        assert!(decoded.map == table.map);
+        assert_eq!(decoded.upcase(u16::from(b'a')), u16::from(b'A'));
+        assert_eq!(decoded.upcase(u16::from(b'_')), u16::from(b'_'));
+    }
+
+    #[test]
+    fn names_compare_ignoring_case() {
+        let table = UpcaseTable::generate();
+        let a: Vec<u16> = "Song.mp3".encode_utf16().collect();
+        let b: Vec<u16> = "SONG.MP3".encode_utf16().collect();
+        assert!(table.eq_ignore_case(&a, &b));
+        assert_eq!(table.name_hash(&a), table.name_hash(&b));
+        let c: Vec<u16> = "Song.mp4".encode_utf16().collect();
+        assert!(!table.eq_ignore_case(&a, &c));
+    }
+}
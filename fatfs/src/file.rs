@@ -10,7 +10,8 @@ const MAX_FILE_SIZE: u32 = u32::MAX;
 
 /// A FAT filesystem file object used for reading and writing data.
 ///
-/// This struct is created by the `open_file` or `create_file` methods on `Dir`.
+/// This struct is created by the `open_file` or `create_file` methods on `Dir`. Dropping it updates the directory
+/// entry but does not flush the storage, call `flush` to make sure the data reached the storage.
 pub struct File<'a, IO: ReadWriteSeek, TP, OCC> {
     // Note first_cluster is None if file is empty
     first_cluster: Option<u32>,
@@ -230,9 +231,11 @@ impl<IO: ReadWriteSeek, TP: TimeProvider, OCC> File<'_, IO, TP, OCC> {
     }
 }
 
+// The storage is not flushed, that would flush it after every directory operation. It is flushed by `flush` and
+// when the filesystem is unmounted.
 impl<IO: ReadWriteSeek, TP, OCC> Drop for File<'_, IO, TP, OCC> {
     fn drop(&mut self) {
-        if let Err(err) = self.flush() {
+        if let Err(err) = self.flush_dir_entry() {
             error!("flush failed {:?}", err);
         }
     }
@@ -1,19 +1,26 @@
 #[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::boxed::Box;
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
 use alloc::string::String;
+#[cfg(all(not(feature = "std"), feature = "alloc"))]
+use alloc::vec::Vec;
 use core::borrow::BorrowMut;
-use core::cell::{Cell, RefCell};
+use core::cell::{Cell, Ref, RefCell};
 use core::convert::TryFrom;
 use core::fmt::Debug;
 use core::marker::PhantomData;
 
 use crate::boot_sector::{format_boot_sector, BiosParameterBlock, BootSector};
+#[cfg(feature = "alloc")]
+use crate::dir::DirIndexes;
 use crate::dir::{Dir, DirRawStream};
 use crate::dir_entry::{DirFileEntryData, FileAttributes, SFN_PADDING, SFN_SIZE};
 use crate::error::Error;
 use crate::file::File;
-use crate::io::{self, IoBase, Read, ReadLeExt, Seek, SeekFrom, Write, WriteLeExt};
+use crate::io::{self, IoBase, Read, ReadLeExt, ReadOnly, Seek, SeekFrom, Write, WriteLeExt};
 use crate::table::{
-    alloc_cluster, count_free_clusters, format_fat, read_fat_flags, ClusterIterator, RESERVED_FAT_ENTRIES,
+    alloc_cluster, count_chain_clusters, count_free_clusters, format_fat, free_cluster_runs, read_fat_flags,
+    write_fat_flags, ClusterIterator, RESERVED_FAT_ENTRIES,
 };
 use crate::time::{DefaultTimeProvider, TimeProvider};
 
@@ -238,6 +245,7 @@ pub struct FsOptions<TP, OCC> {
     pub(crate) oem_cp_converter: OCC,
     pub(crate) time_provider: TP,
     pub(crate) strict: bool,
+    pub(crate) dir_index: bool,
 }
 
 impl FsOptions<DefaultTimeProvider, LossyOemCpConverter> {
@@ -249,6 +257,7 @@ impl FsOptions<DefaultTimeProvider, LossyOemCpConverter> {
             oem_cp_converter: LossyOemCpConverter::new(),
             time_provider: DefaultTimeProvider::new(),
             strict: true,
+            dir_index: false,
         }
     }
 }
@@ -268,6 +277,7 @@ impl<TP: TimeProvider, OCC: OemCpConverter> FsOptions<TP, OCC> {
             oem_cp_converter,
             time_provider: self.time_provider,
             strict: self.strict,
+            dir_index: self.dir_index,
         }
     }
 
@@ -278,6 +288,7 @@ impl<TP: TimeProvider, OCC: OemCpConverter> FsOptions<TP, OCC> {
             oem_cp_converter: self.oem_cp_converter,
             time_provider,
             strict: self.strict,
+            dir_index: self.dir_index,
         }
     }
 
@@ -288,16 +299,29 @@ impl<TP: TimeProvider, OCC: OemCpConverter> FsOptions<TP, OCC> {
             oem_cp_converter: self.oem_cp_converter,
             time_provider: self.time_provider,
             strict,
+            dir_index: self.dir_index,
         }
     }
+
+    /// If enabled directories are indexed in memory by name on first lookup so opening and creating entries does
+    /// not scan the whole directory.
+    ///
+    /// Index of a directory is built once and kept in sync by operations on `Dir`, memory usage grows with the
+    /// number of entries in the looked up directories.
+    #[cfg(feature = "alloc")]
+    #[must_use]
+    pub fn dir_index(mut self, enabled: bool) -> Self {
+        self.dir_index = enabled;
+        self
+    }
 }
 
 /// A FAT volume statistics.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct FileSystemStats {
-    cluster_size: u32,
-    total_clusters: u32,
-    free_clusters: u32,
+    pub(crate) cluster_size: u32,
+    pub(crate) total_clusters: u32,
+    pub(crate) free_clusters: u32,
 }
 
 impl FileSystemStats {
@@ -333,8 +357,20 @@ pub struct FileSystem<IO: ReadWriteSeek, TP = DefaultTimeProvider, OCC = LossyOe
     total_clusters: u32,
     fs_info: RefCell<FsInfoSector>,
     current_status_flags: Cell<FsStatusFlags>,
+    read_only: bool,
+    #[cfg(feature = "alloc")]
+    pub(crate) dir_indexes: RefCell<DirIndexes>,
+    #[cfg(feature = "alloc")]
+    discard_hook: RefCell<Option<DiscardHook>>,
+    // runs of clusters freed since the last discard, as the first cluster and the length
+    #[cfg(feature = "alloc")]
+    freed: RefCell<Vec<(u32, u32)>>,
 }
 
+/// A hook called with the byte offset and length of a range of the storage that is no longer used.
+#[cfg(feature = "alloc")]
+pub type DiscardHook = Box<dyn FnMut(u64, u64) + Send>;
+
 pub trait IntoStorage<T: Read + Write + Seek> {
     fn into_storage(self) -> T;
 }
@@ -352,6 +388,23 @@ impl<T: std::io::Read + std::io::Write + std::io::Seek> IntoStorage<io::StdIoWra
     }
 }
 
+pub trait IntoReadStorage<T: Read + Seek> {
+    fn into_read_storage(self) -> T;
+}
+
+impl<T: Read + Seek> IntoReadStorage<T> for T {
+    fn into_read_storage(self) -> Self {
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read + std::io::Seek> IntoReadStorage<io::StdIoWrapper<T>> for T {
+    fn into_read_storage(self) -> io::StdIoWrapper<Self> {
+        io::StdIoWrapper::new(self)
+    }
+}
+
 impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
     /// Creates a new filesystem object instance.
     ///
@@ -420,9 +473,21 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
             total_clusters,
             fs_info: RefCell::new(fs_info),
             current_status_flags: Cell::new(status_flags),
+            read_only: false,
+            #[cfg(feature = "alloc")]
+            dir_indexes: RefCell::new(DirIndexes::default()),
+            #[cfg(feature = "alloc")]
+            discard_hook: RefCell::new(None),
+            #[cfg(feature = "alloc")]
+            freed: RefCell::new(Vec::new()),
         })
     }
 
+    /// Returns true if the filesystem was mounted by `FileSystem::new_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
     /// Returns a type of File Allocation Table (FAT) used by this filesystem.
     pub fn fat_type(&self) -> FatType {
         self.fat_type
@@ -433,6 +498,18 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
         self.bpb.volume_id
     }
 
+    /// Returns the number of entries the root directory can hold.
+    ///
+    /// Only FAT12 and FAT16 have a fixed size root directory, on FAT32 it grows like any other directory and `None`
+    /// is returned.
+    pub fn max_root_dir_entries(&self) -> Option<u16> {
+        if self.fat_type == FatType::Fat32 {
+            None
+        } else {
+            Some(self.bpb.root_entries)
+        }
+    }
+
     /// Returns a volume label from BPB in the Boot Sector as byte array slice.
     ///
     /// Label is encoded in the OEM codepage.
@@ -486,7 +563,9 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
 
     pub(crate) fn truncate_cluster_chain(&self, cluster: u32) -> Result<(), Error<IO::Error>> {
         let mut iter = self.cluster_iter(cluster);
-        let num_free = iter.truncate()?;
+        let mut run = None;
+        let num_free = iter.truncate(|n| self.collect_freed(&mut run, Some(n)))?;
+        self.collect_freed(&mut run, None);
         let mut fs_info = self.fs_info.borrow_mut();
         fs_info.map_free_clusters(|n| n + num_free);
         Ok(())
@@ -494,12 +573,37 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
 
     pub(crate) fn free_cluster_chain(&self, cluster: u32) -> Result<(), Error<IO::Error>> {
         let mut iter = self.cluster_iter(cluster);
-        let num_free = iter.free()?;
+        let mut run = None;
+        let num_free = iter.free(|n| self.collect_freed(&mut run, Some(n)))?;
+        self.collect_freed(&mut run, None);
         let mut fs_info = self.fs_info.borrow_mut();
         fs_info.map_free_clusters(|n| n + num_free);
         Ok(())
     }
 
+    /// Collects freed clusters into contiguous runs to be discarded by `discard_freed_clusters`.
+    ///
+    /// `None` marks the end of the freed chain.
+    fn collect_freed(&self, run: &mut Option<(u32, u32)>, cluster: Option<u32>) {
+        #[cfg(feature = "alloc")]
+        {
+            if self.discard_hook.borrow().is_none() {
+                return;
+            }
+            match (*run, cluster) {
+                (Some((first, count)), Some(n)) if n == first + count => *run = Some((first, count + 1)),
+                (prev, next) => {
+                    if let Some(prev) = prev {
+                        self.freed.borrow_mut().push(prev);
+                    }
+                    *run = next.map(|n| (n, 1));
+                }
+            }
+        }
+        #[cfg(not(feature = "alloc"))]
+        let _ = (run, cluster);
+    }
+
     pub(crate) fn alloc_cluster(&self, prev_cluster: Option<u32>, zero: bool) -> Result<u32, Error<IO::Error>> {
         trace!("alloc_cluster");
         let hint = self.fs_info.borrow().next_free_cluster;
@@ -532,6 +636,50 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
         })
     }
 
+    /// Clears the dirty and IO error flags in the Boot Sector and the allocation table.
+    ///
+    /// By default flags that were set on mount are kept even after a clean unmount, this should only be called once
+    /// the volume was checked. Writes mark the volume dirty again until it is unmounted.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn clear_status_flags(&mut self) -> Result<(), Error<IO::Error>> {
+        // same as in flush, the flags must not reach the storage before the data they cover
+        self.disk.get_mut().flush()?;
+        let clean = FsStatusFlags {
+            dirty: false,
+            io_error: false,
+        };
+        write_fat_flags(&mut self.fat_slice(), self.fat_type, clean)?;
+        // keep the other bits of the field
+        self.bpb.reserved_1 &= !0b11;
+        let offset = if self.fat_type() == FatType::Fat32 {
+            0x041
+        } else {
+            0x025
+        };
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(io::SeekFrom::Start(offset))?;
+        disk.write_u8(self.bpb.reserved_1)?;
+        disk.flush()?;
+        self.current_status_flags.set(self.bpb.status_flags());
+        Ok(())
+    }
+
+    /// Counts clusters in the chain starting at `first_cluster`.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::CorruptedFileSystem` will be returned if the chain points outside of the volume, to a free or bad
+    ///   cluster, or contains a loop.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub(crate) fn count_chain_clusters(&self, first_cluster: u32) -> Result<u32, Error<IO::Error>> {
+        count_chain_clusters(&mut self.fat_slice(), self.fat_type, first_cluster, self.total_clusters)
+    }
+
     /// Returns filesystem statistics like number of total and free clusters.
     ///
     /// For FAT32 volumes number of free clusters from the FS Information Sector is returned (may be incorrect).
@@ -574,14 +722,107 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
     }
 
     fn unmount_internal(&self) -> Result<(), Error<IO::Error>> {
+        self.flush()
+    }
+
+    /// Writes the cached filesystem information, clears the dirty flag and flushes the storage.
+    ///
+    /// The filesystem stays mounted and the dirty flag is set again by the next write. This is useful to make
+    /// sure everything is written to the storage at some point without unmounting.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn flush(&self) -> Result<(), Error<IO::Error>> {
         self.flush_fs_info()?;
+        // a write-back storage may reorder writes so everything must be written before the volume is marked clean
+        self.disk.borrow_mut().flush()?;
         self.set_dirty_flag(false)?;
         Ok(())
     }
 
+    /// Sets a hook called with the byte offset and length of every contiguous run of free clusters on the storage.
+    ///
+    /// The hook can tell the underlying device that the data is no longer needed (TRIM). It is not called when
+    /// clusters are freed, because the FAT update freeing them may still be buffered by the storage, see
+    /// `discard_freed_clusters` and `discard_free_space`.
+    #[cfg(feature = "alloc")]
+    pub fn set_discard_hook<F: FnMut(u64, u64) + Send + 'static>(&mut self, hook: F) {
+        *self.discard_hook.get_mut() = Some(Box::new(hook));
+    }
+
+    /// Calls the discard hook for the clusters freed since the last discard that are still free.
+    ///
+    /// Call `flush` first so the FAT freeing the clusters is on the storage before their data is discarded,
+    /// otherwise an interruption can leave files pointing to discarded clusters. Clusters allocated again in the
+    /// meantime are skipped.
+    ///
+    /// Returns the number of bytes passed to the hook, which is zero if no hook is set.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    #[cfg(feature = "alloc")]
+    pub fn discard_freed_clusters(&self) -> Result<u64, Error<IO::Error>> {
+        let mut hook = self.discard_hook.borrow_mut();
+        let Some(hook) = hook.as_mut() else {
+            return Ok(0);
+        };
+        let freed = core::mem::take(&mut *self.freed.borrow_mut());
+        let mut discarded = 0;
+        let mut fat = self.fat_slice();
+        for (first, count) in freed {
+            free_cluster_runs(&mut fat, self.fat_type, first..first + count, |first, count| {
+                let len = self.bytes_from_clusters(count);
+                hook(self.offset_from_cluster(first), len);
+                discarded += len;
+            })?;
+        }
+        Ok(discarded)
+    }
+
+    /// Calls the discard hook for every run of free clusters on the volume.
+    ///
+    /// The clusters freed so far are included so they are not discarded again by `discard_freed_clusters`. Call
+    /// `flush` first for the same reason as with `discard_freed_clusters`.
+    ///
+    /// Returns the number of bytes passed to the hook, which is zero if no hook is set.
+    ///
+    /// # Errors
+    ///
+    /// `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    #[cfg(feature = "alloc")]
+    pub fn discard_free_space(&self) -> Result<u64, Error<IO::Error>> {
+        let mut hook = self.discard_hook.borrow_mut();
+        let Some(hook) = hook.as_mut() else {
+            return Ok(0);
+        };
+        self.freed.borrow_mut().clear();
+        let mut discarded = 0;
+        let mut fat = self.fat_slice();
+        let clusters = RESERVED_FAT_ENTRIES..self.total_clusters + RESERVED_FAT_ENTRIES;
+        free_cluster_runs(&mut fat, self.fat_type, clusters, |first, count| {
+            let len = self.bytes_from_clusters(count);
+            hook(self.offset_from_cluster(first), len);
+            discarded += len;
+        })?;
+        Ok(discarded)
+    }
+
+    /// Returns the underlying storage, for example to read statistics of a `BlockCache`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the storage is being accessed by the filesystem at the same time, which cannot happen unless it is
+    /// called from a storage method.
+    pub fn storage(&self) -> Ref<'_, IO> {
+        self.disk.borrow()
+    }
+
     fn flush_fs_info(&self) -> Result<(), Error<IO::Error>> {
         let mut fs_info = self.fs_info.borrow_mut();
-        if self.fat_type == FatType::Fat32 && fs_info.dirty {
+        // counting free clusters updates the cached value but a read-only mount must not write it back
+        if self.fat_type == FatType::Fat32 && fs_info.dirty && !self.read_only {
             let mut disk = self.disk.borrow_mut();
             let fs_info_sector_offset = self.offset_from_sector(u32::from(self.bpb.fs_info_sector));
             disk.seek(SeekFrom::Start(fs_info_sector_offset))?;
@@ -610,8 +851,13 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
             0x025
         };
         let mut disk = self.disk.borrow_mut();
+        // callers may be in the middle of writing so keep the position
+        let pos = disk.seek(io::SeekFrom::Current(0))?;
         disk.seek(io::SeekFrom::Start(offset))?;
         disk.write_u8(encoded)?;
+        // flush right away so a write-back storage cannot write the flag out of order with other data
+        disk.flush()?;
+        disk.seek(io::SeekFrom::Start(pos))?;
         self.current_status_flags.set(flags);
         Ok(())
     }
@@ -635,6 +881,35 @@ impl<IO: Read + Write + Seek, TP, OCC> FileSystem<IO, TP, OCC> {
     }
 }
 
+impl<IO: ReadSeek, TP, OCC> FileSystem<ReadOnly<IO>, TP, OCC> {
+    /// Creates a new filesystem object instance that never writes to the storage.
+    ///
+    /// Unlike `FileSystem::new` the storage only has to implement `Read` and `Seek`. Neither the dirty flag nor the
+    /// FS Information Sector is updated, even on unmount, and accessed dates are not updated whatever `options` say.
+    /// Operations that modify the filesystem fail with the error returned by `IoError::new_read_only_error`.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::CorruptedFileSystem` will be returned if the boot sector and/or the file system information sector
+    ///   contains invalid values.
+    /// * `Error::Io` will be returned if the provided storage object returned an I/O error.
+    ///
+    /// # Panics
+    ///
+    /// Panics in non-optimized build if `storage` position returned by `seek` is not zero.
+    pub fn new_read_only<T: IntoReadStorage<IO>>(
+        storage: T,
+        mut options: FsOptions<TP, OCC>,
+    ) -> Result<Self, Error<IO::Error>> {
+        options.update_accessed_date = false;
+        let mut fs = Self::new(ReadOnly::new(storage.into_read_storage()), options)?;
+        fs.read_only = true;
+        Ok(fs)
+    }
+}
+
 impl<IO: ReadWriteSeek, TP, OCC: OemCpConverter> FileSystem<IO, TP, OCC> {
     /// Returns a volume label from BPB in the Boot Sector as `String`.
     ///
@@ -691,6 +966,62 @@ impl<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> FileSystem<IO, TP
         let entry_opt = self.root_dir().find_volume_entry()?;
         Ok(entry_opt.map(|e| *e.raw_short_name()))
     }
+
+    /// Changes the volume label stored in the BPB (including the backup boot sector) and in the root directory.
+    ///
+    /// Label is encoded in the OEM codepage. The volume entry in the root directory is created if missing.
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if the boot sector has no extended BPB to store the label in.
+    /// * `Error::NotEnoughSpace` will be returned if there is no space for the volume entry in the root directory.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn set_volume_label(&mut self, volume_label: [u8; SFN_SIZE]) -> Result<(), Error<IO::Error>> {
+        // Note: only the label field is written to avoid rewriting entire boot-sector which could be dangerous
+        self.write_ext_bpb_field(0x04, &volume_label)?;
+        self.root_dir().set_volume_entry(volume_label)?;
+        self.bpb.volume_label = volume_label;
+        Ok(())
+    }
+
+    /// Changes the volume identifier (serial number) stored in the BPB (including the backup boot sector).
+    ///
+    /// # Errors
+    ///
+    /// Errors that can be returned:
+    ///
+    /// * `Error::InvalidInput` will be returned if the boot sector has no extended BPB to store the identifier in.
+    /// * `Error::Io` will be returned if the underlying storage object returned an I/O error.
+    pub fn set_volume_id(&mut self, volume_id: u32) -> Result<(), Error<IO::Error>> {
+        self.write_ext_bpb_field(0x00, &volume_id.to_le_bytes())?;
+        self.bpb.volume_id = volume_id;
+        Ok(())
+    }
+
+    /// Writes a field of the extended BPB given by its offset from the volume identifier to both boot sectors.
+    fn write_ext_bpb_field(&self, field_offset: u64, data: &[u8]) -> Result<(), Error<IO::Error>> {
+        // the fields are only valid with the extended boot signature
+        if self.bpb.ext_sig != 0x29 {
+            return Err(Error::InvalidInput);
+        }
+        let volume_id_offset = if self.fat_type() == FatType::Fat32 {
+            0x043
+        } else {
+            0x027
+        };
+        let offset = volume_id_offset + field_offset;
+        let mut disk = self.disk.borrow_mut();
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.write_all(data)?;
+        if self.fat_type() == FatType::Fat32 && self.bpb.backup_boot_sector != 0 {
+            let backup_offset = self.offset_from_sector(u32::from(self.bpb.backup_boot_sector));
+            disk.seek(SeekFrom::Start(backup_offset + offset))?;
+            disk.write_all(data)?;
+        }
+        Ok(())
+    }
 }
 
 /// `Drop` implementation tries to unmount the filesystem when dropping.
@@ -718,11 +1049,11 @@ impl<IO: ReadWriteSeek, TP, OCC> Read for FsIoAdapter<'_, IO, TP, OCC> {
 
 impl<IO: ReadWriteSeek, TP, OCC> Write for FsIoAdapter<'_, IO, TP, OCC> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let size = self.fs.disk.borrow_mut().write(buf)?;
-        if size > 0 {
+        // the volume must be marked dirty on the storage before anything it covers is written
+        if !buf.is_empty() {
             self.fs.set_dirty_flag(true)?;
         }
-        Ok(size)
+        self.fs.disk.borrow_mut().write(buf)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
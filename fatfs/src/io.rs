@@ -239,6 +239,62 @@ impl<T> From<T> for StdIoWrapper<T> {
     }
 }
 
+/// A wrapper struct for storage that can only be read.
+///
+/// `Write` is implemented so the storage can be used by `FileSystem`, but every write fails with the error returned
+/// by `IoError::new_read_only_error`. It is used by `FileSystem::new_read_only`.
+pub struct ReadOnly<T> {
+    inner: T,
+}
+
+impl<T> ReadOnly<T> {
+    /// Creates a new `ReadOnly` instance that wraps the provided `inner` instance.
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Returns a reference to the inner struct
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns inner struct
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: IoBase> IoBase for ReadOnly<T> {
+    type Error = T::Error;
+}
+
+impl<T: Read> Read for ReadOnly<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.inner.read(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.inner.read_exact(buf)
+    }
+}
+
+impl<T: IoBase> Write for ReadOnly<T> {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
+        Err(Self::Error::new_read_only_error())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        // nothing can be buffered
+        Ok(())
+    }
+}
+
+impl<T: Seek> Seek for ReadOnly<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.inner.seek(pos)
+    }
+}
+
 pub(crate) trait ReadLeExt {
     type Error;
     fn read_u8(&mut self) -> Result<u8, Self::Error>;
@@ -63,15 +63,21 @@ extern crate alloc;
 mod log_macros;
 
 mod boot_sector;
+#[cfg(feature = "alloc")]
+mod cache;
 mod dir;
 mod dir_entry;
 mod error;
+#[cfg(feature = "alloc")]
+pub mod exfat;
 mod file;
 mod fs;
 mod io;
 mod table;
 mod time;
 
+#[cfg(feature = "alloc")]
+pub use crate::cache::*;
 pub use crate::dir::*;
 pub use crate::dir_entry::*;
 pub use crate::error::*;
@@ -1,5 +1,6 @@
 use core::borrow::BorrowMut;
 use core::marker::PhantomData;
+use core::ops::Range;
 
 use crate::error::{Error, IoError};
 use crate::fs::{FatType, FsStatusFlags};
@@ -176,6 +177,62 @@ where
     Ok(FsStatusFlags { dirty, io_error })
 }
 
+pub(crate) fn write_fat_flags<S, E>(fat: &mut S, fat_type: FatType, flags: FsStatusFlags) -> Result<(), Error<E>>
+where
+    S: Read + Write + Seek,
+    E: IoError,
+    Error<E>: From<S::Error>,
+{
+    // bits are set when the volume is clean (FAT12 has no flags)
+    let (dirty_bit, io_error_bit) = match fat_type {
+        FatType::Fat12 => return Ok(()),
+        FatType::Fat16 => (1 << 15, 1 << 14),
+        FatType::Fat32 => (1 << 27, 1 << 26),
+    };
+    let mut val = match fat_type {
+        FatType::Fat16 => Fat16::get_raw(fat, 1)?,
+        _ => Fat32::get_raw(fat, 1)?,
+    };
+    val = if flags.dirty { val & !dirty_bit } else { val | dirty_bit };
+    val = if flags.io_error {
+        val & !io_error_bit
+    } else {
+        val | io_error_bit
+    };
+    match fat_type {
+        FatType::Fat16 => Fat16::set_raw(fat, 1, val),
+        _ => Fat32::set_raw(fat, 1, val),
+    }
+}
+
+pub(crate) fn count_chain_clusters<S, E>(
+    fat: &mut S,
+    fat_type: FatType,
+    first_cluster: u32,
+    total_clusters: u32,
+) -> Result<u32, Error<E>>
+where
+    S: Read + Seek,
+    E: IoError,
+    Error<E>: From<S::Error>,
+{
+    let max_valid_cluster_number = total_clusters + RESERVED_FAT_ENTRIES;
+    let mut cluster = first_cluster;
+    let mut count = 0;
+    loop {
+        // a chain longer than the volume must contain a loop
+        if cluster < RESERVED_FAT_ENTRIES || cluster >= max_valid_cluster_number || count >= total_clusters {
+            return Err(Error::CorruptedFileSystem);
+        }
+        count += 1;
+        match read_fat(fat, fat_type, cluster)? {
+            FatValue::Data(n) => cluster = n,
+            FatValue::EndOfChain => return Ok(count),
+            FatValue::Free | FatValue::Bad => return Err(Error::CorruptedFileSystem),
+        }
+    }
+}
+
 pub(crate) fn count_free_clusters<S, E>(fat: &mut S, fat_type: FatType, total_clusters: u32) -> Result<u32, Error<E>>
 where
     S: Read + Seek,
@@ -190,6 +247,38 @@ where
     }
 }
 
+/// Calls `f` with the first cluster and the length of every run of free clusters in `clusters`.
+pub(crate) fn free_cluster_runs<S, E, F>(
+    fat: &mut S,
+    fat_type: FatType,
+    clusters: Range<u32>,
+    mut f: F,
+) -> Result<(), Error<E>>
+where
+    S: Read + Seek,
+    E: IoError,
+    Error<E>: From<S::Error>,
+    F: FnMut(u32, u32),
+{
+    let end_cluster = clusters.end;
+    let mut run_start = None;
+    for cluster in clusters {
+        let free = read_fat(fat, fat_type, cluster)? == FatValue::Free;
+        match (free, run_start) {
+            (true, None) => run_start = Some(cluster),
+            (false, Some(first)) => {
+                f(first, cluster - first);
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(first) = run_start {
+        f(first, end_cluster - first);
+    }
+    Ok(())
+}
+
 pub(crate) fn format_fat<S, E>(
     fat: &mut S,
     fat_type: FatType,
@@ -608,24 +697,26 @@ where
         }
     }
 
-    pub(crate) fn truncate(&mut self) -> Result<u32, Error<E>> {
+    pub(crate) fn truncate(&mut self, on_free: impl FnMut(u32)) -> Result<u32, Error<E>> {
         if let Some(n) = self.cluster {
             // Move to the next cluster
             self.next();
             // Mark previous cluster as end of chain
             write_fat(self.fat.borrow_mut(), self.fat_type, n, FatValue::EndOfChain)?;
             // Free rest of chain
-            self.free()
+            self.free(on_free)
         } else {
             Ok(0)
         }
     }
 
-    pub(crate) fn free(&mut self) -> Result<u32, Error<E>> {
+    /// Frees the rest of the chain, calling `on_free` with every freed cluster.
+    pub(crate) fn free(&mut self, mut on_free: impl FnMut(u32)) -> Result<u32, Error<E>> {
         let mut num_free = 0;
         while let Some(n) = self.cluster {
             self.next();
             write_fat(self.fat.borrow_mut(), self.fat_type, n, FatValue::Free)?;
+            on_free(n);
             num_free += 1;
         }
         Ok(num_free)
@@ -713,7 +804,7 @@ mod tests {
         {
             let mut iter = ClusterIterator::<&mut S, S::Error, S>::new(&mut cur, fat_type, 0x9);
             assert_eq!(iter.nth(3).map(Result::ok), Some(Some(0x16)));
-            assert!(iter.truncate().is_ok());
+            assert!(iter.truncate(|_| ()).is_ok());
         }
         assert_eq!(read_fat(&mut cur, fat_type, 0x16).ok(), Some(FatValue::EndOfChain));
         assert_eq!(read_fat(&mut cur, fat_type, 0x19).ok(), Some(FatValue::Free));
@@ -721,7 +812,7 @@ mod tests {
         // test freeing a chain
         {
             let mut iter = ClusterIterator::<&mut S, S::Error, S>::new(&mut cur, fat_type, 0x9);
-            assert!(iter.free().is_ok());
+            assert!(iter.free(|_| ()).is_ok());
         }
         assert_eq!(read_fat(&mut cur, fat_type, 0x9).ok(), Some(FatValue::Free));
         assert_eq!(read_fat(&mut cur, fat_type, 0xA).ok(), Some(FatValue::Free));
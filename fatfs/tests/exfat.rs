@@ -0,0 +1,428 @@
+use std::io;
+use std::io::prelude::*;
+
+use fatfs::exfat;
+use fatfs::{Error, FileAttributes, FsOptions, StdIoWrapper};
+
+const KB: u64 = 1024;
+const MB: u64 = KB * 1024;
+const TEST_STR: &str = "Hi there Rust programmer!\n";
+
+type FileSystem<'a> = exfat::FileSystem<StdIoWrapper<&'a mut io::Cursor<Vec<u8>>>>;
+
+fn format_storage(opts: exfat::FormatVolumeOptions, total_bytes: u64) -> io::Cursor<Vec<u8>> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    // Init storage to 0xD1 bytes so nothing relies on zeroed storage
+    let mut storage = io::Cursor::new(vec![0xD1_u8; total_bytes as usize]);
+    exfat::format_volume(&mut StdIoWrapper::from(&mut storage), opts).expect("format volume");
+    storage
+}
+
+fn open_fs(storage: &mut io::Cursor<Vec<u8>>) -> FileSystem<'_> {
+    storage.set_position(0);
+    FileSystem::new(storage, FsOptions::new()).expect("open fs")
+}
+
+fn read_file(fs: &FileSystem<'_>, path: &str) -> Vec<u8> {
+    let mut content = Vec::new();
+    fs.root_dir()
+        .open_file(path)
+        .expect("open file")
+        .read_to_end(&mut content)
+        .expect("read file");
+    content
+}
+
+fn write_file(fs: &FileSystem<'_>, path: &str, content: &[u8]) {
+    let mut file = fs.root_dir().create_file(path).expect("create file");
+    file.truncate().expect("truncate");
+    file.write_all(content).expect("write file");
+}
+
+#[test]
+fn test_format_and_mount() {
+    let mut storage = format_storage(
+        exfat::FormatVolumeOptions::new()
+            .volume_id(0xCAFE_BABE)
+            .volume_label("MUSIC"),
+        32 * MB,
+    );
+    let fs = open_fs(&mut storage);
+    assert_eq!(fs.volume_id(), 0xCAFE_BABE);
+    assert_eq!(fs.volume_label(), "MUSIC");
+    assert_eq!(fs.cluster_size(), 4096);
+    assert!(!fs.read_status_flags().dirty());
+
+    let stats = fs.stats();
+    assert_eq!(stats.cluster_size(), 4096);
+    // the allocation bitmap, up-case table and root directory are the only used clusters
+    let used = stats.total_clusters() - stats.free_clusters();
+    assert!((3..=5).contains(&used), "{} used clusters", used);
+    assert!(fs.root_dir().iter().next().is_none());
+}
+
+#[test]
+fn test_format_too_small() {
+    let mut storage = io::Cursor::new(vec![0_u8; 16 * KB as usize]);
+    let result = exfat::format_volume(&mut StdIoWrapper::from(&mut storage), exfat::FormatVolumeOptions::new());
+    assert!(matches!(result, Err(Error::InvalidInput)));
+}
+
+#[test]
+fn test_set_volume_label() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    {
+        let fs = open_fs(&mut storage);
+        assert_eq!(fs.volume_label(), "");
+        fs.set_volume_label("Żółw").unwrap();
+        assert!(fs.set_volume_label("LABEL TOO LONG").is_err());
+        fs.unmount().unwrap();
+    }
+    {
+        let fs = open_fs(&mut storage);
+        assert_eq!(fs.volume_label(), "Żółw");
+        fs.set_volume_label("").unwrap();
+    }
+    let fs = open_fs(&mut storage);
+    assert_eq!(fs.volume_label(), "");
+}
+
+#[test]
+fn test_dirty_flag() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    {
+        let fs = open_fs(&mut storage);
+        write_file(&fs, "a.txt", TEST_STR.as_bytes());
+        // Make the volume dirty by forgetting to unmount it
+        std::mem::forget(fs);
+    }
+    let mut fs = open_fs(&mut storage);
+    assert!(fs.read_status_flags().dirty());
+    fs.clear_status_flags().unwrap();
+    assert!(!fs.read_status_flags().dirty());
+    // Writing makes it dirty again but a clean unmount now clears it
+    write_file(&fs, "b.txt", TEST_STR.as_bytes());
+    fs.unmount().unwrap();
+    let fs = open_fs(&mut storage);
+    assert!(!fs.read_status_flags().dirty());
+    assert!(!fs.read_status_flags().io_error());
+}
+
+#[test]
+fn test_write_read_files() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 16 * MB);
+    // spans several clusters and does not end at a cluster boundary
+    let big = TEST_STR.repeat(1000).into_bytes();
+    {
+        let fs = open_fs(&mut storage);
+        let root_dir = fs.root_dir();
+        root_dir.create_dir("Music").unwrap();
+        root_dir.create_dir("Music/Some Artist with a very long name").unwrap();
+        write_file(&fs, "Music/Some Artist with a very long name/01 - Track.mp3", &big);
+        write_file(&fs, "empty.txt", b"");
+
+        let mut file = root_dir
+            .open_file("Music/Some Artist with a very long name/01 - Track.mp3")
+            .unwrap();
+        file.seek(io::SeekFrom::Start(TEST_STR.len() as u64 * 500)).unwrap();
+        file.write_all(b"-----").unwrap();
+        file.seek(io::SeekFrom::End(0)).unwrap();
+        file.write_all(b"end").unwrap();
+        drop(file);
+        drop(root_dir);
+        fs.unmount().unwrap();
+    }
+
+    let fs = open_fs(&mut storage);
+    let mut expected = big.clone();
+    expected[TEST_STR.len() * 500..TEST_STR.len() * 500 + 5].copy_from_slice(b"-----");
+    expected.extend_from_slice(b"end");
+    assert_eq!(
+        read_file(&fs, "Music/Some Artist with a very long name/01 - Track.mp3"),
+        expected
+    );
+    assert!(read_file(&fs, "empty.txt").is_empty());
+
+    let names = fs
+        .root_dir()
+        .iter()
+        .map(|e| e.unwrap())
+        .map(|e| (e.file_name(), e.is_dir(), e.len()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [(String::from("Music"), true, 0), (String::from("empty.txt"), false, 0)]
+    );
+    let track = fs
+        .root_dir()
+        .open_dir("Music/Some Artist with a very long name")
+        .unwrap()
+        .iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(track.len(), expected.len() as u64);
+    assert!(track.attributes().contains(FileAttributes::ARCHIVE));
+}
+
+#[test]
+fn test_truncate_frees_clusters() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    let fs = open_fs(&mut storage);
+    let free = fs.stats().free_clusters();
+    write_file(&fs, "a.bin", &vec![0xAB; 10 * KB as usize]);
+    assert_eq!(fs.stats().free_clusters(), free - 3);
+
+    let mut file = fs.root_dir().open_file("a.bin").unwrap();
+    file.seek(io::SeekFrom::Start(5000)).unwrap();
+    file.truncate().unwrap();
+    drop(file);
+    assert_eq!(fs.stats().free_clusters(), free - 2);
+    assert_eq!(read_file(&fs, "a.bin"), vec![0xAB; 5000]);
+
+    fs.root_dir().remove("a.bin").unwrap();
+    assert_eq!(fs.stats().free_clusters(), free);
+}
+
+#[test]
+fn test_case_insensitive_names() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    let fs = open_fs(&mut storage);
+    let root_dir = fs.root_dir();
+    root_dir.create_dir("Żółta Łódź").unwrap();
+    write_file(&fs, "Żółta Łódź/Track.MP3", TEST_STR.as_bytes());
+    assert_eq!(read_file(&fs, "żÓŁTA łÓDŹ/track.mp3"), TEST_STR.as_bytes());
+    // creating an existing file opens it
+    root_dir.create_file("ŻÓŁTA ŁÓDŹ/TRACK.mp3").unwrap();
+    assert_eq!(root_dir.open_dir("Żółta Łódź").unwrap().iter().count(), 1);
+    assert!(matches!(
+        root_dir.open_file("Żółta Łódź/Track.mp4"),
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(root_dir.create_file("a/b"), Err(Error::NotFound)));
+    assert!(matches!(
+        root_dir.create_file("bad:name"),
+        Err(Error::InvalidFileNameLength | Error::UnsupportedFileNameCharacter)
+    ));
+}
+
+#[test]
+fn test_directory_grows_past_a_cluster() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new().bytes_per_cluster(4096), 8 * MB);
+    {
+        let fs = open_fs(&mut storage);
+        let dir = fs.root_dir().create_dir("dir").unwrap();
+        // each file takes 3 entries of 32 bytes, so a cluster holds 42 of them
+        for i in 0..100 {
+            dir.create_file(&format!("file {:03}.mp3", i)).unwrap();
+        }
+        drop(dir);
+        fs.unmount().unwrap();
+    }
+    let fs = open_fs(&mut storage);
+    let dir = fs.root_dir().open_dir("dir").unwrap();
+    let names = dir.iter().map(|e| e.unwrap().file_name()).collect::<Vec<_>>();
+    assert_eq!(names.len(), 100);
+    assert_eq!(names[99], "file 099.mp3");
+    assert!(!dir.is_empty().unwrap());
+    assert!(matches!(fs.root_dir().remove("dir"), Err(Error::DirectoryIsNotEmpty)));
+    for name in names {
+        dir.remove(&name).unwrap();
+    }
+    assert!(dir.is_empty().unwrap());
+    fs.root_dir().remove("dir").unwrap();
+}
+
+#[test]
+fn test_rename() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    let fs = open_fs(&mut storage);
+    let root_dir = fs.root_dir();
+    let dir = root_dir.create_dir("dir").unwrap();
+    write_file(&fs, "a.txt", TEST_STR.as_bytes());
+    write_file(&fs, "b.txt", b"b");
+
+    assert!(matches!(
+        root_dir.rename("a.txt", &root_dir, "B.TXT"),
+        Err(Error::AlreadyExists)
+    ));
+    root_dir.rename("a.txt", &root_dir, "A.txt").unwrap();
+    root_dir.rename("A.txt", &dir, "a file with a longer name.txt").unwrap();
+    assert!(matches!(root_dir.open_file("a.txt"), Err(Error::NotFound)));
+    assert_eq!(read_file(&fs, "dir/a file with a longer name.txt"), TEST_STR.as_bytes());
+    assert_eq!(root_dir.iter().count(), 2);
+}
+
+#[test]
+fn test_hardlinks() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    let content = TEST_STR.repeat(500).into_bytes();
+    {
+        let fs = open_fs(&mut storage);
+        let root_dir = fs.root_dir();
+        let music = root_dir.create_dir("Music").unwrap();
+        write_file(&fs, "Music/song.mp3", &content);
+        write_file(&fs, "002.mp3", b"replaced");
+        let free = fs.stats().free_clusters();
+
+        root_dir.create_hardlink("001.mp3", &music, "song.mp3").unwrap();
+        // an existing entry is replaced without freeing what it pointed to
+        root_dir.create_hardlink("002.mp3", &music, "SONG.MP3").unwrap();
+        assert_eq!(fs.stats().free_clusters(), free);
+        assert!(matches!(
+            root_dir.create_hardlink("003.mp3", &root_dir, "Music"),
+            Err(Error::InvalidInput)
+        ));
+
+        let song = music.iter().next().unwrap().unwrap();
+        for link in root_dir.iter().map(|e| e.unwrap()).filter(|e| e.is_file()) {
+            assert_eq!(link.first_cluster(), song.first_cluster());
+            assert_eq!(link.len(), song.len());
+            assert!(link
+                .attributes()
+                .contains(FileAttributes::SYSTEM | FileAttributes::READ_ONLY));
+        }
+        drop(song);
+        drop(music);
+        drop(root_dir);
+        fs.unmount().unwrap();
+    }
+
+    let fs = open_fs(&mut storage);
+    let root_dir = fs.root_dir();
+    assert_eq!(read_file(&fs, "001.mp3"), content);
+    assert_eq!(read_file(&fs, "002.mp3"), content);
+    let free = fs.stats().free_clusters();
+    root_dir.remove_entry("001.mp3").unwrap();
+    root_dir.remove_entry("002.mp3").unwrap();
+    assert_eq!(fs.stats().free_clusters(), free);
+    assert_eq!(read_file(&fs, "Music/song.mp3"), content);
+    root_dir.remove("Music/song.mp3").unwrap();
+    assert!(fs.stats().free_clusters() > free);
+}
+
+#[test]
+fn test_read_only() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    {
+        let fs = open_fs(&mut storage);
+        write_file(&fs, "a.txt", TEST_STR.as_bytes());
+        fs.unmount().unwrap();
+    }
+
+    let image = storage.into_inner();
+    let fs: exfat::FileSystem<fatfs::ReadOnly<StdIoWrapper<io::Cursor<&[u8]>>>> =
+        exfat::FileSystem::new_read_only(io::Cursor::new(image.as_slice()), FsOptions::new()).unwrap();
+    let mut content = String::new();
+    fs.root_dir()
+        .open_file("a.txt")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, TEST_STR);
+    assert!(
+        matches!(fs.root_dir().create_file("b.txt"), Err(Error::Io(err)) if err.kind() == io::ErrorKind::PermissionDenied)
+    );
+    fs.unmount().unwrap();
+}
+
+#[test]
+fn test_sort_and_compact() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new().volume_label("MUSIC"), 8 * MB);
+    let fs = open_fs(&mut storage);
+    let root_dir = fs.root_dir();
+    for name in ["c.mp3", "a.mp3", "removed.mp3", "b.mp3"] {
+        write_file(&fs, name, name.as_bytes());
+    }
+    root_dir.remove("removed.mp3").unwrap();
+    let names = |dir: &exfat::Dir<'_, _, _>| dir.iter().map(|e| e.unwrap().file_name()).collect::<Vec<_>>();
+
+    root_dir.compact().unwrap();
+    assert_eq!(names(&root_dir), ["c.mp3", "a.mp3", "b.mp3"]);
+    let raw = root_dir.raw_entries().unwrap();
+    root_dir.sort_by_key(exfat::DirEntry::file_name).unwrap();
+    assert_eq!(names(&root_dir), ["a.mp3", "b.mp3", "c.mp3"]);
+    assert_eq!(fs.volume_label(), "MUSIC");
+    assert_eq!(read_file(&fs, "b.mp3"), b"b.mp3");
+
+    root_dir.set_raw_entries(&raw).unwrap();
+    assert_eq!(names(&root_dir), ["c.mp3", "a.mp3", "b.mp3"]);
+    assert!(matches!(root_dir.set_raw_entries(&raw[1..]), Err(Error::InvalidInput)));
+    drop(root_dir);
+    fs.unmount().unwrap();
+
+    let fs = open_fs(&mut storage);
+    assert_eq!(fs.volume_label(), "MUSIC");
+    assert_eq!(read_file(&fs, "a.mp3"), b"a.mp3");
+}
+
+#[test]
+fn test_dir_index() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    storage.set_position(0);
+    let fs = FileSystem::new(&mut storage, FsOptions::new().dir_index(true)).unwrap();
+    let root_dir = fs.root_dir();
+    let dir = root_dir.create_dir("links").unwrap();
+    for i in 0..200 {
+        dir.create_file(&format!("{:03}.mp3", i)).unwrap();
+    }
+    assert!(dir.open_file("007.MP3").is_ok());
+    dir.remove("007.mp3").unwrap();
+    assert!(matches!(dir.open_file("007.mp3"), Err(Error::NotFound)));
+    // the freed entries are reused
+    dir.create_file("new.mp3").unwrap();
+    assert_eq!(dir.iter().nth(7).unwrap().unwrap().file_name(), "new.mp3");
+    dir.rename("new.mp3", &dir, "NEW.mp3").unwrap();
+    assert_eq!(dir.open_file("new.mp3").unwrap().seek(io::SeekFrom::End(0)).unwrap(), 0);
+    assert!(matches!(dir.create_dir("100.mp3"), Err(Error::InvalidInput)));
+
+    dir.sort_by_key(|e| std::cmp::Reverse(e.file_name())).unwrap();
+    assert!(dir.open_file("000.mp3").is_ok());
+    drop(dir);
+    root_dir.remove_entry("links/199.mp3").unwrap();
+    assert_eq!(root_dir.open_dir("links").unwrap().iter().count(), 199);
+}
+
+#[test]
+fn test_discard_hook() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new(), 8 * MB);
+    let mut fs = open_fs(&mut storage);
+    let discarded = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let hook_discarded = std::sync::Arc::clone(&discarded);
+    fs.set_discard_hook(move |offset, len| hook_discarded.lock().unwrap().push((offset, len)));
+    write_file(&fs, "a.mp3", &[1; 10_000]);
+    assert_eq!(
+        fs.root_dir()
+            .open_file("a.mp3")
+            .unwrap()
+            .seek(io::SeekFrom::End(0))
+            .unwrap(),
+        10_000
+    );
+    let cluster_count = fs.root_dir().iter().next().unwrap().unwrap().cluster_count().unwrap();
+    assert_eq!(cluster_count, 3);
+    fs.root_dir().remove("a.mp3").unwrap();
+    assert_eq!(fs.discard_freed_clusters().unwrap(), 3 * 4096);
+    assert_eq!(discarded.lock().unwrap().len(), 1);
+    assert_eq!(fs.discard_freed_clusters().unwrap(), 0);
+
+    let free = u64::from(fs.stats().free_clusters()) * 4096;
+    assert_eq!(fs.discard_free_space().unwrap(), free);
+}
+
+#[test]
+fn test_set_volume_id() {
+    let mut storage = format_storage(exfat::FormatVolumeOptions::new().volume_id(1), 8 * MB);
+    {
+        let mut fs = open_fs(&mut storage);
+        fs.set_volume_id(0x1234_5678).unwrap();
+        assert_eq!(fs.volume_id(), 0x1234_5678);
+        fs.unmount().unwrap();
+    }
+    // the boot region checksum is verified when mounting
+    let fs = open_fs(&mut storage);
+    assert_eq!(fs.volume_id(), 0x1234_5678);
+    drop(fs);
+    let backup = &storage.get_ref()[12 * 512..24 * 512];
+    assert_eq!(&backup[100..104], &0x1234_5678_u32.to_le_bytes());
+}
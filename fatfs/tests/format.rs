@@ -158,3 +158,29 @@ fn test_zero_root_dir_clusters() {
     }
     assert_eq!(root_dir.iter().count(), files_to_create);
 }
+
+#[test]
+fn test_read_only_keeps_dirty_fat32_unchanged() {
+    init_logger();
+    let mut storage = io::Cursor::new(vec![0_u8; (50 * MB) as usize]);
+    let opts = fatfs::FormatVolumeOptions::new().fat_type(FatType::Fat32);
+    fatfs::format_volume(&mut StdIoWrapper::from(&mut storage), opts).expect("format volume");
+    storage.set_position(0);
+    {
+        let fs = fatfs::FileSystem::new(&mut storage, fatfs::FsOptions::new()).expect("open fs");
+        fs.root_dir().create_file("test.txt").expect("create file");
+        // Make the volume dirty by forgetting to unmount it
+        std::mem::forget(fs);
+    }
+
+    // the storage cannot be written at all, any attempt would fail the unmount
+    let image = storage.into_inner();
+    let fs: fatfs::FileSystem<fatfs::ReadOnly<StdIoWrapper<io::Cursor<&[u8]>>>> =
+        fatfs::FileSystem::new_read_only(io::Cursor::new(image.as_slice()), fatfs::FsOptions::new()).expect("open fs");
+    assert!(fs.read_status_flags().unwrap().dirty());
+    // counting free clusters of a dirty volume would update the FS Information Sector on unmount
+    let stats = fs.stats().unwrap();
+    assert!(stats.free_clusters() < stats.total_clusters());
+    assert!(fs.root_dir().open_file("test.txt").is_ok());
+    fs.unmount().expect("unmount");
+}
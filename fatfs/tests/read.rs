@@ -190,6 +190,7 @@ fn test_volume_metadata(fs: FileSystem, fat_type: FatType) {
     assert_eq!(fs.volume_label(), "Test!");
     assert_eq!(&fs.read_volume_label_from_root_dir().unwrap().unwrap(), "Test!");
     assert_eq!(fs.fat_type(), fat_type);
+    assert_eq!(fs.max_root_dir_entries().is_none(), fat_type == FatType::Fat32);
 }
 
 #[test]
@@ -267,6 +268,43 @@ fn test_stats_fat32() {
     )
 }
 
+fn test_read_only(filename: &str) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    // a file opened only for reading, accessed dates must not be written even if asked to
+    let file = fs::File::open(filename).unwrap();
+    let options = FsOptions::new().update_accessed_date(true);
+    let fs: fatfs::FileSystem<fatfs::ReadOnly<StdIoWrapper<fs::File>>> =
+        fatfs::FileSystem::new_read_only(file, options).unwrap();
+    assert!(fs.is_read_only());
+    let mut buf = String::new();
+    fs.root_dir()
+        .open_file("short.txt")
+        .unwrap()
+        .read_to_string(&mut buf)
+        .unwrap();
+    assert_eq!(buf, TEST_TEXT);
+    fs.stats().unwrap();
+    assert!(
+        matches!(fs.root_dir().create_file("new.txt"), Err(fatfs::Error::Io(err)) if err.kind() == std::io::ErrorKind::PermissionDenied)
+    );
+    fs.unmount().unwrap();
+}
+
+#[test]
+fn test_read_only_fat12() {
+    test_read_only(FAT12_IMG)
+}
+
+#[test]
+fn test_read_only_fat16() {
+    test_read_only(FAT16_IMG)
+}
+
+#[test]
+fn test_read_only_fat32() {
+    test_read_only(FAT32_IMG)
+}
+
 #[test]
 fn test_multi_thread() {
     call_with_fs(
@@ -400,3 +400,472 @@ fn test_multiple_files_in_directory_fat16() {
 fn test_multiple_files_in_directory_fat32() {
     call_with_fs(test_multiple_files_in_directory, FAT32_IMG, 8)
 }
+
+fn test_set_volume_label(tmp_path: &str) {
+    let mut fs = open_filesystem_rw(tmp_path);
+    fs.set_volume_label(*b"NEW LABEL  ").unwrap();
+    fs.set_volume_id(0xCAFE_F00D).unwrap();
+    assert_eq!(fs.volume_label(), "NEW LABEL");
+    fs.unmount().unwrap();
+    // Both copies of the label must survive remounting
+    let fs = open_filesystem_rw(tmp_path);
+    assert_eq!(fs.volume_label(), "NEW LABEL");
+    assert_eq!(fs.volume_id(), 0xCAFE_F00D);
+    assert_eq!(
+        fs.read_volume_label_from_root_dir().unwrap(),
+        Some("NEW LABEL".to_string())
+    );
+}
+
+#[test]
+fn test_set_volume_label_fat12() {
+    call_with_tmp_img(test_set_volume_label, FAT12_IMG, 9)
+}
+
+#[test]
+fn test_set_volume_label_fat16() {
+    call_with_tmp_img(test_set_volume_label, FAT16_IMG, 9)
+}
+
+#[test]
+fn test_set_volume_label_fat32() {
+    call_with_tmp_img(test_set_volume_label, FAT32_IMG, 9)
+}
+
+fn test_clear_status_flags(tmp_path: &str) {
+    // Make the volume dirty by forgetting to unmount it
+    let fs = open_filesystem_rw(tmp_path);
+    fs.root_dir().create_file("abc.txt").unwrap();
+    mem::forget(fs);
+    let mut fs = open_filesystem_rw(tmp_path);
+    assert!(fs.read_status_flags().unwrap().dirty());
+    fs.clear_status_flags().unwrap();
+    assert!(!fs.read_status_flags().unwrap().dirty());
+    // Writing makes it dirty again but a clean unmount now clears it
+    fs.root_dir().create_file("def.txt").unwrap();
+    fs.unmount().unwrap();
+    let fs = open_filesystem_rw(tmp_path);
+    let status_flags = fs.read_status_flags().unwrap();
+    assert!(!status_flags.dirty());
+    assert!(!status_flags.io_error());
+}
+
+#[test]
+fn test_clear_status_flags_fat12() {
+    call_with_tmp_img(test_clear_status_flags, FAT12_IMG, 10)
+}
+
+#[test]
+fn test_clear_status_flags_fat16() {
+    call_with_tmp_img(test_clear_status_flags, FAT16_IMG, 10)
+}
+
+#[test]
+fn test_clear_status_flags_fat32() {
+    call_with_tmp_img(test_clear_status_flags, FAT32_IMG, 10)
+}
+
+fn test_cluster_count(fs: FileSystem) {
+    let cluster_size = fs.cluster_size() as usize;
+    let mut file = fs.root_dir().create_file("count.txt").unwrap();
+    file.write_all(&vec![1u8; cluster_size * 2 + 1]).unwrap();
+    file.flush().unwrap();
+    let entry = fs
+        .root_dir()
+        .iter()
+        .map(|r| r.unwrap())
+        .find(|e| e.file_name() == "count.txt")
+        .unwrap();
+    assert_eq!(entry.cluster_count().unwrap(), 3);
+    let empty = fs.root_dir().create_file("empty.txt").unwrap();
+    mem::drop(empty);
+    let entry = fs
+        .root_dir()
+        .iter()
+        .map(|r| r.unwrap())
+        .find(|e| e.file_name() == "empty.txt")
+        .unwrap();
+    assert_eq!(entry.first_cluster(), None);
+    assert_eq!(entry.cluster_count().unwrap(), 0);
+}
+
+#[test]
+fn test_cluster_count_fat12() {
+    call_with_fs(test_cluster_count, FAT12_IMG, 11)
+}
+
+#[test]
+fn test_cluster_count_fat16() {
+    call_with_fs(test_cluster_count, FAT16_IMG, 11)
+}
+
+#[test]
+fn test_cluster_count_fat32() {
+    call_with_fs(test_cluster_count, FAT32_IMG, 11)
+}
+
+fn test_compact_and_sort_dir(fs: FileSystem) {
+    let dir = fs.root_dir().create_dir("sort").unwrap();
+    for name in [
+        "b.txt",
+        "a long file name.txt",
+        "C.TXT",
+        "d.txt",
+        "e with long name.txt",
+    ] {
+        dir.create_file(name).unwrap();
+    }
+    dir.remove("C.TXT").unwrap();
+    dir.remove("b.txt").unwrap();
+    let names = |dir: &fatfs::Dir<_, _, _>| dir.iter().map(|r| r.unwrap().file_name()).collect::<Vec<String>>();
+
+    dir.compact().unwrap();
+    assert_eq!(
+        names(&dir),
+        [".", "..", "a long file name.txt", "d.txt", "e with long name.txt"]
+    );
+    // the hole is gone so a new entry is appended
+    dir.create_file("f.txt").unwrap();
+    assert_eq!(
+        names(&dir),
+        [
+            ".",
+            "..",
+            "a long file name.txt",
+            "d.txt",
+            "e with long name.txt",
+            "f.txt"
+        ]
+    );
+
+    let raw = dir.raw_entries().unwrap();
+    dir.sort_by_key(|e| core::cmp::Reverse(e.file_name())).unwrap();
+    assert_eq!(
+        names(&dir),
+        [
+            ".",
+            "..",
+            "f.txt",
+            "e with long name.txt",
+            "d.txt",
+            "a long file name.txt"
+        ]
+    );
+    // the order before sorting can be restored
+    dir.set_raw_entries(&raw).unwrap();
+    assert_eq!(
+        names(&dir),
+        [
+            ".",
+            "..",
+            "a long file name.txt",
+            "d.txt",
+            "e with long name.txt",
+            "f.txt"
+        ]
+    );
+    assert_eq!(dir.raw_entries().unwrap(), raw);
+    assert!(matches!(
+        dir.set_raw_entries(&raw[1..]),
+        Err(fatfs::Error::InvalidInput)
+    ));
+    dir.sort_by_key(|e| core::cmp::Reverse(e.file_name())).unwrap();
+    let file = dir.open_file("e with long name.txt").unwrap();
+    mem::drop(file);
+    dir.open_dir("..").unwrap();
+}
+
+#[test]
+fn test_compact_and_sort_dir_fat12() {
+    call_with_fs(test_compact_and_sort_dir, FAT12_IMG, 12)
+}
+
+#[test]
+fn test_compact_and_sort_dir_fat16() {
+    call_with_fs(test_compact_and_sort_dir, FAT16_IMG, 12)
+}
+
+#[test]
+fn test_compact_and_sort_dir_fat32() {
+    call_with_fs(test_compact_and_sort_dir, FAT32_IMG, 12)
+}
+
+fn test_dir_index(tmp_path: &str) {
+    {
+        let file = fs::OpenOptions::new().read(true).write(true).open(tmp_path).unwrap();
+        let options = FsOptions::new().dir_index(true);
+        let fs = FileSystem::new(BufStream::new(file), options).unwrap();
+        let dir = fs.root_dir().create_dir("indexed").unwrap();
+        for i in 0..20 {
+            let mut file = dir.create_file(&format!("long file name {}.txt", i)).unwrap();
+            file.write_all(TEST_STR.as_bytes()).unwrap();
+        }
+        // lookup ignores case and works with both long and short names
+        assert_eq!(
+            dir.open_file("LONG FILE NAME 3.TXT")
+                .unwrap()
+                .seek(io::SeekFrom::End(0))
+                .unwrap(),
+            TEST_STR.len() as u64
+        );
+        let short_name = dir
+            .iter()
+            .map(|r| r.unwrap())
+            .find(|e| e.file_name() == "long file name 0.txt")
+            .unwrap()
+            .short_file_name();
+        dir.open_file(&short_name).unwrap();
+        // existing files are opened instead of creating new ones
+        dir.create_file("long file name 5.txt").unwrap();
+        dir.remove("long file name 1.txt").unwrap();
+        assert!(matches!(
+            dir.open_file("long file name 1.txt"),
+            Err(fatfs::Error::NotFound)
+        ));
+        dir.rename("long file name 2.txt", &dir, "renamed.txt").unwrap();
+        assert!(matches!(
+            dir.open_file("long file name 2.txt"),
+            Err(fatfs::Error::NotFound)
+        ));
+        dir.open_file("renamed.txt").unwrap();
+        // a new directory reusing the clusters of a removed one must not see its entries
+        let sub_dir = dir.create_dir("sub").unwrap();
+        sub_dir.create_file("old.txt").unwrap();
+        sub_dir.remove("old.txt").unwrap();
+        dir.remove("sub").unwrap();
+        let sub_dir = dir.create_dir("sub2").unwrap();
+        assert!(matches!(sub_dir.open_file("old.txt"), Err(fatfs::Error::NotFound)));
+        dir.compact().unwrap();
+        dir.open_file("long file name 19.txt").unwrap();
+    }
+    // the result is the same as without the index
+    let fs = open_filesystem_rw(tmp_path);
+    let dir = fs.root_dir().open_dir("indexed").unwrap();
+    let entries = dir.iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2 + 19 + 1);
+    let mut short_names = entries.iter().map(|e| e.short_file_name()).collect::<Vec<_>>();
+    short_names.sort();
+    short_names.dedup();
+    assert_eq!(short_names.len(), entries.len());
+    dir.open_file("renamed.txt").unwrap();
+    assert!(matches!(
+        dir.open_file("long file name 1.txt"),
+        Err(fatfs::Error::NotFound)
+    ));
+}
+
+#[test]
+fn test_dir_index_fat12() {
+    call_with_tmp_img(test_dir_index, FAT12_IMG, 13)
+}
+
+#[test]
+fn test_dir_index_fat16() {
+    call_with_tmp_img(test_dir_index, FAT16_IMG, 13)
+}
+
+#[test]
+fn test_dir_index_fat32() {
+    call_with_tmp_img(test_dir_index, FAT32_IMG, 13)
+}
+
+fn test_block_cache(tmp_path: &str) {
+    {
+        let file = fs::OpenOptions::new().read(true).write(true).open(tmp_path).unwrap();
+        let cache = fatfs::BlockCache::new(StdIoWrapper::new(file), 4096, 16);
+        let fs = fatfs::FileSystem::new(cache, FsOptions::new()).unwrap();
+        fs.root_dir().create_dir("cached").unwrap();
+        for i in 0..10 {
+            let mut file = fs.root_dir().create_file(&format!("cached/{}.txt", i)).unwrap();
+            for _ in 0..1000 {
+                file.write_all(TEST_STR.as_bytes()).unwrap();
+            }
+        }
+        fs.flush().unwrap();
+        let stats = fs.storage().stats();
+        assert!(stats.hits() > 0);
+        assert!(stats.bytes_written() >= 10 * 1000 * TEST_STR.len() as u64);
+        fs.unmount().unwrap();
+    }
+    let fs = open_filesystem_rw(tmp_path);
+    let mut file = fs.root_dir().open_file("cached/9.txt").unwrap();
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, TEST_STR.repeat(1000).as_bytes());
+}
+
+#[test]
+fn test_block_cache_fat12() {
+    call_with_tmp_img(test_block_cache, FAT12_IMG, 14)
+}
+
+#[test]
+fn test_block_cache_fat16() {
+    call_with_tmp_img(test_block_cache, FAT16_IMG, 14)
+}
+
+#[test]
+fn test_block_cache_fat32() {
+    call_with_tmp_img(test_block_cache, FAT32_IMG, 14)
+}
+
+/// Offsets and data of the writes in order
+type Writes = std::rc::Rc<std::cell::RefCell<Vec<(u64, Vec<u8>)>>>;
+
+/// In-memory storage that records every write reaching it
+struct WriteLog {
+    image: io::Cursor<Vec<u8>>,
+    writes: Writes,
+}
+
+impl Read for WriteLog {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.image.read(buf)
+    }
+}
+
+impl Write for WriteLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.borrow_mut().push((self.image.position(), buf.to_vec()));
+        self.image.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for WriteLog {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.image.seek(pos)
+    }
+}
+
+#[test]
+fn test_dirty_flag_ordered_with_block_cache() {
+    let mut image = io::Cursor::new(vec![0u8; 8 * 1024 * 1024]);
+    fatfs::format_volume(&mut StdIoWrapper::from(&mut image), fatfs::FormatVolumeOptions::new()).unwrap();
+    let writes = Writes::default();
+    let storage = WriteLog {
+        image,
+        writes: writes.clone(),
+    };
+    // a tiny cache evicts dirty blocks while the file is being written
+    let cache = fatfs::BlockCache::new(StdIoWrapper::new(storage), 4096, 4);
+    let fs = fatfs::FileSystem::new(cache, FsOptions::new()).unwrap();
+    let flag_offset = if fs.fat_type() == fatfs::FatType::Fat32 {
+        0x41_u64
+    } else {
+        0x25
+    };
+    let flag = |(offset, data): &(u64, Vec<u8>)| {
+        let index = flag_offset.checked_sub(*offset)? as usize;
+        data.get(index).map(|x| x & 1 != 0)
+    };
+    {
+        let mut file = fs.root_dir().create_file("evicted.txt").unwrap();
+        for _ in 0..10000 {
+            file.write_all(TEST_STR.as_bytes()).unwrap();
+        }
+    }
+    let written = writes.borrow().len();
+    assert!(written > 1);
+    // the dirty flag reaches the storage before any of the evicted blocks and stays set
+    assert_eq!(flag(&writes.borrow()[0]), Some(true));
+    assert!(writes.borrow().iter().all(|x| flag(x) != Some(false)));
+    fs.unmount().unwrap();
+    // and the clean flag only after everything else
+    let writes = writes.borrow();
+    let last = writes.last().unwrap();
+    assert_eq!(flag(last), Some(false));
+    assert!(writes[written..writes.len() - 1].iter().all(|x| flag(x) != Some(false)));
+}
+
+fn test_discard_hook(tmp_path: &str) {
+    use std::sync::{Arc, Mutex};
+
+    let file = fs::OpenOptions::new().read(true).write(true).open(tmp_path).unwrap();
+    let mut fs = FileSystem::new(BufStream::new(file), FsOptions::new()).unwrap();
+    let cluster_size = u64::from(fs.cluster_size());
+    let ranges = Arc::new(Mutex::new(Vec::new()));
+    let hook_ranges = Arc::clone(&ranges);
+    fs.set_discard_hook(move |offset, len| hook_ranges.lock().unwrap().push((offset, len)));
+    {
+        let mut file = fs.root_dir().create_file("discarded.txt").unwrap();
+        for _ in 0..1000 {
+            file.write_all(TEST_STR.as_bytes()).unwrap();
+        }
+    }
+    let file_clusters = (1000 * TEST_STR.len() as u64 + cluster_size - 1) / cluster_size;
+    let free_before = fs.stats().unwrap().free_clusters();
+    fs.root_dir().remove("discarded.txt").unwrap();
+    // nothing is discarded until the freed clusters are on the storage
+    assert!(ranges.lock().unwrap().is_empty());
+    fs.flush().unwrap();
+    let discarded = fs.discard_freed_clusters().unwrap();
+    assert_eq!(discarded, file_clusters * cluster_size);
+    // contiguous clusters are reported as one range
+    assert!(ranges.lock().unwrap().len() < file_clusters as usize);
+    assert_eq!(
+        ranges.lock().unwrap().iter().map(|(_, len)| len).sum::<u64>(),
+        discarded
+    );
+    assert_eq!(fs.discard_freed_clusters().unwrap(), 0);
+    ranges.lock().unwrap().clear();
+    let discarded = fs.discard_free_space().unwrap();
+    let free_after = fs.stats().unwrap().free_clusters();
+    assert_eq!(free_after, free_before + file_clusters as u32);
+    assert_eq!(discarded, u64::from(free_after) * cluster_size);
+    assert_eq!(
+        ranges.lock().unwrap().iter().map(|(_, len)| len).sum::<u64>(),
+        discarded
+    );
+}
+
+#[test]
+fn test_discard_hook_fat12() {
+    call_with_tmp_img(test_discard_hook, FAT12_IMG, 15)
+}
+
+#[test]
+fn test_discard_hook_fat16() {
+    call_with_tmp_img(test_discard_hook, FAT16_IMG, 15)
+}
+
+#[test]
+fn test_discard_hook_fat32() {
+    call_with_tmp_img(test_discard_hook, FAT32_IMG, 15)
+}
+
+fn test_full_root_dir(tmp_path: &str) {
+    let fs = open_filesystem_rw(tmp_path);
+    let max_entries = fs.max_root_dir_entries().unwrap();
+    let root_dir = fs.root_dir();
+    let mut created = 0;
+    loop {
+        match root_dir.create_file(&format!("F{}", created)) {
+            Ok(_) => created += 1,
+            Err(fatfs::Error::NotEnoughSpace) => break,
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+    }
+    assert!(created > 0 && created < u32::from(max_entries));
+    // a long name needs more entries than a short one so it does not fit either
+    assert!(matches!(
+        root_dir.create_dir("long directory name"),
+        Err(fatfs::Error::NotEnoughSpace)
+    ));
+    // removing an entry makes room again
+    root_dir.remove("F0").unwrap();
+    root_dir.create_file("F0").unwrap();
+}
+
+#[test]
+fn test_full_root_dir_fat12() {
+    call_with_tmp_img(test_full_root_dir, FAT12_IMG, 16)
+}
+
+#[test]
+fn test_full_root_dir_fat16() {
+    call_with_tmp_img(test_full_root_dir, FAT16_IMG, 16)
+}
@@ -0,0 +1,530 @@
+//! Registry of supported audio formats and player profiles
+
+use crate::MUSIC_EXT;
+use crate::prelude::*;
+use clap::ValueEnum;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// Audio formats known to the tool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Mp3,
+    Wma,
+    Wav,
+    Flac,
+    Aac,
+}
+
+impl AudioFormat {
+    pub const ALL: [Self; 5] = [Self::Mp3, Self::Wma, Self::Wav, Self::Flac, Self::Aac];
+
+    /// Human readable name
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mp3 => "MP3",
+            Self::Wma => "WMA",
+            Self::Wav => "WAV",
+            Self::Flac => "FLAC",
+            Self::Aac => "AAC",
+        }
+    }
+
+    /// Extension used for the files on the card (and for ffmpeg output)
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Wma => "wma",
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+            // raw ADTS streams are rarely supported so always use MP4 container
+            Self::Aac => "m4a",
+        }
+    }
+
+    /// Name of the ffmpeg muxer that produces this format
+    pub fn ffmpeg_muxer(self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Wma => "asf",
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+            Self::Aac => "ipod",
+        }
+    }
+
+    /// Lossless formats cannot be made smaller by lowering the bitrate
+    pub fn is_lossless(self) -> bool {
+        matches!(self, Self::Wav | Self::Flac)
+    }
+
+    /// All extensions that are recognized as this format
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Mp3 => &["mp3"],
+            Self::Wma => &["wma"],
+            Self::Wav => &["wav"],
+            Self::Flac => &["flac"],
+            Self::Aac => &["m4a", "aac"],
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|x| x.extensions().iter().any(|y| ext.eq_ignore_ascii_case(y)))
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|x| x.to_str())
+            .and_then(Self::from_extension)
+    }
+
+    /// Detects format of a file on the card, with or without the `MUSIC_EXT` suffix
+    pub fn from_card_name(name: &str) -> Option<Self> {
+        let name = name.strip_suffix(MUSIC_EXT).unwrap_or(name);
+        name.rsplit_once('.')
+            .and_then(|(_, ext)| Self::from_extension(ext))
+    }
+
+    /// Reads duration of the audio stream, returns `None` if the format does not store it
+    pub fn duration<R: Read + Seek>(self, reader: &mut R) -> Result<Option<Duration>> {
+        match self {
+            Self::Mp3 => Ok(Some(mp3_duration::from_read(reader)?)),
+            Self::Wma => asf_duration(reader),
+            Self::Wav => wav_duration(reader),
+            Self::Flac => flac_duration(reader),
+            Self::Aac => mp4_duration(reader),
+        }
+    }
+}
+
+/// Reads duration of a file on the host filesystem
+pub fn probe_duration(path: &Path) -> Result<Option<Duration>> {
+    let Some(format) = AudioFormat::from_path(path) else {
+        return Ok(None);
+    };
+
+    let file = std::fs::File::open(path)?;
+    format.duration(&mut std::io::BufReader::new(file))
+}
+
+/// Set of formats and filesystems a player can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum PlayerProfile {
+    /// Only MP3 files, safe choice for any player
+    #[default]
+    Basic,
+
+    /// Only MP3 files on FAT16 cards up to 2 GiB, for old players that do not read FAT32
+    Legacy,
+
+    /// MP3, WMA and WAV files, common for players with "WMA" printed on the box
+    Wma,
+
+    /// MP3, WMA, WAV and AAC files
+    Modern,
+}
+
+impl PlayerProfile {
+    pub fn formats(self) -> &'static [AudioFormat] {
+        match self {
+            Self::Basic | Self::Legacy => &[AudioFormat::Mp3],
+            Self::Wma => &[AudioFormat::Mp3, AudioFormat::Wma, AudioFormat::Wav],
+            Self::Modern => &[
+                AudioFormat::Mp3,
+                AudioFormat::Wma,
+                AudioFormat::Wav,
+                AudioFormat::Aac,
+            ],
+        }
+    }
+
+    pub fn supports(self, format: AudioFormat) -> bool {
+        self.formats().contains(&format)
+    }
+
+    /// Player can read FAT32 cards, otherwise only FAT12 and FAT16
+    pub fn supports_fat32(self) -> bool {
+        self != Self::Legacy
+    }
+
+    /// Biggest volume the player can read
+    pub fn max_volume_size(self) -> Option<u64> {
+        match self {
+            // FAT16 with 32 KiB clusters holds a bit less than 2 GiB
+            Self::Legacy => Some(2047 * 1024 * 1024),
+            _ => None,
+        }
+    }
+
+    /// Format the file should be stored as on the card, transcoding to MP3 if not supported
+    pub fn target_format(self, format: AudioFormat) -> AudioFormat {
+        if self.supports(format) {
+            format
+        } else {
+            AudioFormat::Mp3
+        }
+    }
+}
+
+fn read_array<const N: usize, R: Read>(reader: &mut R) -> std::io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn wav_duration<R: Read + Seek>(reader: &mut R) -> Result<Option<Duration>> {
+    let header = read_array::<12, _>(reader)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        bail!("Invalid WAV header");
+    }
+
+    let mut byte_rate: Option<u32> = None;
+    loop {
+        let chunk = read_array::<8, _>(reader)?;
+        let size = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+
+        match &chunk[0..4] {
+            b"fmt " => {
+                if size < 16 {
+                    bail!("Invalid WAV format chunk");
+                }
+
+                let fmt = read_array::<16, _>(reader)?;
+                byte_rate = Some(u32::from_le_bytes(fmt[8..12].try_into().unwrap()));
+
+                // skip extended format data
+                reader.seek(SeekFrom::Current(i64::from(size) - 16))?;
+            }
+            b"data" => {
+                return Ok(byte_rate
+                    .filter(|x| *x != 0)
+                    .map(|x| Duration::from_secs_f64(f64::from(size) / f64::from(x))));
+            }
+            _ => {
+                reader.seek(SeekFrom::Current(i64::from(size)))?;
+            }
+        }
+
+        // chunks are padded to even size
+        if size % 2 == 1 {
+            reader.seek(SeekFrom::Current(1))?;
+        }
+    }
+}
+
+fn flac_duration<R: Read + Seek>(reader: &mut R) -> Result<Option<Duration>> {
+    if &read_array::<4, _>(reader)? != b"fLaC" {
+        bail!("Invalid FLAC header");
+    }
+
+    // STREAMINFO is always the first metadata block
+    let block_header = read_array::<4, _>(reader)?;
+    if block_header[0] & 0x7F != 0 {
+        bail!("FLAC file does not start with STREAMINFO");
+    }
+
+    let info = read_array::<34, _>(reader)?;
+    let sample_rate =
+        (u32::from(info[10]) << 12) | (u32::from(info[11]) << 4) | (u32::from(info[12]) >> 4);
+    let total_samples = (u64::from(info[13] & 0x0F) << 32)
+        | u64::from(u32::from_be_bytes(info[14..18].try_into().unwrap()));
+
+    // total samples of 0 means unknown
+    if sample_rate == 0 || total_samples == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Duration::from_secs_f64(
+        total_samples as f64 / f64::from(sample_rate),
+    )))
+}
+
+fn asf_duration<R: Read + Seek>(reader: &mut R) -> Result<Option<Duration>> {
+    const HEADER_GUID: [u8; 16] = [
+        0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE,
+        0x6C,
+    ];
+    const FILE_PROPERTIES_GUID: [u8; 16] = [
+        0xA1, 0xDC, 0xAB, 0x8C, 0x47, 0xA9, 0xCF, 0x11, 0x8E, 0xE4, 0x00, 0xC0, 0x0C, 0x20, 0x53,
+        0x65,
+    ];
+
+    let header = read_array::<30, _>(reader)?;
+    if header[0..16] != HEADER_GUID {
+        bail!("Invalid ASF header");
+    }
+
+    let object_count = u32::from_le_bytes(header[24..28].try_into().unwrap());
+    for _ in 0..object_count {
+        let object = read_array::<24, _>(reader)?;
+        let size = u64::from_le_bytes(object[16..24].try_into().unwrap());
+        if size < 24 {
+            bail!("Invalid ASF object size");
+        }
+
+        if object[0..16] == FILE_PROPERTIES_GUID {
+            // file id, file size, creation date and data packets count
+            reader.seek(SeekFrom::Current(16 + 8 + 8 + 8))?;
+            let fields = read_array::<24, _>(reader)?;
+
+            // play duration is in 100ns units and includes preroll in ms
+            let play_duration = u64::from_le_bytes(fields[0..8].try_into().unwrap());
+            let preroll = u64::from_le_bytes(fields[16..24].try_into().unwrap());
+
+            let duration = Duration::from_nanos(play_duration.saturating_mul(100))
+                .saturating_sub(Duration::from_millis(preroll));
+            return Ok(Some(duration));
+        }
+
+        let Ok(offset) = i64::try_from(size - 24) else {
+            bail!("Invalid ASF object size");
+        };
+        reader.seek(SeekFrom::Current(offset))?;
+    }
+
+    Ok(None)
+}
+
+fn mp4_duration<R: Read + Seek>(reader: &mut R) -> Result<Option<Duration>> {
+    // walk the top level boxes until "moov" then look for "mvhd" inside it
+    let mut in_moov = false;
+    loop {
+        let header = match read_array::<8, _>(reader) {
+            Ok(x) => x,
+            // raw ADTS streams and truncated files have no duration
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut size = u64::from(u32::from_be_bytes(header[0..4].try_into().unwrap()));
+        let mut header_size: u64 = 8;
+
+        // 64-bit box size
+        if size == 1 {
+            size = u64::from_be_bytes(read_array::<8, _>(reader)?);
+            header_size += 8;
+        }
+
+        if size != 0 && size < header_size {
+            bail!("Invalid MP4 box size");
+        }
+
+        match &header[4..8] {
+            b"moov" if !in_moov => in_moov = true,
+            b"mvhd" if in_moov => {
+                let version = read_array::<4, _>(reader)?[0];
+                let (timescale, duration) = if version == 1 {
+                    let data = read_array::<28, _>(reader)?;
+                    (
+                        u32::from_be_bytes(data[16..20].try_into().unwrap()),
+                        u64::from_be_bytes(data[20..28].try_into().unwrap()),
+                    )
+                } else {
+                    let data = read_array::<16, _>(reader)?;
+                    (
+                        u32::from_be_bytes(data[8..12].try_into().unwrap()),
+                        u64::from(u32::from_be_bytes(data[12..16].try_into().unwrap())),
+                    )
+                };
+
+                if timescale == 0 {
+                    return Ok(None);
+                }
+
+                return Ok(Some(Duration::from_secs_f64(
+                    duration as f64 / f64::from(timescale),
+                )));
+            }
+            // size of 0 means the box extends to the end of file
+            _ if size == 0 => return Ok(None),
+            _ => {
+                let Ok(offset) = i64::try_from(size - header_size) else {
+                    bail!("Invalid MP4 box size");
+                };
+                reader.seek(SeekFrom::Current(offset))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn duration(format: AudioFormat, data: Vec<u8>) -> Option<Duration> {
+        format.duration(&mut Cursor::new(data)).unwrap()
+    }
+
+    fn wav(byte_rate: u32, data_size: u32) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+
+        // odd sized chunk that has to be skipped with its padding
+        data.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+
+        data.extend_from_slice(b"fmt \x10\0\0\0");
+        data.extend_from_slice(&[1, 0, 2, 0, 0x44, 0xAC, 0, 0]);
+        data.extend_from_slice(&byte_rate.to_le_bytes());
+        data.extend_from_slice(&[4, 0, 16, 0]);
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&data_size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn wav_duration_from_byte_rate() {
+        assert_eq!(
+            duration(AudioFormat::Wav, wav(176_400, 176_400 * 3)),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(duration(AudioFormat::Wav, wav(0, 1000)), None);
+        assert!(
+            AudioFormat::Wav
+                .duration(&mut Cursor::new(b"RIFF\0\0\0\0AVI ".to_vec()))
+                .is_err()
+        );
+    }
+
+    fn flac(sample_rate: u32, total_samples: u64) -> Vec<u8> {
+        let mut info = [0u8; 34];
+        info[10] = (sample_rate >> 12) as u8;
+        info[11] = (sample_rate >> 4) as u8;
+        info[12] = ((sample_rate & 0x0F) << 4) as u8;
+        info[13] = ((total_samples >> 32) & 0x0F) as u8;
+        info[14..18].copy_from_slice(&(total_samples as u32).to_be_bytes());
+
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[0x80, 0, 0, 34]);
+        data.extend_from_slice(&info);
+        data
+    }
+
+    #[test]
+    fn flac_duration_from_streaminfo() {
+        assert_eq!(
+            duration(AudioFormat::Flac, flac(44_100, 44_100 * 10)),
+            Some(Duration::from_secs(10))
+        );
+
+        // unknown sample count
+        assert_eq!(duration(AudioFormat::Flac, flac(48_000, 0)), None);
+    }
+
+    #[test]
+    fn asf_duration_without_preroll() {
+        let mut data = vec![
+            0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62,
+            0xCE, 0x6C,
+        ];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[1, 2]);
+
+        // unknown object that has to be skipped
+        data.extend_from_slice(&[0xFF; 16]);
+        data.extend_from_slice(&28u64.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+
+        data.extend_from_slice(&[
+            0xA1, 0xDC, 0xAB, 0x8C, 0x47, 0xA9, 0xCF, 0x11, 0x8E, 0xE4, 0x00, 0xC0, 0x0C, 0x20,
+            0x53, 0x65,
+        ]);
+        data.extend_from_slice(&104u64.to_le_bytes());
+        data.extend_from_slice(&[0; 40]);
+        data.extend_from_slice(&50_000_000u64.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&1000u64.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+
+        assert_eq!(
+            duration(AudioFormat::Wma, data),
+            Some(Duration::from_secs(4))
+        );
+    }
+
+    fn mp4_box(name: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut data = (content.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(name);
+        data.extend_from_slice(content);
+        data
+    }
+
+    #[test]
+    fn mp4_duration_from_movie_header() {
+        let mut mvhd = vec![0; 12];
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&2500u32.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 80]);
+
+        let mut data = mp4_box(b"ftyp", b"M4A \0\0\0\0");
+        data.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd)));
+        assert_eq!(
+            duration(AudioFormat::Aac, data),
+            Some(Duration::from_millis(2500))
+        );
+
+        // raw ADTS stream
+        assert_eq!(duration(AudioFormat::Aac, vec![0xFF, 0xF1, 0x50]), None);
+    }
+
+    #[test]
+    fn oversized_boxes_are_rejected() {
+        // 64-bit size that would seek back to the start of the file and loop forever
+        let mut data = mp4_box(b"ftyp", &[]);
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"free");
+        data.extend_from_slice(&(u64::MAX - 7).to_be_bytes());
+        assert!(AudioFormat::Aac.duration(&mut Cursor::new(data)).is_err());
+
+        let mut data = vec![
+            0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62,
+            0xCE, 0x6C,
+        ];
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[1, 2]);
+        data.extend_from_slice(&[0xFF; 16]);
+        data.extend_from_slice(&(1u64 << 63).to_le_bytes());
+        assert!(AudioFormat::Wma.duration(&mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn profiles_transcode_unsupported_formats() {
+        assert_eq!(
+            PlayerProfile::Basic.target_format(AudioFormat::Flac),
+            AudioFormat::Mp3
+        );
+        assert_eq!(
+            PlayerProfile::Wma.target_format(AudioFormat::Wma),
+            AudioFormat::Wma
+        );
+        assert_eq!(
+            PlayerProfile::Modern.target_format(AudioFormat::Aac),
+            AudioFormat::Aac
+        );
+
+        for profile in PlayerProfile::value_variants() {
+            assert!(profile.supports(AudioFormat::Mp3));
+        }
+
+        assert!(!PlayerProfile::Legacy.supports_fat32());
+        assert!(PlayerProfile::Legacy.max_volume_size().is_some());
+        assert_eq!(PlayerProfile::Modern.max_volume_size(), None);
+    }
+
+    #[test]
+    fn formats_from_names() {
+        assert_eq!(
+            AudioFormat::from_path(Path::new("a/b.M4A")),
+            Some(AudioFormat::Aac)
+        );
+        assert_eq!(
+            AudioFormat::from_card_name(&format!("song.wma{MUSIC_EXT}")),
+            Some(AudioFormat::Wma)
+        );
+        assert_eq!(AudioFormat::from_card_name("song.ogg"), None);
+    }
+}
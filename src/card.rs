@@ -0,0 +1,1854 @@
+//! Card (or image) managed by the tool
+
+use crate::audio::AudioFormat;
+use crate::discard::Discarder;
+use crate::error::{DeviceError, UnknownVolume};
+use crate::format::{FatKind, Layout};
+use crate::prelude::*;
+use crate::report::{Event, Progress};
+use crate::volume::{self, Volume};
+use crate::{DIRTY_FLAG_FILE, JOURNAL_FILE, LABEL, LINK_DIR, MUSIC_DIR, MUSIC_EXT, README_FILE};
+use fatfs::{
+    BlockCache, FileSystem, FsOptions, FsStatusFlags, ReadOnly, ReadWriteSeek, StdIoWrapper, exfat,
+};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Music file in the music directory
+#[derive(Debug, Clone)]
+pub struct Song {
+    /// Name on the card including `MUSIC_EXT`
+    pub name: String,
+    pub format: AudioFormat,
+    pub size: u64,
+}
+
+/// Overview of the card contents
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    /// Volume label without padding
+    pub label: String,
+
+    /// Volume ID in `XXXX-XXXX` form, tells apart cards with the same label
+    pub volume_id: String,
+    pub songs: usize,
+    pub links: usize,
+
+    /// Dirty flag file is present so the links must be cleaned before editing
+    pub has_links: bool,
+
+    /// Operation that was interrupted and has to be resumed or rolled back
+    pub interrupted: Option<&'static str>,
+
+    /// Last session unmounted the card, otherwise it was probably unplugged while writing
+    pub clean_unmount: bool,
+
+    /// Some device reported an I/O error while writing to the card
+    pub io_error: bool,
+
+    /// FAT variant, like `FAT32`
+    pub fat_type: String,
+
+    /// Entries the root directory can hold, only FAT12 and FAT16 have a limit
+    pub root_entries: Option<u16>,
+    pub cluster_size: u64,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+/// Name of the file in the music directory
+pub fn card_name(path: &Path) -> String {
+    format!(
+        "{}{MUSIC_EXT}",
+        path.file_name()
+            .unwrap()
+            .to_string_lossy()
+            .chars()
+            // only allow ascii characters
+            .filter(|c| c.is_ascii())
+            .collect::<String>(),
+    )
+}
+
+/// Operation in progress, written to the card before anything is modified and removed once it
+/// is done
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Journal {
+    /// Links to create as pairs of the link name and the song name
+    Shuffle {
+        links: Vec<(String, String)>,
+    },
+    Clean {
+        songs: bool,
+    },
+
+    /// Directories are rewritten in place so their raw entries are kept to restore them, entries
+    /// may be lost or duplicated if interrupted
+    Sort {
+        sort: bool,
+
+        /// Raw entries of the song directory before the rewrite
+        #[serde(with = "hex")]
+        music_entries: Vec<u8>,
+
+        /// Raw entries of the link directory before the rewrite
+        #[serde(with = "hex")]
+        link_entries: Vec<u8>,
+    },
+}
+
+impl Journal {
+    pub fn operation(&self) -> &'static str {
+        match self {
+            Self::Shuffle { .. } => "shuffle",
+            Self::Clean { .. } => "clean",
+            Self::Sort { .. } => "sort",
+        }
+    }
+}
+
+/// Raw bytes as a hex string, much shorter than an array of numbers
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let text = data.iter().map(|x| format!("{x:02x}")).collect::<String>();
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if !text.is_ascii() || text.len() % 2 != 0 {
+            return Err(D::Error::custom("invalid hex string"));
+        }
+
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Removes the label padding, f32ms pads it with zeroes instead of spaces
+fn trim_label(label: &str) -> &str {
+    label.trim_end_matches(['\0', ' '])
+}
+
+/// Label in the padded form as text, exFAT stores it as text without padding
+pub(crate) fn label_text(label: &[u8; 11]) -> String {
+    trim_label(&String::from_utf8_lossy(label)).to_string()
+}
+
+/// Converts a label to the padded form stored on the card, lowercase letters are converted to
+/// uppercase as most systems do
+pub fn parse_label(label: &str) -> Result<[u8; 11]> {
+    let label = label.to_ascii_uppercase();
+    if label.trim().is_empty() {
+        bail!("Label can not be empty");
+    }
+
+    if label.len() > 11 {
+        bail!("Label {label:?} is longer than 11 characters");
+    }
+
+    if let Some(c) = label
+        .chars()
+        .find(|c| !c.is_ascii() || c.is_ascii_control() || "\"*+,./:;<=>?[\\]|".contains(*c))
+    {
+        bail!("Label can not contain {c:?}");
+    }
+
+    // same padding as LABEL
+    let mut bytes = [0u8; 11];
+    bytes[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(bytes)
+}
+
+/// Volume ID in the usual `XXXX-XXXX` form, shown by lsblk as the UUID of the partition
+pub fn format_volume_id(volume_id: u32) -> String {
+    format!("{:04X}-{:04X}", volume_id >> 16, volume_id & 0xFFFF)
+}
+
+/// How many times the songs have to repeat to play for at least `fill`
+pub fn repeat_count(songs: usize, duration: Duration, fill: Duration) -> Result<usize> {
+    if songs < 3 {
+        bail!("Shuffling with repeat_fill requires at least 3 songs!");
+    }
+
+    if duration.as_secs_f64() > fill.as_secs_f64() {
+        bail!(
+            "Duration requested is lower than total duration of songs ({} < {})",
+            humantime::format_duration(fill),
+            humantime::format_duration(duration)
+        );
+    }
+
+    // NOTE: this will usually overshoot but it does not matter
+    Ok((fill.as_secs_f64() / duration.as_secs_f64()).ceil().round() as usize)
+}
+
+/// Size of a cached block, matches the usual page size of flash memory
+const CACHE_BLOCK_SIZE: u32 = 4096;
+
+/// Number of cached blocks, 16 MiB in total which is enough for the FAT of a big card
+const CACHE_BLOCKS: usize = 4096;
+
+/// Granularity of comparing in write tracking mode
+const SECTOR_SIZE: u32 = 512;
+
+/// Usual allocation unit of SD cards, the card erases and rewrites it as a whole
+const ERASE_BLOCK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Storage of a card, all access goes through the cache to avoid small scattered writes
+pub type CardStorage<S> = BlockCache<StdIoWrapper<S>>;
+
+/// Storage of a card mounted read-only, nothing is written to it, not even the dirty flag
+pub type ReadOnlyStorage<S> = ReadOnly<BlockCache<ReadOnly<StdIoWrapper<S>>>>;
+
+/// Filesystem with the music and links
+pub struct Card<IO: ReadWriteSeek> {
+    fs: Volume<IO>,
+
+    /// Flags as they were when the card was opened, writing marks it dirty until unmounted
+    flags: FsStatusFlags,
+
+    /// Discards clusters as they are freed, shared with the hook of the filesystem
+    discarder: Option<Arc<Mutex<Discarder>>>,
+}
+
+/// Size of a device or an image file in bytes
+pub fn storage_size(path: impl AsRef<Path>) -> Result<u64> {
+    let mut file = open_file(path.as_ref(), true)?;
+    Ok(file.seek(std::io::SeekFrom::End(0))?)
+}
+
+fn open_file(path: &Path, readonly: bool) -> Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(!readonly);
+
+    // for block devices the kernel refuses exclusive open while the device is mounted
+    let is_block_device = std::fs::metadata(path).is_ok_and(|x| x.file_type().is_block_device());
+    if !readonly && is_block_device {
+        options.custom_flags(nix::fcntl::OFlag::O_EXCL.bits());
+    }
+
+    let file = options
+        .open(path)
+        .map_err(|err| match err.raw_os_error() {
+            Some(code) if code == nix::errno::Errno::EBUSY as i32 => {
+                anyhow!("device is busy, it is probably mounted")
+            }
+            _ => err.into(),
+        })
+        .with_context(|| DeviceError(path.to_string_lossy().to_string()))?;
+
+    Ok(file)
+}
+
+/// Device or image file that is synced on flush, `File::flush` does nothing so the order of
+/// writes reaching the card would be up to the kernel
+pub struct SyncedFile {
+    file: std::fs::File,
+
+    /// Written since the last sync
+    written: bool,
+}
+
+impl SyncedFile {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: open_file(path, false)?,
+            written: false,
+        })
+    }
+}
+
+impl Read for SyncedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for SyncedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written = true;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.written {
+            self.file.sync_data()?;
+            self.written = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl Seek for SyncedFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Card<CardStorage<SyncedFile>> {
+    /// Opens a device or an image file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(SyncedFile::open(path.as_ref())?)
+    }
+
+    /// Opens a device or an image file for writing in write tracking mode, unchanged sectors
+    /// are not rewritten and writes are grouped by erase blocks to reduce wear
+    pub fn open_tracked(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new_tracked(SyncedFile::open(path.as_ref())?)
+    }
+
+    /// Formats a device or an image file
+    pub fn format_path(path: impl AsRef<Path>, layout: &Layout) -> Result<Self> {
+        Self::format_with(SyncedFile::open(path.as_ref())?, layout)
+    }
+}
+
+impl Card<ReadOnlyStorage<std::fs::File>> {
+    /// Opens a device or an image file for reading only, the card is left exactly as it was
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new_read_only(open_file(path.as_ref(), true)?)
+    }
+}
+
+fn cache<S: Read + Write + Seek>(storage: S) -> CardStorage<S> {
+    BlockCache::new(StdIoWrapper::from(storage), CACHE_BLOCK_SIZE, CACHE_BLOCKS)
+}
+
+// link directory can hold tens of thousands of entries, scanning it for every link is slow
+fn fs_options() -> FsOptions<fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter> {
+    FsOptions::new().dir_index(true)
+}
+
+impl<S: Read + Seek> Card<ReadOnlyStorage<S>> {
+    /// Opens the filesystem on the storage without ever writing to it
+    pub fn new_read_only(storage: S) -> Result<Self> {
+        let mut storage =
+            BlockCache::read_only(StdIoWrapper::from(storage), CACHE_BLOCK_SIZE, CACHE_BLOCKS);
+        let fs = if volume::is_exfat(&mut storage)? {
+            Volume::ExFat(exfat::FileSystem::new_read_only(storage, fs_options())?)
+        } else {
+            Volume::Fat(FileSystem::new_read_only(storage, fs_options())?)
+        };
+        Self::from_fs(fs)
+    }
+}
+
+impl<S: Read + Write + Seek> Card<CardStorage<S>> {
+    /// Opens the filesystem on the storage
+    pub fn new(storage: S) -> Result<Self> {
+        Self::mount(cache(storage))
+    }
+
+    /// Opens the filesystem on the storage in write tracking mode
+    pub fn new_tracked(storage: S) -> Result<Self> {
+        Self::mount(cache(storage).track_writes(SECTOR_SIZE, ERASE_BLOCK_SIZE))
+    }
+
+    fn mount(storage: CardStorage<S>) -> Result<Self> {
+        Self::from_fs(Volume::new(storage, fs_options())?)
+    }
+
+    /// Formats the storage with the default layout (FAT32) and creates the directory structure
+    pub fn format(storage: S) -> Result<Self> {
+        Self::format_with(storage, &Layout::default())
+    }
+
+    /// Formats the storage with the layout and creates the directory structure
+    pub fn format_with(storage: S, layout: &Layout) -> Result<Self> {
+        let mut storage = cache(storage);
+
+        // quick format
+        match layout.fat_type {
+            FatKind::ExFat => exfat::format_volume(&mut storage, layout.exfat_volume_options()),
+            _ => fatfs::format_volume(&mut storage, layout.volume_options()),
+        }
+        .with_context(|| {
+            anyhow!("Could not format as {layout}, try a different preset or cluster size")
+        })?;
+
+        fatfs::Seek::seek(&mut storage, fatfs::SeekFrom::Start(0))?;
+
+        let card = Self::mount(storage)?;
+        {
+            let root_dir = card.fs.root_dir();
+            root_dir.create_dir(MUSIC_DIR)?;
+            root_dir.create_dir(LINK_DIR)?;
+        }
+        card.write_readme()?;
+
+        Ok(card)
+    }
+
+    /// Writes everything and marks the filesystem as cleanly unmounted, reports how much was
+    /// read and written
+    pub fn unmount(self, progress: &impl Progress) -> Result<()> {
+        // NOTE without this the hardlinks wont play on the mp3 player!
+        self.fs.flush()?;
+
+        // only once nothing on the card points to the freed clusters anymore
+        self.fs.discard_freed_clusters()?;
+
+        let mut bytes_discarded = 0;
+        if let Some(discarder) = &self.discarder {
+            let mut discarder = discarder.lock().unwrap();
+            if let Some(err) = discarder.take_error() {
+                progress.warning(format!("stopped discarding freed clusters, {err:#}"));
+            }
+            bytes_discarded = discarder.bytes();
+        }
+
+        let stats = self.fs.storage().stats();
+        progress.event(Event::Storage {
+            cache_hits: stats.hits(),
+            cache_misses: stats.misses(),
+            bytes_read: stats.bytes_read(),
+            bytes_written: stats.bytes_written(),
+            writes: stats.writes(),
+            bytes_unchanged: stats.bytes_unchanged(),
+            bytes_discarded,
+        });
+
+        self.fs.unmount()?;
+        Ok(())
+    }
+}
+
+impl<IO: ReadWriteSeek<Error = std::io::Error>> Card<IO> {
+    fn from_fs(fs: Volume<IO>) -> Result<Self> {
+        let flags = fs.read_status_flags()?;
+
+        Ok(Self {
+            fs,
+            flags,
+            discarder: None,
+        })
+    }
+
+    /// Discards clusters on the device once they are freed and the card is unmounted
+    pub fn set_discard(&mut self, discarder: Discarder) {
+        let discarder = Arc::new(Mutex::new(discarder));
+        let hook = Arc::clone(&discarder);
+        self.fs
+            .set_discard_hook(move |offset, len| hook.lock().unwrap().discard(offset, len));
+        self.discarder = Some(discarder);
+    }
+
+    /// Discards all free space on the device, returns the number of bytes discarded
+    pub fn trim(&self) -> Result<u64> {
+        let Some(discarder) = &self.discarder else {
+            bail!("Discarding is not enabled");
+        };
+
+        // clusters freed by the recovery must not be discarded while still in use on the card
+        self.fs.flush()?;
+        self.fs.discard_free_space()?;
+
+        let mut discarder = discarder.lock().unwrap();
+        if let Some(err) = discarder.take_error() {
+            return Err(err.context("Failed to discard free space"));
+        }
+
+        Ok(discarder.bytes())
+    }
+
+    /// FAT type of the filesystem
+    pub fn fat_type(&self) -> FatKind {
+        self.fs.fat_kind()
+    }
+
+    /// Makes sure the volume was created (or adopted) by f32ms so nothing else gets modified,
+    /// the label can be changed so only the directories are checked
+    pub fn check(&self) -> Result<()> {
+        // the label can be changed so the readme written by f32ms counts as well
+        let label = self.label();
+        if label != label_text(&LABEL) && !self.has_readme()? {
+            return Err(
+                UnknownVolume(format!("label is {label:?} and {README_FILE} is missing")).into(),
+            );
+        }
+
+        for dir in [MUSIC_DIR, LINK_DIR] {
+            match self.fs.root_dir().open_dir(dir) {
+                Ok(_) => {}
+                Err(fatfs::Error::NotFound | fatfs::Error::InvalidInput) => {
+                    return Err(UnknownVolume(format!("{dir}/ directory is missing")).into());
+                }
+                Err(err) => bail!(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the readme in the root directory is the one written by f32ms
+    fn has_readme(&self) -> Result<bool> {
+        let file = match self.fs.root_dir().open_file(README_FILE) {
+            Ok(x) => x,
+            Err(fatfs::Error::NotFound) => return Ok(false),
+            Err(err) => bail!(err),
+        };
+
+        // only the first line, the rest may be edited or have different line endings
+        let first_line = crate::text::README.lines().next().unwrap_or_default();
+        let mut data = vec![];
+        file.take(first_line.len() as u64).read_to_end(&mut data)?;
+
+        Ok(data == first_line.as_bytes())
+    }
+
+    /// Writes the readme which marks the card as created by f32ms
+    fn write_readme(&self) -> Result<()> {
+        let mut file = self.root_dir_full(self.fs.root_dir().create_file(README_FILE))?;
+        file.truncate()?;
+        file.write_all(crate::text::README.as_bytes())?;
+        Ok(())
+    }
+
+    /// Lists paths of audio files outside of the music and links directories
+    pub fn foreign_songs(&self) -> Result<Vec<String>> {
+        let root_dir = self.fs.root_dir();
+        let mut paths = vec![];
+
+        // directories left to scan, as prefixes of the paths
+        let mut queue = vec![String::new()];
+        while let Some(prefix) = queue.pop() {
+            let dir = if prefix.is_empty() {
+                root_dir.clone()
+            } else {
+                root_dir.open_dir(&prefix)?
+            };
+
+            for entry in dir.iter() {
+                let entry = entry?;
+                let name = entry.file_name();
+
+                // skip dot entries and hidden files like macOS metadata
+                if name.starts_with('.') {
+                    continue;
+                }
+
+                if entry.is_dir() {
+                    if prefix.is_empty() && (name == MUSIC_DIR || name == LINK_DIR) {
+                        continue;
+                    }
+
+                    queue.push(format!("{prefix}{name}/"));
+                } else if AudioFormat::from_path(Path::new(&name)).is_some() {
+                    paths.push(format!("{prefix}{name}"));
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Converts an existing volume into the f32ms layout without formatting, sets the label and
+    /// moves all audio files into the music directory, returns number of files moved
+    pub fn adopt(&mut self, progress: &impl Progress) -> Result<usize> {
+        self.fs.set_volume_label(LABEL)?;
+        self.write_readme()?;
+
+        let paths = self.foreign_songs()?;
+        let root_dir = self.fs.root_dir();
+        let music_dir = self.root_dir_full(root_dir.create_dir(MUSIC_DIR))?;
+        self.root_dir_full(root_dir.create_dir(LINK_DIR))?;
+
+        let mut moved = 0usize;
+        for (i, path) in paths.iter().enumerate() {
+            progress.progress("Moving files", i + 1, paths.len());
+
+            let name = card_name(Path::new(path));
+            match music_dir.open_file(&name) {
+                Ok(_) => {
+                    progress.event(Event::FileSkipped {
+                        path: path.clone(),
+                        reason: format!("{name:?} already exists"),
+                    });
+                    continue;
+                }
+                Err(fatfs::Error::NotFound) => {}
+                Err(err) => bail!(err),
+            }
+
+            // only the entry moves, the data stays where it is
+            root_dir
+                .rename(path, &music_dir, &name)
+                .with_context(|| anyhow!("Failed to move {path:?}"))?;
+
+            progress.event(Event::FileMoved {
+                path: path.clone(),
+                name,
+            });
+            moved += 1;
+        }
+        progress.finish_progress();
+
+        Ok(moved)
+    }
+
+    /// Lists music files, ignores any directories and unknown files
+    pub fn songs(&self) -> Result<Vec<Song>> {
+        let music_dir = match self.fs.root_dir().open_dir(MUSIC_DIR) {
+            Ok(x) => x,
+            Err(fatfs::Error::NotFound) => return Ok(vec![]),
+            Err(err) => bail!(err),
+        };
+
+        let mut songs = vec![];
+        for entry in music_dir.iter() {
+            let entry = entry?;
+            if !entry.is_file() {
+                continue;
+            }
+
+            let name = entry.file_name();
+            if let Some(format) = AudioFormat::from_card_name(&name) {
+                songs.push(Song {
+                    name,
+                    format,
+                    size: entry.len(),
+                });
+            }
+        }
+
+        Ok(songs)
+    }
+
+    /// Reads duration of the song, `None` if the format does not store it
+    pub fn song_duration(&self, song: &Song) -> Result<Option<Duration>> {
+        let mut file = self
+            .fs
+            .root_dir()
+            .open_dir(MUSIC_DIR)?
+            .open_file(&song.name)?;
+
+        song.format
+            .duration(&mut file)
+            .with_context(|| anyhow!("Could not read duration of {:?}", song.name))
+    }
+
+    /// Lists names of the links, ignores any directories
+    pub fn links(&self) -> Result<Vec<String>> {
+        let link_dir = match self.fs.root_dir().open_dir(LINK_DIR) {
+            Ok(x) => x,
+            Err(fatfs::Error::NotFound) => return Ok(vec![]),
+            Err(err) => bail!(err),
+        };
+
+        let mut links = vec![];
+        for entry in link_dir.iter() {
+            let entry = entry?;
+            if entry.is_file() {
+                links.push(entry.file_name());
+            }
+        }
+
+        Ok(links)
+    }
+
+    /// Names and sizes of all files in the music directory including unknown formats, cleaning
+    /// songs removes all of them
+    pub fn music_files(&self) -> Result<Vec<(String, u64)>> {
+        let music_dir = match self.fs.root_dir().open_dir(MUSIC_DIR) {
+            Ok(x) => x,
+            Err(fatfs::Error::NotFound) => return Ok(vec![]),
+            Err(err) => bail!(err),
+        };
+
+        let mut files = vec![];
+        for entry in music_dir.iter() {
+            let entry = entry?;
+            if entry.is_file() {
+                files.push((entry.file_name(), entry.len()));
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Checks if a file with the name exists in the music directory
+    pub fn has_song(&self, name: &str) -> Result<bool> {
+        let music_dir = match self.fs.root_dir().open_dir(MUSIC_DIR) {
+            Ok(x) => x,
+            Err(fatfs::Error::NotFound) => return Ok(false),
+            Err(err) => bail!(err),
+        };
+
+        match music_dir.open_file(name) {
+            Ok(_) => Ok(true),
+            Err(fatfs::Error::NotFound) => Ok(false),
+            Err(err) => bail!(err),
+        }
+    }
+
+    /// Copies data into the music directory, returns `None` without writing anything if the file
+    /// already exists
+    pub fn import_file(&self, name: &str, reader: &mut impl Read) -> Result<Option<u64>> {
+        let music_dir = self.root_dir_full(self.fs.root_dir().create_dir(MUSIC_DIR))?;
+
+        // do not overwrite as that could break hardlinks and corrupt the filesystem in the process
+        match music_dir.open_file(name) {
+            Ok(_) => return Ok(None),
+            Err(fatfs::Error::NotFound) => {}
+            Err(err) => bail!(err),
+        }
+
+        let mut fat_file = music_dir
+            .create_file(name)
+            .with_context(|| anyhow!("Failed to create file {name:?}"))?;
+
+        let mut buf_fat_file = BufWriter::new(&mut fat_file);
+        let bytes = std::io::copy(reader, &mut buf_fat_file)?;
+        buf_fat_file.flush()?;
+
+        Ok(Some(bytes))
+    }
+
+    /// Copies files into the music directory, returns number of copied and skipped files
+    pub fn import(&self, files: &[PathBuf], progress: &impl Progress) -> Result<(usize, usize)> {
+        let mut copied = 0usize;
+        let mut skipped = 0usize;
+
+        for (i, path) in files.iter().enumerate() {
+            progress.progress("Copying files", i + 1, files.len());
+
+            let name = card_name(path);
+            let file = std::fs::File::open(path)
+                .with_context(|| anyhow!("Failed to open file {path:?}"))?;
+
+            match self
+                .import_file(&name, &mut BufReader::new(file))
+                .with_context(|| anyhow!("Failed to copy {path:?}"))?
+            {
+                Some(bytes) => {
+                    progress.event(Event::FileCopied {
+                        source: path.to_string_lossy().to_string(),
+                        name,
+                        bytes,
+                    });
+                    copied += 1;
+                }
+                None => {
+                    progress.event(Event::FileSkipped {
+                        path: path.to_string_lossy().to_string(),
+                        reason: format!("{name:?} already exists"),
+                    });
+                    skipped += 1;
+                }
+            }
+        }
+        progress.finish_progress();
+
+        Ok((copied, skipped))
+    }
+
+    /// Replaces the links with new ones in random order, every song is linked `repeat_count`
+    /// times, returns number of links created
+    pub fn shuffle(
+        &self,
+        songs: &[Song],
+        repeat_count: usize,
+        progress: &impl Progress,
+    ) -> Result<usize> {
+        let mut rng = rand::rng();
+        let mut songs = songs.iter().collect::<Vec<_>>();
+        let mut links = Vec::with_capacity(repeat_count * songs.len());
+
+        for repeat_index in 0..repeat_count {
+            songs.shuffle(&mut rng);
+
+            for (i, song) in songs.iter().enumerate() {
+                let index = (repeat_index * songs.len()) + i;
+                let link_name = format!("{}.{}", index, song.format.extension());
+                links.push((link_name, song.name.clone()));
+            }
+        }
+
+        self.journaled(&Journal::Shuffle { links }, progress)
+    }
+
+    /// Removes the links (and optionally the songs) so the card can be edited directly, returns
+    /// number of entries removed
+    pub fn clean(&self, songs: bool, progress: &impl Progress) -> Result<usize> {
+        // the links directory must exist, otherwise this is probably not the right card
+        self.fs.root_dir().open_dir(LINK_DIR)?;
+
+        self.journaled(&Journal::Clean { songs }, progress)
+    }
+
+    /// Rewrites the song and link directories without the holes left by removed entries, with
+    /// `sort` the songs are ordered by name and the links by their index so players going by
+    /// directory order play them in the shuffled order, returns number of entries rewritten
+    pub fn sort(&self, sort: bool, progress: &impl Progress) -> Result<usize> {
+        let root_dir = self.fs.root_dir();
+        let journal = Journal::Sort {
+            sort,
+            music_entries: root_dir.open_dir(MUSIC_DIR)?.raw_entries()?,
+            link_entries: root_dir.open_dir(LINK_DIR)?.raw_entries()?,
+        };
+
+        self.journaled(&journal, progress)
+    }
+
+    /// Puts back the entries of the song and link directories as they were before a sort
+    fn restore_dirs(&self, music_entries: &[u8], link_entries: &[u8]) -> Result<()> {
+        let root_dir = self.fs.root_dir();
+        root_dir
+            .open_dir(MUSIC_DIR)?
+            .set_raw_entries(music_entries)?;
+        root_dir.open_dir(LINK_DIR)?.set_raw_entries(link_entries)?;
+        Ok(())
+    }
+
+    fn rewrite_dirs(&self, sort: bool, progress: &impl Progress) -> Result<usize> {
+        let root_dir = self.fs.root_dir();
+        let mut count = 0;
+
+        for (i, dir_name) in [MUSIC_DIR, LINK_DIR].into_iter().enumerate() {
+            let dir = root_dir.open_dir(dir_name)?;
+
+            if !sort {
+                dir.compact()?;
+            } else if dir_name == LINK_DIR {
+                dir.sort_by_key(|entry| {
+                    let name = entry.file_name();
+                    let index = name
+                        .split_once('.')
+                        .and_then(|(index, _)| index.parse::<usize>().ok());
+
+                    // anything that is not a link goes last
+                    (index.unwrap_or(usize::MAX), name)
+                })?;
+            } else {
+                dir.sort_by_key(|entry| entry.file_name().to_lowercase())?;
+            }
+
+            count += dir.iter().flatten().filter(|x| !x.is_dir()).count();
+            progress.progress("Rewriting directories", i + 1, 2);
+        }
+        progress.finish_progress();
+
+        Ok(count)
+    }
+
+    /// Reads the journal of an interrupted operation
+    pub fn journal(&self) -> Result<Option<Journal>> {
+        let mut file = match self.fs.root_dir().open_file(JOURNAL_FILE) {
+            Ok(x) => x,
+            Err(fatfs::Error::NotFound) => return Ok(None),
+            Err(err) => bail!(err),
+        };
+
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+
+        let journal = serde_json::from_str(&data)
+            .with_context(|| anyhow!("Journal {JOURNAL_FILE:?} is corrupted"))?;
+
+        Ok(Some(journal))
+    }
+
+    /// Finishes the interrupted operation, returns number of entries created or removed
+    pub fn resume(&self, progress: &impl Progress) -> Result<usize> {
+        let Some(journal) = self.journal()? else {
+            return Ok(0);
+        };
+
+        // a sort may have lost entries so it starts from the directories as they were
+        if let Journal::Sort {
+            music_entries,
+            link_entries,
+            ..
+        } = &journal
+        {
+            self.restore_dirs(music_entries, link_entries)?;
+        }
+
+        // all operations start from scratch so it does not matter where they stopped
+        self.journaled(&journal, progress)
+    }
+
+    /// Brings the card into a consistent state after an interrupted operation by removing all
+    /// links, songs are kept, an interrupted sort is undone instead, returns number of links
+    /// removed
+    pub fn rollback(&self, progress: &impl Progress) -> Result<usize> {
+        let root_dir = self.fs.root_dir();
+
+        let removed = match self.journal() {
+            // the links were fine before the sort
+            Ok(Some(Journal::Sort {
+                music_entries,
+                link_entries,
+                ..
+            })) => {
+                self.restore_dirs(&music_entries, &link_entries)?;
+                0
+            }
+            _ => {
+                let removed = self.remove_links(progress)?;
+                let _ = root_dir.remove(DIRTY_FLAG_FILE);
+                removed
+            }
+        };
+
+        // the journal stays until the rollback is on the card
+        self.fs.flush()?;
+        root_dir.remove(JOURNAL_FILE)?;
+        self.fs.flush()?;
+
+        Ok(removed)
+    }
+
+    /// Runs the operation with the journal written beforehand
+    fn journaled(&self, journal: &Journal, progress: &impl Progress) -> Result<usize> {
+        let root_dir = self.fs.root_dir();
+
+        {
+            let mut file = self.root_dir_full(root_dir.create_file(JOURNAL_FILE))?;
+            file.truncate()?;
+            file.write_all(&serde_json::to_vec(journal)?)?;
+
+            // make sure it is on the card before anything else is modified
+            file.flush()?;
+        }
+
+        let count = match journal {
+            Journal::Shuffle { links } => self.create_links(links, progress)?,
+            Journal::Clean { songs } => self.remove_all(*songs, progress)?,
+            Journal::Sort { sort, .. } => self.rewrite_dirs(*sort, progress)?,
+        };
+
+        // the cache writes blocks in order of their offset, the root directory without the
+        // journal could otherwise reach the card before the rest of the operation
+        self.fs.flush()?;
+        root_dir.remove(JOURNAL_FILE)?;
+        self.fs.flush()?;
+
+        Ok(count)
+    }
+
+    /// Replaces the links with the ones given as pairs of the link name and the song name
+    fn create_links(&self, links: &[(String, String)], progress: &impl Progress) -> Result<usize> {
+        let root_dir = self.fs.root_dir();
+        let music_dir = root_dir.open_dir(MUSIC_DIR)?;
+
+        // basically a flag that the filesystem contains links
+        self.root_dir_full(root_dir.create_file(DIRTY_FLAG_FILE))?;
+
+        self.remove_links(progress)?;
+        let link_dir = self.root_dir_full(root_dir.create_dir(LINK_DIR))?;
+
+        // otherwise the new links fill the holes left by the old ones in no particular order
+        link_dir.compact()?;
+
+        for (i, (link_name, song_name)) in links.iter().enumerate() {
+            link_dir.create_hardlink(link_name, &music_dir, song_name)?;
+
+            progress.event(Event::LinkCreated {
+                name: format!("{LINK_DIR}/{link_name}"),
+                target: format!("{MUSIC_DIR}/{song_name}"),
+            });
+            progress.progress("Creating new links", i + 1, links.len());
+        }
+        progress.finish_progress();
+
+        Ok(links.len())
+    }
+
+    /// Removes the links without freeing the clusters they share with the songs
+    fn remove_links(&self, progress: &impl Progress) -> Result<usize> {
+        let links = self.links()?;
+        if links.is_empty() {
+            return Ok(0);
+        }
+
+        let link_dir = self.fs.root_dir().open_dir(LINK_DIR)?;
+        for (i, name) in links.iter().enumerate() {
+            link_dir.remove_entry(name)?;
+            progress.event(Event::EntryRemoved {
+                name: format!("{LINK_DIR}/{name}"),
+            });
+            progress.progress("Removing old links", i + 1, links.len());
+        }
+        progress.finish_progress();
+
+        Ok(links.len())
+    }
+
+    /// Removes the links and optionally the songs
+    fn remove_all(&self, songs: bool, progress: &impl Progress) -> Result<usize> {
+        let root_dir = self.fs.root_dir();
+        let mut removed = self.remove_links(progress)?;
+
+        // optionally remove all songs
+        if songs {
+            let music_dir = root_dir.open_dir(MUSIC_DIR)?;
+
+            // remove files ignoring any directories
+            let files = self.music_files()?;
+
+            for (i, (name, _)) in files.iter().enumerate() {
+                music_dir.remove(name)?;
+                progress.event(Event::EntryRemoved {
+                    name: format!("{MUSIC_DIR}/{name}"),
+                });
+                progress.progress("Removing songs", i + 1, files.len());
+            }
+            progress.finish_progress();
+            removed += files.len();
+        }
+
+        // delete the flag file if present
+        let _ = root_dir.remove(DIRTY_FLAG_FILE);
+
+        Ok(removed)
+    }
+
+    /// Explains running out of entries in the root directory, it has a fixed size on FAT12 and
+    /// FAT16
+    fn root_dir_full<T>(&self, result: Result<T, fatfs::Error<std::io::Error>>) -> Result<T> {
+        match (result, self.fs.max_root_dir_entries()) {
+            (Err(err @ fatfs::Error::NotEnoughSpace), Some(entries)) => {
+                Err(anyhow!(err).context(format!(
+                    "Root directory is full, it can only hold {entries} entries on {}, remove some files from it",
+                    self.fs.fat_kind()
+                )))
+            }
+            (result, _) => Ok(result?),
+        }
+    }
+
+    /// Volume label without padding
+    pub fn label(&self) -> String {
+        trim_label(&self.fs.volume_label()).to_string()
+    }
+
+    /// Volume ID (serial number) from the boot sector
+    pub fn volume_id(&self) -> u32 {
+        self.fs.volume_id()
+    }
+
+    /// Changes the label in the boot sector and in the root directory, the readme is written
+    /// as well so the card is still recognized with the new label
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        let label = parse_label(label)?;
+        self.write_readme()?;
+        self.fs.set_volume_label(label)?;
+        Ok(())
+    }
+
+    /// Changes the volume ID to a random one, returns the new ID
+    pub fn new_volume_id(&mut self) -> Result<u32> {
+        let volume_id = rand::random();
+        self.fs.set_volume_id(volume_id)?;
+        Ok(volume_id)
+    }
+
+    /// Summarizes the card contents and free space
+    pub fn status(&self) -> Result<Status> {
+        let stats = self.fs.stats()?;
+        let cluster_size = u64::from(stats.cluster_size());
+
+        Ok(Status {
+            label: self.label(),
+            volume_id: format_volume_id(self.volume_id()),
+            songs: self.songs()?.len(),
+            links: self.links()?.len(),
+            has_links: match self.fs.root_dir().open_file(DIRTY_FLAG_FILE) {
+                Ok(_) => true,
+                Err(fatfs::Error::NotFound) => false,
+                Err(err) => bail!(err),
+            },
+            interrupted: self.journal()?.map(|x| x.operation()),
+            clean_unmount: !self.flags.dirty(),
+            io_error: self.flags.io_error(),
+            fat_type: self.fs.fat_kind().to_string(),
+            root_entries: self.fs.max_root_dir_entries(),
+            cluster_size,
+            total_bytes: u64::from(stats.total_clusters()) * cluster_size,
+            free_bytes: u64::from(stats.free_clusters()) * cluster_size,
+        })
+    }
+
+    /// Dirty and I/O error flags as they were when the card was opened
+    pub fn status_flags(&self) -> FsStatusFlags {
+        self.flags
+    }
+
+    /// Clears the dirty and I/O error flags, should only be called once the card was checked
+    pub fn mark_clean(&mut self) -> Result<()> {
+        self.fs.clear_status_flags()?;
+        self.flags = self.fs.read_status_flags()?;
+        Ok(())
+    }
+
+    /// Checks that the cluster chains of the songs are intact and that every link points to a
+    /// song, returns descriptions of the problems found
+    pub fn check_consistency(&self, progress: &impl Progress) -> Result<Vec<String>> {
+        let root_dir = self.fs.root_dir();
+        let cluster_size = u64::from(self.fs.cluster_size());
+        let mut problems = vec![];
+
+        // songs by the first cluster of their data
+        let mut songs: HashMap<u32, String> = HashMap::new();
+
+        let music_dir = match root_dir.open_dir(MUSIC_DIR) {
+            Ok(x) => Some(x),
+            Err(fatfs::Error::NotFound) => None,
+            Err(err) => bail!(err),
+        };
+
+        let entries = match &music_dir {
+            Some(dir) => dir
+                .iter()
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|x| x.is_file())
+                .collect(),
+            None => vec![],
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            progress.progress("Checking songs", i + 1, entries.len());
+
+            let name = format!("{MUSIC_DIR}/{}", entry.file_name());
+            match entry.cluster_count() {
+                Ok(count) if u64::from(count) < entry.len().div_ceil(cluster_size) => {
+                    problems.push(format!("{name} has less data than its size"));
+                }
+                Ok(_) => {}
+                Err(fatfs::Error::CorruptedFileSystem) => {
+                    problems.push(format!("{name} has a broken cluster chain"));
+                }
+                Err(err) => bail!(err),
+            }
+
+            if let Some(cluster) = entry.first_cluster()
+                && let Some(other) = songs.insert(cluster, name.clone())
+            {
+                problems.push(format!("{name} and {other} share the same data"));
+            }
+        }
+        progress.finish_progress();
+
+        let link_dir = match root_dir.open_dir(LINK_DIR) {
+            Ok(x) => Some(x),
+            Err(fatfs::Error::NotFound) => None,
+            Err(err) => bail!(err),
+        };
+
+        let entries = match &link_dir {
+            Some(dir) => dir
+                .iter()
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|x| x.is_file())
+                .collect(),
+            None => vec![],
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            progress.progress("Checking links", i + 1, entries.len());
+
+            // playing a link to freed or reused clusters plays garbage
+            let points_to_song = match entry.first_cluster() {
+                Some(cluster) => songs.contains_key(&cluster),
+                None => entry.len() == 0,
+            };
+
+            if !points_to_song {
+                problems.push(format!(
+                    "{LINK_DIR}/{} does not point to any song",
+                    entry.file_name()
+                ));
+            }
+        }
+        progress.finish_progress();
+
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Smallest size fatfs is willing to format as FAT32
+    const IMAGE_SIZE: usize = 40 * 1024 * 1024;
+
+    fn card() -> Card<CardStorage<Cursor<Vec<u8>>>> {
+        Card::format(Cursor::new(vec![0u8; IMAGE_SIZE])).unwrap()
+    }
+
+    fn import<IO: ReadWriteSeek<Error = std::io::Error>>(card: &Card<IO>, name: &str) {
+        // duration is not needed so the content does not matter
+        card.import_file(name, &mut Cursor::new(vec![0u8; 4096]))
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn format_creates_structure() {
+        let status = card().status().unwrap();
+
+        assert_eq!(status.label, "FAT32MS");
+        assert_eq!(status.songs, 0);
+        assert_eq!(status.links, 0);
+        assert!(!status.has_links);
+    }
+
+    #[test]
+    fn format_uses_layout() {
+        use crate::format::{FatKind, FormatOptions};
+
+        let options = FormatOptions {
+            fats: Some(1),
+            label: Some(parse_label("small").unwrap()),
+            ..Default::default()
+        };
+        let layout = options.layout(IMAGE_SIZE as u64).unwrap();
+        assert_eq!(layout.fat_type, FatKind::Fat16);
+
+        let card = Card::format_with(Cursor::new(vec![0u8; IMAGE_SIZE]), &layout).unwrap();
+        assert_eq!(card.fat_type(), FatKind::Fat16);
+        assert_eq!(Some(card.fs.cluster_size()), layout.cluster_size);
+        assert_eq!(card.label(), "SMALL");
+        card.check().unwrap();
+
+        import(&card, "a.mp3.x");
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 3, &()).unwrap();
+        assert_eq!(card.links().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn works_on_fat12_and_fat16() {
+        use crate::format::{FatKind, FormatOptions};
+
+        for (fat_type, size) in [
+            (FatKind::Fat12, 8 * 1024 * 1024),
+            (FatKind::Fat16, IMAGE_SIZE),
+        ] {
+            let options = FormatOptions {
+                fat_type: Some(fat_type),
+                ..Default::default()
+            };
+            let layout = options.layout(size as u64).unwrap();
+            let mut image = Cursor::new(vec![0u8; size]);
+            let card = Card::format_with(&mut image, &layout).unwrap();
+            for name in ["a.mp3.x", "b.mp3.x", "c.mp3.x"] {
+                import(&card, name);
+            }
+
+            let songs = card.songs().unwrap();
+            card.shuffle(&songs, 3, &()).unwrap();
+            card.sort(true, &()).unwrap();
+            assert_eq!(card.links().unwrap().len(), 9);
+            assert!(card.check_consistency(&()).unwrap().is_empty());
+            card.unmount(&()).unwrap();
+
+            image.set_position(0);
+            let card = Card::new(&mut image).unwrap();
+            card.check().unwrap();
+            assert_eq!(card.status().unwrap().links, 9);
+            card.clean(true, &()).unwrap();
+            assert!(card.songs().unwrap().is_empty());
+            assert!(card.check_consistency(&()).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn full_root_dir_is_explained() {
+        use crate::format::{FatKind, FormatOptions};
+
+        let options = FormatOptions {
+            fat_type: Some(FatKind::Fat16),
+            root_entries: Some(16),
+            ..Default::default()
+        };
+        let layout = options.layout(IMAGE_SIZE as u64).unwrap();
+        let card = Card::format_with(Cursor::new(vec![0u8; IMAGE_SIZE]), &layout).unwrap();
+        import(&card, "a.mp3.x");
+        assert_eq!(card.status().unwrap().root_entries, Some(16));
+
+        // fill the rest of the root directory with short names taking one entry each
+        let root_dir = card.fs.root_dir();
+        for i in 0.. {
+            match root_dir.create_file(&format!("F{i}")) {
+                Ok(_) => {}
+                Err(fatfs::Error::NotEnoughSpace) => break,
+                Err(err) => panic!("{err}"),
+            }
+        }
+
+        let songs = card.songs().unwrap();
+        let err = card.shuffle(&songs, 1, &()).unwrap_err();
+        assert!(err.to_string().contains("Root directory is full"));
+    }
+
+    #[test]
+    fn import_does_not_overwrite() {
+        let card = card();
+        import(&card, "a.mp3.x");
+
+        let result = card
+            .import_file("a.mp3.x", &mut Cursor::new(vec![1u8; 16]))
+            .unwrap();
+
+        assert_eq!(result, None);
+        assert_eq!(card.songs().unwrap()[0].size, 4096);
+    }
+
+    #[test]
+    fn shuffle_and_clean() {
+        let card = card();
+        for name in ["a.mp3.x", "b.mp3.x", "c.wma.x", "notes.txt"] {
+            import(&card, name);
+        }
+
+        let songs = card.songs().unwrap();
+        assert_eq!(songs.len(), 3);
+
+        assert_eq!(card.shuffle(&songs, 2, &()).unwrap(), 6);
+
+        let mut links = card.links().unwrap();
+        links.sort();
+        assert_eq!(links.len(), 6);
+        assert!(links.iter().any(|x| x.ends_with(".wma")));
+        assert!(card.status().unwrap().has_links);
+
+        // links share clusters with the songs so the songs must survive the cleanup
+        assert_eq!(card.clean(false, &()).unwrap(), 6);
+        assert_eq!(card.links().unwrap().len(), 0);
+        assert_eq!(card.songs().unwrap().len(), 3);
+        assert!(!card.status().unwrap().has_links);
+
+        // files in unknown formats are removed as well
+        assert_eq!(card.music_files().unwrap().len(), 4);
+        assert_eq!(card.clean(true, &()).unwrap(), 4);
+        assert!(card.music_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_refuses_foreign_volume() {
+        assert!(card().check().is_ok());
+
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        fatfs::format_volume(
+            &mut StdIoWrapper::from(&mut image),
+            fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32),
+        )
+        .unwrap();
+
+        let card = Card::new(image).unwrap();
+        let err = card.check().unwrap_err();
+        assert!(err.downcast_ref::<UnknownVolume>().is_some());
+
+        // the directories alone do not make it an f32ms card, neither does a foreign readme
+        {
+            let root_dir = card.fs.root_dir();
+            root_dir.create_dir(MUSIC_DIR).unwrap();
+            root_dir.create_dir(LINK_DIR).unwrap();
+            let mut readme = root_dir.create_file(README_FILE).unwrap();
+            readme.write_all(b"Some other readme").unwrap();
+        }
+        let err = card.check().unwrap_err();
+        assert!(err.downcast_ref::<UnknownVolume>().is_some());
+
+        // cards formatted with a custom label are recognized by the readme
+        let layout = Layout {
+            label: parse_label("music").unwrap(),
+            ..Default::default()
+        };
+        let card = Card::format_with(Cursor::new(vec![0u8; IMAGE_SIZE]), &layout).unwrap();
+        assert_eq!(card.label(), "MUSIC");
+        card.check().unwrap();
+    }
+
+    #[test]
+    fn works_on_exfat() {
+        use crate::format::{FatKind, FormatOptions};
+
+        let options = FormatOptions {
+            fat_type: Some(FatKind::ExFat),
+            ..Default::default()
+        };
+        let layout = options.layout(IMAGE_SIZE as u64).unwrap();
+        assert_eq!(layout.fats, 1);
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        let card = Card::format_with(&mut image, &layout).unwrap();
+        assert_eq!(card.fat_type(), FatKind::ExFat);
+        for name in ["b.mp3.x", "a.mp3.x", "c.mp3.x"] {
+            import(&card, name);
+        }
+
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 3, &()).unwrap();
+        card.sort(true, &()).unwrap();
+        let expected = (0..9).map(|i| format!("{i}.mp3")).collect::<Vec<_>>();
+        assert_eq!(card.links().unwrap(), expected);
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+        card.unmount(&()).unwrap();
+
+        image.set_position(0);
+        let mut card = Card::new(&mut image).unwrap();
+        card.check().unwrap();
+        let status = card.status().unwrap();
+        assert_eq!(status.fat_type, "exFAT");
+        assert_eq!(status.label, "FAT32MS");
+        assert_eq!(status.links, 9);
+        assert_eq!(status.root_entries, None);
+        assert!(status.clean_unmount);
+
+        card.set_label("exfat").unwrap();
+        let volume_id = card.new_volume_id().unwrap();
+        card.clean(true, &()).unwrap();
+        assert!(card.songs().unwrap().is_empty());
+        card.unmount(&()).unwrap();
+
+        image.set_position(0);
+        let card = Card::new_read_only(&mut image).unwrap();
+        card.check().unwrap();
+        assert_eq!(card.label(), "EXFAT");
+        assert_eq!(card.volume_id(), volume_id);
+        assert_eq!(card.links().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn exfat_card_can_be_adopted() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        fatfs::exfat::format_volume(
+            &mut StdIoWrapper::from(&mut image),
+            fatfs::exfat::FormatVolumeOptions::new().volume_label("SDXC"),
+        )
+        .unwrap();
+        {
+            let fs = fatfs::exfat::FileSystem::new(&mut image, FsOptions::new()).unwrap();
+            fs.root_dir().create_dir("Music").unwrap();
+            fs.root_dir().create_file("Music/song.mp3").unwrap();
+            fs.unmount().unwrap();
+        }
+
+        image.set_position(0);
+        let mut card = Card::new(&mut image).unwrap();
+        assert!(card.check().is_err());
+        assert_eq!(card.adopt(&()).unwrap(), 1);
+        card.check().unwrap();
+        assert_eq!(card.songs().unwrap()[0].name, "song.mp3.x");
+    }
+
+    #[test]
+    fn label_and_volume_id_can_be_changed() {
+        assert_ne!(card().volume_id(), card().volume_id());
+
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        let mut card = Card::format(&mut image).unwrap();
+        assert!(card.set_label("a/b").is_err());
+        assert!(card.set_label("twelve chars").is_err());
+
+        // cards from older versions only have the label
+        card.fs.root_dir().remove(README_FILE).unwrap();
+        card.set_label("My Music").unwrap();
+        let volume_id = card.new_volume_id().unwrap();
+        card.unmount(&()).unwrap();
+
+        image.set_position(0);
+        let card = Card::new(&mut image).unwrap();
+        assert_eq!(card.label(), "MY MUSIC");
+        assert_eq!(card.volume_id(), volume_id);
+        let Volume::Fat(fs) = &card.fs else {
+            panic!("formatted as FAT32");
+        };
+        let root_label = fs.read_volume_label_from_root_dir().unwrap();
+        assert_eq!(trim_label(&root_label.unwrap()), "MY MUSIC");
+        // still recognized as f32ms card
+        card.check().unwrap();
+        assert_eq!(format_volume_id(0x1234_ABCD), "1234-ABCD");
+    }
+
+    #[test]
+    fn adopt_moves_songs() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        fatfs::format_volume(
+            &mut StdIoWrapper::from(&mut image),
+            fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32),
+        )
+        .unwrap();
+
+        let mut card = Card::new(image).unwrap();
+        {
+            let root_dir = card.fs.root_dir();
+            root_dir.create_file("a.mp3").unwrap();
+            root_dir.create_file("notes.txt").unwrap();
+            root_dir
+                .create_dir("Album")
+                .unwrap()
+                .create_file("b.wma")
+                .unwrap();
+        }
+
+        let mut paths = card.foreign_songs().unwrap();
+        paths.sort();
+        assert_eq!(paths, ["Album/b.wma", "a.mp3"]);
+
+        assert_eq!(card.adopt(&()).unwrap(), 2);
+        assert!(card.check().is_ok());
+        assert_eq!(card.songs().unwrap().len(), 2);
+        assert!(card.foreign_songs().unwrap().is_empty());
+    }
+
+    /// Simulates an operation that was interrupted right after writing the journal
+    fn interrupt(card: &Card<CardStorage<Cursor<Vec<u8>>>>, journal: &Journal) {
+        let mut file = card.fs.root_dir().create_file(JOURNAL_FILE).unwrap();
+        file.write_all(&serde_json::to_vec(journal).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn resume_and_rollback_interrupted_operations() {
+        let card = card();
+        for name in ["a.mp3.x", "b.mp3.x", "c.mp3.x"] {
+            import(&card, name);
+        }
+
+        let links = vec![
+            ("0.mp3".to_string(), "b.mp3.x".to_string()),
+            ("1.mp3".to_string(), "a.mp3.x".to_string()),
+        ];
+        interrupt(&card, &Journal::Shuffle { links });
+        assert_eq!(card.status().unwrap().interrupted, Some("shuffle"));
+
+        assert_eq!(card.resume(&()).unwrap(), 2);
+        assert_eq!(card.links().unwrap().len(), 2);
+        assert_eq!(card.journal().unwrap(), None);
+
+        interrupt(&card, &Journal::Clean { songs: true });
+        assert_eq!(card.rollback(&()).unwrap(), 2);
+        assert_eq!(card.links().unwrap().len(), 0);
+        assert_eq!(card.songs().unwrap().len(), 3);
+
+        let status = card.status().unwrap();
+        assert!(!status.has_links);
+        assert_eq!(status.interrupted, None);
+
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 2, &()).unwrap();
+        let root_dir = card.fs.root_dir();
+        let link_dir = root_dir.open_dir(LINK_DIR).unwrap();
+        let link_entries = link_dir.raw_entries().unwrap();
+        let sort = Journal::Sort {
+            sort: true,
+            music_entries: root_dir.open_dir(MUSIC_DIR).unwrap().raw_entries().unwrap(),
+            link_entries: link_entries.clone(),
+        };
+
+        // the rewrite was interrupted after clearing the links, only . and .. are left
+        link_dir.set_raw_entries(&link_entries[..64]).unwrap();
+        interrupt(&card, &sort);
+        assert_eq!(card.status().unwrap().interrupted, Some("sort"));
+        assert_eq!(card.resume(&()).unwrap(), 9);
+        assert_eq!(card.journal().unwrap(), None);
+        assert_eq!(card.links().unwrap().len(), 6);
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+
+        // rolling back a sort keeps the links as they were
+        link_dir.set_raw_entries(&link_entries[..64]).unwrap();
+        interrupt(&card, &sort);
+        assert_eq!(card.rollback(&()).unwrap(), 0);
+        assert_eq!(card.journal().unwrap(), None);
+        assert_eq!(link_dir.raw_entries().unwrap(), link_entries);
+        assert!(card.status().unwrap().has_links);
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+    }
+
+    /// Offsets and data of the writes
+    type Writes = std::rc::Rc<std::cell::RefCell<Vec<(u64, Vec<u8>)>>>;
+
+    /// Storage that records every write
+    struct WriteLog {
+        inner: Cursor<Vec<u8>>,
+        writes: Writes,
+    }
+
+    impl Read for WriteLog {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for WriteLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = self.inner.write(buf)?;
+            let offset = self.inner.position() - n as u64;
+            self.writes.borrow_mut().push((offset, buf[..n].to_vec()));
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for WriteLog {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn journal_is_removed_after_the_operation_is_written() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        let card = Card::format(&mut image).unwrap();
+        for name in ["a.mp3.x", "b.mp3.x", "c.mp3.x"] {
+            import(&card, name);
+        }
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 4, &()).unwrap();
+        card.unmount(&()).unwrap();
+        let before = image.into_inner();
+
+        let writes = Writes::default();
+        let card = Card::new(WriteLog {
+            inner: Cursor::new(before.clone()),
+            writes: std::rc::Rc::clone(&writes),
+        })
+        .unwrap();
+        assert_eq!(card.clean(true, &()).unwrap(), 15);
+        card.unmount(&()).unwrap();
+
+        // unplugging the card after any of the writes leaves either the journal or a clean card
+        let mut image = before;
+        let mut journal_written = false;
+        for (offset, data) in writes.borrow().iter() {
+            let offset = *offset as usize;
+            image[offset..offset + data.len()].copy_from_slice(data);
+
+            let card = Card::new_read_only(Cursor::new(&image[..])).unwrap();
+            // a partially written journal is reported as corrupted, which stops the next command
+            if !matches!(card.journal(), Ok(None)) {
+                journal_written = true;
+            } else if journal_written {
+                assert!(card.links().unwrap().is_empty());
+                assert!(card.music_files().unwrap().is_empty());
+            }
+        }
+        assert!(journal_written);
+    }
+
+    #[test]
+    fn dirty_card_is_checked_and_marked_clean() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+
+        // unplugged without unmounting
+        let card = Card::format(&mut image).unwrap();
+        card.import_file("a.mp3.x", &mut Cursor::new(vec![0u8; 4096]))
+            .unwrap();
+        std::mem::forget(card);
+        image.set_position(0);
+
+        let mut card = Card::new(&mut image).unwrap();
+        assert!(card.status_flags().dirty());
+        assert!(!card.status().unwrap().clean_unmount);
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+
+        card.mark_clean().unwrap();
+        card.unmount(&()).unwrap();
+        image.set_position(0);
+
+        let card = Card::new(&mut image).unwrap();
+        assert!(card.status().unwrap().clean_unmount);
+    }
+
+    #[test]
+    fn read_only_card_is_left_unchanged() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+
+        // unplugged without unmounting so the free cluster count on the card is stale
+        let card = Card::format(&mut image).unwrap();
+        import(&card, "a.mp3.x");
+        std::mem::forget(card);
+        image.set_position(0);
+        let before = image.get_ref().clone();
+
+        let card = Card::new_read_only(&mut image).unwrap();
+        let status = card.status().unwrap();
+        assert!(!status.clean_unmount);
+        assert_eq!(status.songs, 1);
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+        assert!(
+            card.import_file("b.mp3.x", &mut Cursor::new(vec![0u8; 4096]))
+                .is_err()
+        );
+        drop(card);
+
+        assert!(image.get_ref() == &before);
+
+        // storage that can only be read works as well
+        let card = Card::new_read_only(Cursor::new(&before[..])).unwrap();
+        assert_eq!(card.songs().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn consistency_check_finds_dangling_links() {
+        let card = card();
+        for name in ["a.mp3.x", "b.mp3.x", "c.mp3.x"] {
+            import(&card, name);
+        }
+
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 1, &()).unwrap();
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+
+        // freeing the data of a song leaves its link pointing to free clusters
+        let music_dir = card.fs.root_dir().open_dir(MUSIC_DIR).unwrap();
+        music_dir.remove("b.mp3.x").unwrap();
+
+        let problems = card.check_consistency(&()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("does not point to any song"));
+    }
+
+    #[test]
+    fn sort_orders_songs_and_links() {
+        let card = card();
+        for name in ["c.mp3.x", "B.mp3.x", "a.mp3.x", "d.mp3.x"] {
+            import(&card, name);
+        }
+
+        let music_dir = card.fs.root_dir().open_dir(MUSIC_DIR).unwrap();
+        music_dir.remove("d.mp3.x").unwrap();
+
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 4, &()).unwrap();
+
+        // mess up the order of links so sorting has something to do
+        let link_dir = card.fs.root_dir().open_dir(LINK_DIR).unwrap();
+        link_dir
+            .sort_by_key(|entry| std::cmp::Reverse(entry.file_name()))
+            .unwrap();
+
+        assert_eq!(card.sort(true, &()).unwrap(), 15);
+
+        let names = card.songs().unwrap().into_iter().map(|x| x.name);
+        assert_eq!(names.collect::<Vec<_>>(), ["a.mp3.x", "B.mp3.x", "c.mp3.x"]);
+
+        let indexes = card
+            .links()
+            .unwrap()
+            .iter()
+            .map(|x| x.split_once('.').unwrap().0.parse::<usize>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(indexes, (0..12).collect::<Vec<_>>());
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn discard_punches_holes_into_image() {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        let card = Card::format(&mut image).unwrap();
+        card.import_file("a.mp3.x", &mut Cursor::new(vec![0xAAu8; 1024 * 1024]))
+            .unwrap();
+        card.unmount(&()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("f32ms-discard-{}.img", std::process::id()));
+        std::fs::write(&path, image.into_inner()).unwrap();
+        let allocated = || std::fs::metadata(&path).unwrap().blocks() * 512;
+        let before = allocated();
+
+        let mut card = Card::open(&path).unwrap();
+        card.set_discard(Discarder::open(&path).unwrap());
+        card.fs
+            .root_dir()
+            .remove(&format!("{MUSIC_DIR}/a.mp3.x"))
+            .unwrap();
+        // the song stays on the card until the removal is written
+        assert_eq!(allocated(), before);
+        card.unmount(&()).unwrap();
+        // clusters are not aligned to the blocks of the host filesystem, partial blocks at both
+        // ends are only zeroed
+        assert!(allocated() <= before - 1024 * 1024 + 2 * 4096);
+
+        let mut card = Card::open(&path).unwrap();
+        card.set_discard(Discarder::open(&path).unwrap());
+        let free = card.status().unwrap().free_bytes;
+        assert_eq!(card.trim().unwrap(), free);
+        card.unmount(&()).unwrap();
+        // only the filesystem structures are left
+        assert!(allocated() <= before - free + 2 * 4096);
+
+        // the song is gone but the filesystem is intact
+        let card = Card::open_read_only(&path).unwrap();
+        assert!(card.songs().unwrap().is_empty());
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Bytes written by the shuffle reported when unmounting
+    fn shuffle_bytes_written(card: Card<CardStorage<&mut Cursor<Vec<u8>>>>) -> u64 {
+        struct Written(std::cell::Cell<u64>);
+        impl Progress for Written {
+            fn event(&self, event: Event) {
+                if let Event::Storage { bytes_written, .. } = event {
+                    self.0.set(bytes_written);
+                }
+            }
+        }
+
+        let songs = card.songs().unwrap();
+        card.shuffle(&songs, 10, &()).unwrap();
+
+        let written = Written(Default::default());
+        card.unmount(&written).unwrap();
+        written.0.get()
+    }
+
+    #[test]
+    fn tracked_card_writes_less() {
+        let mut image = Cursor::new(vec![0u8; IMAGE_SIZE]);
+        let card = Card::format(&mut image).unwrap();
+        for name in ["a.mp3.x", "b.mp3.x", "c.mp3.x"] {
+            card.import_file(name, &mut Cursor::new(vec![0u8; 4096]))
+                .unwrap();
+        }
+        card.unmount(&()).unwrap();
+
+        let mut tracked_image = image.clone();
+        image.set_position(0);
+        let written = shuffle_bytes_written(Card::new(&mut image).unwrap());
+
+        tracked_image.set_position(0);
+        let tracked_written = shuffle_bytes_written(Card::new_tracked(&mut tracked_image).unwrap());
+        assert!(tracked_written < written, "{tracked_written} >= {written}");
+
+        tracked_image.set_position(0);
+        let card = Card::new(&mut tracked_image).unwrap();
+        assert_eq!(card.links().unwrap().len(), 30);
+        assert!(card.check_consistency(&()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn repeat_count_fills_duration() {
+        let count = repeat_count(3, Duration::from_secs(60), Duration::from_secs(150)).unwrap();
+        assert_eq!(count, 3);
+
+        assert!(repeat_count(2, Duration::from_secs(60), Duration::from_secs(150)).is_err());
+        assert!(repeat_count(3, Duration::from_secs(60), Duration::from_secs(30)).is_err());
+    }
+}
@@ -1,11 +1,24 @@
 use std::path::PathBuf;
 
-use clap::{Args, Parser, Subcommand};
+use crate::output::OutputFormat;
+use clap::builder::TypedValueParser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use f32ms::audio::PlayerProfile;
+use f32ms::format::{FatKind, FormatPreset};
 use humantime::Duration;
+use serde::{Deserialize, Serialize};
 
-/// Utility for shuffling MP3 music files for dumb MP3 players
+const EXIT_CODES: &str = "Exit codes:
+  0  Success
+  1  Error
+  2  Invalid arguments
+  3  Aborted by the user or input required with --no-input or without a terminal
+  4  Device could not be found, opened, read or written
+  5  Filesystem on the device is invalid or was not created by f32ms";
+
+/// Utility for shuffling music files for dumb MP3 players
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about)]
+#[command(author, version, about, after_help = EXIT_CODES)]
 pub struct Cli {
     /// Target block device, leave empty for prompt
     // hide the flag on windows cause its useless
@@ -16,10 +29,78 @@ pub struct Cli {
     #[clap(long)]
     pub show_all_disks: bool,
 
+    /// Answer yes to all confirmation prompts
+    #[clap(short, long, global = true)]
+    pub yes: bool,
+
+    /// Never read from stdin, fail if any input would be required
+    #[clap(long, global = true)]
+    pub no_input: bool,
+
+    /// Output format, JSON prints a single event per line
+    #[clap(
+        long = "output",
+        id = "output_format",
+        value_enum,
+        global = true,
+        default_value_t
+    )]
+    pub output_format: OutputFormat,
+
+    /// Print what would be done without changing anything, the device is only opened for reading
+    #[clap(long, global = true)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub card: CardArgs,
+
+    /// Audio formats and filesystems supported by the player
+    ///
+    /// Unsupported formats are skipped on import and transcoded to MP3 when processing, format
+    /// picks a filesystem the player can read
+    #[clap(long, value_enum, default_value_t)]
+    pub profile: PlayerProfile,
+
     #[command(subcommand)]
     pub cmd: CliCommands,
 }
 
+/// How the card is opened by commands that modify it
+#[derive(Args, Debug, Clone)]
+pub struct CardArgs {
+    /// Modify the volume even if it does not have the f32ms label and directories
+    #[clap(long, global = true)]
+    pub force: bool,
+
+    /// Unmount the device if it is mounted instead of refusing to modify it
+    #[clap(long, global = true)]
+    pub unmount: bool,
+
+    /// What to do with an operation that was interrupted, asks if not given
+    #[clap(long, value_enum, global = true)]
+    pub recover: Option<Recover>,
+
+    /// Only rewrite sectors that changed and group writes by erase blocks to reduce wear of the
+    /// card, reads more to compare
+    #[clap(long, global = true)]
+    pub track_writes: bool,
+
+    /// Tell the card which clusters were freed so it can erase them in advance (TRIM), punches
+    /// holes into image files
+    #[clap(long, global = true)]
+    pub discard: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recover {
+    /// Finish the interrupted operation
+    Resume,
+
+    /// Remove all links to get to a consistent state, songs are kept, an interrupted sort is undone
+    /// instead
+    Rollback,
+}
+
 #[derive(Args, Debug, Clone)]
 pub struct CmdShuffle {
     /// Repeats all songs until they fill up at minimum this amount of time
@@ -39,30 +120,185 @@ pub struct CmdClean {
     pub songs: bool,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct CmdFormat {
+    /// Layout recommended for the size of the card, the options below override it
+    #[clap(long, value_enum, default_value_t)]
+    pub preset: FormatPreset,
+
+    /// FAT variant, FAT16 only fits cards up to 4 GiB (2 GiB with 32 KiB clusters) and FAT12 up
+    /// to 256 MiB, exFAT is for SDXC cards over 32 GiB
+    #[clap(long, value_enum)]
+    pub fat_type: Option<FatKind>,
+
+    /// Bytes per cluster, a power of two (ex. 16K, 32K), bigger clusters waste more space but
+    /// the FAT is smaller
+    #[clap(long, value_parser = parse_cluster_size)]
+    pub cluster_size: Option<u32>,
+
+    /// Number of FATs, one saves a write for every change but leaves no backup, exFAT always has
+    /// one
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub fats: Option<u8>,
+
+    /// Bytes per logical sector
+    #[clap(
+        long,
+        value_parser = clap::builder::PossibleValuesParser::new(["512", "1024", "2048", "4096"])
+            .map(|x| x.parse::<u16>().unwrap())
+    )]
+    pub sector_size: Option<u16>,
+
+    /// Entries the root directory can hold on FAT12 and FAT16 where it has a fixed size (512 by
+    /// default), every song copied directly into the root directory uses at least one
+    #[clap(long)]
+    pub root_entries: Option<u16>,
+
+    /// Volume label, up to 11 characters
+    #[clap(long)]
+    pub label: Option<String>,
+}
+
+/// Parses cluster size with the same suffixes as other sizes
+fn parse_cluster_size(input: &str) -> Result<u32, String> {
+    let size = f32ms::util::parse_size(input)?;
+    u32::try_from(size)
+        .ok()
+        .filter(|x| x.is_power_of_two() && (512..=64 * 1024).contains(x))
+        .ok_or_else(|| {
+            format!("cluster size must be a power of two between 512 and 64K, got {size}")
+        })
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CmdSort {
+    /// Only remove the holes left by deleted entries, keep the current order
+    #[clap(long)]
+    pub no_sort: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CmdLabel {
+    /// New label, up to 11 characters, lowercase letters are stored as uppercase
+    pub label: Option<String>,
+
+    /// Give the card a new random volume ID, for cards that were cloned from another one
+    #[clap(long)]
+    pub new_id: bool,
+}
+
 #[derive(Args, Debug, Clone)]
 pub struct CmdImport {
-    /// Files or directories to recursively scan for MP3 files to import
+    /// Files or directories to recursively scan for audio files to import
     #[clap(required = true, num_args = 1..)]
     pub paths: Vec<PathBuf>,
 }
 
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalize {
+    /// Do not change the loudness
+    Off,
+
+    /// Normalize each track separately
+    Track,
+
+    /// Normalize albums as a whole, keeps the differences between tracks of an album
+    Album,
+}
+
 #[derive(Args, Debug, Clone)]
 pub struct CmdProcess {
     /// Overwrite existing files
     #[clap(short, long)]
     pub overwrite: bool,
 
-    /// Adjust volume of files (in decibels)
+    /// Adjust volume of files (in decibels), applied on top of normalization
     ///
     /// +/-10dB => doubles or halves the volume
     #[clap(short, long, allow_negative_numbers = true)]
     pub volume_adjustment: Option<f64>,
 
-    /// Output path
+    /// Preset of processing steps, either builtin (music, audiobook) or from the presets file
+    #[clap(short, long, default_value = "music")]
+    pub preset: String,
+
+    /// Presets file, defaults to `$XDG_CONFIG_HOME/f32ms/presets.json` if it exists
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Loudness normalization, files in the same directory are considered an album
+    #[clap(long, value_enum)]
+    pub normalize: Option<Normalize>,
+
+    /// Target loudness for normalization (in LUFS)
+    #[clap(long, allow_negative_numbers = true)]
+    pub target_lufs: Option<f64>,
+
+    /// Anything quieter than this is trimmed from the start and end (in decibels)
+    #[clap(
+        long,
+        allow_negative_numbers = true,
+        conflicts_with = "no_silence_trim"
+    )]
+    pub silence_threshold: Option<f64>,
+
+    /// Do not trim silence
+    #[clap(long)]
+    pub no_silence_trim: bool,
+
+    /// Fade in duration (in seconds)
+    #[clap(long)]
+    pub fade_in: Option<f64>,
+
+    /// Fade out duration (in seconds)
+    #[clap(long)]
+    pub fade_out: Option<f64>,
+
+    /// Encoder bitrate (in kbps)
+    #[clap(long, conflicts_with_all = ["vbr_quality", "fit", "fit_card"])]
+    pub bitrate: Option<u32>,
+
+    /// MP3 VBR quality, 0 is the best and 9 the smallest
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=9), conflicts_with_all = ["fit", "fit_card"])]
+    pub vbr_quality: Option<u8>,
+
+    /// Output sample rate (in Hz)
+    #[clap(long)]
+    pub sample_rate: Option<u32>,
+
+    /// Downmix to mono, halves the size of lossless files
+    #[clap(long)]
+    pub mono: bool,
+
+    /// Pick the highest bitrate such that the whole output fits into this size (ex. 4G, 512M)
+    #[clap(long, value_parser = f32ms::util::parse_size, conflicts_with = "fit_card")]
+    pub fit: Option<u64>,
+
+    /// Same as `--fit` but uses free space of the target device
+    #[clap(long)]
+    pub fit_card: bool,
+
+    /// Keep embedded cover art
+    #[clap(long)]
+    pub keep_cover: bool,
+
+    /// Metadata tags to keep, everything else is removed (comma separated)
+    #[clap(long, value_delimiter = ',')]
+    pub keep_metadata: Option<Vec<String>>,
+
+    /// Number of files to process in parallel, defaults to number of CPUs
+    #[clap(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Output directory
+    ///
+    /// Directory structure of the inputs is preserved, so `album/cd1/01.mp3` ends up as
+    /// `OUTPUT/album/cd1/01.mp3`
     #[clap(required = true)]
     pub output: PathBuf,
 
-    /// Files or directories to recursively scan for MP3 files to fix
+    /// Files or directories to recursively scan for audio files to fix
     #[clap(required = true, num_args = 1..)]
     pub paths: Vec<PathBuf>,
 }
@@ -72,9 +308,13 @@ pub enum CliCommands {
     /// Formats device/partition (ERASES ALL DATA!)
     ///
     /// In case target is a device block file then it formats it to contain a
-    /// single FAT32 partition with MBR/BIOS partition table
+    /// single FAT partition with MBR/BIOS partition table
+    ///
+    /// FAT16 is used for cards up to 2 GiB, FAT32 up to 32 GiB and exFAT for bigger ones unless
+    /// the options say otherwise, with the legacy player profile the card is always FAT16 and at
+    /// most 2 GiB
     #[cfg_attr(target_os = "windows", clap(skip))]
-    Format,
+    Format(CmdFormat),
 
     /// Shuffle music
     Shuffle(CmdShuffle),
@@ -82,13 +322,41 @@ pub enum CliCommands {
     /// Cleans up the links making it editable directly
     Clean(CmdClean),
 
+    /// Sorts songs by name and links by their index in the directory entries
+    ///
+    /// Some players ignore the names and play files in the order of directory entries, which
+    /// gets mixed up as files are removed and added
+    Sort(CmdSort),
+
+    /// Tells the card that all free space is unused so it can erase it in advance (TRIM)
+    ///
+    /// Keeps writes fast on cards that were filled before, deleted files can not be recovered
+    /// afterwards
+    Trim,
+
+    /// Converts an existing FAT music stick to f32ms layout without formatting
+    ///
+    /// Sets the label, creates the directories and moves all audio files into the music directory
+    Adopt,
+
+    /// Shows the label, number of songs and links and free space
+    Status,
+
+    /// Shows or changes the label and volume ID of the card
+    ///
+    /// Cards are formatted with a random volume ID which tells them apart when they all have the
+    /// default label
+    Label(CmdLabel),
+
     /// Imports file into the filesystem without mounting it, will not overwrite files
     Import(CmdImport),
 
     /// Processes files using ffmpeg to apply some adjustments (recommended)
     ///
+    /// Files in formats not supported by the player profile are transcoded to MP3
+    ///
     /// All options have a description but always test if the files are playable on a computer!
-    Process(CmdProcess),
+    Process(Box<CmdProcess>),
 }
 
 #[cfg(test)]
@@ -1,3 +1,6 @@
+mod card;
+pub use card::{open_card, open_card_read_only, open_card_unchecked};
+
 mod format;
 pub use format::format;
 
@@ -7,6 +10,21 @@ pub use shuffle::shuffle;
 mod clean;
 pub use clean::clean;
 
+mod sort;
+pub use sort::sort;
+
+mod trim;
+pub use trim::trim;
+
+mod adopt;
+pub use adopt::adopt;
+
+mod status;
+pub use status::status;
+
+mod label;
+pub use label::label;
+
 mod import;
 pub use import::import;
 
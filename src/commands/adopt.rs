@@ -0,0 +1,65 @@
+use crate::cli::CardArgs;
+use crate::commands::open_card_unchecked;
+use crate::output::Reporter;
+use f32ms::Card;
+use f32ms::card::card_name;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+use f32ms::{LINK_DIR, MUSIC_DIR, README_FILE};
+use std::path::Path;
+
+pub fn adopt(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+) -> Result<()> {
+    // the volume is not checked as it is not f32ms yet
+    if dry_run {
+        let card = Card::open_read_only(&target.path)?;
+        let paths = card.foreign_songs()?;
+
+        reporter.info(format!(
+            "Would change label from {:?} to {:?}",
+            card.label(),
+            String::from_utf8_lossy(&f32ms::LABEL).trim_end_matches('\0')
+        ));
+        reporter.info(format!(
+            "Would create {MUSIC_DIR}/, {LINK_DIR}/ and {README_FILE}"
+        ));
+
+        for path in &paths {
+            reporter.info(format!(
+                "Would move {path:?} to {MUSIC_DIR}/{}",
+                card_name(Path::new(path))
+            ));
+        }
+
+        return Ok(());
+    }
+
+    let mut card = open_card_unchecked(&target, card_args)?;
+    let paths = card.foreign_songs()?;
+
+    if interactive {
+        reporter.confirm(format!(
+            "Adopting partition {target}, {} audio files will be moved to {MUSIC_DIR}/, do you wish to proceed?",
+            paths.len()
+        ))?;
+    }
+
+    let moved = card.adopt(reporter)?;
+    card.unmount(reporter)?;
+
+    reporter.event(Event::Summary {
+        command: "adopt",
+        unit: "files moved",
+        done: moved,
+        skipped: paths.len() - moved,
+        failed: 0,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use crate::cli::{CardArgs, Recover};
+use crate::output::Reporter;
+use f32ms::Card;
+use f32ms::card::{CardStorage, Journal, ReadOnlyStorage, SyncedFile};
+use f32ms::discard::Discarder;
+use f32ms::error::Aborted;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+use fatfs::ReadWriteSeek;
+use std::fs::File;
+
+/// Opens the card for a command, makes sure it was created by f32ms and takes care of any
+/// interrupted operation
+pub fn open_card(
+    target: &BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    args: &CardArgs,
+) -> Result<Card<CardStorage<SyncedFile>>> {
+    let mut card = open_card_unchecked(target, args)?;
+    prepare(&mut card, reporter, interactive, false, args)?;
+    Ok(card)
+}
+
+/// Opens the card for a dry run, it is checked the same way but nothing is written to it, not
+/// even the dirty flag
+pub fn open_card_read_only(
+    target: &BlockDevice,
+    reporter: &Reporter,
+    args: &CardArgs,
+) -> Result<Card<ReadOnlyStorage<File>>> {
+    let mut card = Card::open_read_only(&target.path)?;
+    prepare(&mut card, reporter, false, true, args)?;
+    Ok(card)
+}
+
+/// Opens the card the way the arguments ask for without checking anything
+pub fn open_card_unchecked(
+    target: &BlockDevice,
+    args: &CardArgs,
+) -> Result<Card<CardStorage<SyncedFile>>> {
+    let mut card = if args.track_writes {
+        Card::open_tracked(&target.path)?
+    } else {
+        Card::open(&target.path)?
+    };
+
+    if args.discard {
+        card.set_discard(Discarder::open(&target.path)?);
+    }
+
+    Ok(card)
+}
+
+fn prepare<IO: ReadWriteSeek<Error = std::io::Error>>(
+    card: &mut Card<IO>,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    args: &CardArgs,
+) -> Result<()> {
+    if !args.force {
+        card.check()?;
+    }
+
+    check_flags(card, reporter, interactive, dry_run, args.force)?;
+    recover(card, reporter, dry_run, args.recover)
+}
+
+/// Checks the card if the last session did not unmount it cleanly, it is marked clean again if
+/// no problems were found
+fn check_flags<IO: ReadWriteSeek<Error = std::io::Error>>(
+    card: &mut Card<IO>,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    force: bool,
+) -> Result<()> {
+    let flags = card.status_flags();
+    if !flags.dirty() && !flags.io_error() {
+        return Ok(());
+    }
+
+    if flags.dirty() {
+        reporter
+            .warning("the card was not unmounted cleanly, it was probably unplugged while writing");
+    }
+
+    if flags.io_error() {
+        reporter.warning("an I/O error was recorded on the card, it may be failing");
+    }
+
+    reporter.info("Checking the card..");
+    let problems = card.check_consistency(reporter)?;
+
+    if problems.is_empty() {
+        reporter.info("No problems found");
+
+        if !dry_run {
+            card.mark_clean()?;
+        }
+
+        return Ok(());
+    }
+
+    for problem in &problems {
+        reporter.warning(problem.clone());
+    }
+
+    if force || dry_run {
+        return Ok(());
+    }
+
+    // removing the songs and importing them again fixes anything the check can find
+    let message = format!(
+        "Found {} problems, running clean --songs and importing the songs again is recommended",
+        problems.len()
+    );
+
+    if !interactive {
+        return Err(anyhow!(fatfs::Error::<std::io::Error>::CorruptedFileSystem)
+            .context(format!("{message}, use --force to continue anyway")));
+    }
+
+    reporter.confirm(format!("{message}, do you wish to continue anyway?"))
+}
+
+/// Resumes or rolls back an interrupted operation, must be called before the card is modified
+fn recover<IO: ReadWriteSeek<Error = std::io::Error>>(
+    card: &Card<IO>,
+    reporter: &Reporter,
+    dry_run: bool,
+    recover: Option<Recover>,
+) -> Result<()> {
+    let Some(journal) = card.journal()? else {
+        return Ok(());
+    };
+
+    let operation = journal.operation();
+    reporter.warning(format!("previous {operation} was interrupted"));
+
+    if dry_run {
+        reporter.info(format!(
+            "Would resume or roll back the {operation} before anything else"
+        ));
+        return Ok(());
+    }
+
+    let rollback = match journal {
+        Journal::Sort { .. } => "restoring the directories",
+        _ => "removing all links",
+    };
+
+    let recover = match recover {
+        Some(x) => x,
+        None => loop {
+            match reporter
+                .ask(
+                    &format!(
+                        "Resume the {operation} or roll back by {rollback}? (resume/rollback)"
+                    ),
+                    "use --recover",
+                )?
+                .as_str()
+            {
+                "resume" => break Recover::Resume,
+                "rollback" => break Recover::Rollback,
+                "" => return Err(Aborted("No recovery selected".to_string()).into()),
+                _ => continue,
+            }
+        },
+    };
+
+    let count = match recover {
+        Recover::Resume => card.resume(reporter)?,
+        Recover::Rollback => card.rollback(reporter)?,
+    };
+
+    reporter.event(Event::Summary {
+        command: "recover",
+        unit: match recover {
+            Recover::Resume => "entries resumed",
+            Recover::Rollback => "links removed",
+        },
+        done: count,
+        skipped: 0,
+        failed: 0,
+    });
+
+    Ok(())
+}
@@ -1,71 +1,56 @@
-use crate::cli::CmdClean;
-use crate::util::BlockDevice;
-use crate::{DIRTY_FLAG_FILE, LINK_DIR, MUSIC_DIR, prelude::*};
-use fatfs::{FileSystem, FsOptions};
-use fscommon::BufStream;
-use std::io::Write;
-
-pub fn clean(target: BlockDevice, interactive: bool, args: CmdClean) -> Result<()> {
-    if interactive {
-        crate::confirm_prompt(format!(
+use crate::cli::{CardArgs, CmdClean};
+use crate::commands::{open_card, open_card_read_only};
+use crate::output::Reporter;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+use f32ms::{DIRTY_FLAG_FILE, LINK_DIR, MUSIC_DIR};
+
+pub fn clean(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+    args: CmdClean,
+) -> Result<()> {
+    if interactive && !dry_run {
+        reporter.confirm(format!(
             "Cleaning partition {target}, do you wish to proceed?",
         ))?;
     }
 
-    let file = target.open(false)?;
-    let stream = BufStream::new(file);
-    let fs = FileSystem::new(stream, FsOptions::new())?;
-
-    {
-        let root_dir = fs.root_dir();
-        let link_dir = root_dir.open_dir(LINK_DIR)?;
-
-        // count links ignoring any directories
-        let links = link_dir
-            .iter()
-            .flatten()
-            .filter(|x| x.is_file())
-            .map(|x| x.file_name())
-            .collect::<Vec<_>>();
+    if dry_run {
+        let card = open_card_read_only(&target, reporter, card_args)?;
+        reporter.info(format!(
+            "Would remove {} links from {LINK_DIR}/",
+            card.links()?.len()
+        ));
 
-        if !links.is_empty() {
-            for (i, file_name) in links.iter().enumerate() {
-                link_dir.remove_entry(file_name)?;
-                print!("\rRemoving old links [{}/{}]", i + 1, links.len());
-                let _ = std::io::stdout().flush();
+        if args.songs {
+            for (name, size) in card.music_files()? {
+                reporter.info(format!("Would remove {MUSIC_DIR}/{name} ({size} bytes)"));
             }
-            println!();
         }
 
-        // optionally remove all songs
-        if args.songs {
-            let music_dir = root_dir.open_dir(MUSIC_DIR)?;
-
-            // count files ignoring any directories
-            let files = music_dir
-                .iter()
-                .flatten()
-                .filter(|x| x.is_file())
-                .map(|x| x.file_name())
-                .collect::<Vec<_>>();
-
-            if !files.is_empty() {
-                for (i, file_name) in files.iter().enumerate() {
-                    music_dir.remove(&file_name)?;
-                    print!("\rRemoving song [{}/{}]", i + 1, files.len());
-                    let _ = std::io::stdout().flush();
-                }
-                println!();
-            }
+        if card.status()?.has_links {
+            reporter.info(format!("Would remove {DIRTY_FLAG_FILE}"));
         }
 
-        // delete the flag file if present
-        let _ = root_dir.remove(DIRTY_FLAG_FILE);
+        return Ok(());
     }
 
-    fs.unmount()?;
-
-    println!("Done!");
+    let card = open_card(&target, reporter, interactive, card_args)?;
+    let removed = card.clean(args.songs, reporter)?;
+    card.unmount(reporter)?;
+
+    reporter.event(Event::Summary {
+        command: "clean",
+        unit: "entries removed",
+        done: removed,
+        skipped: 0,
+        failed: 0,
+    });
 
     Ok(())
 }
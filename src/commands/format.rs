@@ -1,13 +1,62 @@
-use crate::prelude::*;
-use crate::util::BlockDevice;
-use crate::{LABEL, LINK_DIR, MUSIC_DIR};
-use fatfs::{FileSystem, FsOptions};
-use fscommon::BufStream;
+use crate::cli::CmdFormat;
+use crate::output::Reporter;
+use f32ms::audio::PlayerProfile;
+use f32ms::card::{parse_label, storage_size};
+use f32ms::format::{FatKind, FormatOptions};
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+use f32ms::{Card, LINK_DIR, MUSIC_DIR, README_FILE};
 use std::io::prelude::*;
 
-pub fn format(mut target: BlockDevice, interactive: bool) -> Result<()> {
+pub fn format(
+    mut target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    profile: PlayerProfile,
+    args: CmdFormat,
+) -> Result<()> {
+    let options = FormatOptions {
+        preset: args.preset,
+        profile,
+        fat_type: args.fat_type,
+        cluster_size: args.cluster_size,
+        fats: args.fats,
+        sector_size: args.sector_size,
+        root_entries: args.root_entries,
+        label: args.label.as_deref().map(parse_label).transpose()?,
+    };
+
+    // the partition created on a disk is about the size of the disk, checked before anything
+    // is wiped so invalid options fail early
+    let layout = options.layout(storage_size(&target.path)?)?;
+
+    if dry_run {
+        if !target.is_partition {
+            reporter.info(format!(
+                "Would wipe {} and create a single {} partition",
+                target.path, layout.fat_type
+            ));
+        }
+
+        reporter.info(format!(
+            "Would format the {} as {layout}",
+            if target.is_partition {
+                format!("partition {}", target.path)
+            } else {
+                "new partition".to_string()
+            },
+        ));
+        reporter.info(format!(
+            "Would create {MUSIC_DIR}/, {LINK_DIR}/ and {README_FILE}"
+        ));
+
+        return Ok(());
+    }
+
     if interactive {
-        crate::confirm_prompt(format!(
+        reporter.confirm(format!(
             "Formatting {} {target}, do you wish to proceed?",
             if target.is_partition {
                 "partition"
@@ -19,8 +68,8 @@ pub fn format(mut target: BlockDevice, interactive: bool) -> Result<()> {
 
     // if its a disk format the whole disk
     if !target.is_partition {
-        println!("Formatting the disk..");
-        format_disk(&target.path)?;
+        reporter.info("Formatting the disk..");
+        format_disk(&target.path, layout.fat_type, layout.volume_size)?;
 
         // wait for the partition to be reloaded
         std::thread::sleep(std::time::Duration::from_secs(1));
@@ -35,38 +84,35 @@ pub fn format(mut target: BlockDevice, interactive: bool) -> Result<()> {
             .with_context(|| anyhow!("Could not find a partition after formatting"))?;
     }
 
-    println!("Formatting the partition..");
-    format_partition(&target)?;
-
-    println!("Setting up the directory structure..");
-    setup(&target)?;
+    let layout = options.layout(storage_size(&target.path)?)?;
+    if target.is_partition
+        && let Some(volume_size) = layout.volume_size
+    {
+        reporter.warning(format!(
+            "only the first {} MiB of the partition are used as the player does not support bigger cards",
+            volume_size / 1024 / 1024
+        ));
+    }
 
-    println!(
-        "Formatting done, for any other commands please use {:?} as the device path",
+    reporter.info(format!("Formatting the partition as {layout}.."));
+    Card::format_path(&target.path, &layout)?.unmount(reporter)?;
+
+    reporter.event(Event::Summary {
+        command: "format",
+        unit: "partition formatted",
+        done: 1,
+        skipped: 0,
+        failed: 0,
+    });
+    reporter.info(format!(
+        "For any other commands please use {:?} as the device path",
         target.path
-    );
-
-    Ok(())
-}
-
-fn format_partition(target: &BlockDevice) -> Result<()> {
-    use fatfs::{FormatVolumeOptions, StdIoWrapper, format_volume};
-
-    let file = target.open(false)?;
-    let mut stream = StdIoWrapper::from(BufStream::new(file));
-
-    // quick format
-    format_volume(
-        &mut stream,
-        FormatVolumeOptions::new()
-            .fat_type(fatfs::FatType::Fat32)
-            .volume_label(LABEL),
-    )?;
+    ));
 
     Ok(())
 }
 
-fn format_disk(path: &str) -> Result<()> {
+fn format_disk(path: &str, fat_type: FatKind, size: Option<u64>) -> Result<()> {
     let mut child = std::process::Command::new("sfdisk")
         // always wipe partitions
         .args(["--wipe", "always", "--wipe-partitions", "always", path])
@@ -81,8 +127,19 @@ fn format_disk(path: &str) -> Result<()> {
         .take()
         .with_context(|| anyhow!("Unable to take child stdin"))?;
 
-    // NOTE: basically create MBR partition table and single "W95 FAT32 (LBA)" partition
-    stdin.write_all(b"label: dos\ntype=c")?;
+    // NOTE: basically create MBR partition table and single "W95 FAT32 (LBA)", "W95 FAT16
+    // (LBA)", "FAT12" or "HPFS/NTFS/exFAT" partition
+    let partition_type = match fat_type {
+        FatKind::Fat12 => "1",
+        FatKind::Fat16 => "e",
+        FatKind::Fat32 => "c",
+        FatKind::ExFat => "7",
+    };
+    let mut script = format!("label: dos\ntype={partition_type}");
+    if let Some(size) = size {
+        script += &format!(",size={}MiB", size / 1024 / 1024);
+    }
+    stdin.write_all(script.as_bytes())?;
 
     drop(stdin);
 
@@ -90,25 +147,3 @@ fn format_disk(path: &str) -> Result<()> {
 
     Ok(())
 }
-
-fn setup(target: &BlockDevice) -> Result<()> {
-    let file = target.open(false)?;
-    let stream = BufStream::new(file);
-
-    // create the structure
-    let fs = FileSystem::new(stream, FsOptions::new())?;
-    {
-        let root_dir = fs.root_dir();
-        root_dir.create_dir(MUSIC_DIR)?;
-        root_dir.create_dir(LINK_DIR)?;
-
-        {
-            let mut readme = root_dir.create_file("README.txt")?;
-            readme.write(crate::text::README.as_bytes())?;
-        }
-    }
-
-    fs.unmount()?;
-
-    Ok(())
-}
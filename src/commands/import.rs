@@ -1,83 +1,137 @@
-use crate::cli::CmdImport;
-use crate::util::{BlockDevice, find_mp3_files};
-use crate::{MUSIC_DIR, MUSIC_EXT, prelude::*};
-use fatfs::{FileSystem, FsOptions};
-use fscommon::BufStream;
-use std::io::{BufReader, BufWriter, Write};
+use crate::cli::{CardArgs, CmdImport};
+use crate::commands::{open_card, open_card_read_only};
+use crate::output::Reporter;
+use f32ms::MUSIC_DIR;
+use f32ms::audio::{AudioFormat, PlayerProfile};
+use f32ms::card::{Card, card_name};
+use f32ms::format::FatKind;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::{BlockDevice, find_audio_files};
+use fatfs::ReadWriteSeek;
 use std::path::PathBuf;
 
-pub fn import(target: BlockDevice, interactive: bool, args: CmdImport) -> Result<()> {
-    println!("Scanning for files..");
+/// Warns when the card is formatted in a way the player cannot read
+fn check_fat_type<IO: ReadWriteSeek<Error = std::io::Error>>(
+    card: &Card<IO>,
+    reporter: &Reporter,
+    profile: PlayerProfile,
+) {
+    let fat_type = card.fat_type();
+    if matches!(fat_type, FatKind::Fat32 | FatKind::ExFat) && !profile.supports_fat32() {
+        reporter.warning(format!(
+            "the card is {fat_type} which the player may not read, reformat it with the profile to use FAT16"
+        ));
+    }
+}
+
+/// Prints what would be copied without writing anything
+fn dry_run(
+    target: &BlockDevice,
+    reporter: &Reporter,
+    card_args: &CardArgs,
+    profile: PlayerProfile,
+    files: &[PathBuf],
+) -> Result<()> {
+    let card = open_card_read_only(target, reporter, card_args)?;
+    check_fat_type(&card, reporter, profile);
+    let status = card.status()?;
+
+    let mut total: u64 = 0;
+    let mut count = 0usize;
+    for path in files {
+        let name = card_name(path);
+
+        if card.has_song(&name)? {
+            reporter.info(format!("Would skip {name:?}, already exists"));
+            continue;
+        }
+
+        let size = std::fs::metadata(path)
+            .with_context(|| anyhow!("Failed to read size of {path:?}"))?
+            .len();
+
+        reporter.info(format!(
+            "Would copy {path:?} to {MUSIC_DIR}/{name} ({size} bytes)"
+        ));
+        total += size;
+        count += 1;
+    }
+
+    reporter.info(format!(
+        "Would copy {count} files, {total} bytes of {} bytes free",
+        status.free_bytes
+    ));
+
+    if total > status.free_bytes {
+        reporter.warning("the files would not fit");
+    }
+
+    Ok(())
+}
+
+pub fn import(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+    profile: PlayerProfile,
+    args: CmdImport,
+) -> Result<()> {
+    reporter.info("Scanning for files..");
     let mut files: Vec<PathBuf> = vec![];
 
     for path in args.paths {
-        find_mp3_files(&mut files, path.clone())
+        find_audio_files(&mut files, path.clone())
             .with_context(|| anyhow!("Error scanning {path:?}"))?;
     }
 
+    reporter.event(Event::ScanComplete { files: files.len() });
+
+    // the player would not be able to play these anyway
+    let mut unsupported = 0usize;
+    files.retain(|path| {
+        let format = AudioFormat::from_path(path).unwrap();
+        if !profile.supports(format) {
+            reporter.event(Event::FileSkipped {
+                path: path.to_string_lossy().to_string(),
+                reason: format!(
+                    "{} is not supported by the player (use process command to convert it)",
+                    format.name()
+                ),
+            });
+            unsupported += 1;
+            return false;
+        }
+
+        true
+    });
+
+    if dry_run {
+        return self::dry_run(&target, reporter, card_args, profile, &files);
+    }
+
     if interactive {
-        crate::confirm_prompt(format!(
-            "Importing {} MP3 files, do you wish to proceed?",
+        reporter.confirm(format!(
+            "Importing {} audio files, do you wish to proceed?",
             files.len()
         ))?;
     }
 
-    let file = target.open(false)?;
-    let stream = BufStream::new(file);
-    let fs = FileSystem::new(stream, FsOptions::new())?;
-
-    {
-        let root_dir = fs.root_dir();
-        let music_dir = root_dir.create_dir(MUSIC_DIR)?;
-
-        for (i, path) in files.iter().enumerate() {
-            // update progress
-            print!("\rCopying files [{}/{}]", i + 1, files.len());
-            let _ = std::io::stdout().flush();
-
-            let name = format!(
-                "{}{MUSIC_EXT}",
-                path.file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .chars()
-                    // only allow ascii characters
-                    .filter(|c| c.is_ascii())
-                    .collect::<String>(),
-            );
-
-            let mut file = std::fs::OpenOptions::new()
-                .read(true)
-                .write(false)
-                .open(path)
-                .with_context(|| anyhow!("Failed to open file {path:?}"))?;
-
-            // do not overwrite as that could break hardlinks and corrupt the filesystem in the process
-            match music_dir.open_file(&name) {
-                Ok(_) => {
-                    println!("\rSkipping {name:?} already exists");
-                    continue;
-                }
-                Err(fatfs::Error::NotFound) => {}
-                Err(err) => bail!(err),
-            }
-
-            let mut fat_file = music_dir
-                .create_file(&name)
-                .with_context(|| anyhow!("Failed to create file {name:?}"))?;
-
-            let mut buf_file = BufReader::new(&mut file);
-            let mut buf_fat_file = BufWriter::new(&mut fat_file);
-
-            std::io::copy(&mut buf_file, &mut buf_fat_file)
-                .with_context(|| anyhow!("Failed to copy {path:?}"))?;
-        }
-    }
+    let card = open_card(&target, reporter, interactive, card_args)?;
+    check_fat_type(&card, reporter, profile);
 
-    // NOTE without this the hardlinks wont play on the mp3 player!
-    fs.unmount()?;
+    let (copied, skipped) = card.import(&files, reporter)?;
+    card.unmount(reporter)?;
 
-    println!("\nDone!");
+    reporter.event(Event::Summary {
+        command: "import",
+        unit: "files copied",
+        done: copied,
+        skipped: skipped + unsupported,
+        failed: 0,
+    });
 
     Ok(())
 }
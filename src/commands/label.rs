@@ -0,0 +1,95 @@
+use crate::cli::{CardArgs, CmdLabel};
+use crate::commands::{open_card, open_card_read_only};
+use crate::output::Reporter;
+use f32ms::Card;
+use f32ms::card::{format_volume_id, parse_label};
+use f32ms::prelude::*;
+use f32ms::report::Progress;
+use f32ms::util::BlockDevice;
+
+pub fn label(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+    args: CmdLabel,
+) -> Result<()> {
+    if args.label.is_none() && !args.new_id {
+        let card = Card::open_read_only(&target.path)?;
+        reporter.info(format!(
+            "Label: {}\nVolume ID: {}",
+            card.label(),
+            format_volume_id(card.volume_id())
+        ));
+        return Ok(());
+    }
+
+    // fail on invalid labels before touching the card
+    let label = match &args.label {
+        Some(label) => {
+            let bytes = parse_label(label)?;
+            Some(
+                String::from_utf8_lossy(&bytes)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )
+        }
+        None => None,
+    };
+
+    if interactive && !dry_run {
+        reporter.confirm(format!(
+            "Changing {} of partition {target}, do you wish to proceed?",
+            match (&label, args.new_id) {
+                (Some(label), false) => format!("label to {label:?}"),
+                (Some(label), true) => format!("label to {label:?} and volume ID"),
+                (None, _) => "volume ID".to_string(),
+            }
+        ))?;
+    }
+
+    if dry_run {
+        let card = open_card_read_only(&target, reporter, card_args)?;
+        let old_label = card.label();
+        let old_volume_id = format_volume_id(card.volume_id());
+
+        if let Some(label) = &label {
+            reporter.info(format!(
+                "Would change label from {old_label:?} to {label:?}"
+            ));
+        }
+        if args.new_id {
+            reporter.info(format!(
+                "Would change volume ID {old_volume_id} to a random one"
+            ));
+        }
+        return Ok(());
+    }
+
+    let mut card = open_card(&target, reporter, interactive, card_args)?;
+    let old_label = card.label();
+    let old_volume_id = format_volume_id(card.volume_id());
+
+    if let Some(label) = &label {
+        card.set_label(label)?;
+    }
+    let new_volume_id = if args.new_id {
+        Some(card.new_volume_id()?)
+    } else {
+        None
+    };
+    card.unmount(reporter)?;
+
+    if let Some(label) = &label {
+        reporter.info(format!("Label changed from {old_label:?} to {label:?}"));
+    }
+    if let Some(volume_id) = new_volume_id {
+        reporter.info(format!(
+            "Volume ID changed from {old_volume_id} to {}",
+            format_volume_id(volume_id)
+        ));
+    }
+
+    Ok(())
+}
@@ -1,96 +1,336 @@
-use crate::cli::CmdProcess;
-use crate::prelude::*;
-use crate::util::find_mp3_files;
-use std::{
-    io::Write,
-    path::{Path, PathBuf},
-};
-
-fn process_file(input: &Path, output: &Path, args: &CmdProcess) -> Result<()> {
-    // skip existing files if not overwriting
-    if !args.overwrite && output.exists() {
-        return Ok(());
+mod fit;
+mod loudness;
+mod pipeline;
+mod validate;
+
+use crate::cli::{CmdProcess, Normalize};
+use crate::output::Reporter;
+use f32ms::Card;
+use f32ms::audio::{AudioFormat, PlayerProfile, probe_duration};
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::{BlockDevice, find_audio_files};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+use fit::Budget;
+use loudness::Loudness;
+use pipeline::Pipeline;
+
+/// Single file to process
+struct Task {
+    input: PathBuf,
+    output: PathBuf,
+    format: AudioFormat,
+
+    /// Index of the album (directory) the file belongs to
+    album: usize,
+}
+
+/// Runs `f` for each item using `jobs` threads, `on_result` is called in order of the items
+fn parallel_map<T: Sync, R: Send>(
+    jobs: usize,
+    items: &[T],
+    f: impl Fn(&T) -> R + Sync,
+    mut on_result: impl FnMut(usize, R),
+) {
+    let next_item = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel::<(usize, R)>();
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let sender = sender.clone();
+            let next_item = &next_item;
+            let f = &f;
+
+            scope.spawn(move || {
+                loop {
+                    let index = next_item.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else {
+                        break;
+                    };
+
+                    // receiver only goes away if the main thread panicked
+                    if sender.send((index, f(item))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // only workers hold senders now so the loop ends once they are all done
+        drop(sender);
+
+        // results arrive out of order, report them in the order of the items
+        let mut pending = BTreeMap::new();
+        let mut reported = 0usize;
+
+        for (index, result) in receiver {
+            pending.insert(index, result);
+
+            while let Some(result) = pending.remove(&reported) {
+                on_result(reported, result);
+                reported += 1;
+            }
+        }
+    });
+}
+
+/// Output path relative to the output directory, mirrors the tree below the source root
+fn relative_output(root: &Path, input: &Path, format: AudioFormat) -> PathBuf {
+    let relative = match input.strip_prefix(root) {
+        // keep the name of the root directory like `cp -r` does
+        Ok(rest) if !rest.as_os_str().is_empty() => root
+            .file_name()
+            .map(|x| Path::new(x).join(rest))
+            .unwrap_or_else(|| rest.to_path_buf()),
+
+        // root is the file itself
+        _ => PathBuf::from(input.file_name().unwrap()),
+    };
+
+    relative.with_extension(format.extension())
+}
+
+/// Temporary file ffmpeg writes into, renamed to the output only on success so that any existing
+/// output is always complete
+fn partial_path(output: &Path) -> PathBuf {
+    output.with_file_name(format!(
+        ".{}.part",
+        output.file_name().unwrap().to_string_lossy()
+    ))
+}
+
+/// Arguments for ffmpeg to process `input` into `output`
+fn ffmpeg_args(
+    input: &Path,
+    output: &Path,
+    format: AudioFormat,
+    pipeline: &Pipeline,
+    gain: f64,
+) -> Result<Vec<String>> {
+    let mut args = ["-nostdin", "-y", "-i", input.to_str().unwrap()]
+        .map(String::from)
+        .to_vec();
+    args.extend(pipeline.output_args(format));
+
+    // you can only specify filter once so one big huge string
+    args.extend(["-filter:a".to_string(), pipeline.filters(gain).join(",")]);
+    args.extend(pipeline.metadata_args(input)?);
+
+    // the partial file has no usable extension
+    args.extend(["-f", format.ffmpeg_muxer(), output.to_str().unwrap()].map(String::from));
+
+    Ok(args)
+}
+
+/// Quotes the argument for printing as a shell command
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Processes the file and returns warnings about the output
+fn process_file(
+    input: &Path,
+    output: &Path,
+    format: AudioFormat,
+    pipeline: &Pipeline,
+    gain: f64,
+) -> Result<Vec<String>> {
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Failed to create directory {parent:?}"))?;
     }
 
+    let partial = partial_path(output);
+
     // TODO run status in debug builds!
     let cmd = std::process::Command::new("ffmpeg")
-        .args([
-            "-nostdin",
-            "-y",
-            "-i",
-            input.to_str().unwrap(),
-            "-map",
-            "a",
-            "-filter:a",
-            // you can only specify filter once so one big huge string
-            &[
-                // remove silence at the start
-                "silenceremove=start_periods=1:start_duration=1:start_threshold=-60dB:detection=peak",
-                "aformat=dblp",
-
-                // remove silence at the end
-                "areverse",
-                "silenceremove=start_periods=1:start_duration=1:start_threshold=-60dB:detection=peak",
-                "aformat=dblp",
-                "areverse",
-
-                // apply replaygain
-                "volume=replaygain=track",
-
-                // apply volume adjustment
-                &format!("volume={}dB", args.volume_adjustment.unwrap_or(0.0)),
-            ].join(","),
-            // remove metadata (including replay_gain)
-            "-map_metadata",
-            "-1",
-            output.to_str().unwrap()
-        ])
+        .args(ffmpeg_args(input, &partial, format, pipeline, gain)?)
         .output()
         .with_context(|| anyhow!("Could not run ffmpeg"))?;
 
     if !cmd.status.success() {
-        bail!(
-            "Failed to process {input:?}, exit code {:?}",
-            cmd.status.code()
-        )
+        let _ = std::fs::remove_file(&partial);
+        bail!("ffmpeg exited with code {:?}", cmd.status.code())
     }
 
-    let orig = mp3_duration::from_path(input)
+    // broken output must not end up at the final path, it would be skipped on the next run
+    let warnings = match check_output(input, &partial, format) {
+        Ok(x) => x,
+        Err(err) => {
+            let _ = std::fs::remove_file(&partial);
+            return Err(err.context("Output failed validation"));
+        }
+    };
+
+    std::fs::rename(&partial, output)
+        .with_context(|| anyhow!("Could not move partial output to {output:?}"))?;
+
+    Ok(warnings)
+}
+
+/// Validates the output and compares its duration to the input
+fn check_output(input: &Path, output: &Path, format: AudioFormat) -> Result<Vec<String>> {
+    let mut warnings = validate::validate(output, format)?;
+
+    let orig = probe_duration(input)
         .with_context(|| anyhow!("Could not read duration from input {input:?}"))?;
 
-    let out = mp3_duration::from_path(output)
+    // the output has no usable extension so use the format directly
+    let out = std::fs::File::open(output)
+        .map_err(anyhow::Error::from)
+        .and_then(|x| format.duration(&mut std::io::BufReader::new(x)))
         .with_context(|| anyhow!("Could not read duration from output {output:?}"))?;
 
+    // some formats do not store duration so there is nothing to compare
+    let (Some(orig), Some(out)) = (orig, out) else {
+        return Ok(warnings);
+    };
+
     // the output may be entirely broken
     if out.as_secs_f64() == 0.0 {
-        println!("\rWarning: output file {output:?} has length of 0 seconds!");
-        return Ok(());
+        bail!("output has length of 0 seconds");
     }
 
     let diff = orig.as_secs_f64() - out.as_secs_f64();
-    let procentage = (diff / out.as_secs_f64()) * 100.0;
+    let percentage = (diff / orig.as_secs_f64()) * 100.0;
 
     // check if file duration has changed a lot
-    if procentage >= 80.00 {
-        println!("\rWarning: output file {output:?} duration was reduced by over 80%!");
+    if percentage >= 80.00 {
+        warnings.push(format!(
+            "duration was reduced by {percentage:.0}% (from {:.0}s to {:.0}s)",
+            orig.as_secs_f64(),
+            out.as_secs_f64()
+        ));
     }
 
-    Ok(())
+    Ok(warnings)
 }
 
-pub fn process(interactive: bool, args: CmdProcess) -> Result<()> {
-    println!("Scanning for files..");
-    let mut files: Vec<PathBuf> = vec![];
+/// Space available on the card for `--fit-card`
+fn card_budget(target: &BlockDevice) -> Result<Budget> {
+    let status = Card::open_read_only(&target.path)?.status()?;
+
+    Ok(Budget {
+        size: status.free_bytes,
+        cluster_size: status.cluster_size,
+    })
+}
+
+pub fn process(
+    target: Option<BlockDevice>,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    profile: PlayerProfile,
+    args: CmdProcess,
+) -> Result<()> {
+    let mut pipeline = Pipeline::from_args(&args)?;
+
+    let budget = match (&target, args.fit) {
+        (Some(target), _) => Some(
+            card_budget(target)
+                .with_context(|| anyhow!("Could not read free space of {target}"))?,
+        ),
+        (None, Some(size)) => Some(Budget {
+            size,
+            cluster_size: Budget::DEFAULT_CLUSTER_SIZE,
+        }),
+        (None, None) => None,
+    };
 
-    for path in &args.paths {
-        find_mp3_files(&mut files, path.clone())
-            .with_context(|| anyhow!("Error scanning {path:?}"))?;
+    reporter.info("Scanning for files..");
+    let mut tasks: Vec<Task> = vec![];
+    let mut albums: HashMap<PathBuf, usize> = HashMap::new();
+
+    for root in &args.paths {
+        let mut files: Vec<PathBuf> = vec![];
+        find_audio_files(&mut files, root.clone())
+            .with_context(|| anyhow!("Error scanning {root:?}"))?;
+
+        for input in files {
+            // transcode to MP3 if the player cannot play the format
+            let format = profile.target_format(AudioFormat::from_path(&input).unwrap());
+            let output = args.output.join(relative_output(root, &input, format));
+
+            // files in the same directory are considered an album
+            let album_count = albums.len();
+            let album = *albums
+                .entry(input.parent().map(Path::to_path_buf).unwrap_or_default())
+                .or_insert(album_count);
+
+            tasks.push(Task {
+                input,
+                output,
+                format,
+                album,
+            });
+        }
+    }
+    reporter.event(Event::ScanComplete { files: tasks.len() });
+
+    // FAT is case insensitive so compare the paths that way, transcoding can also make two
+    // files collide (song.flac and song.mp3)
+    {
+        let mut outputs: HashMap<String, &Path> = HashMap::new();
+        let mut collisions = vec![];
+
+        for task in &tasks {
+            let key = task.output.to_string_lossy().to_lowercase();
+            if let Some(other) = outputs.insert(key, &task.input) {
+                collisions.push((other, &task.input, &task.output));
+            }
+        }
+
+        if !collisions.is_empty() {
+            for (a, b, output) in &collisions {
+                reporter.warning(format!(
+                    "{a:?} and {b:?} would both be written to {output:?}"
+                ));
+            }
+
+            bail!("Found {} output name collisions", collisions.len());
+        }
     }
 
-    if interactive {
-        crate::confirm_prompt(format!(
-            "Fixing {} MP3 files, do you wish to proceed?",
-            files.len()
+    // skip existing files if not overwriting, partial outputs are never left at the final path
+    // so existing files are complete
+    let (tasks, existing): (Vec<_>, Vec<_>) = tasks
+        .into_iter()
+        .partition(|x| args.overwrite || !x.output.exists());
+
+    if let Some(budget) = &budget {
+        let bitrate = fit::fit_bitrate(budget, &tasks, &existing)?;
+        reporter.info(format!(
+            "Using {bitrate} kbps to fit into {} MiB",
+            budget.size / 1024 / 1024
+        ));
+
+        pipeline.bitrate = Some(bitrate);
+        pipeline.vbr_quality = None;
+    }
+
+    if interactive && !dry_run {
+        let transcoded = tasks
+            .iter()
+            .filter(|x| Some(x.format) != AudioFormat::from_path(&x.input))
+            .count();
+
+        reporter.confirm(format!(
+            "Fixing {} audio files ({transcoded} will be transcoded to MP3, {} already processed), do you wish to proceed?",
+            tasks.len(),
+            existing.len(),
         ))?;
     }
 
@@ -98,22 +338,181 @@ pub fn process(interactive: bool, args: CmdProcess) -> Result<()> {
         if !args.output.is_dir() {
             bail!("Output path is not a directory");
         }
-    } else {
+    } else if !dry_run {
         std::fs::create_dir_all(&args.output)
             .with_context(|| anyhow!("Failed to create output directory {:?}", args.output))?;
     }
 
-    for (i, path) in files.iter().enumerate() {
-        // update progress
-        print!("\rProcessing files [{}/{}]", i + 1, files.len());
-        let _ = std::io::stdout().flush();
+    let jobs = args
+        .jobs
+        .or_else(|| std::thread::available_parallelism().ok().map(|x| x.get()))
+        .unwrap_or(1);
+
+    let mut failed: Vec<(&Path, anyhow::Error)> = vec![];
+
+    // measure loudness of every file, album mode needs every file of the album even if some of
+    // them were already processed
+    let mut loudness: Vec<Option<Loudness>> = vec![None; tasks.len()];
+    let mut album_loudness: HashMap<usize, Loudness> = HashMap::new();
+
+    if pipeline.normalize != Normalize::Off {
+        let pre_gain_filters = pipeline.pre_gain_filters();
+        let to_analyze = match pipeline.normalize {
+            Normalize::Album => tasks
+                .iter()
+                .chain(
+                    existing
+                        .iter()
+                        .filter(|x| tasks.iter().any(|y| y.album == x.album)),
+                )
+                .collect::<Vec<_>>(),
+            _ => tasks.iter().collect::<Vec<_>>(),
+        };
+
+        let mut measured: HashMap<usize, Vec<Loudness>> = HashMap::new();
+
+        parallel_map(
+            jobs,
+            &to_analyze,
+            |task| Loudness::analyze(&task.input, &pre_gain_filters),
+            |i, result| {
+                let task = to_analyze[i];
+                match result {
+                    Ok(x) => {
+                        measured.entry(task.album).or_default().push(x);
+
+                        // only the tasks to process are at the start
+                        if i < tasks.len() {
+                            loudness[i] = Some(x);
+                        }
+                    }
+                    Err(err) => {
+                        // the file would fail processing anyway
+                        if i < tasks.len() {
+                            failed.push((&task.input, err));
+                        } else {
+                            reporter
+                                .warning(format!("could not analyze {:?}: {err:#}", task.input));
+                        }
+                    }
+                }
+
+                reporter.progress("Analyzing loudness", i + 1, to_analyze.len());
+            },
+        );
+        reporter.finish_progress();
+
+        album_loudness = measured
+            .into_iter()
+            .filter_map(|(album, tracks)| Loudness::combine(&tracks).map(|x| (album, x)))
+            .collect();
+    }
+
+    // compute gain for each file, files that failed analysis are left out
+    let mut gains: Vec<Option<f64>> = vec![None; tasks.len()];
+    for (i, task) in tasks.iter().enumerate() {
+        let measured = match pipeline.normalize {
+            Normalize::Off => {
+                gains[i] = Some(args.volume_adjustment.unwrap_or(0.0));
+                continue;
+            }
+            Normalize::Track => loudness[i],
+            Normalize::Album => loudness[i].and(album_loudness.get(&task.album).copied()),
+        };
+
+        if let Some(measured) = measured {
+            let (gain, limited) = measured.gain_to(pipeline.target_lufs);
+            if limited {
+                reporter.warning(format!(
+                    "gain of {:?} was limited to {gain:+.2} dB to prevent clipping",
+                    task.input
+                ));
+            }
 
-        let output = args.output.join(path.file_name().unwrap());
-        process_file(path, output.as_path(), &args)?;
+            gains[i] = Some(gain + args.volume_adjustment.unwrap_or(0.0));
+        }
     }
 
-    println!();
-    println!("Done!");
+    let mut processed = 0usize;
+    let to_process = tasks
+        .iter()
+        .zip(gains.iter())
+        .filter_map(|(task, gain)| gain.map(|x| (task, x)))
+        .collect::<Vec<_>>();
+
+    if dry_run {
+        for (task, gain) in &to_process {
+            let command = ffmpeg_args(&task.input, &task.output, task.format, &pipeline, *gain)?
+                .iter()
+                .map(|x| shell_quote(x))
+                .collect::<Vec<_>>()
+                .join(" ");
+            reporter.info(format!("ffmpeg {command}"));
+        }
+
+        reporter.info(format!(
+            "Would process {} files, skip {} existing, {} failed analysis",
+            to_process.len(),
+            existing.len(),
+            failed.len()
+        ));
+
+        for (path, err) in &failed {
+            reporter.event(Event::FileFailed {
+                path: path.to_string_lossy().to_string(),
+                error: format!("{err:#}"),
+            });
+        }
+
+        return Ok(());
+    }
+
+    parallel_map(
+        jobs,
+        &to_process,
+        |(task, gain)| process_file(&task.input, &task.output, task.format, &pipeline, *gain),
+        |i, result| {
+            let (task, gain) = to_process[i];
+            match result {
+                Ok(warnings) => {
+                    processed += 1;
+
+                    // report the applied gain
+                    reporter.event(Event::FileProcessed {
+                        input: task.input.to_string_lossy().to_string(),
+                        output: task.output.to_string_lossy().to_string(),
+                        gain,
+                    });
+                    for warning in warnings {
+                        reporter.warning(format!("{:?}: {warning}", task.output));
+                    }
+                }
+                Err(err) => failed.push((&task.input, err)),
+            }
+
+            reporter.progress("Processing files", i + 1, to_process.len());
+        },
+    );
+    reporter.finish_progress();
+
+    for (path, err) in &failed {
+        reporter.event(Event::FileFailed {
+            path: path.to_string_lossy().to_string(),
+            error: format!("{err:#}"),
+        });
+    }
+
+    reporter.event(Event::Summary {
+        command: "process",
+        unit: "files processed",
+        done: processed,
+        skipped: existing.len(),
+        failed: failed.len(),
+    });
+
+    if !failed.is_empty() {
+        bail!("Failed to process {} files", failed.len());
+    }
 
     Ok(())
 }
@@ -0,0 +1,148 @@
+//! Picking the bitrate so that the output fits into given space
+
+use super::Task;
+use super::pipeline::probe;
+use f32ms::audio::probe_duration;
+use f32ms::prelude::*;
+
+/// Bitrates to try from the best, all of them are valid for MPEG-1 Layer III
+const BITRATES: [u32; 14] = [
+    320, 256, 224, 192, 160, 128, 112, 96, 80, 64, 56, 48, 40, 32,
+];
+
+/// Part of the space kept free for directories, links and container overhead
+const RESERVE: f64 = 0.02;
+
+/// Space the files will take on the card
+pub struct Budget {
+    /// Available space in bytes
+    pub size: u64,
+
+    /// Every file takes a whole number of clusters
+    pub cluster_size: u64,
+}
+
+impl Budget {
+    /// Cluster size assumed when the card is not known, used by FAT32 on most SD cards
+    pub const DEFAULT_CLUSTER_SIZE: u64 = 32 * 1024;
+
+    fn allocated(&self, size: u64) -> u64 {
+        size.div_ceil(self.cluster_size) * self.cluster_size
+    }
+}
+
+/// Returns the highest bitrate (in kbps) at which `tasks` fit together with already processed
+/// `existing` files
+pub fn fit_bitrate(budget: &Budget, tasks: &[Task], existing: &[Task]) -> Result<u32> {
+    // files that are not re-encoded take the same space at any bitrate
+    let mut fixed: Vec<u64> = vec![];
+    for task in existing {
+        let size = std::fs::metadata(&task.output)
+            .with_context(|| anyhow!("Could not read size of {:?}", task.output))?
+            .len();
+        fixed.push(size);
+    }
+
+    let mut durations: Vec<f64> = vec![];
+    for task in tasks {
+        if task.format.is_lossless() {
+            // silence trimming can only make it smaller
+            let size = std::fs::metadata(&task.input)
+                .with_context(|| anyhow!("Could not read size of {:?}", task.input))?
+                .len();
+            fixed.push(size);
+            continue;
+        }
+
+        // not every format stores the duration so ask ffprobe as a fallback
+        let duration = match probe_duration(&task.input).ok().flatten() {
+            Some(x) => x.as_secs_f64(),
+            None => probe(&task.input)?
+                .duration
+                .with_context(|| anyhow!("Could not read duration of {:?}", task.input))?,
+        };
+        durations.push(duration);
+    }
+
+    choose_bitrate(budget, &fixed, &durations)
+}
+
+/// Returns the highest bitrate at which files of `durations` (in seconds) fit together with
+/// files of `fixed` sizes
+fn choose_bitrate(budget: &Budget, fixed: &[u64], durations: &[f64]) -> Result<u32> {
+    let fixed: u64 = fixed.iter().map(|x| budget.allocated(*x)).sum();
+    let available = (budget.size as f64 * (1.0 - RESERVE)) as u64;
+
+    for bitrate in BITRATES {
+        let total = fixed
+            + durations
+                .iter()
+                .map(|x| budget.allocated((x * f64::from(bitrate) * 1000.0 / 8.0) as u64))
+                .sum::<u64>();
+
+        if total <= available {
+            return Ok(bitrate);
+        }
+    }
+
+    bail!(
+        "Files do not fit into {} MiB even at {} kbps",
+        budget.size / 1024 / 1024,
+        BITRATES[BITRATES.len() - 1]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn budget(size: u64) -> Budget {
+        Budget {
+            size,
+            cluster_size: Budget::DEFAULT_CLUSTER_SIZE,
+        }
+    }
+
+    #[test]
+    fn highest_bitrate_that_fits() {
+        // an hour at 320 kbps is about 137 MiB
+        assert_eq!(
+            choose_bitrate(&budget(200 * MIB), &[], &[3600.0]).unwrap(),
+            320
+        );
+        assert_eq!(
+            choose_bitrate(&budget(100 * MIB), &[], &[3600.0]).unwrap(),
+            224
+        );
+
+        // the reserve does not count as free space
+        assert_eq!(
+            choose_bitrate(&budget(137 * MIB + MIB / 2), &[], &[3600.0]).unwrap(),
+            256
+        );
+    }
+
+    #[test]
+    fn fixed_files_take_whole_clusters() {
+        // tiny files take a cluster each
+        let fixed = vec![1; 1300];
+        let durations = [600.0];
+        assert_eq!(
+            choose_bitrate(&budget(64 * MIB), &[], &durations).unwrap(),
+            320
+        );
+        assert_eq!(
+            choose_bitrate(&budget(64 * MIB), &fixed, &durations).unwrap(),
+            256
+        );
+    }
+
+    #[test]
+    fn nothing_fits() {
+        assert!(choose_bitrate(&budget(MIB), &[], &[3600.0]).is_err());
+        assert!(choose_bitrate(&budget(10 * MIB), &[20 * MIB], &[]).is_err());
+        assert_eq!(choose_bitrate(&budget(MIB), &[], &[]).unwrap(), 320);
+    }
+}
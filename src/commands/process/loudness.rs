@@ -0,0 +1,157 @@
+//! Loudness analysis using ffmpeg `ebur128` filter
+
+use f32ms::audio::probe_duration;
+use f32ms::prelude::*;
+use std::path::Path;
+
+/// Gain is limited so that true peak of the output stays below this level (dBTP)
+const MAX_TRUE_PEAK: f64 = -1.0;
+
+/// Loudness of a track or an album
+#[derive(Debug, Clone, Copy)]
+pub struct Loudness {
+    /// Integrated loudness (LUFS)
+    pub integrated: f64,
+
+    /// True peak (dBTP)
+    pub true_peak: f64,
+
+    /// Duration in seconds used to weight tracks when computing album loudness
+    pub duration: f64,
+}
+
+impl Loudness {
+    /// Measures loudness of the file after applying `filters`
+    pub fn analyze(input: &Path, filters: &[String]) -> Result<Self> {
+        let filter = filters
+            .iter()
+            .map(String::as_str)
+            .chain(["ebur128=peak=true:framelog=quiet"])
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let cmd = std::process::Command::new("ffmpeg")
+            .args([
+                "-nostdin",
+                "-hide_banner",
+                "-nostats",
+                "-i",
+                input.to_str().unwrap(),
+                "-map",
+                "a",
+                "-filter:a",
+                &filter,
+                "-f",
+                "null",
+                "-",
+            ])
+            .output()
+            .with_context(|| anyhow!("Could not run ffmpeg"))?;
+
+        if !cmd.status.success() {
+            bail!(
+                "ffmpeg loudness analysis exited with code {:?}",
+                cmd.status.code()
+            )
+        }
+
+        // the summary is printed at the very end so take the last values
+        let stderr = String::from_utf8_lossy(&cmd.stderr);
+        let value = |key: &str| -> Option<f64> {
+            stderr
+                .lines()
+                .rev()
+                .find_map(|x| x.trim().strip_prefix(key))
+                .and_then(|x| x.split_whitespace().next())
+                .and_then(|x| x.parse::<f64>().ok())
+        };
+
+        let integrated =
+            value("I:").with_context(|| anyhow!("Could not parse integrated loudness"))?;
+        let true_peak = value("Peak:").with_context(|| anyhow!("Could not parse true peak"))?;
+
+        // weight of 1 is good enough if the duration is unknown
+        let duration = probe_duration(input)
+            .ok()
+            .flatten()
+            .map(|x| x.as_secs_f64())
+            .unwrap_or(1.0);
+
+        Ok(Self {
+            integrated,
+            true_peak,
+            duration,
+        })
+    }
+
+    /// Combines loudness of multiple tracks by averaging their energy weighted by duration
+    ///
+    /// Returns `None` if there are no tracks or all of them are empty
+    pub fn combine(tracks: &[Self]) -> Option<Self> {
+        let duration: f64 = tracks.iter().map(|x| x.duration).sum();
+        if duration <= 0.0 {
+            return None;
+        }
+
+        let energy: f64 = tracks
+            .iter()
+            .map(|x| x.duration * 10f64.powf(x.integrated / 10.0))
+            .sum::<f64>()
+            / duration;
+
+        Some(Self {
+            integrated: 10.0 * energy.log10(),
+            true_peak: tracks
+                .iter()
+                .map(|x| x.true_peak)
+                .fold(f64::NEG_INFINITY, f64::max),
+            duration,
+        })
+    }
+
+    /// Gain (dB) required to reach `target` (LUFS), limited to prevent clipping
+    ///
+    /// Returns the gain and whether it was limited
+    pub fn gain_to(&self, target: f64) -> (f64, bool) {
+        let gain = target - self.integrated;
+        let max_gain = MAX_TRUE_PEAK - self.true_peak;
+
+        if gain > max_gain {
+            (max_gain, true)
+        } else {
+            (gain, false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(integrated: f64, true_peak: f64, duration: f64) -> Loudness {
+        Loudness {
+            integrated,
+            true_peak,
+            duration,
+        }
+    }
+
+    #[test]
+    fn combine_weights_by_duration() {
+        let album =
+            Loudness::combine(&[track(-20.0, -3.0, 10.0), track(-20.0, -1.0, 30.0)]).unwrap();
+        assert!((album.integrated + 20.0).abs() < 1e-9);
+        assert_eq!(album.true_peak, -1.0);
+        assert_eq!(album.duration, 40.0);
+
+        // the long quiet track dominates
+        let album = Loudness::combine(&[track(-10.0, 0.0, 1.0), track(-30.0, 0.0, 99.0)]).unwrap();
+        assert!(album.integrated < -25.0);
+    }
+
+    #[test]
+    fn combine_without_duration() {
+        assert!(Loudness::combine(&[]).is_none());
+        assert!(Loudness::combine(&[track(-20.0, -1.0, 0.0), track(-18.0, -1.0, 0.0)]).is_none());
+    }
+}
@@ -0,0 +1,368 @@
+//! Configurable processing steps and presets
+
+use crate::cli::{CmdProcess, Normalize};
+use f32ms::audio::AudioFormat;
+use f32ms::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Removal of silence at the start and the end of the track
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SilenceTrim {
+    /// Anything quieter than this is considered silence (in dB)
+    pub threshold: f64,
+
+    /// Minimum duration of non-silence that ends the silence (in seconds)
+    pub duration: f64,
+}
+
+/// Steps applied to each file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pipeline {
+    /// Silence trimming, disabled if not set
+    pub silence: Option<SilenceTrim>,
+
+    /// Fade in duration (in seconds)
+    pub fade_in: Option<f64>,
+
+    /// Fade out duration (in seconds)
+    pub fade_out: Option<f64>,
+
+    /// Loudness normalization mode
+    pub normalize: Normalize,
+
+    /// Target loudness for normalization (in LUFS)
+    pub target_lufs: f64,
+
+    /// Encoder bitrate (in kbps), encoder default if not set
+    pub bitrate: Option<u32>,
+
+    /// MP3 VBR quality from 0 (best) to 9 (smallest), takes priority over bitrate
+    pub vbr_quality: Option<u8>,
+
+    /// Output sample rate (in Hz), same as input if not set
+    pub sample_rate: Option<u32>,
+
+    /// Downmix to a single channel
+    pub mono: bool,
+
+    /// Remove embedded cover art
+    pub strip_cover: bool,
+
+    /// Metadata tags to keep (case insensitive), everything else is removed
+    pub keep_metadata: Vec<String>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::music()
+    }
+}
+
+impl Pipeline {
+    /// Names of presets that are always available
+    pub const BUILTIN_PRESETS: [&str; 2] = ["music", "audiobook"];
+
+    /// Preset for music, trims silence and normalizes each track
+    pub fn music() -> Self {
+        Self {
+            silence: Some(SilenceTrim {
+                threshold: -60.0,
+                duration: 1.0,
+            }),
+            fade_in: None,
+            fade_out: None,
+            normalize: Normalize::Track,
+            target_lufs: -16.0,
+            bitrate: None,
+            vbr_quality: None,
+            sample_rate: None,
+            mono: false,
+            strip_cover: true,
+            keep_metadata: vec![],
+        }
+    }
+
+    /// Preset for audiobooks, speech does not need stereo nor high bitrate and chapters should
+    /// stay recognizable on players that show tags
+    pub fn audiobook() -> Self {
+        Self {
+            silence: Some(SilenceTrim {
+                threshold: -50.0,
+                duration: 0.5,
+            }),
+            fade_in: None,
+            fade_out: None,
+            normalize: Normalize::Album,
+            target_lufs: -18.0,
+            bitrate: Some(64),
+            vbr_quality: None,
+            sample_rate: Some(44100),
+            mono: true,
+            strip_cover: true,
+            keep_metadata: ["title", "album", "artist", "track"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "music" => Some(Self::music()),
+            "audiobook" => Some(Self::audiobook()),
+            _ => None,
+        }
+    }
+
+    /// Default location of the presets file
+    pub fn default_config_path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|x| Path::new(&x).join(".config")))
+            .map(|x| x.join("f32ms").join("presets.json"))
+    }
+
+    /// Loads presets file, a JSON object of preset name to pipeline, fields missing from a preset
+    /// are taken from the music preset
+    fn load_presets(path: &Path) -> Result<BTreeMap<String, Self>> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Could not read presets file {path:?}"))?;
+
+        serde_json::from_str(&data).with_context(|| anyhow!("Invalid presets file {path:?}"))
+    }
+
+    /// Builds the pipeline from selected preset and command line overrides
+    pub fn from_args(args: &CmdProcess) -> Result<Self> {
+        let config_path = args
+            .config
+            .clone()
+            .or_else(|| Self::default_config_path().filter(|x| x.try_exists().unwrap_or(false)));
+
+        let mut presets = match &config_path {
+            Some(path) => Self::load_presets(path)?,
+            None => BTreeMap::new(),
+        };
+
+        // presets in the file take priority over builtin ones
+        let mut pipeline = match presets.remove(&args.preset) {
+            Some(x) => x,
+            None => Self::builtin(&args.preset).with_context(|| {
+                anyhow!(
+                    "Unknown preset {:?}, available presets are {}",
+                    args.preset,
+                    Self::BUILTIN_PRESETS
+                        .iter()
+                        .map(|x| x.to_string())
+                        .chain(presets.into_keys())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?,
+        };
+
+        if args.no_silence_trim {
+            pipeline.silence = None;
+        } else if let Some(threshold) = args.silence_threshold {
+            let duration = pipeline.silence.map(|x| x.duration).unwrap_or(1.0);
+            pipeline.silence = Some(SilenceTrim {
+                threshold,
+                duration,
+            });
+        }
+
+        if let Some(x) = args.fade_in {
+            pipeline.fade_in = Some(x);
+        }
+
+        if let Some(x) = args.fade_out {
+            pipeline.fade_out = Some(x);
+        }
+
+        if let Some(x) = args.normalize {
+            pipeline.normalize = x;
+        }
+
+        if let Some(x) = args.target_lufs {
+            pipeline.target_lufs = x;
+        }
+
+        // explicit bitrate or quality replaces the one from the preset
+        if let Some(x) = args.bitrate {
+            pipeline.bitrate = Some(x);
+            pipeline.vbr_quality = None;
+        }
+
+        if let Some(x) = args.vbr_quality {
+            pipeline.vbr_quality = Some(x);
+        }
+
+        if let Some(x) = args.sample_rate {
+            pipeline.sample_rate = Some(x);
+        }
+
+        if args.mono {
+            pipeline.mono = true;
+        }
+
+        if args.keep_cover {
+            pipeline.strip_cover = false;
+        }
+
+        if let Some(x) = &args.keep_metadata {
+            pipeline.keep_metadata = x.clone();
+        }
+
+        Ok(pipeline)
+    }
+
+    /// Filters applied before the gain, loudness is measured after these
+    pub fn pre_gain_filters(&self) -> Vec<String> {
+        let mut filters = vec![];
+
+        if let Some(silence) = self.silence {
+            let remove = format!(
+                "silenceremove=start_periods=1:start_duration={}:start_threshold={}dB:detection=peak",
+                silence.duration, silence.threshold
+            );
+
+            // remove silence at the start
+            filters.push(remove.clone());
+            filters.push("aformat=dblp".to_string());
+
+            // remove silence at the end
+            filters.push("areverse".to_string());
+            filters.push(remove);
+            filters.push("aformat=dblp".to_string());
+            filters.push("areverse".to_string());
+        }
+
+        filters
+    }
+
+    /// All filters with gain (in dB) applied
+    pub fn filters(&self, gain: f64) -> Vec<String> {
+        let mut filters = self.pre_gain_filters();
+        filters.push(format!("volume={gain}dB"));
+
+        if let Some(x) = self.fade_in {
+            filters.push(format!("afade=t=in:d={x}"));
+        }
+
+        // fade out without knowing the duration
+        if let Some(x) = self.fade_out {
+            filters.push("areverse".to_string());
+            filters.push(format!("afade=t=in:d={x}"));
+            filters.push("areverse".to_string());
+        }
+
+        filters
+    }
+
+    /// Encoder and stream selection arguments
+    pub fn output_args(&self, format: AudioFormat) -> Vec<String> {
+        let mut args = vec![];
+
+        if self.strip_cover {
+            args.extend(["-map", "0:a"].map(String::from));
+        } else {
+            // the cover is optional and it must not be re-encoded
+            args.extend(["-map", "0:a", "-map", "0:v?", "-c:v", "copy"].map(String::from));
+        }
+
+        // lossless formats have no bitrate to choose
+        if !format.is_lossless() {
+            match (self.vbr_quality, self.bitrate) {
+                // only LAME has a meaningful quality scale
+                (Some(x), _) if format == AudioFormat::Mp3 => {
+                    args.extend(["-q:a".to_string(), x.to_string()]);
+                }
+                (_, Some(x)) => args.extend(["-b:a".to_string(), format!("{x}k")]),
+                _ => {}
+            }
+        }
+
+        if let Some(x) = self.sample_rate {
+            args.extend(["-ar".to_string(), x.to_string()]);
+        }
+
+        if self.mono {
+            args.extend(["-ac", "1"].map(String::from));
+        }
+
+        args
+    }
+
+    /// Metadata arguments, reads the tags of the input if any are kept
+    pub fn metadata_args(&self, input: &Path) -> Result<Vec<String>> {
+        // remove metadata (including replay_gain)
+        let mut args = ["-map_metadata", "-1"].map(String::from).to_vec();
+
+        if self.keep_metadata.is_empty() {
+            return Ok(args);
+        }
+
+        for (key, value) in probe(input)?.tags {
+            if self
+                .keep_metadata
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(&key))
+            {
+                args.extend(["-metadata".to_string(), format!("{key}={value}")]);
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Information about the file read by ffprobe
+pub struct Probe {
+    /// Duration in seconds
+    pub duration: Option<f64>,
+
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Reads duration and tags of the file using ffprobe
+pub fn probe(input: &Path) -> Result<Probe> {
+    #[derive(Deserialize)]
+    struct Format {
+        // ffprobe prints numbers as strings
+        duration: Option<String>,
+
+        #[serde(default)]
+        tags: BTreeMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Output {
+        format: Format,
+    }
+
+    let cmd = std::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "quiet",
+            "-print_format",
+            "json",
+            "-show_format",
+            input.to_str().unwrap(),
+        ])
+        .output()
+        .with_context(|| anyhow!("Could not run ffprobe"))?;
+
+    if !cmd.status.success() {
+        bail!("ffprobe exited with code {:?}", cmd.status.code())
+    }
+
+    let output = serde_json::from_slice::<Output>(&cmd.stdout)
+        .with_context(|| anyhow!("Error parsing ffprobe output"))?;
+
+    Ok(Probe {
+        duration: output.format.duration.and_then(|x| x.parse().ok()),
+        tags: output.format.tags,
+    })
+}
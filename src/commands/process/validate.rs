@@ -0,0 +1,374 @@
+//! Validation of processed files before they are moved into place
+
+use f32ms::audio::AudioFormat;
+use f32ms::prelude::*;
+use std::path::Path;
+
+/// Embedded pictures larger than this are known to freeze or crash cheap players
+const MAX_PICTURE_SIZE: usize = 256 * 1024;
+
+/// Peak level (dBFS) at which the output is considered clipped
+const CLIPPING_LEVEL: f64 = -0.01;
+
+/// Bitrates (kbps) of MPEG Layer III by bitrate index, MPEG-1 and MPEG-2/2.5 respectively
+const BITRATES_V1: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// Sample rates of MPEG-1, MPEG-2 has half and MPEG 2.5 quarter of these
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// Checks the file, returns an error if it cannot be decoded and warnings about anything players
+/// may have problems with
+pub fn validate(path: &Path, format: AudioFormat) -> Result<Vec<String>> {
+    let mut warnings = decode(path)?;
+
+    if format == AudioFormat::Mp3 {
+        let data = std::fs::read(path).with_context(|| anyhow!("Could not read {path:?}"))?;
+        warnings.extend(check_mp3(&data)?);
+    }
+
+    Ok(warnings)
+}
+
+/// Decodes the whole file, fails on any decoding error and warns about clipping
+fn decode(path: &Path) -> Result<Vec<String>> {
+    let cmd = std::process::Command::new("ffmpeg")
+        .args([
+            "-nostdin",
+            "-hide_banner",
+            "-nostats",
+            // prefix each line with its level so errors can be told apart
+            "-loglevel",
+            "level+info",
+            "-i",
+            path.to_str().unwrap(),
+            "-map",
+            "0:a",
+            "-filter:a",
+            "volumedetect",
+            "-f",
+            "null",
+            "-",
+        ])
+        .output()
+        .with_context(|| anyhow!("Could not run ffmpeg"))?;
+
+    if !cmd.status.success() {
+        bail!("decoding exited with code {:?}", cmd.status.code())
+    }
+
+    let stderr = String::from_utf8_lossy(&cmd.stderr);
+    let errors = stderr
+        .lines()
+        .filter(|x| x.contains("[error]") || x.contains("[fatal]"))
+        .collect::<Vec<_>>();
+
+    if let Some(first) = errors.first() {
+        bail!("{} decoding errors, first: {first}", errors.len());
+    }
+
+    let mut warnings = vec![];
+
+    let max_volume = stderr
+        .lines()
+        .find_map(|x| x.split_once("max_volume:"))
+        .and_then(|(_, x)| x.split_whitespace().next())
+        .and_then(|x| x.parse::<f64>().ok());
+
+    if let Some(x) = max_volume
+        && x >= CLIPPING_LEVEL
+    {
+        warnings.push(format!(
+            "output peaks at {x:+.2} dBFS and is likely clipped, lower the volume"
+        ));
+    }
+
+    Ok(warnings)
+}
+
+/// Inspects ID3v2 tag and MPEG frame headers
+fn check_mp3(data: &[u8]) -> Result<Vec<String>> {
+    let mut warnings = vec![];
+    let mut offset = 0usize;
+
+    // ID3v2 tag is always at the start
+    if data.len() >= 10 && &data[0..3] == b"ID3" {
+        let version = data[3];
+        let flags = data[5];
+        let size = syncsafe(&data[6..10]);
+        let end = (10 + size).min(data.len());
+
+        if version == 4 && flags & 0x80 != 0 {
+            warnings.push("ID3v2.4 tag uses unsynchronisation".to_string());
+        }
+
+        if let Some(size) = largest_picture(&data[10..end], version, flags)
+            && size > MAX_PICTURE_SIZE
+        {
+            warnings.push(format!("embedded picture is {} KiB", size / 1024));
+        }
+
+        offset = end;
+    }
+
+    let mut first: Option<(u8, u32)> = None;
+    let mut frames = 0usize;
+    let mut lost_sync = 0usize;
+    let mut free_format = false;
+    let mut mixed = false;
+
+    while offset + 4 <= data.len() {
+        // ID3v1 tag at the end
+        if &data[offset..offset + 3] == b"TAG" && data.len() - offset == 128 {
+            break;
+        }
+
+        let Some((version, sample_rate, length)) = frame_header(&data[offset..offset + 4]) else {
+            lost_sync += 1;
+
+            // look for the next frame
+            offset += 1;
+            while offset + 4 <= data.len() && frame_header(&data[offset..offset + 4]).is_none() {
+                offset += 1;
+            }
+            continue;
+        };
+
+        // free format frames have no length in the header
+        let Some(length) = length else {
+            free_format = true;
+            break;
+        };
+
+        match first {
+            None => first = Some((version, sample_rate)),
+            Some(x) if x != (version, sample_rate) => mixed = true,
+            _ => {}
+        }
+
+        frames += 1;
+        offset += length;
+    }
+
+    if free_format {
+        warnings.push("free format bitrate is not supported by most players".to_string());
+    }
+
+    let Some((version, _)) = first else {
+        if free_format {
+            return Ok(warnings);
+        }
+
+        bail!("no MPEG Layer III frames found");
+    };
+
+    if version == 0 {
+        warnings.push("MPEG 2.5 is not supported by most players".to_string());
+    }
+
+    if mixed {
+        warnings.push("frames have different MPEG versions or sample rates".to_string());
+    }
+
+    // a single junk block between tag and audio is common, more means broken stream
+    if lost_sync > 1 {
+        warnings.push(format!(
+            "lost frame sync {lost_sync} times in {frames} frames"
+        ));
+    }
+
+    Ok(warnings)
+}
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0usize, |acc, x| (acc << 7) | usize::from(x & 0x7F))
+}
+
+/// Size of the largest APIC frame in the ID3v2 tag body
+fn largest_picture(tag: &[u8], version: u8, flags: u8) -> Option<usize> {
+    // frames of older versions use 3 character ids, those are not worth parsing
+    if !(3..=4).contains(&version) {
+        return None;
+    }
+
+    let mut offset = 0usize;
+
+    // skip extended header
+    if flags & 0x40 != 0 && tag.len() >= 4 {
+        offset = if version == 4 {
+            syncsafe(&tag[0..4])
+        } else {
+            4 + u32::from_be_bytes(tag[0..4].try_into().unwrap()) as usize
+        };
+    }
+
+    let mut largest: Option<usize> = None;
+    while offset + 10 <= tag.len() {
+        let id = &tag[offset..offset + 4];
+
+        // padding
+        if id[0] == 0 {
+            break;
+        }
+
+        let size = if version == 4 {
+            syncsafe(&tag[offset + 4..offset + 8])
+        } else {
+            u32::from_be_bytes(tag[offset + 4..offset + 8].try_into().unwrap()) as usize
+        };
+
+        if id == b"APIC" {
+            largest = Some(largest.unwrap_or(0).max(size));
+        }
+
+        offset += 10 + size;
+    }
+
+    largest
+}
+
+/// Parses MPEG Layer III frame header, returns version bits, sample rate and frame length (`None`
+/// for free format)
+fn frame_header(header: &[u8]) -> Option<(u8, u32, Option<usize>)> {
+    if header.len() < 4 {
+        return None;
+    }
+
+    // 11 bits of frame sync
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    // 0 = MPEG 2.5, 1 = reserved, 2 = MPEG-2, 3 = MPEG-1
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate_index = usize::from(header[2] >> 4);
+    let sample_rate_index = usize::from((header[2] >> 2) & 0x03);
+    let padding = usize::from((header[2] >> 1) & 0x01);
+
+    // only Layer III is expected
+    if version == 1 || layer != 1 || bitrate_index == 15 || sample_rate_index == 3 {
+        return None;
+    }
+
+    let sample_rate = SAMPLE_RATES[sample_rate_index]
+        >> match version {
+            3 => 0,
+            2 => 1,
+            _ => 2,
+        };
+
+    if bitrate_index == 0 {
+        return Some((version, sample_rate, None));
+    }
+
+    let (bitrate, samples) = if version == 3 {
+        (BITRATES_V1[bitrate_index], 144)
+    } else {
+        (BITRATES_V2[bitrate_index], 72)
+    };
+
+    let length = (samples * bitrate * 1000 / sample_rate) as usize + padding;
+    Some((version, sample_rate, Some(length)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG-1 Layer III, 128 kbps, 44.1 kHz
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+
+    fn frame(header: [u8; 4]) -> Vec<u8> {
+        let (_, _, length) = frame_header(&header).unwrap();
+        let mut data = header.to_vec();
+        data.resize(length.unwrap(), 0);
+        data
+    }
+
+    fn stream(frames: usize) -> Vec<u8> {
+        (0..frames).flat_map(|_| frame(HEADER)).collect()
+    }
+
+    #[test]
+    fn valid_frame() {
+        assert_eq!(frame_header(&HEADER), Some((3, 44100, Some(417))));
+
+        // padding bit adds a byte
+        assert_eq!(
+            frame_header(&[0xFF, 0xFB, 0x92, 0x00]),
+            Some((3, 44100, Some(418)))
+        );
+
+        // MPEG-2, 64 kbps, 22.05 kHz
+        assert_eq!(
+            frame_header(&[0xFF, 0xF3, 0x80, 0x00]),
+            Some((2, 22050, Some(208)))
+        );
+
+        // free format
+        assert_eq!(
+            frame_header(&[0xFF, 0xFB, 0x00, 0x00]),
+            Some((3, 44100, None))
+        );
+
+        assert!(check_mp3(&stream(10)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_sync_word() {
+        assert_eq!(frame_header(&[0xFE, 0xFB, 0x90, 0x00]), None);
+        assert_eq!(frame_header(&[0xFF, 0x1B, 0x90, 0x00]), None);
+
+        // not Layer III
+        assert_eq!(frame_header(&[0xFF, 0xFD, 0x90, 0x00]), None);
+
+        // junk between frames loses the sync every time
+        let mut data = vec![];
+        for _ in 0..3 {
+            data.extend(frame(HEADER));
+            data.extend_from_slice(b"junk");
+        }
+        let warnings = check_mp3(&data).unwrap();
+        assert!(
+            warnings
+                .iter()
+                .any(|x| x.contains("lost frame sync 3 times"))
+        );
+
+        assert!(check_mp3(&[0u8; 1024]).is_err());
+    }
+
+    #[test]
+    fn reserved_indexes() {
+        // reserved version
+        assert_eq!(frame_header(&[0xFF, 0xEB, 0x90, 0x00]), None);
+
+        // bitrate index 15
+        assert_eq!(frame_header(&[0xFF, 0xFB, 0xF0, 0x00]), None);
+
+        // sample rate index 3
+        assert_eq!(frame_header(&[0xFF, 0xFB, 0x9C, 0x00]), None);
+    }
+
+    #[test]
+    fn truncated_buffer() {
+        assert_eq!(frame_header(&HEADER[..3]), None);
+        assert_eq!(frame_header(&[]), None);
+        assert!(check_mp3(&HEADER[..3]).is_err());
+
+        // last frame cut in half is still counted
+        let mut data = stream(3);
+        data.truncate(data.len() - 200);
+        assert!(check_mp3(&data).unwrap().is_empty());
+
+        // tag claiming more data than there is
+        let mut data = b"ID3\x03\x00\x00\x00\x00\x7F\x7F".to_vec();
+        data.extend(stream(2));
+        assert!(check_mp3(&data).is_err());
+    }
+}
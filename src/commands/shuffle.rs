@@ -1,126 +1,117 @@
-use crate::cli::CmdShuffle;
-use crate::util::BlockDevice;
-use crate::{DIRTY_FLAG_FILE, prelude::*};
-use crate::{LINK_DIR, MUSIC_DIR, MUSIC_EXT};
-use fatfs::{FileSystem, FsOptions};
-use fscommon::BufStream;
+use crate::cli::{CardArgs, CmdShuffle};
+use crate::commands::{open_card, open_card_read_only};
+use crate::output::Reporter;
+use f32ms::Card;
+use f32ms::card::{Song, repeat_count};
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+use f32ms::{DIRTY_FLAG_FILE, LINK_DIR, MUSIC_DIR};
+use fatfs::ReadWriteSeek;
 use rand::seq::SliceRandom;
-use std::io::Write;
 use std::time::Duration;
 
-pub fn shuffle(target: BlockDevice, interactive: bool, cmd_args: CmdShuffle) -> Result<()> {
+pub fn shuffle(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+    cmd_args: CmdShuffle,
+) -> Result<()> {
+    if dry_run {
+        let card = open_card_read_only(&target, reporter, card_args)?;
+        let (mut songs, repeat_count) = songs_to_shuffle(&card, reporter, false, &cmd_args)?;
+
+        reporter.info(format!("Would create {DIRTY_FLAG_FILE}"));
+        reporter.info(format!(
+            "Would remove {} old links from {LINK_DIR}/",
+            card.links()?.len()
+        ));
+        reporter.info(format!(
+            "Would create {} links, for example:",
+            repeat_count * songs.len()
+        ));
+
+        // the real order is different every time
+        let mut rng = rand::rng();
+        for repeat_index in 0..repeat_count {
+            songs.shuffle(&mut rng);
+
+            for (i, song) in songs.iter().enumerate() {
+                let index = (repeat_index * songs.len()) + i;
+                reporter.info(format!(
+                    "  {LINK_DIR}/{index}.{} -> {MUSIC_DIR}/{}",
+                    song.format.extension(),
+                    song.name
+                ));
+            }
+        }
+
+        return Ok(());
+    }
+
     if interactive {
-        crate::confirm_prompt(format!(
+        reporter.confirm(format!(
             "Shuffling music on partition {target}, do you wish to proceed?",
         ))?;
     }
 
-    let file = target.open(false)?;
-    let stream = BufStream::new(file);
-    let fs = FileSystem::new(stream, FsOptions::new())?;
-
-    {
-        let root_dir = fs.root_dir();
+    let card = open_card(&target, reporter, interactive, card_args)?;
+    let (songs, repeat_count) = songs_to_shuffle(&card, reporter, interactive, &cmd_args)?;
 
-        let music_dir = root_dir.open_dir(MUSIC_DIR)?;
+    let links_created = card.shuffle(&songs, repeat_count, reporter)?;
+    card.unmount(reporter)?;
 
-        let mut music: Vec<String> = vec![];
-        let mut duration: Duration = Duration::from_secs(0);
+    reporter.event(Event::Summary {
+        command: "shuffle",
+        unit: "links created",
+        done: links_created,
+        skipped: 0,
+        failed: 0,
+    });
 
-        for entry in music_dir.iter().flatten() {
-            if !entry.is_file() {
-                continue;
-            }
+    Ok(())
+}
 
-            let name = entry.file_name();
-            if name.ends_with(&format!(".mp3{MUSIC_EXT}")) || name.ends_with(".mp3") {
-                let mut file = entry.to_file();
-                let dur = mp3_duration::from_read(&mut file)?;
-                music.push(name);
-                duration += dur;
-            }
+/// Songs on the card and how many times they have to repeat
+fn songs_to_shuffle<IO: ReadWriteSeek<Error = std::io::Error>>(
+    card: &Card<IO>,
+    reporter: &Reporter,
+    interactive: bool,
+    cmd_args: &CmdShuffle,
+) -> Result<(Vec<Song>, usize)> {
+    let songs = card.songs()?;
+
+    let Some(repeat_duration) = &cmd_args.repeat_fill else {
+        return Ok((songs, 1));
+    };
+
+    let mut duration: Duration = Duration::from_secs(0);
+    for song in &songs {
+        match card.song_duration(song)? {
+            Some(dur) => duration += dur,
+            None => reporter.warning(format!(
+                "could not determine duration of {:?}, it will not count towards total duration",
+                song.name
+            )),
         }
+    }
 
-        let repeat_count = if let Some(repeat_duration) = cmd_args.repeat_fill {
-            if music.len() < 3 {
-                bail!("Shuffling with repeat_fill requires at least 3 songs!");
-            }
-
-            if duration.as_secs_f64() > repeat_duration.as_secs_f64() {
-                bail!(
-                    "Duration requested is lower than total duration of songs ({} < {})",
-                    repeat_duration,
-                    humantime::format_duration(duration)
-                );
-            }
-
-            println!(
-                "Found {} songs, total duration is {}",
-                music.len(),
-                humantime::format_duration(duration)
-            );
-
-            // NOTE: this will usually overshoot but it does not matter
-            let repeat_count = (repeat_duration.as_secs_f64() / duration.as_secs_f64())
-                .ceil()
-                .round() as usize;
-
-            if interactive {
-                crate::confirm_prompt(format!(
-                    "The songs would repeat {repeat_count} times to achieve duration of at least {}, do you wish to proceed?",
-                    repeat_duration
-                ))?;
-            }
-
-            repeat_count
-        } else {
-            1
-        };
-
-        // basically a flag that the filesystem contains links
-        root_dir.create_file(DIRTY_FLAG_FILE)?;
-
-        let mut rng = rand::rng();
-        let music_len = music.len();
-
-        let link_dir = root_dir.create_dir(LINK_DIR)?;
-
-        // clean the old links before creating new ones
-        {
-            // gather links ignoring any directories
-            let old_links = link_dir
-                .iter()
-                .flatten()
-                .filter(|x| x.is_file())
-                .map(|x| x.file_name())
-                .collect::<Vec<_>>();
-
-            if !old_links.is_empty() {
-                for (i, file_name) in old_links.iter().enumerate() {
-                    link_dir.remove_entry(file_name)?;
-                    print!("\rRemoving old links [{}/{}]", i + 1, old_links.len());
-                    let _ = std::io::stdout().flush();
-                }
-                println!();
-            }
-        }
+    let repeat_count = repeat_count(songs.len(), duration, **repeat_duration)?;
 
-        let total_link_count = repeat_count * music_len;
-        for repeat_index in 0..repeat_count {
-            music.shuffle(&mut rng);
+    reporter.info(format!(
+        "Found {} songs, total duration is {}",
+        songs.len(),
+        humantime::format_duration(duration)
+    ));
 
-            for (i, name) in music.iter().enumerate() {
-                let index = (repeat_index * music_len) + i;
-                print!("\rCreating new links [{}/{total_link_count}]", index + 1);
-                let _ = std::io::stdout().flush();
-                link_dir.create_hardlink(&format!("{}.mp3", index), &music_dir, name)?;
-            }
-        }
+    if interactive {
+        reporter.confirm(format!(
+            "The songs would repeat {repeat_count} times to achieve duration of at least {}, do you wish to proceed?",
+            repeat_duration
+        ))?;
     }
 
-    fs.unmount()?;
-
-    println!("\nDone!");
-
-    Ok(())
+    Ok((songs, repeat_count))
 }
@@ -0,0 +1,53 @@
+use crate::cli::{CardArgs, CmdSort};
+use crate::commands::{open_card, open_card_read_only};
+use crate::output::Reporter;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+use f32ms::{LINK_DIR, MUSIC_DIR};
+
+pub fn sort(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+    args: CmdSort,
+) -> Result<()> {
+    if interactive && !dry_run {
+        reporter.confirm(format!(
+            "Rewriting directories on partition {target}, do you wish to proceed?",
+        ))?;
+    }
+
+    if dry_run {
+        let card = open_card_read_only(&target, reporter, card_args)?;
+        let songs = card.songs()?.len();
+        let links = card.links()?.len();
+
+        if args.no_sort {
+            reporter.info(format!(
+                "Would compact {MUSIC_DIR}/ with {songs} songs and {LINK_DIR}/ with {links} links"
+            ));
+        } else {
+            reporter.info(format!("Would sort {songs} songs in {MUSIC_DIR}/ by name"));
+            reporter.info(format!("Would sort {links} links in {LINK_DIR}/ by index"));
+        }
+
+        return Ok(());
+    }
+
+    let card = open_card(&target, reporter, interactive, card_args)?;
+    let rewritten = card.sort(!args.no_sort, reporter)?;
+    card.unmount(reporter)?;
+
+    reporter.event(Event::Summary {
+        command: "sort",
+        unit: "entries rewritten",
+        done: rewritten,
+        skipped: 0,
+        failed: 0,
+    });
+
+    Ok(())
+}
@@ -0,0 +1,12 @@
+use crate::output::Reporter;
+use f32ms::Card;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+
+pub fn status(target: BlockDevice, reporter: &Reporter) -> Result<()> {
+    let status = Card::open_read_only(&target.path)?.status()?;
+    reporter.event(Event::Status(status));
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use crate::cli::CardArgs;
+use crate::commands::{open_card, open_card_read_only};
+use crate::output::Reporter;
+use f32ms::discard::Discarder;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
+
+pub fn trim(
+    target: BlockDevice,
+    reporter: &Reporter,
+    interactive: bool,
+    dry_run: bool,
+    card_args: &CardArgs,
+) -> Result<()> {
+    if interactive && !dry_run {
+        reporter.confirm(format!(
+            "Discarding free space on partition {target}, deleted files will not be recoverable, do you wish to proceed?",
+        ))?;
+    }
+
+    if dry_run {
+        let card = open_card_read_only(&target, reporter, card_args)?;
+        let free = card.status()?.free_bytes;
+        reporter.info(format!(
+            "Would discard {} MiB of free space",
+            free / 1024 / 1024
+        ));
+        return Ok(());
+    }
+
+    let mut card = open_card(&target, reporter, interactive, card_args)?;
+
+    // the free space is discarded even without --discard
+    card.set_discard(Discarder::open(&target.path)?);
+    let discarded = card.trim()?;
+    card.unmount(reporter)?;
+
+    reporter.event(Event::Summary {
+        command: "trim",
+        unit: "MiB discarded",
+        done: (discarded / 1024 / 1024) as usize,
+        skipped: 0,
+        failed: 0,
+    });
+
+    Ok(())
+}
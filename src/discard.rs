@@ -0,0 +1,86 @@
+//! Telling the card which ranges are no longer used (TRIM)
+//!
+//! The card can erase such ranges in advance instead of copying the stale data around when
+//! rewriting its erase blocks
+
+use crate::error::DeviceError;
+use crate::prelude::*;
+use nix::fcntl::{FallocateFlags, fallocate};
+use std::fs::File;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// BLKDISCARD from linux/fs.h, takes the offset and length in bytes
+nix::ioctl_write_ptr_bad!(blkdiscard, nix::request_code_none!(0x12, 119), [u64; 2]);
+
+/// Discards ranges of a device with `BLKDISCARD` or punches holes into an image file
+///
+/// The first error is kept and nothing is discarded after it, discarding is only an optimization
+/// so the caller decides whether it is worth a warning or an error
+#[derive(Debug)]
+pub struct Discarder {
+    path: String,
+    file: File,
+    block_device: bool,
+    bytes: u64,
+    error: Option<std::io::Error>,
+}
+
+impl Discarder {
+    /// Opens the device or image file, separately from the card as the filesystem owns that one
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| DeviceError(path.to_string_lossy().to_string()))?;
+        let block_device = file.metadata()?.file_type().is_block_device();
+
+        Ok(Self {
+            path: path.to_string_lossy().to_string(),
+            file,
+            block_device,
+            bytes: 0,
+            error: None,
+        })
+    }
+
+    /// Discards `len` bytes at `offset`, does nothing after a failure
+    pub fn discard(&mut self, offset: u64, len: u64) {
+        if self.error.is_some() || len == 0 {
+            return;
+        }
+
+        let result = if self.block_device {
+            // SAFETY: the argument is a valid pointer to the range for the duration of the call
+            unsafe { blkdiscard(self.file.as_raw_fd(), &[offset, len]) }.map(|_| ())
+        } else {
+            // keep the size so the image stays the size of the card
+            fallocate(
+                &self.file,
+                FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                offset as i64,
+                len as i64,
+            )
+        };
+
+        match result {
+            Ok(()) => self.bytes += len,
+            Err(err) => self.error = Some(err.into()),
+        }
+    }
+
+    /// Bytes discarded so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Takes the error that stopped discarding, with the device as context
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        let path = self.path.clone();
+        self.error
+            .take()
+            .map(|err| anyhow!(err).context(DeviceError(path)))
+    }
+}
@@ -0,0 +1,82 @@
+//! Error kinds that map to distinct exit codes
+
+use std::fmt::Display;
+
+/// User declined a prompt or the prompt could not be shown
+#[derive(Debug)]
+pub struct Aborted(pub String);
+
+impl Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Aborted: {}", self.0)
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Context for errors while finding or opening the device
+#[derive(Debug)]
+pub struct DeviceError(pub String);
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not access device {:?}", self.0)
+    }
+}
+
+/// Volume does not have the label or layout created by f32ms
+#[derive(Debug)]
+pub struct UnknownVolume(pub String);
+
+impl Display for UnknownVolume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Refusing to modify volume not created by f32ms, {} (use adopt command or --force)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownVolume {}
+
+/// Exit codes, 2 is used by clap for invalid arguments
+pub mod exit_code {
+    /// Any other error
+    pub const ERROR: u8 = 1;
+
+    /// User declined a prompt or input was required but not allowed
+    pub const ABORTED: u8 = 3;
+
+    /// Device could not be found, opened, read or written
+    pub const DEVICE: u8 = 4;
+
+    /// Filesystem on the device is invalid or in unexpected state
+    pub const FILESYSTEM: u8 = 5;
+}
+
+/// Picks the exit code by the first recognized error in the chain
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    if err.downcast_ref::<Aborted>().is_some() {
+        return exit_code::ABORTED;
+    }
+
+    if err.downcast_ref::<DeviceError>().is_some() {
+        return exit_code::DEVICE;
+    }
+
+    if err.downcast_ref::<UnknownVolume>().is_some() {
+        return exit_code::FILESYSTEM;
+    }
+
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<fatfs::Error<std::io::Error>>() {
+            return match err {
+                fatfs::Error::Io(_) => exit_code::DEVICE,
+                _ => exit_code::FILESYSTEM,
+            };
+        }
+    }
+
+    exit_code::ERROR
+}
@@ -0,0 +1,465 @@
+//! Filesystem layout used when formatting a card
+
+use crate::LABEL;
+use crate::audio::PlayerProfile;
+use crate::card::label_text;
+use crate::prelude::*;
+use clap::ValueEnum;
+use fatfs::{FatType, FormatVolumeOptions, exfat};
+use std::fmt::Display;
+
+/// Cards up to this size are SD (not SDHC) cards which players expect to be FAT16
+const SMALL_CARD_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Bigger cards are SDXC cards which players expect to be exFAT
+const SDHC_CARD_SIZE: u64 = 32 * 1024 * 1024 * 1024;
+
+/// FAT variant of the card
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FatKind {
+    /// For tiny cards up to 256 MiB (with 64 KiB clusters)
+    Fat12,
+
+    /// For cards up to 2 GiB and players that do not support FAT32
+    Fat16,
+    Fat32,
+
+    /// For SDXC cards over 32 GiB, players made for them may not read FAT32
+    #[value(name = "exfat")]
+    ExFat,
+}
+
+impl FatKind {
+    /// FAT type for fatfs, `None` for exFAT which is formatted by its own module
+    fn fat_type(self) -> Option<FatType> {
+        match self {
+            Self::Fat12 => Some(FatType::Fat12),
+            Self::Fat16 => Some(FatType::Fat16),
+            Self::Fat32 => Some(FatType::Fat32),
+            Self::ExFat => None,
+        }
+    }
+
+    /// Range of cluster counts the FAT type must have, the type is determined by the count
+    fn clusters(self) -> std::ops::Range<u64> {
+        match self {
+            Self::Fat12 => 1..4085,
+            Self::Fat16 => 4085..65525,
+            Self::Fat32 => 65525..0x0FFF_FFF5,
+            Self::ExFat => 1..0xFFFF_FFF5,
+        }
+    }
+}
+
+impl From<FatType> for FatKind {
+    fn from(fat_type: FatType) -> Self {
+        match fat_type {
+            FatType::Fat12 => Self::Fat12,
+            FatType::Fat16 => Self::Fat16,
+            FatType::Fat32 => Self::Fat32,
+        }
+    }
+}
+
+impl Display for FatKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Fat12 => "FAT12",
+            Self::Fat16 => "FAT16",
+            Self::Fat32 => "FAT32",
+            Self::ExFat => "exFAT",
+        })
+    }
+}
+
+/// Layouts recommended for cards of different sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum FormatPreset {
+    /// Small preset for cards up to 2 GiB, standard preset up to 32 GiB and exFAT preset
+    /// otherwise
+    #[default]
+    Auto,
+
+    /// FAT16 with 32 KiB clusters, for SD cards up to 2 GiB
+    Small,
+
+    /// FAT32 with 32 KiB clusters, for SDHC and bigger cards
+    Standard,
+
+    /// FAT32 with 64 KiB clusters, less clusters to track on big cards but some players do not
+    /// support clusters bigger than 32 KiB
+    Large,
+
+    /// exFAT with 128 KiB clusters, for SDXC cards over 32 GiB
+    Exfat,
+}
+
+impl FormatPreset {
+    /// FAT type and cluster size of the preset
+    fn layout(self, size: u64) -> (FatKind, u32) {
+        match self {
+            Self::Auto if size <= SMALL_CARD_SIZE => Self::Small.layout(size),
+            Self::Auto if size <= SDHC_CARD_SIZE => Self::Standard.layout(size),
+            Self::Auto => Self::Exfat.layout(size),
+            Self::Small => (FatKind::Fat16, 32 * 1024),
+            Self::Standard => (FatKind::Fat32, 32 * 1024),
+            Self::Large => (FatKind::Fat32, 64 * 1024),
+            Self::Exfat => (FatKind::ExFat, 128 * 1024),
+        }
+    }
+}
+
+/// Format options given by the user, anything not set is taken from the preset
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    pub preset: FormatPreset,
+
+    /// Player the card is for, limits the FAT type and size of the volume
+    pub profile: PlayerProfile,
+    pub fat_type: Option<FatKind>,
+
+    /// Bytes per cluster, must be a power of two
+    pub cluster_size: Option<u32>,
+
+    /// Number of FATs, the second one is a backup, exFAT always has one
+    pub fats: Option<u8>,
+
+    /// Bytes per logical sector, must be a power of two
+    pub sector_size: Option<u16>,
+
+    /// Entries the root directory can hold, it has a fixed size on FAT12 and FAT16
+    pub root_entries: Option<u16>,
+    pub label: Option<[u8; 11]>,
+}
+
+impl FormatOptions {
+    /// Resolves the layout for a card of `size` bytes
+    pub fn layout(&self, size: u64) -> Result<Layout> {
+        // the rest of a bigger card is left unused
+        let volume_size = self.profile.max_volume_size().filter(|max| size > *max);
+        let size = volume_size.unwrap_or(size);
+
+        let preset = match self.preset {
+            FormatPreset::Auto if !self.profile.supports_fat32() => FormatPreset::Small,
+            preset => preset,
+        };
+        let (preset_fat_type, preset_cluster_size) = preset.layout(size);
+        let fat_type = self.fat_type.unwrap_or(preset_fat_type);
+        if matches!(fat_type, FatKind::Fat32 | FatKind::ExFat) && !self.profile.supports_fat32() {
+            bail!(
+                "Player profile {:?} does not support {fat_type}",
+                format!("{:?}", self.profile).to_lowercase()
+            );
+        }
+
+        let sector_size = self.sector_size.unwrap_or(512);
+        if !sector_size.is_power_of_two() || !(512..=4096).contains(&sector_size) {
+            bail!("Sector size must be a power of two between 512 and 4096 bytes");
+        }
+
+        let cluster_size = match self.cluster_size {
+            Some(cluster_size) => cluster_size,
+            // cluster size of the preset may not give a valid number of clusters for the card
+            None => fit_cluster_size(fat_type, size, preset_cluster_size, sector_size),
+        };
+        // exFAT allows clusters of up to 32 MiB
+        let max_cluster_size = match fat_type {
+            FatKind::ExFat => 32 * 1024 * 1024,
+            _ => u32::from(sector_size) * 128,
+        };
+        if !cluster_size.is_power_of_two()
+            || cluster_size < u32::from(sector_size)
+            || cluster_size > max_cluster_size
+        {
+            bail!(
+                "Cluster size must be a power of two between the sector size and {max_cluster_size} bytes"
+            );
+        }
+
+        // the FAT type is determined by the number of clusters so fatfs would refuse the layout
+        let cluster_count = size / u64::from(cluster_size);
+        let clusters = fat_type.clusters();
+        if !clusters.contains(&cluster_count) {
+            bail!(
+                "{fat_type} needs {} to {} clusters but {cluster_size} byte clusters give {cluster_count} on {} MiB, use a different cluster size or FAT type",
+                clusters.start,
+                clusters.end - 1,
+                size / 1024 / 1024
+            );
+        }
+
+        let fats = match fat_type {
+            FatKind::ExFat => self.fats.unwrap_or(1),
+            _ => self.fats.unwrap_or(2),
+        };
+        if !(1..=2).contains(&fats) {
+            bail!("Number of FATs must be 1 or 2");
+        }
+        if fat_type == FatKind::ExFat && fats != 1 {
+            bail!("exFAT can only be formatted with 1 FAT");
+        }
+
+        if let Some(root_entries) = self.root_entries {
+            if matches!(fat_type, FatKind::Fat32 | FatKind::ExFat) {
+                bail!("Root directory size can only be set for FAT12 and FAT16");
+            }
+
+            // the root directory must fill whole sectors
+            let entries_per_sector = sector_size / 32;
+            if root_entries == 0 || root_entries % entries_per_sector != 0 {
+                bail!("Root directory entries must be a multiple of {entries_per_sector}");
+            }
+        }
+
+        Ok(Layout {
+            fat_type,
+            cluster_size: Some(cluster_size),
+            fats,
+            sector_size,
+            root_entries: self.root_entries,
+            volume_size,
+            label: self.label.unwrap_or(LABEL),
+        })
+    }
+}
+
+/// Halves or doubles the cluster size until the card has a valid number of clusters for the FAT
+/// type, leaves some room for the FAT itself
+fn fit_cluster_size(fat_type: FatKind, size: u64, cluster_size: u32, sector_size: u16) -> u32 {
+    let clusters = fat_type.clusters();
+    let mut cluster_size = cluster_size;
+    while cluster_size > u32::from(sector_size)
+        && size / u64::from(cluster_size) < clusters.start * 102 / 100
+    {
+        cluster_size /= 2;
+    }
+    while cluster_size < 64 * 1024 && size / u64::from(cluster_size) >= clusters.end {
+        cluster_size *= 2;
+    }
+    cluster_size
+}
+
+/// Filesystem layout of a card
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub fat_type: FatKind,
+
+    /// Chosen by the size of the card if not set
+    pub cluster_size: Option<u32>,
+    pub fats: u8,
+    pub sector_size: u16,
+
+    /// Entries of the fixed size root directory on FAT12 and FAT16, 512 if not set
+    pub root_entries: Option<u16>,
+
+    /// Size of the volume if only the beginning of the card is used
+    pub volume_size: Option<u64>,
+    pub label: [u8; 11],
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            fat_type: FatKind::Fat32,
+            cluster_size: None,
+            fats: 2,
+            sector_size: 512,
+            root_entries: None,
+            volume_size: None,
+            label: LABEL,
+        }
+    }
+}
+
+impl Layout {
+    /// Options for fatfs, the volume ID is random to distinguish cards that all have the same
+    /// label
+    pub(crate) fn volume_options(&self) -> FormatVolumeOptions {
+        let mut options = FormatVolumeOptions::new();
+        if let Some(fat_type) = self.fat_type.fat_type() {
+            options = options.fat_type(fat_type);
+        }
+        options = options
+            .fats(self.fats)
+            .bytes_per_sector(self.sector_size)
+            .volume_label(self.label)
+            .volume_id(rand::random());
+        if let Some(cluster_size) = self.cluster_size {
+            options = options.bytes_per_cluster(cluster_size);
+        }
+        if let Some(root_entries) = self.root_entries {
+            options = options.max_root_dir_entries(root_entries);
+        }
+        if let Some(volume_size) = self.volume_size {
+            options = options.total_sectors((volume_size / u64::from(self.sector_size)) as u32);
+        }
+        options
+    }
+
+    /// Options for the exFAT module of fatfs
+    pub(crate) fn exfat_volume_options(&self) -> exfat::FormatVolumeOptions {
+        let mut options = exfat::FormatVolumeOptions::new()
+            .bytes_per_sector(self.sector_size)
+            .volume_label(&label_text(&self.label))
+            .volume_id(rand::random());
+        if let Some(cluster_size) = self.cluster_size {
+            options = options.bytes_per_cluster(cluster_size);
+        }
+        if let Some(volume_size) = self.volume_size {
+            options = options.total_sectors(volume_size / u64::from(self.sector_size));
+        }
+        options
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.fat_type)?;
+        if let Some(cluster_size) = self.cluster_size {
+            write!(f, " with {} KiB clusters", f64::from(cluster_size) / 1024.0)?;
+        }
+        write!(f, ", {} FATs, {} byte sectors", self.fats, self.sector_size)?;
+        if let Some(root_entries) = self.root_entries {
+            write!(f, ", {root_entries} root directory entries")?;
+        }
+        if let Some(volume_size) = self.volume_size {
+            write!(f, ", {} MiB volume", volume_size / 1024 / 1024)?;
+        }
+        write!(f, " and label {:?}", label_text(&self.label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn presets_fit_the_card() {
+        let layout = FormatOptions::default().layout(GIB).unwrap();
+        assert_eq!(layout.fat_type, FatKind::Fat16);
+        assert_eq!(layout.cluster_size, Some(32 * 1024));
+
+        // FAT16 with 32 KiB clusters would have too few clusters
+        let layout = FormatOptions::default().layout(64 * 1024 * 1024).unwrap();
+        assert_eq!(layout.cluster_size, Some(8 * 1024));
+
+        let layout = FormatOptions::default().layout(32 * GIB).unwrap();
+        assert_eq!(layout.fat_type, FatKind::Fat32);
+        assert_eq!(layout.cluster_size, Some(32 * 1024));
+
+        // forcing FAT16 on a big card needs bigger clusters
+        let options = FormatOptions {
+            fat_type: Some(FatKind::Fat16),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.layout(3 * GIB).unwrap().cluster_size,
+            Some(64 * 1024)
+        );
+
+        // but even 64 KiB clusters are too many for FAT16 from 4 GiB
+        assert!(options.layout(4 * GIB).is_err());
+    }
+
+    #[test]
+    fn sdxc_cards_get_exfat() {
+        let layout = FormatOptions::default().layout(64 * GIB).unwrap();
+        assert_eq!(layout.fat_type, FatKind::ExFat);
+        assert_eq!(layout.cluster_size, Some(128 * 1024));
+        assert_eq!(layout.fats, 1);
+
+        // FAT32 is still possible for players that do not read exFAT
+        let options = FormatOptions {
+            preset: FormatPreset::Standard,
+            ..Default::default()
+        };
+        assert_eq!(options.layout(64 * GIB).unwrap().fat_type, FatKind::Fat32);
+
+        // exFAT has a single FAT and a root directory that grows
+        let options = |fats, root_entries| FormatOptions {
+            fat_type: Some(FatKind::ExFat),
+            fats,
+            root_entries,
+            ..Default::default()
+        };
+        assert!(options(Some(2), None).layout(64 * GIB).is_err());
+        assert!(options(None, Some(512)).layout(64 * GIB).is_err());
+        assert!(options(None, None).layout(GIB).is_ok());
+
+        let options = FormatOptions {
+            profile: PlayerProfile::Legacy,
+            fat_type: Some(FatKind::ExFat),
+            ..Default::default()
+        };
+        assert!(options.layout(64 * GIB).is_err());
+    }
+
+    #[test]
+    fn cluster_count_must_match_fat_type() {
+        let options = |fat_type, cluster_size| FormatOptions {
+            fat_type: Some(fat_type),
+            cluster_size: Some(cluster_size),
+            ..Default::default()
+        };
+
+        assert!(options(FatKind::Fat32, 32 * 1024).layout(GIB).is_err());
+        assert!(options(FatKind::Fat32, 4096).layout(GIB).is_ok());
+        assert!(options(FatKind::Fat16, 4096).layout(GIB).is_err());
+        assert!(options(FatKind::Fat12, 64 * 1024).layout(GIB).is_err());
+        assert!(
+            options(FatKind::Fat12, 64 * 1024)
+                .layout(128 * 1024 * 1024)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn profile_limits_the_layout() {
+        let options = FormatOptions {
+            profile: PlayerProfile::Legacy,
+            ..Default::default()
+        };
+        let layout = options.layout(8 * GIB).unwrap();
+        assert_eq!(layout.fat_type, FatKind::Fat16);
+        assert_eq!(layout.cluster_size, Some(32 * 1024));
+        assert_eq!(layout.volume_size, Some(2 * GIB - 1024 * 1024));
+
+        let options = FormatOptions {
+            fat_type: Some(FatKind::Fat32),
+            ..options
+        };
+        assert!(options.layout(8 * GIB).is_err());
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        for options in [
+            FormatOptions {
+                cluster_size: Some(3000),
+                ..Default::default()
+            },
+            FormatOptions {
+                cluster_size: Some(1024),
+                sector_size: Some(4096),
+                ..Default::default()
+            },
+            FormatOptions {
+                fats: Some(3),
+                ..Default::default()
+            },
+            FormatOptions {
+                root_entries: Some(100),
+                ..Default::default()
+            },
+            FormatOptions {
+                preset: FormatPreset::Standard,
+                root_entries: Some(128),
+                ..Default::default()
+            },
+        ] {
+            assert!(options.layout(GIB).is_err());
+        }
+    }
+}
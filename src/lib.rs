@@ -0,0 +1,42 @@
+//! Managing music on FAT cards for dumb MP3 players
+//!
+//! Music is stored in `ORIG/` with an extension the player ignores and played through hardlinks
+//! in `LINK/` that are named in shuffled order
+
+pub mod audio;
+pub mod card;
+pub mod discard;
+pub mod error;
+pub mod format;
+pub mod mount;
+pub mod report;
+pub mod text;
+pub mod util;
+mod volume;
+
+pub use card::Card;
+
+pub mod prelude {
+    pub use anyhow::{Context, Result, anyhow, bail};
+}
+
+/// Partition label, something recognizable so you don't mess with it
+pub const LABEL: [u8; 11] = [b'F', b'A', b'T', b'3', b'2', b'M', b'S', 0, 0, 0, 0];
+
+/// Directory containing the original music files
+pub const MUSIC_DIR: &str = "ORIG";
+
+/// Suffix used to prevent player from playing the original music files
+pub const MUSIC_EXT: &str = ".x";
+
+/// Directory that contains all hardlinks
+pub const LINK_DIR: &str = "LINK";
+
+/// File that signifies if the partition is dirty and contains hardlinks
+pub const DIRTY_FLAG_FILE: &str = "DO_NOT_MODIFY";
+
+/// Readme written to every card, it also identifies cards with a custom label
+pub const README_FILE: &str = "README.txt";
+
+/// Journal of the operation in progress, only present if it was interrupted
+pub const JOURNAL_FILE: &str = "JOURNAL.TXT";
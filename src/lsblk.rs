@@ -1,5 +1,6 @@
-use crate::prelude::*;
-use crate::util::BlockDevice;
+use f32ms::error::DeviceError;
+use f32ms::prelude::*;
+use f32ms::util::BlockDevice;
 use serde::Deserialize;
 use std::fmt::Display;
 
@@ -18,6 +19,9 @@ struct BlockDeviceInfo {
 
     pub label: Option<String>,
 
+    /// Volume ID for FAT filesystems, tells apart cards with the same label
+    pub uuid: Option<String>,
+
     #[serde(rename = "rm")]
     pub removable: bool,
 
@@ -61,6 +65,10 @@ impl Display for BlockDeviceInfo {
             write!(f, " {:?}", label.trim())?;
         }
 
+        if let Some(uuid) = self.uuid.as_ref() {
+            write!(f, " {}", uuid.trim())?;
+        }
+
         if let Some(model) = self.model.as_ref() {
             write!(f, " {:?}", model.trim())?;
         }
@@ -71,14 +79,14 @@ impl Display for BlockDeviceInfo {
     }
 }
 
-impl Into<BlockDevice> for &BlockDeviceInfo {
-    fn into(self) -> BlockDevice {
+impl From<&BlockDeviceInfo> for BlockDevice {
+    fn from(info: &BlockDeviceInfo) -> Self {
         BlockDevice {
-            path: self.path.clone(),
-            removable: self.removable,
-            is_partition: self.is_partition(),
-            repr: format!("{}", self),
-            partitions: self
+            path: info.path.clone(),
+            removable: info.removable,
+            is_partition: info.is_partition(),
+            repr: format!("{}", info),
+            partitions: info
                 .children
                 .as_ref()
                 .map(|x| x.iter().map(|y| y.into()).collect()),
@@ -86,9 +94,9 @@ impl Into<BlockDevice> for &BlockDeviceInfo {
     }
 }
 
-impl Into<BlockDevice> for BlockDeviceInfo {
-    fn into(self) -> BlockDevice {
-        Into::<BlockDevice>::into(&self)
+impl From<BlockDeviceInfo> for BlockDevice {
+    fn from(info: BlockDeviceInfo) -> Self {
+        Self::from(&info)
     }
 }
 
@@ -111,10 +119,14 @@ fn query(path: Option<&str>) -> Result<Vec<BlockDeviceInfo>> {
     }
 
     let stdout = String::from_utf8(cmd.stdout)?;
-    Ok(BlockDeviceInfo::parse(&stdout)?)
+    BlockDeviceInfo::parse(&stdout)
 }
 
 pub fn query_block_device(path: &str) -> Result<BlockDevice> {
+    query_block_device_inner(path).with_context(|| DeviceError(path.to_string()))
+}
+
+fn query_block_device_inner(path: &str) -> Result<BlockDevice> {
     if !std::fs::exists(path).unwrap_or(false) {
         bail!("Block device {path:?} does not exist");
     }
@@ -126,9 +138,5 @@ pub fn query_block_device(path: &str) -> Result<BlockDevice> {
 }
 
 pub fn query_all_block_devices() -> Result<Vec<BlockDevice>> {
-    query(None).map(|x| {
-        x.iter()
-            .map(|y| Into::<BlockDevice>::into(y))
-            .collect::<Vec<_>>()
-    })
+    query(None).map(|x| x.iter().map(Into::<BlockDevice>::into).collect::<Vec<_>>())
 }
@@ -1,49 +1,20 @@
 mod cli;
 mod commands;
 mod lsblk;
-mod text;
-mod util;
+mod output;
 
-pub mod prelude {
-    pub use anyhow::{Context, Result, anyhow, bail};
-}
-
-use crate::util::BlockDevice;
+use crate::output::{OutputFormat, Reporter};
 use clap::Parser;
-use prelude::*;
+use f32ms::error::{self, Aborted, DeviceError};
+use f32ms::mount;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use f32ms::util::BlockDevice;
 use std::io::prelude::*;
 
-/// Partition label, something recognizable so you don't mess with it
-const LABEL: [u8; 11] = [b'F', b'A', b'T', b'3', b'2', b'M', b'S', 0, 0, 0, 0];
-
-/// Directory containing the original music files
-const MUSIC_DIR: &str = "ORIG";
-
-/// Suffix used to prevent player from playing the original music files
-const MUSIC_EXT: &str = ".x";
-
-/// Directory that contains all hardlinks
-const LINK_DIR: &str = "LINK";
-
-/// File that signifies if the partition is dirty and contains hardlinks
-const DIRTY_FLAG_FILE: &str = "DO_NOT_MODIFY";
+fn ask_for_target(reporter: &Reporter, no_disk: bool, only_removable: bool) -> Result<BlockDevice> {
+    reporter.ensure_can_prompt("Enter device path", "pass the device path as an argument")?;
 
-fn confirm_prompt(prompt: String) -> Result<()> {
-    print!("{prompt} (y/N): ");
-    std::io::stdout().flush()?;
-
-    let mut buffer = String::new();
-    std::io::stdin().read_line(&mut buffer)?;
-
-    match buffer.trim() {
-        "y" | "Y" | "yes" => {}
-        _ => bail!("User aborted"),
-    }
-
-    Ok(())
-}
-
-fn ask_for_target(no_disk: bool, only_removable: bool) -> Result<BlockDevice> {
     let devices = lsblk::query_all_block_devices()?;
 
     let find_device = |path: &str| -> Option<&BlockDevice> {
@@ -92,7 +63,7 @@ fn ask_for_target(no_disk: bool, only_removable: bool) -> Result<BlockDevice> {
 
         // allow user to abort by entering nothing
         if ans.is_empty() {
-            bail!("User aborted");
+            return Err(Aborted("No device selected".to_string()).into());
         }
 
         if let Some(device) = find_device(&ans) {
@@ -111,47 +82,202 @@ fn ask_for_target(no_disk: bool, only_removable: bool) -> Result<BlockDevice> {
     Ok(device.clone())
 }
 
-fn main() -> Result<()> {
+/// Refuses to write to a mounted device (or a disk with mounted partitions), unless it can unmount
+/// it
+fn ensure_not_mounted(reporter: &Reporter, target: &BlockDevice, unmount: bool) -> Result<()> {
+    let mut paths = vec![target.path.as_str()];
+    if let Some(partitions) = &target.partitions {
+        paths.extend(partitions.iter().map(|x| x.path.as_str()));
+    }
+
+    for path in paths {
+        for mount_point in mount::mount_points(path)? {
+            if !unmount {
+                return Err(anyhow!(
+                    "{path:?} is mounted at {mount_point:?}, unmount it first or use --unmount"
+                )
+                .context(DeviceError(path.to_string())));
+            }
+
+            reporter.info(format!("Unmounting {path:?} from {mount_point:?}"));
+            mount::unmount(&mount_point).with_context(|| DeviceError(path.to_string()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> std::process::ExitCode {
     let args = cli::Cli::parse();
 
+    let reporter = Reporter::new(args.output_format, args.no_input);
+
+    match run(&reporter, args) {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            let exit_code = error::exit_code(&err);
+
+            reporter.finish_progress();
+            if reporter.format() == OutputFormat::Json {
+                reporter.event(Event::Error {
+                    message: format!("{err:#}"),
+                    exit_code,
+                });
+            }
+
+            eprintln!("Error: {err:?}");
+            std::process::ExitCode::from(exit_code)
+        }
+    }
+}
+
+fn run(reporter: &Reporter, args: cli::Cli) -> Result<()> {
+    // confirmations are only skipped when explicitly requested
+    let interactive = !args.yes;
+
     match args.cmd {
-        cli::CliCommands::Format => {
+        cli::CliCommands::Format(x) => {
             let target = if let Some(target) = args.target.as_ref() {
                 crate::lsblk::query_block_device(target)?
             } else {
-                crate::ask_for_target(false, !args.show_all_disks)?
+                crate::ask_for_target(reporter, false, !args.show_all_disks)?
             };
 
-            commands::format(target, true)?;
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::format(target, reporter, interactive, args.dry_run, args.profile, x)?;
         }
         cli::CliCommands::Shuffle(x) => {
             let target = if let Some(target) = args.target.as_ref() {
                 crate::lsblk::query_block_device(target)?
             } else {
-                crate::ask_for_target(true, !args.show_all_disks)?
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
             };
 
-            commands::shuffle(target, true, x)?;
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::shuffle(target, reporter, interactive, args.dry_run, &args.card, x)?;
         }
         cli::CliCommands::Clean(x) => {
             let target = if let Some(target) = args.target.as_ref() {
                 crate::lsblk::query_block_device(target)?
             } else {
-                crate::ask_for_target(true, !args.show_all_disks)?
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
+            };
+
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::clean(target, reporter, interactive, args.dry_run, &args.card, x)?;
+        }
+        cli::CliCommands::Sort(x) => {
+            let target = if let Some(target) = args.target.as_ref() {
+                crate::lsblk::query_block_device(target)?
+            } else {
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
+            };
+
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::sort(target, reporter, interactive, args.dry_run, &args.card, x)?;
+        }
+        cli::CliCommands::Trim => {
+            let target = if let Some(target) = args.target.as_ref() {
+                crate::lsblk::query_block_device(target)?
+            } else {
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
             };
 
-            commands::clean(target, true, x)?;
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::trim(target, reporter, interactive, args.dry_run, &args.card)?;
+        }
+        cli::CliCommands::Adopt => {
+            let target = if let Some(target) = args.target.as_ref() {
+                crate::lsblk::query_block_device(target)?
+            } else {
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
+            };
+
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::adopt(target, reporter, interactive, args.dry_run, &args.card)?;
+        }
+        cli::CliCommands::Status => {
+            let target = if let Some(target) = args.target.as_ref() {
+                crate::lsblk::query_block_device(target)?
+            } else {
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
+            };
+
+            commands::status(target, reporter)?;
+        }
+        cli::CliCommands::Label(x) => {
+            let target = if let Some(target) = args.target.as_ref() {
+                crate::lsblk::query_block_device(target)?
+            } else {
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
+            };
+
+            // only showing the label does not write anything
+            let writes = x.label.is_some() || x.new_id;
+            if writes && !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::label(target, reporter, interactive, args.dry_run, &args.card, x)?;
         }
         cli::CliCommands::Import(x) => {
             let target = if let Some(target) = args.target.as_ref() {
                 crate::lsblk::query_block_device(target)?
             } else {
-                crate::ask_for_target(true, !args.show_all_disks)?
+                crate::ask_for_target(reporter, true, !args.show_all_disks)?
+            };
+
+            if !args.dry_run {
+                ensure_not_mounted(reporter, &target, args.card.unmount)?;
+            }
+
+            commands::import(
+                target,
+                reporter,
+                interactive,
+                args.dry_run,
+                &args.card,
+                args.profile,
+                x,
+            )?;
+        }
+        cli::CliCommands::Process(x) => {
+            // the device is only needed to know how much space there is
+            let target = if !x.fit_card {
+                None
+            } else if let Some(target) = args.target.as_ref() {
+                Some(crate::lsblk::query_block_device(target)?)
+            } else {
+                Some(crate::ask_for_target(reporter, true, !args.show_all_disks)?)
             };
 
-            commands::import(target, true, x)?;
+            commands::process(
+                target,
+                reporter,
+                interactive,
+                args.dry_run,
+                args.profile,
+                *x,
+            )?;
         }
-        cli::CliCommands::Process(x) => commands::process(true, x)?,
     }
 
     Ok(())
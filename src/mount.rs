@@ -0,0 +1,105 @@
+//! Checking whether a device is mounted, writing under a mounted driver corrupts both views
+
+use crate::prelude::*;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Decodes octal escapes used for spaces and other special characters in mountinfo paths
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(pos) = rest.find('\\') {
+        output.push_str(&rest[..pos]);
+
+        let escaped = rest.get(pos + 1..pos + 4).unwrap_or("");
+        match u8::from_str_radix(escaped, 8) {
+            Ok(byte) if escaped.len() == 3 => {
+                output.push(char::from(byte));
+                rest = &rest[pos + 4..];
+            }
+            _ => {
+                output.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Returns mount points with the `major:minor` device number from mountinfo
+fn parse_mountinfo(input: &str, device: &str) -> Vec<String> {
+    input
+        .lines()
+        .filter_map(|line| {
+            // ID, parent ID, major:minor, root, mount point, ...
+            let mut fields = line.split(' ');
+            let dev = fields.nth(2)?;
+            let mount_point = fields.nth(1)?;
+
+            (dev == device).then(|| unescape(mount_point))
+        })
+        .collect()
+}
+
+/// Lists mount points of the block device, empty for anything that is not a block device
+pub fn mount_points(path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let metadata =
+        std::fs::metadata(path).with_context(|| anyhow!("Could not read metadata of {path:?}"))?;
+
+    // images are only mounted through loop devices which are separate block devices
+    if !metadata.file_type().is_block_device() {
+        return Ok(vec![]);
+    }
+
+    let rdev = metadata.rdev();
+    let device = format!(
+        "{}:{}",
+        nix::sys::stat::major(rdev),
+        nix::sys::stat::minor(rdev)
+    );
+
+    let mountinfo = std::fs::read_to_string(MOUNTINFO)
+        .with_context(|| anyhow!("Could not read {MOUNTINFO}"))?;
+
+    Ok(parse_mountinfo(&mountinfo, &device))
+}
+
+/// Unmounts the filesystem at the mount point using `umount`
+pub fn unmount(mount_point: &str) -> Result<()> {
+    let status = std::process::Command::new("umount")
+        .arg(mount_point)
+        .status()
+        .with_context(|| anyhow!("Could not run umount"))?;
+
+    if !status.success() {
+        bail!("Could not unmount {mount_point:?}, umount exited with {status}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mountinfo_matches_device() {
+        let input = "\
+25 28 0:6 / /dev rw,relatime - devtmpfs devtmpfs rw
+90 28 8:17 / /run/media/user/MY\\040STICK rw,nosuid - vfat /dev/sdb1 rw
+91 28 8:17 / /mnt/music rw - vfat /dev/sdb1 rw
+92 28 8:18 / /mnt/other rw - vfat /dev/sdb2 rw";
+
+        assert_eq!(
+            parse_mountinfo(input, "8:17"),
+            ["/run/media/user/MY STICK", "/mnt/music"]
+        );
+        assert!(parse_mountinfo(input, "8:16").is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! Printing of events as human readable text or JSON lines and prompts
+
+use clap::ValueEnum;
+use f32ms::error::Aborted;
+use f32ms::prelude::*;
+use f32ms::report::{Event, Progress};
+use std::cell::Cell;
+use std::io::{IsTerminal, Write};
+
+/// How the output is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text with progress on a single line
+    #[default]
+    Text,
+
+    /// Single JSON object per line, prompts are not possible so `--yes` is required to confirm
+    Json,
+}
+
+/// Prints events and asks for confirmation
+pub struct Reporter {
+    format: OutputFormat,
+    no_input: bool,
+
+    /// Progress line is printed without a newline
+    in_progress: Cell<bool>,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat, no_input: bool) -> Self {
+        Self {
+            format,
+            no_input,
+            in_progress: Cell::new(false),
+        }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Fails if the user cannot be asked, piping answers into stdin is not supported
+    pub fn ensure_can_prompt(&self, prompt: &str, hint: &str) -> Result<()> {
+        let reason = if self.no_input {
+            "--no-input was given"
+        } else if self.format == OutputFormat::Json {
+            "output is JSON"
+        } else if !std::io::stdin().is_terminal() {
+            "stdin is not a terminal"
+        } else {
+            return Ok(());
+        };
+
+        Err(Aborted(format!("{prompt:?} requires input but {reason}, {hint}")).into())
+    }
+
+    /// Asks the user to confirm, anything but yes aborts
+    pub fn confirm(&self, prompt: String) -> Result<()> {
+        match self
+            .ask(&format!("{prompt} (y/N)"), "use --yes to confirm")?
+            .as_str()
+        {
+            "y" | "Y" | "yes" => {}
+            _ => return Err(Aborted("User declined".to_string()).into()),
+        }
+
+        Ok(())
+    }
+
+    /// Reads a single line answer, `hint` tells how to avoid the prompt
+    pub fn ask(&self, prompt: &str, hint: &str) -> Result<String> {
+        self.ensure_can_prompt(prompt, hint)?;
+        self.finish_progress();
+
+        print!("{prompt}: ");
+        std::io::stdout().flush()?;
+
+        let mut buffer = String::new();
+        std::io::stdin().read_line(&mut buffer)?;
+
+        Ok(buffer.trim().to_string())
+    }
+}
+
+impl Progress for Reporter {
+    fn event(&self, event: Event) {
+        match self.format {
+            OutputFormat::Json => {
+                // events only contain strings and numbers
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+            OutputFormat::Text => {
+                let Some(text) = event.text() else {
+                    return;
+                };
+
+                // progress keeps overwriting the same line, anything else overwrites it once
+                if matches!(event, Event::Progress { .. }) {
+                    print!("\r{text}");
+                    let _ = std::io::stdout().flush();
+                    self.in_progress.set(true);
+                } else if self.in_progress.get() {
+                    println!("\r{text}");
+                } else {
+                    println!("{text}");
+                }
+            }
+        }
+    }
+
+    /// Ends the progress line
+    fn finish_progress(&self) {
+        if self.in_progress.replace(false) {
+            println!();
+        }
+    }
+}
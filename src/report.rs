@@ -0,0 +1,229 @@
+//! Events emitted by long running operations
+
+use crate::card::Status;
+use serde::Serialize;
+
+/// Anything worth telling the user
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Info {
+        message: String,
+    },
+    Warning {
+        message: String,
+    },
+
+    /// Progress of a long running stage
+    Progress {
+        stage: &'static str,
+        current: usize,
+        total: usize,
+    },
+
+    /// Files to work on were found
+    ScanComplete {
+        files: usize,
+    },
+
+    FileCopied {
+        source: String,
+        name: String,
+        bytes: u64,
+    },
+
+    /// File already on the card was moved into the music directory
+    FileMoved {
+        path: String,
+        name: String,
+    },
+
+    FileSkipped {
+        path: String,
+        reason: String,
+    },
+
+    FileProcessed {
+        input: String,
+        output: String,
+        gain: f64,
+    },
+
+    FileFailed {
+        path: String,
+        error: String,
+    },
+
+    LinkCreated {
+        name: String,
+        target: String,
+    },
+
+    EntryRemoved {
+        name: String,
+    },
+
+    /// Overview of the card
+    Status(Status),
+
+    /// Storage access of the whole command, emitted when the card is unmounted
+    Storage {
+        cache_hits: u64,
+        cache_misses: u64,
+        bytes_read: u64,
+        bytes_written: u64,
+        writes: u64,
+
+        /// Bytes skipped because they were the same on the card, only in write tracking mode
+        bytes_unchanged: u64,
+
+        /// Bytes of freed clusters the card was told are no longer used, only with discarding
+        bytes_discarded: u64,
+    },
+
+    /// Result of the whole command
+    Summary {
+        command: &'static str,
+        unit: &'static str,
+        done: usize,
+        skipped: usize,
+        failed: usize,
+    },
+
+    /// Command failed, only emitted as JSON
+    Error {
+        message: String,
+        exit_code: u8,
+    },
+}
+
+impl Event {
+    /// Text representation, `None` for events that are too noisy to print
+    pub fn text(&self) -> Option<String> {
+        Some(match self {
+            Self::Info { message } => message.clone(),
+            Self::Warning { message } => format!("Warning: {message}"),
+            Self::Progress {
+                stage,
+                current,
+                total,
+            } => format!("{stage} [{current}/{total}]"),
+            Self::ScanComplete { files } => format!("Found {files} files"),
+            Self::FileSkipped { path, reason } => format!("Skipping {path:?}, {reason}"),
+            Self::FileProcessed { output, gain, .. } => format!("{output:?}: {gain:+.2} dB"),
+            Self::FileFailed { path, error } => format!("Failed {path:?}: {error}"),
+            Self::Summary {
+                unit,
+                done,
+                skipped,
+                failed,
+                ..
+            } => {
+                let mut text = format!("Done! {done} {unit}");
+                if *skipped != 0 {
+                    text += &format!(", {skipped} skipped");
+                }
+                if *failed != 0 {
+                    text += &format!(", {failed} failed");
+                }
+                text
+            }
+            Self::Status(status) => {
+                let mut text = format!(
+                    "Label: {}\nVolume ID: {}\nSongs: {}\nLinks: {}\nFree: {} of {} MiB ({}, cluster size {} KiB)",
+                    status.label,
+                    status.volume_id,
+                    status.songs,
+                    status.links,
+                    status.free_bytes / 1024 / 1024,
+                    status.total_bytes / 1024 / 1024,
+                    status.fat_type,
+                    status.cluster_size / 1024,
+                );
+                if let Some(entries) = status.root_entries {
+                    text += &format!("\nRoot directory can hold at most {entries} entries");
+                }
+                if !status.clean_unmount {
+                    text += "\nLast session did not unmount cleanly, the card was probably unplugged while writing";
+                }
+                if status.io_error {
+                    text += "\nAn I/O error was recorded on the card, it may be failing";
+                }
+                if let Some(operation) = status.interrupted {
+                    text += &format!(
+                        "\nLast {operation} was interrupted, it will be resumed or rolled back by the next command"
+                    );
+                }
+                if status.has_links {
+                    text +=
+                        "\nLinks are present, use clean command before editing the card directly";
+                }
+                text
+            }
+            Self::Storage {
+                cache_hits,
+                cache_misses,
+                bytes_read,
+                bytes_written,
+                writes,
+                bytes_unchanged,
+                bytes_discarded,
+            } => {
+                let accesses = (cache_hits + cache_misses).max(1);
+                let mut text = format!(
+                    "Written {} KiB in {writes} writes, read {} KiB, {}% of accesses served from cache",
+                    bytes_written / 1024,
+                    bytes_read / 1024,
+                    cache_hits * 100 / accesses
+                );
+                if *bytes_unchanged != 0 {
+                    text += &format!(", {} KiB unchanged not rewritten", bytes_unchanged / 1024);
+                }
+                if *bytes_discarded != 0 {
+                    text += &format!(", {} KiB discarded", bytes_discarded / 1024);
+                }
+                text
+            }
+            Self::Error { message, .. } => format!("Error: {message}"),
+            Self::FileCopied { .. }
+            | Self::FileMoved { .. }
+            | Self::LinkCreated { .. }
+            | Self::EntryRemoved { .. } => {
+                return None;
+            }
+        })
+    }
+}
+
+/// Receives events from long running operations
+pub trait Progress {
+    fn event(&self, event: Event);
+
+    /// Called after the last progress event of a stage
+    fn finish_progress(&self) {}
+
+    fn info(&self, message: impl Into<String>) {
+        self.event(Event::Info {
+            message: message.into(),
+        });
+    }
+
+    fn warning(&self, message: impl Into<String>) {
+        self.event(Event::Warning {
+            message: message.into(),
+        });
+    }
+
+    fn progress(&self, stage: &'static str, current: usize, total: usize) {
+        self.event(Event::Progress {
+            stage,
+            current,
+            total,
+        });
+    }
+}
+
+/// Ignores all events
+impl Progress for () {
+    fn event(&self, _event: Event) {}
+}
@@ -1,3 +1,4 @@
+use crate::audio::AudioFormat;
 use std::{fmt::Display, path::PathBuf};
 
 #[derive(Debug, Clone)]
@@ -25,30 +26,76 @@ impl Display for BlockDevice {
     }
 }
 
-impl BlockDevice {
-    pub fn open(&self, readonly: bool) -> std::io::Result<std::fs::File> {
-        std::fs::OpenOptions::new()
-            .read(true)
-            .write(!readonly)
-            .open(&self.path)
-    }
-}
-
-pub fn find_mp3_files(vec: &mut Vec<PathBuf>, path: PathBuf) -> std::io::Result<()> {
+pub fn find_audio_files(vec: &mut Vec<PathBuf>, path: PathBuf) -> std::io::Result<()> {
     if path.is_dir() {
         let paths = std::fs::read_dir(&path)?;
         for path_result in paths {
             let full_path = path_result?.path();
-            find_mp3_files(vec, full_path)?;
-        }
-    } else {
-        // only collect MP3 files
-        if let Some(ext) = path.extension() {
-            if ext == "mp3" {
-                vec.push(path);
-            }
+            find_audio_files(vec, full_path)?;
         }
+    } else if AudioFormat::from_path(&path).is_some() {
+        // only collect known audio files
+        vec.push(path);
     }
 
     Ok(())
 }
+
+/// Parses size in bytes with optional binary suffix (K, M, G, T), like `4G` or `512MiB`
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let number_end = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, suffix) = input.split_at(number_end);
+
+    // only digits with an optional fraction like `1.5`
+    if number.matches('.').count() > 1 || !number.contains(|c: char| c.is_ascii_digit()) {
+        return Err(format!("invalid size {input:?}"));
+    }
+
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid size {input:?}"))?;
+
+    let suffix = suffix.trim().to_ascii_uppercase();
+    let exponent = match suffix.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(format!("invalid size suffix {suffix:?}")),
+    };
+
+    Ok((number * 1024f64.powi(exponent)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_with_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("4G"), Ok(4 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("512MiB"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size(" 2 mb "), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1T"), Ok(1024u64.pow(4)));
+    }
+
+    #[test]
+    fn fractional_sizes() {
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+        assert_eq!(parse_size(".5M"), Ok(512 * 1024));
+        assert_eq!(parse_size("2.G"), Ok(2 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        for input in ["", ".", "G", "1.2.3G", "-1G", "1X", "1GG", "1e3"] {
+            assert!(parse_size(input).is_err(), "{input:?} was accepted");
+        }
+    }
+}
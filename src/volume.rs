@@ -0,0 +1,331 @@
+//! Filesystem of a card, either FAT or exFAT, with the operations f32ms needs
+//!
+//! fatfs has separate types for exFAT so every type here dispatches to one of them
+
+use crate::card::label_text;
+use crate::format::FatKind;
+use fatfs::{
+    DefaultTimeProvider, Error, FileSystemStats, FsOptions, FsStatusFlags, LossyOemCpConverter,
+    ReadWriteSeek, exfat,
+};
+use std::cell::Ref;
+
+type Tp = DefaultTimeProvider;
+type Occ = LossyOemCpConverter;
+type Result<T, IO> = std::result::Result<T, Error<<IO as fatfs::IoBase>::Error>>;
+
+/// Runs the same expression on the FAT or exFAT value
+macro_rules! dispatch {
+    ($value:expr, $x:ident => $body:expr) => {
+        match $value {
+            Self::Fat($x) => $body,
+            Self::ExFat($x) => $body,
+        }
+    };
+}
+
+/// Mounted FAT or exFAT filesystem
+pub enum Volume<IO: ReadWriteSeek> {
+    Fat(fatfs::FileSystem<IO, Tp, Occ>),
+    ExFat(exfat::FileSystem<IO, Tp>),
+}
+
+pub enum Dir<'a, IO: ReadWriteSeek> {
+    Fat(fatfs::Dir<'a, IO, Tp, Occ>),
+    ExFat(exfat::Dir<'a, IO, Tp>),
+}
+
+pub enum DirEntry<'a, IO: ReadWriteSeek> {
+    Fat(fatfs::DirEntry<'a, IO, Tp, Occ>),
+    ExFat(exfat::DirEntry<'a, IO, Tp>),
+}
+
+pub enum DirIter<'a, IO: ReadWriteSeek> {
+    Fat(fatfs::DirIter<'a, IO, Tp, Occ>),
+    ExFat(exfat::DirIter<'a, IO, Tp>),
+}
+
+pub enum File<'a, IO: ReadWriteSeek> {
+    Fat(fatfs::File<'a, IO, Tp, Occ>),
+    ExFat(exfat::File<'a, IO, Tp>),
+}
+
+/// Checks the filesystem name in the boot sector, fatfs only reports exFAT as corrupted FAT
+pub fn is_exfat<IO: fatfs::ReadSeek>(storage: &mut IO) -> Result<bool, IO> {
+    let mut name = [0u8; 8];
+    storage.seek(fatfs::SeekFrom::Start(3))?;
+    fatfs::Read::read_exact(storage, &mut name)?;
+    storage.seek(fatfs::SeekFrom::Start(0))?;
+    Ok(&name == b"EXFAT   ")
+}
+
+impl<IO: ReadWriteSeek> Volume<IO> {
+    /// Mounts the filesystem, the storage must be at its start
+    pub fn new(mut storage: IO, options: FsOptions<Tp, Occ>) -> Result<Self, IO> {
+        Ok(if is_exfat(&mut storage)? {
+            Self::ExFat(exfat::FileSystem::new(storage, options)?)
+        } else {
+            Self::Fat(fatfs::FileSystem::new(storage, options)?)
+        })
+    }
+
+    pub fn root_dir(&self) -> Dir<'_, IO> {
+        match self {
+            Self::Fat(fs) => Dir::Fat(fs.root_dir()),
+            Self::ExFat(fs) => Dir::ExFat(fs.root_dir()),
+        }
+    }
+
+    pub fn fat_kind(&self) -> FatKind {
+        match self {
+            Self::Fat(fs) => FatKind::from(fs.fat_type()),
+            Self::ExFat(_) => FatKind::ExFat,
+        }
+    }
+
+    /// Entries the fixed size root directory of FAT12 and FAT16 can hold
+    pub fn max_root_dir_entries(&self) -> Option<u16> {
+        match self {
+            Self::Fat(fs) => fs.max_root_dir_entries(),
+            Self::ExFat(_) => None,
+        }
+    }
+
+    pub fn volume_label(&self) -> String {
+        dispatch!(self, fs => fs.volume_label())
+    }
+
+    /// Sets the label given in the padded FAT form
+    pub fn set_volume_label(&mut self, label: [u8; 11]) -> Result<(), IO> {
+        match self {
+            Self::Fat(fs) => fs.set_volume_label(label),
+            Self::ExFat(fs) => fs.set_volume_label(&label_text(&label)),
+        }
+    }
+
+    pub fn volume_id(&self) -> u32 {
+        dispatch!(self, fs => fs.volume_id())
+    }
+
+    pub fn set_volume_id(&mut self, volume_id: u32) -> Result<(), IO> {
+        dispatch!(self, fs => fs.set_volume_id(volume_id))
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        dispatch!(self, fs => fs.cluster_size())
+    }
+
+    pub fn stats(&self) -> Result<FileSystemStats, IO> {
+        match self {
+            Self::Fat(fs) => fs.stats(),
+            Self::ExFat(fs) => Ok(fs.stats()),
+        }
+    }
+
+    pub fn read_status_flags(&self) -> Result<FsStatusFlags, IO> {
+        match self {
+            Self::Fat(fs) => fs.read_status_flags(),
+            Self::ExFat(fs) => Ok(fs.read_status_flags()),
+        }
+    }
+
+    pub fn clear_status_flags(&mut self) -> Result<(), IO> {
+        dispatch!(self, fs => fs.clear_status_flags())
+    }
+
+    pub fn storage(&self) -> Ref<'_, IO> {
+        dispatch!(self, fs => fs.storage())
+    }
+
+    pub fn set_discard_hook<F: FnMut(u64, u64) + Send + 'static>(&mut self, hook: F) {
+        dispatch!(self, fs => fs.set_discard_hook(hook));
+    }
+
+    pub fn discard_freed_clusters(&self) -> Result<u64, IO> {
+        dispatch!(self, fs => fs.discard_freed_clusters())
+    }
+
+    pub fn discard_free_space(&self) -> Result<u64, IO> {
+        dispatch!(self, fs => fs.discard_free_space())
+    }
+
+    pub fn flush(&self) -> Result<(), IO> {
+        dispatch!(self, fs => fs.flush())
+    }
+
+    pub fn unmount(self) -> Result<(), IO> {
+        dispatch!(self, fs => fs.unmount())
+    }
+}
+
+impl<'a, IO: ReadWriteSeek> Dir<'a, IO> {
+    pub fn iter(&self) -> DirIter<'a, IO> {
+        match self {
+            Self::Fat(dir) => DirIter::Fat(dir.iter()),
+            Self::ExFat(dir) => DirIter::ExFat(dir.iter()),
+        }
+    }
+
+    pub fn open_dir(&self, path: &str) -> Result<Self, IO> {
+        Ok(match self {
+            Self::Fat(dir) => Self::Fat(dir.open_dir(path)?),
+            Self::ExFat(dir) => Self::ExFat(dir.open_dir(path)?),
+        })
+    }
+
+    pub fn open_file(&self, path: &str) -> Result<File<'a, IO>, IO> {
+        Ok(match self {
+            Self::Fat(dir) => File::Fat(dir.open_file(path)?),
+            Self::ExFat(dir) => File::ExFat(dir.open_file(path)?),
+        })
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<Self, IO> {
+        Ok(match self {
+            Self::Fat(dir) => Self::Fat(dir.create_dir(path)?),
+            Self::ExFat(dir) => Self::ExFat(dir.create_dir(path)?),
+        })
+    }
+
+    pub fn create_file(&self, path: &str) -> Result<File<'a, IO>, IO> {
+        Ok(match self {
+            Self::Fat(dir) => File::Fat(dir.create_file(path)?),
+            Self::ExFat(dir) => File::ExFat(dir.create_file(path)?),
+        })
+    }
+
+    pub fn remove(&self, path: &str) -> Result<(), IO> {
+        dispatch!(self, dir => dir.remove(path))
+    }
+
+    /// Removes the entry without freeing its clusters, for hardlinks
+    pub fn remove_entry(&self, path: &str) -> Result<(), IO> {
+        dispatch!(self, dir => dir.remove_entry(path))
+    }
+
+    pub fn rename(&self, src_path: &str, dst_dir: &Self, dst_path: &str) -> Result<(), IO> {
+        match (self, dst_dir) {
+            (Self::Fat(dir), Self::Fat(dst_dir)) => dir.rename(src_path, dst_dir, dst_path),
+            (Self::ExFat(dir), Self::ExFat(dst_dir)) => dir.rename(src_path, dst_dir, dst_path),
+            _ => unreachable!("directories of different filesystems"),
+        }
+    }
+
+    pub fn create_hardlink(&self, path: &str, target_dir: &Self, target: &str) -> Result<(), IO> {
+        match (self, target_dir) {
+            (Self::Fat(dir), Self::Fat(target_dir)) => {
+                dir.create_hardlink(path, target_dir, target)
+            }
+            (Self::ExFat(dir), Self::ExFat(target_dir)) => {
+                dir.create_hardlink(path, target_dir, target)
+            }
+            _ => unreachable!("directories of different filesystems"),
+        }
+    }
+
+    pub fn compact(&self) -> Result<(), IO> {
+        dispatch!(self, dir => dir.compact())
+    }
+
+    pub fn sort_by_key<K: Ord, F: FnMut(&DirEntry<'a, IO>) -> K>(
+        &self,
+        mut f: F,
+    ) -> Result<(), IO> {
+        match self {
+            Self::Fat(dir) => dir.sort_by_key(|entry| f(&DirEntry::Fat(entry.clone()))),
+            Self::ExFat(dir) => dir.sort_by_key(|entry| f(&DirEntry::ExFat(entry.clone()))),
+        }
+    }
+
+    pub fn raw_entries(&self) -> Result<Vec<u8>, IO> {
+        dispatch!(self, dir => dir.raw_entries())
+    }
+
+    pub fn set_raw_entries(&self, data: &[u8]) -> Result<(), IO> {
+        dispatch!(self, dir => dir.set_raw_entries(data))
+    }
+}
+
+impl<IO: ReadWriteSeek> Clone for Dir<'_, IO> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Fat(dir) => Self::Fat(dir.clone()),
+            Self::ExFat(dir) => Self::ExFat(dir.clone()),
+        }
+    }
+}
+
+impl<IO: ReadWriteSeek> DirEntry<'_, IO> {
+    pub fn file_name(&self) -> String {
+        dispatch!(self, entry => entry.file_name())
+    }
+
+    pub fn is_dir(&self) -> bool {
+        dispatch!(self, entry => entry.is_dir())
+    }
+
+    pub fn is_file(&self) -> bool {
+        dispatch!(self, entry => entry.is_file())
+    }
+
+    pub fn len(&self) -> u64 {
+        dispatch!(self, entry => entry.len())
+    }
+
+    pub fn first_cluster(&self) -> Option<u32> {
+        dispatch!(self, entry => entry.first_cluster())
+    }
+
+    pub fn cluster_count(&self) -> Result<u32, IO> {
+        dispatch!(self, entry => entry.cluster_count())
+    }
+}
+
+impl<'a, IO: ReadWriteSeek> Iterator for DirIter<'a, IO> {
+    type Item = Result<DirEntry<'a, IO>, IO>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Fat(iter) => iter.next().map(|x| x.map(DirEntry::Fat)),
+            Self::ExFat(iter) => iter.next().map(|x| x.map(DirEntry::ExFat)),
+        }
+    }
+}
+
+impl<IO: ReadWriteSeek> File<'_, IO> {
+    /// Truncates the file at the current position
+    pub fn truncate(&mut self) -> Result<(), IO> {
+        dispatch!(self, file => file.truncate())
+    }
+}
+
+impl<IO: ReadWriteSeek> std::io::Read for File<'_, IO>
+where
+    std::io::Error: From<Error<IO::Error>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        dispatch!(self, file => std::io::Read::read(file, buf))
+    }
+}
+
+impl<IO: ReadWriteSeek> std::io::Write for File<'_, IO>
+where
+    std::io::Error: From<Error<IO::Error>>,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        dispatch!(self, file => std::io::Write::write(file, buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        dispatch!(self, file => std::io::Write::flush(file))
+    }
+}
+
+impl<IO: ReadWriteSeek> std::io::Seek for File<'_, IO>
+where
+    std::io::Error: From<Error<IO::Error>>,
+{
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        dispatch!(self, file => std::io::Seek::seek(file, pos))
+    }
+}